@@ -0,0 +1,2 @@
+ALTER TABLE `user`
+    ADD COLUMN `public_key` varchar(256) NOT NULL DEFAULT '';
@@ -0,0 +1,23 @@
+CREATE TABLE IF NOT EXISTS `identity_key`
+(
+    `id`                 int(11) NOT NULL AUTO_INCREMENT,
+    `address`            varchar(256) NOT NULL DEFAULT '',
+    `public_key`         varchar(256) NOT NULL DEFAULT '',
+    `identity_key`       varchar(256) NOT NULL DEFAULT '',
+    `identity_key_sign`  varchar(256) NOT NULL DEFAULT '',
+    `signed_prekey_id`   int(11) unsigned NOT NULL DEFAULT 0,
+    `signed_prekey`      varchar(256) NOT NULL DEFAULT '',
+    `signed_prekey_sign` varchar(256) NOT NULL DEFAULT '',
+    PRIMARY KEY (`id`),
+    UNIQUE KEY `address_UNIQUE` (`address`)
+) ENGINE=InnoDB DEFAULT CHARSET=utf8mb4;
+
+CREATE TABLE IF NOT EXISTS `one_time_prekey`
+(
+    `id`      int(11) NOT NULL AUTO_INCREMENT,
+    `address` varchar(256) NOT NULL DEFAULT '',
+    `key_id`  int(11) unsigned NOT NULL DEFAULT 0,
+    `prekey`  varchar(256) NOT NULL DEFAULT '',
+    PRIMARY KEY (`id`),
+    UNIQUE KEY `address_key_id_UNIQUE` (`address`, `key_id`)
+) ENGINE=InnoDB DEFAULT CHARSET=utf8mb4;
@@ -0,0 +1,2 @@
+ALTER TABLE `user`
+    ADD COLUMN `session_expire_ms` bigint(20) unsigned NOT NULL DEFAULT 0;
@@ -0,0 +1,17 @@
+CREATE TABLE IF NOT EXISTS `device`
+(
+    `id`                int(11) NOT NULL AUTO_INCREMENT,
+    `address`           varchar(256) NOT NULL DEFAULT '',
+    `device_id`         varchar(256) NOT NULL DEFAULT '',
+    `session`           varchar(256) NOT NULL DEFAULT '',
+    `secret`            varchar(256) NOT NULL DEFAULT '',
+    `session_expire_ms` bigint(20) unsigned NOT NULL DEFAULT 0,
+    `last_seen_ms`      bigint(20) unsigned NOT NULL DEFAULT 0,
+    PRIMARY KEY (`id`),
+    UNIQUE KEY `device_id_UNIQUE` (`device_id`),
+    KEY `address_INDEX` (`address`),
+    KEY `session_INDEX` (`session`)
+) ENGINE=InnoDB DEFAULT CHARSET=utf8mb4;
+
+INSERT IGNORE INTO `device` (`address`, `device_id`, `session`, `secret`, `session_expire_ms`)
+SELECT `address`, `device_id`, `session`, `secret`, `session_expire_ms` FROM `user` WHERE `session` <> '';
@@ -0,0 +1,5 @@
+-- Devices are told apart per account, the same device_id may come back on another one
+ALTER TABLE `device`
+    DROP INDEX `device_id_UNIQUE`,
+    DROP INDEX `address_INDEX`,
+    ADD UNIQUE KEY `address_device_id_UNIQUE` (`address`, `device_id`);
@@ -5,7 +5,6 @@ use tokio::{fs::File, io::AsyncReadExt};
 use crate::{web_server::WebServerConfig, p2p::client::P2PConfig};
 
 const PORT: u16 = 8085;
-const TCP_PORT: &str = "7000";
 const TCP_SERVER_HOST: &str = "127.0.0.1";
 const TCP_SERVER_PORT: &str = "6000";
 const SERVER_HOST: &str = "http://127.0.0.1:28100";
@@ -32,9 +31,10 @@ impl Default for Config {
         let web_server = WebServerConfig {
             port: PORT,
             server_host: SERVER_HOST.to_string(),
+            server_public_key: None,
+            allow_unsigned_server: false,
         };
         let p2p = P2PConfig {
-            local_port: TCP_PORT.to_string(),
             server_port: TCP_SERVER_PORT.to_string(),
             server_host: TCP_SERVER_HOST.to_string(),
             client_name: CLIENT_NAME.to_string(),
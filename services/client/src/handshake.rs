@@ -0,0 +1,396 @@
+use std::sync::Arc;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use tokio::spawn;
+use tokio::sync::Mutex;
+use tokio::time::sleep;
+use uuid::Uuid;
+use common::beans::{DeviceInfoRequest, DeviceInfoResponse, LinkedDevice, ListDevicesRequest, RemoveDeviceRequest, RevokeSessionRequest, SESSION_PROTOCOL_LEGACY, SESSION_PROTOCOL_SIGNED};
+use common::errors::{HTTP_ERROR, INVALID_DEVICE_ID, INVALID_DH_ERROR, NavajoError, NavajoResult, UNTRUSTED_SERVER_KEY, VERIFY_SIGN_ERROR};
+use ncrypto::algo::diffie_hellman::DiffieHellman;
+use ncrypto::algo::kdf::{SessionKeys, SessionTranscript};
+use crate::http::HttpClient;
+use crate::prekey::PrekeyManager;
+use crate::session::SessionClient;
+
+const SESSION_CHECK_INTERVAL_SECONDS: u64 = 60;
+/// Sessions are renewed this long before the server stops accepting them.
+const SESSION_RENEW_MARGIN_MS: u128 = 24 * 60 * 60 * 1000; // 1 day
+
+/// Runs the session handshake with the server, and again before the session expires
+/// or once the server rejected it.
+pub struct SessionManager {
+    session_client: Arc<SessionClient>,
+    http_client: Arc<HttpClient>,
+    prekey_manager: Arc<PrekeyManager>,
+    device_id: String,
+    server_host: String,
+    // Pinned server identity key. Without it the first key seen is trusted.
+    server_public_key: Option<String>,
+    // Old servers have no identity key, only accepted unsigned when configured so
+    allow_unsigned_server: bool,
+    // Concurrent handshakes would overwrite each other's session
+    lock: Mutex<()>,
+}
+
+impl SessionManager {
+    pub fn new(
+        session_client: Arc<SessionClient>,
+        http_client: Arc<HttpClient>,
+        prekey_manager: Arc<PrekeyManager>,
+        device_id: String,
+        server_host: String,
+        server_public_key: Option<String>,
+        allow_unsigned_server: bool,
+    ) -> Arc<Self> {
+        Arc::new(Self {
+            session_client,
+            http_client,
+            prekey_manager,
+            device_id,
+            server_host,
+            server_public_key,
+            allow_unsigned_server,
+            lock: Mutex::new(()),
+        })
+    }
+
+    pub fn start(self: Arc<Self>) {
+        spawn(async move {
+            loop {
+                if let Err(err) = self.ensure_session().await {
+                    println!("Establish session failed, {}", err);
+                }
+                sleep(Duration::from_secs(SESSION_CHECK_INTERVAL_SECONDS)).await;
+            }
+        });
+    }
+
+    /// Handshakes when there is an account but no session, or one about to expire.
+    pub async fn ensure_session(&self) -> NavajoResult<()> {
+        let _guard = self.lock.lock().await;
+        if self.session_client.get_device_account(&self.device_id).await.is_none() {
+            return Ok(());
+        }
+        if self.session_client.get_session(&self.device_id).await.is_some() {
+            // Sessions stored before expiry was tracked are renewed right away
+            let expire_ms = self.session_client.get_session_expire(&self.device_id).await.unwrap_or(0);
+            if !needs_renewal(expire_ms, now_ms()) {
+                return Ok(());
+            }
+        }
+        self.handshake().await.map(|_| ())
+    }
+
+    /// Handshakes unconditionally, returns the new session and its secret.
+    pub async fn create_session(&self) -> NavajoResult<(String, String)> {
+        let _guard = self.lock.lock().await;
+        self.handshake().await
+    }
+
+    /// The server rejected `session`. Handshakes again unless it has been replaced already.
+    pub async fn renew_rejected(&self, session: &str) -> NavajoResult<()> {
+        let _guard = self.lock.lock().await;
+        if self.session_client.get_session(&self.device_id).await.as_deref() != Some(session) {
+            return Ok(());
+        }
+        println!("Session rejected by server, handshake again");
+        self.handshake().await.map(|_| ())
+    }
+
+    /// Forgets the account and its session, after asking the server to revoke it.
+    /// Local state is dropped even when the server can't be reached.
+    pub async fn logout(&self) {
+        let _guard = self.lock.lock().await;
+        if let Err(err) = self.revoke_session().await {
+            println!("Revoke session failed, {}", err);
+        }
+        self.forget_account().await;
+    }
+
+    /// Devices linked to the account, this one included.
+    pub async fn list_devices(&self) -> NavajoResult<Vec<LinkedDevice>> {
+        let account = self.session_client.get_device_account(&self.device_id)
+            .await.ok_or_else(|| NavajoError::new(INVALID_DEVICE_ID))?;
+        let mut request = ListDevicesRequest {
+            address: account.address.to_string(),
+            public_key: account.key_pair.gen_public_key(),
+            nonce: Uuid::new_v4().to_string(),
+            time_ms: now_ms(),
+            sign: String::new(),
+        };
+        request.sign = account.sign_data(&request.transcript());
+        let response = self.http_client.list_devices(&request)
+            .await.map_err(|_| NavajoError::new(HTTP_ERROR))?;
+        Ok(response.devices)
+    }
+
+    /// Unlinks a device of the account. Removing this device logs it out.
+    pub async fn remove_device(&self, device_id: &str) -> NavajoResult<()> {
+        let _guard = self.lock.lock().await;
+        let account = self.session_client.get_device_account(&self.device_id)
+            .await.ok_or_else(|| NavajoError::new(INVALID_DEVICE_ID))?;
+        let mut request = RemoveDeviceRequest {
+            address: account.address.to_string(),
+            public_key: account.key_pair.gen_public_key(),
+            device_id: device_id.to_string(),
+            nonce: Uuid::new_v4().to_string(),
+            time_ms: now_ms(),
+            sign: String::new(),
+        };
+        request.sign = account.sign_data(&request.transcript());
+        self.http_client.remove_device(&request)
+            .await.map_err(|_| NavajoError::new(HTTP_ERROR))?;
+        if device_id == self.device_id {
+            self.forget_account().await;
+        }
+        Ok(())
+    }
+
+    async fn forget_account(&self) {
+        let session_client = self.session_client.clone();
+        let device_id = &self.device_id;
+
+        session_client.del_device_account(device_id).await;
+        session_client.del_session(device_id).await;
+        session_client.del_secret(device_id).await;
+        session_client.del_session_expire(device_id).await;
+        session_client.del_ratchet_state(device_id).await;
+    }
+
+    async fn revoke_session(&self) -> NavajoResult<()> {
+        let device_id = &self.device_id;
+        let account = match self.session_client.get_device_account(device_id).await {
+            Some(account) => account,
+            None => return Ok(()),
+        };
+        if self.session_client.get_session(device_id).await.is_none() {
+            return Ok(());
+        }
+        let mut request = RevokeSessionRequest {
+            address: account.address.to_string(),
+            public_key: account.key_pair.gen_public_key(),
+            device_id: device_id.to_string(),
+            nonce: Uuid::new_v4().to_string(),
+            time_ms: now_ms(),
+            sign: String::new(),
+        };
+        request.sign = account.sign_data(&request.transcript());
+        self.http_client.revoke_session(&request)
+            .await.map_err(|_| NavajoError::new(HTTP_ERROR))
+    }
+
+    async fn handshake(&self) -> NavajoResult<(String, String)> {
+        let session_client = self.session_client.clone();
+        let http_client = self.http_client.clone();
+        let device_id  = &self.device_id;
+
+        let account = session_client.get_device_account(device_id)
+            .await.ok_or_else(|| NavajoError::new(INVALID_DEVICE_ID))?;
+
+        let dh = DiffieHellman::new();
+        let dh_pub = dh.public_key_to_str();
+        let content = Uuid::new_v4().to_string();
+        let address = &account.address;
+        let public_key = account.key_pair.gen_public_key();
+        let mut body = DeviceInfoRequest {
+            device_id: String::from(device_id),
+            content,
+            public_key,
+            address: String::from(address),
+            sign: String::new(),
+            dh_pub,
+            protocol_version: SESSION_PROTOCOL_SIGNED,
+            time_ms: now_ms(),
+        };
+        body.sign = account.sign_data(&body.transcript());
+        let response = http_client.create_session(&body)
+            .await.map_err(|_| NavajoError::new(HTTP_ERROR))?;
+        self.verify_server_identity(&body, &response).await?;
+        let DeviceInfoResponse { session, dh_pub: server_dh_pub, protocol_version, session_expire_ms, .. } = response;
+        let shared_secret = dh.compute_shared_secret_from_str(&server_dh_pub)
+            .map_err(|_| NavajoError::new(INVALID_DH_ERROR))?;
+        let keys = if protocol_version == SESSION_PROTOCOL_LEGACY {
+            SessionKeys::legacy(&shared_secret)
+        } else {
+            let transcript = SessionTranscript {
+                session: &session,
+                device_id,
+                client_dh_pub: &body.dh_pub,
+                server_dh_pub: &server_dh_pub,
+            };
+            SessionKeys::derive(&shared_secret, &transcript)
+        };
+        let secret = keys.encode_to_str();
+        // Old servers don't say, their sessions are kept until they reject them
+        let session_expire_ms = match session_expire_ms {
+            0 => u128::MAX,
+            expire_ms => expire_ms,
+        };
+        session_client.set_session(device_id, &session).await;
+        session_client.set_secret(device_id, &secret).await;
+        session_client.set_session_expire(device_id, session_expire_ms).await;
+        if let Err(err) = self.prekey_manager.replenish().await {
+            println!("Replenish prekeys failed, {}", err);
+        }
+        Ok((session, secret))
+    }
+
+    async fn verify_server_identity(&self, request: &DeviceInfoRequest, response: &DeviceInfoResponse) -> NavajoResult<()> {
+        let session_client = self.session_client.clone();
+        let server_host = &self.server_host;
+
+        let trusted = match &self.server_public_key {
+            Some(key) => Some(key.to_string()),
+            None => session_client.get_server_public_key(server_host).await,
+        };
+        match &trusted {
+            Some(key) if key != &response.server_public_key => return Err(NavajoError::new(UNTRUSTED_SERVER_KEY)),
+            // Old servers have no identity key, there is nothing to verify or pin
+            None if response.server_public_key.is_empty() && self.allow_unsigned_server => return Ok(()),
+            // Otherwise someone may have stripped it
+            None if response.server_public_key.is_empty() => return Err(NavajoError::new(UNTRUSTED_SERVER_KEY)),
+            _ => {}
+        }
+        if !response.verify_sign(request) {
+            return Err(NavajoError::new(VERIFY_SIGN_ERROR));
+        }
+        if trusted.is_none() {
+            session_client.set_server_public_key(server_host, &response.server_public_key).await;
+        }
+        Ok(())
+    }
+}
+
+/// Whether a session expiring at `expire_ms` is within the renewal margin at `now`.
+fn needs_renewal(expire_ms: u128, now: u128) -> bool {
+    expire_ms.saturating_sub(now) <= SESSION_RENEW_MARGIN_MS
+}
+
+fn now_ms() -> u128 {
+    SystemTime::now().duration_since(UNIX_EPOCH).unwrap().as_millis()
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+    use common::beans::{DeviceInfoRequest, DeviceInfoResponse, SESSION_PROTOCOL_SIGNED};
+    use uuid::Uuid;
+    use common::account::Account;
+    use common::key_pair::KeyPair;
+    use crate::handshake::{needs_renewal, now_ms, SESSION_RENEW_MARGIN_MS, SessionManager};
+    use crate::http::HttpClient;
+    use crate::keystore::storage::KeyDB;
+    use crate::prekey::PrekeyManager;
+    use crate::session::SessionClient;
+
+    /// Each with a server host of its own, pinned keys of earlier runs don't get in the way.
+    async fn session_manager(device_id: &str, allow_unsigned_server: bool) -> Arc<SessionManager> {
+        let session_client = SessionClient::new(Arc::new(KeyDB::init().await.unwrap()));
+        // Never reached by these tests
+        let http_client = HttpClient::new("http://127.0.0.1:1");
+        let prekey_manager = PrekeyManager::new(session_client.clone(), http_client.clone(), device_id.to_string());
+        SessionManager::new(
+            session_client,
+            http_client,
+            prekey_manager,
+            device_id.to_string(),
+            Uuid::new_v4().to_string(),
+            None,
+            allow_unsigned_server,
+        )
+    }
+
+    fn request() -> DeviceInfoRequest {
+        DeviceInfoRequest {
+            device_id: String::from("handshake_device"),
+            protocol_version: SESSION_PROTOCOL_SIGNED,
+            time_ms: now_ms(),
+            ..Default::default()
+        }
+    }
+
+    fn response(request: &DeviceInfoRequest, identity: Option<&KeyPair>) -> DeviceInfoResponse {
+        let mut response = DeviceInfoResponse {
+            session: String::from("session"),
+            protocol_version: SESSION_PROTOCOL_SIGNED,
+            time_ms: now_ms(),
+            ..Default::default()
+        };
+        if let Some(identity) = identity {
+            response.server_public_key = identity.gen_public_key();
+            response.sign = identity.sign(&response.transcript(request));
+        }
+        response
+    }
+
+    #[actix_rt::test]
+    async fn test_verify_server_identity() {
+        let request = request();
+        let identity = KeyPair::new();
+
+        // Unsigned responses are only taken from servers configured as old ones
+        let manager = session_manager("handshake_device", false).await;
+        assert!(manager.verify_server_identity(&request, &response(&request, None)).await.is_err());
+        let legacy = session_manager("handshake_device", true).await;
+        assert!(legacy.verify_server_identity(&request, &response(&request, None)).await.is_ok());
+
+        // The first key seen is pinned, a stripped or other one is refused from then on
+        assert!(manager.verify_server_identity(&request, &response(&request, Some(&identity))).await.is_ok());
+        assert!(manager.verify_server_identity(&request, &response(&request, Some(&identity))).await.is_ok());
+        assert!(manager.verify_server_identity(&request, &response(&request, None)).await.is_err());
+        assert!(manager.verify_server_identity(&request, &response(&request, Some(&KeyPair::new()))).await.is_err());
+        assert!(legacy.verify_server_identity(&request, &response(&request, Some(&identity))).await.is_ok());
+        assert!(legacy.verify_server_identity(&request, &response(&request, None)).await.is_err());
+    }
+
+    #[test]
+    fn test_needs_renewal() {
+        let now = now_ms();
+        assert!(!needs_renewal(now + SESSION_RENEW_MARGIN_MS + 1, now));
+        assert!(needs_renewal(now + SESSION_RENEW_MARGIN_MS, now));
+        assert!(needs_renewal(now, now));
+        // Expired, or stored before expiry was tracked
+        assert!(needs_renewal(now - 1, now));
+        assert!(needs_renewal(0, now));
+        // Sessions of old servers last until rejected
+        assert!(!needs_renewal(u128::MAX, now));
+    }
+
+    #[actix_rt::test]
+    async fn test_renewal() {
+        // The server is never reached, handshakes fail
+        let device_id = Uuid::new_v4().to_string();
+        let manager = session_manager(&device_id, false).await;
+        let session_client = manager.session_client.clone();
+        session_client.set_device_account(&device_id, &Account::new()).await;
+        session_client.set_session(&device_id, "current").await;
+
+        session_client.set_session_expire(&device_id, now_ms() + 2 * SESSION_RENEW_MARGIN_MS).await;
+        assert!(manager.ensure_session().await.is_ok());
+        session_client.set_session_expire(&device_id, now_ms() + SESSION_RENEW_MARGIN_MS / 2).await;
+        assert!(manager.ensure_session().await.is_err());
+
+        // Rejections of a session already replaced are ignored
+        session_client.set_session_expire(&device_id, now_ms() + 2 * SESSION_RENEW_MARGIN_MS).await;
+        assert!(manager.renew_rejected("replaced").await.is_ok());
+        assert!(manager.renew_rejected("current").await.is_err());
+    }
+
+    #[actix_rt::test]
+    async fn test_logout_forgets_ratchet_state() {
+        let device_id = Uuid::new_v4().to_string();
+        let manager = session_manager(&device_id, false).await;
+        let session_client = manager.session_client.clone();
+        session_client.set_device_account(&device_id, &Account::new()).await;
+        session_client.set_ratchet_session(&device_id, "alice", "state").await;
+        session_client.set_ratchet_init(&device_id, "ephemeral_key", "alice").await;
+        // Another device's state is kept
+        session_client.set_ratchet_session("other_device", "alice", "state").await;
+
+        manager.logout().await;
+        assert!(session_client.get_device_account(&device_id).await.is_none());
+        assert!(session_client.get_ratchet_session(&device_id, "alice").await.is_none());
+        assert!(session_client.get_ratchet_init(&device_id, "ephemeral_key").await.is_none());
+        assert!(session_client.get_ratchet_session("other_device", "alice").await.is_some());
+    }
+}
@@ -1,6 +1,6 @@
 use std::error::Error;
 use std::sync::Arc;
-use common::beans::{ApiResponse, DeviceInfoRequest, DeviceInfoResponse};
+use common::beans::{ApiResponse, DeviceInfoRequest, DeviceInfoResponse, DeviceListResponse, ListDevicesRequest, PrekeyBundle, PrekeyCountResponse, PrekeyUploadRequest, PublicKeyResponse, RemoveDeviceRequest, RevokeSessionRequest};
 
 pub struct HttpClient {
     host: String,
@@ -18,4 +18,58 @@ impl HttpClient {
         let response: ApiResponse<DeviceInfoResponse> = resp.json().await?;
         Ok(response.content)
     }
+
+    pub async fn revoke_session(&self, body: &RevokeSessionRequest) -> Result<(), Box<dyn Error>> {
+        let client = reqwest::Client::new();
+        let url = format!("{}/device/revoke_session", self.host);
+        client.post(url).json(&body).send().await?.error_for_status()?;
+        Ok(())
+    }
+
+    pub async fn list_devices(&self, body: &ListDevicesRequest) -> Result<DeviceListResponse, Box<dyn Error>> {
+        let client = reqwest::Client::new();
+        let url = format!("{}/device/list", self.host);
+        let resp = client.post(url).json(&body).send().await?.error_for_status()?;
+        let response: ApiResponse<DeviceListResponse> = resp.json().await?;
+        Ok(response.content)
+    }
+
+    pub async fn remove_device(&self, body: &RemoveDeviceRequest) -> Result<(), Box<dyn Error>> {
+        let client = reqwest::Client::new();
+        let url = format!("{}/device/remove", self.host);
+        client.post(url).json(&body).send().await?.error_for_status()?;
+        Ok(())
+    }
+
+    pub async fn get_public_key(&self, address: &str) -> Result<PublicKeyResponse, Box<dyn Error>> {
+        let client = reqwest::Client::new();
+        let url = format!("{}/device/public_key", self.host);
+        let resp = client.get(url).query(&[("address", address)]).send().await?;
+        let response: ApiResponse<PublicKeyResponse> = resp.json().await?;
+        Ok(response.content)
+    }
+
+    pub async fn upload_prekeys(&self, body: &PrekeyUploadRequest) -> Result<PrekeyCountResponse, Box<dyn Error>> {
+        let client = reqwest::Client::new();
+        let url = format!("{}/prekey/upload", self.host);
+        let resp = client.post(url).json(&body).send().await?;
+        let response: ApiResponse<PrekeyCountResponse> = resp.json().await?;
+        Ok(response.content)
+    }
+
+    pub async fn count_prekeys(&self, address: &str) -> Result<PrekeyCountResponse, Box<dyn Error>> {
+        let client = reqwest::Client::new();
+        let url = format!("{}/prekey/count", self.host);
+        let resp = client.get(url).query(&[("address", address)]).send().await?;
+        let response: ApiResponse<PrekeyCountResponse> = resp.json().await?;
+        Ok(response.content)
+    }
+
+    pub async fn get_prekey_bundle(&self, address: &str) -> Result<PrekeyBundle, Box<dyn Error>> {
+        let client = reqwest::Client::new();
+        let url = format!("{}/prekey/bundle", self.host);
+        let resp = client.get(url).query(&[("address", address)]).send().await?;
+        let response: ApiResponse<PrekeyBundle> = resp.json().await?;
+        Ok(response.content)
+    }
 }
\ No newline at end of file
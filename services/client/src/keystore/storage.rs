@@ -31,6 +31,11 @@ impl KeyDB {
     pub async fn remove(&self, key: &str) -> Option<()> {
         self.store.lock().await.remove(key).await
     }
+
+    /// Every entry whose key starts with `prefix`.
+    pub async fn find_by_prefix(&self, prefix: &str) -> Vec<(String, String)> {
+        self.store.lock().await.find_by_prefix(prefix).await
+    }
 }
 
 struct InMemStore {
@@ -63,6 +68,11 @@ impl InMemStore {
         self.kv.remove(key);
         Some(())
     }
+
+    // Only the file has every entry
+    async fn find_by_prefix(&mut self, prefix: &str) -> Vec<(String, String)> {
+        self.persist.find_by_prefix(prefix).await.unwrap_or_default()
+    }
 }
 
 struct Persist {
@@ -75,11 +85,23 @@ impl Persist {
             .read(true)
             .write(true)
             .create(true)
+            .truncate(false)
             .append(false)
             .open(".navajo_ks")
             .await.map_err(|err| NavajoError::new(IoError(err)))?;
 
-        Ok(Self { file })
+        let mut persist = Self { file };
+        persist.migrate().await;
+        Ok(persist)
+    }
+
+    // Rewrites a store saved with the legacy zero-nonce format into the current envelope
+    async fn migrate(&mut self) -> Option<()> {
+        let res = self.read_file().await?;
+        if res.is_empty() {
+            return Some(());
+        }
+        self.write_file(&res).await
     }
 
     async fn get(&mut self, key: &str) -> Option<String> {
@@ -99,13 +121,18 @@ impl Persist {
         self.write_file(&res).await
     }
 
+    async fn find_by_prefix(&mut self, prefix: &str) -> Option<Vec<(String, String)>> {
+        let res = self.read_file().await?;
+        Some(res.into_iter().filter(|(key, _)| key.starts_with(prefix)).collect())
+    }
+
     async fn read_file(&mut self) -> Option<HashMap<String, String>> {
         let mut buf = Vec::new();
         self.file.read_to_end(&mut buf).await.ok()?;
         self.file.rewind().await.ok()?;
 
         let secret = key_store_secret()?;
-        let decoded = aes::decode(&secret, &buf).unwrap_or(buf);
+        let decoded = aes::decode_with_legacy(&secret, &buf).unwrap_or(buf);
 
         let res: HashMap<String, String> = serde_json::from_slice(&decoded).unwrap_or_else(|_| Default::default());
         Some(res)
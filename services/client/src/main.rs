@@ -2,11 +2,16 @@ use std::env::args;
 use std::sync::Arc;
 use uuid::Uuid;
 use crate::config::Config;
+use crate::handshake::SessionManager;
 use crate::http::HttpClient;
 use crate::keystore::storage::KeyDB;
 use crate::p2p::channel::create_signal_channel;
+use crate::outbox::Outbox;
 use crate::p2p::client::P2PClient;
+use crate::prekey::PrekeyManager;
+use crate::ratchet::RatchetSessions;
 use crate::session::SessionClient;
+use crate::status::MessageStatuses;
 use crate::web_server::WebServer;
 
 mod session;
@@ -17,6 +22,11 @@ mod p2p;
 mod web_server;
 mod config;
 mod keystore;
+mod prekey;
+mod ratchet;
+mod status;
+mod outbox;
+mod handshake;
 
 #[actix_web::main]
 async fn main() -> std::io::Result<()> {
@@ -36,11 +46,41 @@ async fn main() -> std::io::Result<()> {
     let http_client = HttpClient::new(&server_config.server_host);
     let device_id = generate_device_id(&p2p_config.client_name, &session_client).await;
 
+    let prekey_manager = PrekeyManager::new(
+        session_client.clone(),
+        http_client.clone(),
+        device_id.clone(),
+    );
+
+    let session_manager = SessionManager::new(
+        session_client.clone(),
+        http_client.clone(),
+        prekey_manager.clone(),
+        device_id.clone(),
+        server_config.server_host.clone(),
+        server_config.server_public_key.clone(),
+        server_config.allow_unsigned_server,
+    );
+
+    let ratchet_sessions = RatchetSessions::new(
+        session_client.clone(),
+        http_client.clone(),
+        prekey_manager.clone(),
+        device_id.clone(),
+    );
+
+    let message_statuses = MessageStatuses::new(session_client.clone(), device_id.clone());
+    let outbox = Outbox::new(session_client.clone(), device_id.clone());
+
     let p2p_client = P2PClient::new(
         p2p_config,
         tx.clone(),
         rx,
         session_client.clone(),
+        ratchet_sessions.clone(),
+        message_statuses.clone(),
+        outbox.clone(),
+        session_manager.clone(),
         device_id.clone(),
     );
 
@@ -48,11 +88,16 @@ async fn main() -> std::io::Result<()> {
         server_config,
         session_client,
         http_client.clone(),
+        session_manager,
+        ratchet_sessions,
+        message_statuses,
+        outbox,
         device_id.clone(),
         tx.clone(),
     );
 
     p2p_client.start().await;
+    prekey_manager.start();
     web_server.start().await
 }
 
@@ -0,0 +1,144 @@
+use std::sync::Arc;
+use std::time::{SystemTime, UNIX_EPOCH};
+use serde::{Deserialize, Serialize};
+use p2p::message::Message;
+use crate::session::SessionClient;
+
+/// Chats the server never acknowledged are given up after this, the server
+/// remembers a `request_id` for as long.
+const OUTBOX_EXPIRE_MS: u128 = 7 * 24 * 60 * 60 * 1000; // 7 days
+
+#[derive(Serialize, Deserialize)]
+struct OutboxEntry {
+    message: Message,
+    queued_time_ms: u128,
+}
+
+/// Chats waiting for the server's status, persisted so they survive reconnects and restarts.
+/// Each chat is stored on its own, by `request_id`.
+pub struct Outbox {
+    session_client: Arc<SessionClient>,
+    device_id: String,
+}
+
+impl Outbox {
+    pub fn new(session_client: Arc<SessionClient>, device_id: String) -> Arc<Self> {
+        Arc::new(Self { session_client, device_id })
+    }
+
+    pub async fn push(&self, message: &Message) {
+        if !matches!(message, Message::ChatInfoMessage { .. }) {
+            return;
+        }
+        self.save(&OutboxEntry {
+            message: message.clone(),
+            queued_time_ms: now_ms(),
+        }).await;
+    }
+
+    /// Drops the chat `request_id` once the server reported its status.
+    pub async fn ack(&self, request_id: &str) {
+        self.session_client.del_outbox_entry(&self.device_id, request_id).await;
+    }
+
+    /// Chats to send again, oldest first, with a fresh timestamp so the server doesn't take
+    /// them for stale.
+    pub async fn pending(&self) -> Vec<Message> {
+        self.migrate_legacy_outbox().await;
+        let mut entries: Vec<OutboxEntry> = self.session_client.get_outbox_entries(&self.device_id).await.iter()
+            .filter_map(|json_str| serde_json::from_str(json_str).ok())
+            .collect();
+        entries.sort_by_key(|entry| entry.queued_time_ms);
+        let now = now_ms();
+        let (entries, expired): (Vec<OutboxEntry>, Vec<OutboxEntry>) = entries.into_iter()
+            .partition(|entry| now.saturating_sub(entry.queued_time_ms) < OUTBOX_EXPIRE_MS);
+        if !expired.is_empty() {
+            println!("Give up {} unacknowledged messages", expired.len());
+        }
+        for entry in &expired {
+            self.ack(&entry.message.common_info().request_id).await;
+        }
+        entries.into_iter().map(|entry| {
+            let mut message = entry.message;
+            if let Message::ChatInfoMessage { common_info, .. } = &mut message {
+                common_info.time_ms = now;
+            }
+            message
+        }).collect()
+    }
+
+    async fn save(&self, entry: &OutboxEntry) {
+        if let Ok(json_str) = serde_json::to_string(entry) {
+            let request_id = &entry.message.common_info().request_id;
+            self.session_client.set_outbox_entry(&self.device_id, request_id, &json_str).await;
+        }
+    }
+
+    // Older clients kept the whole outbox in one list
+    async fn migrate_legacy_outbox(&self) {
+        let json_str = match self.session_client.get_legacy_outbox(&self.device_id).await {
+            Some(json_str) => json_str,
+            None => return,
+        };
+        let entries: Vec<OutboxEntry> = serde_json::from_str(&json_str).unwrap_or_default();
+        for entry in &entries {
+            self.save(entry).await;
+        }
+        self.session_client.del_legacy_outbox(&self.device_id).await;
+    }
+}
+
+fn now_ms() -> u128 {
+    SystemTime::now().duration_since(UNIX_EPOCH).unwrap().as_millis()
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+    use std::time::Duration;
+    use tokio::time::sleep;
+    use p2p::message::Message;
+    use crate::keystore::storage::KeyDB;
+    use crate::outbox::{now_ms, Outbox, OutboxEntry};
+    use crate::session::SessionClient;
+
+    #[actix_rt::test]
+    async fn test_outbox() {
+        let key_db = Arc::new(KeyDB::init().await.unwrap());
+        let session_client = SessionClient::new(key_db.clone());
+        let outbox = Outbox::new(session_client.clone(), String::from("outbox_device"));
+        let chat = |to: &str| Message::ChatInfoMessage {
+            common_info: Default::default(),
+            from_address: String::from("alice"),
+            to_address: to.to_string(),
+            info_type: 0,
+            content: String::from("hi"),
+        };
+        let first = chat("bob");
+        let second = chat("carol");
+        outbox.push(&first).await;
+        // Ordered by the time they were queued, in ms
+        sleep(Duration::from_millis(2)).await;
+        outbox.push(&second).await;
+
+        let pending = outbox.pending().await;
+        let ids: Vec<&str> = pending.iter().map(|message| message.common_info().request_id.as_str()).collect();
+        assert_eq!(ids, vec![first.common_info().request_id.as_str(), second.common_info().request_id.as_str()]);
+
+        outbox.ack(&first.common_info().request_id).await;
+        let pending = outbox.pending().await;
+        assert_eq!(pending.len(), 1);
+        assert_eq!(pending[0].common_info().request_id, second.common_info().request_id);
+        outbox.ack(&second.common_info().request_id).await;
+        assert!(outbox.pending().await.is_empty());
+
+        // A whole list saved by an older client is moved to one entry per chat
+        let legacy = serde_json::to_string(&[OutboxEntry { message: first.clone(), queued_time_ms: now_ms() }]).unwrap();
+        key_db.set("client_outbox:outbox_device", &legacy).await;
+        let pending = outbox.pending().await;
+        assert_eq!(pending.len(), 1);
+        assert_eq!(pending[0].common_info().request_id, first.common_info().request_id);
+        assert!(session_client.get_legacy_outbox("outbox_device").await.is_none());
+        outbox.ack(&first.common_info().request_id).await;
+    }
+}
@@ -1,26 +1,40 @@
 use std::sync::Arc;
 use std::time::Duration;
+use bytes::BytesMut;
+use futures::{SinkExt, StreamExt};
 use serde::Deserialize;
 use tokio::{io, select, spawn};
 use tokio::io::{AsyncReadExt, AsyncWriteExt, ReadHalf, WriteHalf};
 use tokio::net::{TcpSocket, TcpStream};
 use tokio::sync::{broadcast, mpsc};
-use tokio::time::sleep;
-use common::errors::{NavajoError, NavajoResult};
+use tokio::time::{sleep, timeout};
+use tokio_util::codec::{Decoder, Encoder, FramedRead, FramedWrite};
+use common::e2e;
+use common::errors::{INVALID_DEVICE_ID, INVALID_SESSION, NavajoError, NavajoResult};
 use common::errors::NavajoErrorRepr::SocketError;
-use p2p::message::Message::PingMessage;
-use p2p::message::P2PMessage;
-use p2p::packet::readers::{CryptoReader, PacketExtractor};
-use p2p::packet::writers::{MessageWriter, Writer};
+use ncrypto::algo::kdf::SessionKeys;
+use p2p::message::Message::{ChatInfoMessage, PingMessage};
+use p2p::message::{E2E_TEXT_TYPE, Message, P2PMessage, RATCHET_TEXT_TYPE, RECEIPT_DELIVERED, TEXT_TYPE};
+use p2p::packet::codec::{Frame, FRAME_PROTOCOL_VERSION, FrameCodec, Framing, P2PCodec};
+use p2p::packet::readers::CryptoReader;
+use p2p::packet::writers::seal_frame;
+use p2p::replay::{DEFAULT_WINDOW_CAPACITY, ReplayWindow};
+use crate::handshake::SessionManager;
+use crate::outbox::Outbox;
 use crate::p2p::channel::create_client_channel;
+use crate::ratchet::RatchetSessions;
 use crate::session::SessionClient;
+use crate::status::MessageStatuses;
 
 type ChannelSignalSender = Arc<mpsc::Sender<P2PMessage>>;
 type ChannelSignalReceiver = mpsc::Receiver<P2PMessage>;
 
+const HELLO_TIMEOUT_SECONDS: u64 = 3;
+const READ_BUFFER_CAPACITY: usize = 8 * 1024;
+const OUTBOX_RETRY_SECONDS: u64 = 30;
+
 #[derive(Clone, Deserialize)]
 pub struct P2PConfig {
-    pub local_port: String,
     pub server_port: String,
     pub server_host: String,
     pub client_name: String,
@@ -31,16 +45,24 @@ pub struct P2PClient {
     signal_channel_tx: ChannelSignalSender,
     signal_channel_rx: ChannelSignalReceiver,
     session_client: Arc<SessionClient>,
+    ratchet_sessions: Arc<RatchetSessions>,
+    message_statuses: Arc<MessageStatuses>,
+    outbox: Arc<Outbox>,
+    session_manager: Arc<SessionManager>,
     device_id: String,
-    message_writer: Arc<MessageWriter>,
 }
 
 impl P2PClient {
+    #[allow(clippy::too_many_arguments)]
     pub fn new(
         config: P2PConfig,
         signal_channel_tx: ChannelSignalSender,
         signal_channel_rx: ChannelSignalReceiver,
         session_client: Arc<SessionClient>,
+        ratchet_sessions: Arc<RatchetSessions>,
+        message_statuses: Arc<MessageStatuses>,
+        outbox: Arc<Outbox>,
+        session_manager: Arc<SessionManager>,
         device_id: String,
     ) -> Self {
         Self {
@@ -48,12 +70,17 @@ impl P2PClient {
             signal_channel_tx,
             signal_channel_rx,
             session_client,
+            ratchet_sessions,
+            message_statuses,
+            outbox,
+            session_manager,
             device_id,
-            message_writer: Arc::new(MessageWriter),
         }
     }
 
     pub async fn start(mut self) {
+        // Establishes the session as soon as there is an account, and renews it before expiry
+        self.session_manager.clone().start();
         spawn(async move {
             loop {
                 match self.connect().await {
@@ -66,31 +93,40 @@ impl P2PClient {
     }
 
     async fn connect(&mut self) -> NavajoResult<()> {
-        let socket = TcpSocket::new_v4()?;
-
-        let server_url = format!("{}:{}", self.config.server_host, self.config.server_port);
-        let addr = server_url.parse().unwrap();
-        let stream = socket.connect(addr).await?;
+        // Without a session every outgoing message would be dropped
+        if let Err(err) = self.session_manager.ensure_session().await {
+            println!("Establish session failed, {}", err);
+        }
+        self.message_statuses.prune().await;
+        let (stream, framing, read_buf) = self.open_stream().await?;
         let (r, w) = io::split(stream);
-        println!("Server Connected");
+        println!("Server Connected, {:?} framing", framing);
 
         let (socket_close_tx, mut socket_close_rx) = broadcast::channel(1);
 
         let (channel_tx, channel_rx) = create_client_channel();
         let ping_channel_tx = channel_tx.clone();
+        let outbox_channel_tx = channel_tx.clone();
 
         let socket_close_write_rx = socket_close_tx.subscribe();
         let socket_close_ping_rx = socket_close_tx.subscribe();
+        let socket_close_outbox_rx = socket_close_tx.subscribe();
 
+        let mut r = FramedRead::new(r, framing.codec());
+        *r.read_buffer_mut() = read_buf;
         self.start_socket_read_thread(r, socket_close_tx);
-        self.start_socket_write_thread(w, channel_rx, socket_close_write_rx);
+        self.start_socket_write_thread(FramedWrite::new(w, framing.codec()), channel_rx, socket_close_write_rx);
 
         self.start_ping_thread(ping_channel_tx, socket_close_ping_rx);
+        self.start_outbox_thread(outbox_channel_tx, socket_close_outbox_rx);
 
         loop {
             select! {
                 Some(signal) = self.signal_channel_rx.recv() => {
-                    channel_tx.send(signal).await;
+                    // The writer only stops once the socket is broken
+                    if channel_tx.send(signal).await.is_err() {
+                        break;
+                    }
                 }
                 _ = socket_close_rx.recv() => {
                     break;
@@ -100,31 +136,86 @@ impl P2PClient {
         Err(NavajoError::new(SocketError { message: "Connection closed" }))
     }
 
+    /// Offers the binary framing first. A server that doesn't answer the hello may be an
+    /// old one, it gets a fresh connection with the legacy framing. The next connect offers
+    /// the binary framing again, the hello may only have been slow. Also returns what was
+    /// read past the hello.
+    async fn open_stream(&self) -> NavajoResult<(TcpStream, Framing, BytesMut)> {
+        let mut stream = self.tcp_connect().await?;
+        match negotiate(&mut stream).await {
+            Ok(read_buf) => return Ok((stream, Framing::Binary, read_buf)),
+            Err(err) => println!("Fall back to legacy framing for this connection, {}", err),
+        }
+        Ok((self.tcp_connect().await?, Framing::Legacy, BytesMut::new()))
+    }
+
+    async fn tcp_connect(&self) -> NavajoResult<TcpStream> {
+        let socket = TcpSocket::new_v4()?;
+
+        let server_url = format!("{}:{}", self.config.server_host, self.config.server_port);
+        let addr = server_url.parse().unwrap();
+        Ok(socket.connect(addr).await?)
+    }
+
     fn start_socket_read_thread(
         &self,
-        r: ReadHalf<TcpStream>,
+        r: FramedRead<ReadHalf<TcpStream>, P2PCodec>,
         socket_close_tx: broadcast::Sender<()>
     ) {
         // Socket read handler thread, to handle message sent by server
         let session_client = self.session_client.clone();
+        let ratchet_sessions = self.ratchet_sessions.clone();
+        let message_statuses = self.message_statuses.clone();
+        let outbox = self.outbox.clone();
+        let session_manager = self.session_manager.clone();
+        // Receipts go out through the signal channel like any other message
+        let receipt_tx = self.signal_channel_tx.clone();
         let client_name = self.config.client_name.to_string();
         spawn(async move {
-            socket_read_handle(r, &session_client, client_name, socket_close_tx).await;
+            socket_read_handle(
+                r,
+                &session_client,
+                &ratchet_sessions,
+                &message_statuses,
+                &outbox,
+                &session_manager,
+                receipt_tx,
+                client_name,
+                socket_close_tx,
+            ).await;
         });
     }
 
     fn start_socket_write_thread(
         &self,
-        w: WriteHalf<TcpStream>,
+        w: FramedWrite<WriteHalf<TcpStream>, P2PCodec>,
         channel_rx: mpsc::Receiver<P2PMessage>,
         socket_close_write_rx: broadcast::Receiver<()>
     ) {
         // Channel handler thread, to handler action of send message to socket
         let session_client = self.session_client.clone();
         let client_name = self.config.client_name.clone();
-        let message_writer = self.message_writer.clone();
         spawn(async move {
-            channel_handle(w, channel_rx, &session_client, client_name, &message_writer, socket_close_write_rx).await;
+            channel_handle(w, channel_rx, &session_client, client_name, socket_close_write_rx).await;
+        });
+    }
+
+    fn start_outbox_thread(
+        &self,
+        outbox_channel_tx: Arc<mpsc::Sender<P2PMessage>>,
+        mut socket_close_outbox_rx: broadcast::Receiver<()>
+    ) {
+        // Resends unacknowledged chats, right after connecting and then periodically
+        let outbox = self.outbox.clone();
+        spawn(async move {
+            select! {
+                _ = socket_close_outbox_rx.recv() => {
+                    println!("Outbox stopped");
+                }
+                Err(err) = resend_outbox(&outbox, outbox_channel_tx) => {
+                    println!("Outbox stopped, {}", err);
+                }
+            }
         });
     }
 
@@ -149,6 +240,16 @@ impl P2PClient {
     }
 }
 
+async fn resend_outbox(outbox: &Outbox, channel_tx: ChannelSignalSender) -> NavajoResult<()> {
+    loop {
+        for message in outbox.pending().await {
+            channel_tx.send((&message).into()).await
+                .map_err(|_| NavajoError::new(SocketError { message: "Connection closed" }))?;
+        }
+        sleep(Duration::from_secs(OUTBOX_RETRY_SECONDS)).await;
+    }
+}
+
 async fn ping(
     session_client: &SessionClient,
     channel_tx: ChannelSignalSender,
@@ -163,6 +264,7 @@ async fn ping(
         }
         let account = opt.unwrap();
         let ping_message = PingMessage {
+            common_info: Default::default(),
             address: account.address,
             device_id: device_id.to_string(),
         };
@@ -171,30 +273,86 @@ async fn ping(
     }
 }
 
+/// Returns the bytes read past the server's hello, the frames the server sent right after it.
+async fn negotiate(stream: &mut TcpStream) -> NavajoResult<BytesMut> {
+    let mut codec = FrameCodec;
+    let mut buf = BytesMut::new();
+    codec.encode(Frame::Hello { version: FRAME_PROTOCOL_VERSION }, &mut buf)?;
+    stream.write_all(&buf).await?;
+    // Room for what the server sends right after its hello, it goes to the FramedRead
+    let mut buf = BytesMut::with_capacity(READ_BUFFER_CAPACITY);
+
+    let read_hello = async {
+        loop {
+            if let Some(frame) = codec.decode(&mut buf)? {
+                return Ok(frame);
+            }
+            if stream.read_buf(&mut buf).await? == 0 {
+                return Err(NavajoError::new(SocketError { message: "Connection closed" }));
+            }
+        }
+    };
+    match timeout(Duration::from_secs(HELLO_TIMEOUT_SECONDS), read_hello).await {
+        Ok(Ok(Frame::Hello { .. })) => Ok(buf),
+        Ok(Err(err)) => Err(err),
+        _ => Err(NavajoError::new(SocketError { message: "No hello from server" })),
+    }
+}
+
+#[allow(clippy::too_many_arguments)]
 async fn socket_read_handle(
-    mut r: ReadHalf<TcpStream>,
+    mut r: FramedRead<ReadHalf<TcpStream>, P2PCodec>,
     session_client: &SessionClient,
+    ratchet_sessions: &RatchetSessions,
+    message_statuses: &MessageStatuses,
+    outbox: &Outbox,
+    session_manager: &SessionManager,
+    receipt_tx: ChannelSignalSender,
     client_name: String,
     socket_close_tx: broadcast::Sender<()>
 ) {
-    let mut extractor = PacketExtractor::new();
-    let mut buf = vec![0; 1024];
+    // Queued offline messages can be arbitrarily old, so only duplicates are dropped
+    let mut replay_window = ReplayWindow::dedupe_only(DEFAULT_WINDOW_CAPACITY);
     loop {
-        match r.read(&mut buf).await {
-            Ok(0) => {
+        match r.next().await {
+            None => {
                 socket_close_tx.send(()).unwrap();
                 println!("Socket closed by server");
                 return ;
             },
-            Ok(n) => {
-                let message = handle_message(n, &buf, &mut extractor, session_client, &client_name).await;
-                if let Some(mes) = message {
-                    println!("{:?}", mes);
+            Some(Ok(Frame::Data { payload, .. })) => {
+                let message = handle_message(&payload, &mut replay_window, session_client, &client_name).await
+                    .and_then(|mes| Message::try_from(&mes));
+                match message {
+                    Ok(mes) => {
+                        if let Message::ChatStatusMessage { common_info, .. } = &mes {
+                            outbox.ack(&common_info.response_id).await;
+                        }
+                        message_statuses.apply(&mes).await;
+                        match open_message(mes, session_client, ratchet_sessions, &client_name).await {
+                            Ok(mes) => {
+                                // Delivered only once this device could read it
+                                if let Some(receipt) = Message::receipt(&mes, RECEIPT_DELIVERED) {
+                                    message_statuses.record_received(&mes).await;
+                                    let _ = receipt_tx.send((&receipt).into()).await;
+                                }
+                                println!("{:?}", mes);
+                            }
+                            Err(err) => println!("Open message failed, {}", err),
+                        }
+                    }
+                    Err(err) => println!("Drop packet, {}", err),
                 }
             },
-            Err(_) => {
+            Some(Ok(Frame::Hello { .. })) => {},
+            Some(Ok(Frame::SessionInvalid { session })) => {
+                if let Err(err) = session_manager.renew_rejected(&session).await {
+                    println!("Renew session failed, {}", err);
+                }
+            },
+            Some(Err(err)) => {
                 socket_close_tx.send(()).unwrap();
-                println!("Socket exception");
+                println!("Socket exception, {}", err);
                 return ;
             },
         }
@@ -202,11 +360,10 @@ async fn socket_read_handle(
 }
 
 async fn channel_handle(
-    mut w: WriteHalf<TcpStream>,
+    mut w: FramedWrite<WriteHalf<TcpStream>, P2PCodec>,
     mut channel_rx: mpsc::Receiver<P2PMessage>,
     session_client: &SessionClient,
     client_name: String,
-    message_writer: &MessageWriter,
     mut socket_close_write_rx: broadcast::Receiver<()>
 ) {
     loop {
@@ -215,11 +372,13 @@ async fn channel_handle(
                 let encoded = encode_message(
                     session_client,
                     client_name.clone(),
-                    message_writer,
                     signal
                 ).await;
-                if let Some(buf) = encoded {
-                    w.write_all(buf.as_slice()).await.unwrap();
+                if let Some(frame) = encoded {
+                    if let Err(err) = w.send(frame).await {
+                        println!("Message send failed, {}", err);
+                        break;
+                    }
                     println!("Message sent");
                 }
             }
@@ -233,29 +392,86 @@ async fn channel_handle(
 async fn encode_message(
     session_client: &SessionClient,
     client_name: String,
-    message_writer: &MessageWriter,
     message: P2PMessage
-) -> Option<Vec<u8>> {
-    let message_str: String = (&message).into();
+) -> Option<Frame> {
     let device_id = session_client.get_device_id(&client_name).await?;
     let session = session_client.get_session(&device_id).await?;
-    let secret = session_client.get_secret(&device_id).await?;
-    let params = &[session.as_str(), secret.as_str()];
-    let result = message_writer.process(&message_str, params)?;
-    Some(result.as_bytes().to_vec())
+    let keys = SessionKeys::decode_from_str(&session_client.get_secret(&device_id).await?)?;
+    let secret = keys.client_to_server_str();
+    seal_frame(&session, &secret, &message).ok()
 }
 
 async fn handle_message(
-    n: usize,
-    buf: &[u8],
-    extractor: &mut PacketExtractor,
+    payload: &[u8],
+    replay_window: &mut ReplayWindow,
+    session_client: &SessionClient,
+    client_name: &str,
+) -> NavajoResult<P2PMessage> {
+    let device_id = session_client.get_device_id(client_name).await
+        .ok_or_else(|| NavajoError::new(INVALID_SESSION))?;
+    let secret = session_client.get_secret(&device_id).await
+        .ok_or_else(|| NavajoError::new(INVALID_SESSION))?;
+    let keys = SessionKeys::decode_from_str(&secret).ok_or_else(|| NavajoError::new(INVALID_SESSION))?;
+    let mut crypto_reader = CryptoReader::new(&keys.server_to_client_str()).accept_legacy(keys.is_legacy());
+    crypto_reader.open_checked(payload, replay_window)
+}
+
+/// Decrypts end-to-end encrypted chats, other messages are returned as they are.
+async fn open_message(
+    mut message: Message,
     session_client: &SessionClient,
+    ratchet_sessions: &RatchetSessions,
     client_name: &str,
-) -> Option<P2PMessage> {
-    let str = String::from_utf8_lossy(&buf[..n]).to_string();
-    let packet_content = extractor.extract(&str)?;
-    let device_id = session_client.get_device_id(client_name).await?;
-    let secret = session_client.get_secret(&device_id).await?;
-    let mut crypto_reader = CryptoReader::new(&secret);
-    crypto_reader.process(&packet_content)
-}
\ No newline at end of file
+) -> NavajoResult<Message> {
+    if let ChatInfoMessage { info_type, content, from_address, .. } = &mut message {
+        if *info_type != E2E_TEXT_TYPE && *info_type != RATCHET_TEXT_TYPE {
+            return Ok(message);
+        }
+        let device_id = session_client.get_device_id(client_name).await;
+        let account = match device_id {
+            Some(device_id) => session_client.get_device_account(&device_id).await,
+            None => None,
+        };
+        let account = account.ok_or_else(|| NavajoError::new(INVALID_DEVICE_ID))?;
+        let plaintext = match *info_type {
+            RATCHET_TEXT_TYPE => ratchet_sessions.decrypt_from(&account, from_address, content).await?,
+            _ => e2e::open(&account.key_pair, from_address, content)?,
+        };
+        *content = plaintext;
+        *info_type = TEXT_TYPE;
+    }
+    Ok(message)
+}
+
+#[cfg(test)]
+mod tests {
+    use bytes::BytesMut;
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+    use tokio::net::{TcpListener, TcpStream};
+    use tokio::spawn;
+    use tokio_util::codec::{Decoder, Encoder};
+    use p2p::packet::codec::{Frame, FRAME_PROTOCOL_VERSION, FrameCodec};
+    use crate::p2p::client::negotiate;
+
+    #[actix_rt::test]
+    async fn test_negotiate_keeps_read_ahead() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        spawn(async move {
+            let (mut socket, _) = listener.accept().await.unwrap();
+            let mut hello = [0u8; 64];
+            let _ = socket.read(&mut hello).await.unwrap();
+            // The hello and the first frame in one write
+            let mut buf = BytesMut::new();
+            FrameCodec.encode(Frame::Hello { version: FRAME_PROTOCOL_VERSION }, &mut buf).unwrap();
+            FrameCodec.encode(Frame::SessionInvalid { session: String::from("session") }, &mut buf).unwrap();
+            socket.write_all(&buf).await.unwrap();
+        });
+        let mut stream = TcpStream::connect(addr).await.unwrap();
+        let mut read_buf = negotiate(&mut stream).await.unwrap();
+        match FrameCodec.decode(&mut read_buf).unwrap() {
+            Some(Frame::SessionInvalid { session }) => assert_eq!(session, "session"),
+            other => panic!("expected the frame after the hello, got {:?}", other),
+        }
+    }
+}
@@ -0,0 +1,135 @@
+use std::sync::Arc;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use tokio::spawn;
+use tokio::time::sleep;
+use uuid::Uuid;
+use common::account::Account;
+use common::beans::{identity_key_transcript, OneTimePrekey, PrekeyUploadRequest, signed_prekey_transcript, SignedIdentity};
+use common::errors::{HTTP_ERROR, INVALID_KEY_PAIR, NavajoError, NavajoResult};
+use ncrypto::algo::x3dh::X25519KeyPair;
+use crate::http::HttpClient;
+use crate::session::SessionClient;
+
+const PREKEY_BATCH_SIZE: u32 = 50;
+const PREKEY_CHECK_INTERVAL_SECONDS: u64 = 60;
+
+/// Keeps this device's X3DH identity key, signed prekey and one-time prekeys published.
+pub struct PrekeyManager {
+    session_client: Arc<SessionClient>,
+    http_client: Arc<HttpClient>,
+    device_id: String,
+}
+
+impl PrekeyManager {
+    pub fn new(session_client: Arc<SessionClient>, http_client: Arc<HttpClient>, device_id: String) -> Arc<Self> {
+        Arc::new(Self { session_client, http_client, device_id })
+    }
+
+    pub fn start(self: Arc<Self>) {
+        spawn(async move {
+            loop {
+                if let Err(err) = self.replenish().await {
+                    println!("Replenish prekeys failed, {}", err);
+                }
+                sleep(Duration::from_secs(PREKEY_CHECK_INTERVAL_SECONDS)).await;
+            }
+        });
+    }
+
+    /// Uploads a new batch when the server reports that few one-time prekeys are left.
+    pub async fn replenish(&self) -> NavajoResult<()> {
+        let account = match self.session_client.get_device_account(&self.device_id).await {
+            Some(account) => account,
+            None => return Ok(()),
+        };
+        let count = self.http_client.count_prekeys(&account.address)
+            .await.map_err(|_| NavajoError::new(HTTP_ERROR))?;
+        if !count.low {
+            return Ok(());
+        }
+        let count = self.upload(&account, PREKEY_BATCH_SIZE).await?;
+        println!("Uploaded prekeys, {} available", count);
+        Ok(())
+    }
+
+    async fn upload(&self, account: &Account, batch_size: u32) -> NavajoResult<u32> {
+        let identity = self.signed_identity(account).await?;
+        let mut one_time_prekeys = vec![];
+        for _ in 0..batch_size {
+            let key_id = self.session_client.next_prekey_id(&self.device_id).await;
+            let prekey = X25519KeyPair::new();
+            self.session_client.set_one_time_prekey(&self.device_id, key_id, &prekey.secret_to_str()).await;
+            one_time_prekeys.push(OneTimePrekey {
+                key_id,
+                prekey: prekey.public_key_to_str(),
+            });
+        }
+        let mut request = PrekeyUploadRequest {
+            identity,
+            one_time_prekeys,
+            nonce: Uuid::new_v4().to_string(),
+            time_ms: SystemTime::now().duration_since(UNIX_EPOCH).unwrap().as_millis(),
+            sign: String::new(),
+        };
+        request.sign = account.sign_data(&request.transcript());
+        let response = self.http_client.upload_prekeys(&request)
+            .await.map_err(|_| NavajoError::new(HTTP_ERROR))?;
+        Ok(response.count)
+    }
+
+    pub async fn signed_identity(&self, account: &Account) -> NavajoResult<SignedIdentity> {
+        let identity_key = self.identity_key().await?.public_key_to_str();
+        let (signed_prekey_id, signed_prekey) = self.current_signed_prekey().await?;
+        let signed_prekey = signed_prekey.public_key_to_str();
+        Ok(SignedIdentity {
+            address: account.address.to_string(),
+            public_key: account.key_pair.gen_public_key(),
+            identity_key_sign: account.sign_data(&identity_key_transcript(&identity_key)),
+            identity_key,
+            signed_prekey_sign: account.sign_data(&signed_prekey_transcript(signed_prekey_id, &signed_prekey)),
+            signed_prekey_id,
+            signed_prekey,
+        })
+    }
+
+    pub async fn identity_key(&self) -> NavajoResult<X25519KeyPair> {
+        let session_client = self.session_client.clone();
+        let device_id = &self.device_id;
+
+        match session_client.get_identity_key(device_id).await {
+            Some(secret) => X25519KeyPair::from_secret_str(&secret).ok_or_else(|| NavajoError::new(INVALID_KEY_PAIR)),
+            None => {
+                let key = X25519KeyPair::new();
+                session_client.set_identity_key(device_id, &key.secret_to_str()).await;
+                Ok(key)
+            }
+        }
+    }
+
+    /// The signed prekey a sender used, if it is still the current one.
+    pub async fn signed_prekey(&self, key_id: u32) -> Option<X25519KeyPair> {
+        let (current_id, secret) = self.session_client.get_signed_prekey(&self.device_id).await?;
+        if current_id != key_id {
+            return None;
+        }
+        X25519KeyPair::from_secret_str(&secret)
+    }
+
+    async fn current_signed_prekey(&self) -> NavajoResult<(u32, X25519KeyPair)> {
+        let session_client = self.session_client.clone();
+        let device_id = &self.device_id;
+
+        match session_client.get_signed_prekey(device_id).await {
+            Some((key_id, secret)) => {
+                let key = X25519KeyPair::from_secret_str(&secret).ok_or_else(|| NavajoError::new(INVALID_KEY_PAIR))?;
+                Ok((key_id, key))
+            }
+            None => {
+                let key_id = session_client.next_prekey_id(device_id).await;
+                let key = X25519KeyPair::new();
+                session_client.set_signed_prekey(device_id, key_id, &key.secret_to_str()).await;
+                Ok((key_id, key))
+            }
+        }
+    }
+}
@@ -0,0 +1,228 @@
+use std::sync::Arc;
+use serde::{Deserialize, Serialize};
+use tokio::sync::Mutex;
+use common::account::Account;
+use common::e2e::{RatchetContent, RatchetInit};
+use common::errors::{E2E_DECRYPT_ERROR, E2E_ENCRYPT_ERROR, INVALID_KEY_PAIR, NavajoError, NavajoResult, REPLAY_ERROR, VERIFY_SIGN_ERROR};
+use ncrypto::algo::ratchet::RatchetState;
+use ncrypto::algo::x3dh::{initiate, PrekeyBundleKeys, respond, X25519KeyPair};
+use crate::http::HttpClient;
+use crate::prekey::PrekeyManager;
+use crate::session::SessionClient;
+
+/// Ratchet state of one contact as persisted in `KeyDB`.
+#[derive(Serialize, Deserialize)]
+struct StoredSession {
+    state: RatchetState,
+    init: Option<RatchetInit>,
+}
+
+/// Per-contact Double Ratchet sessions, started with X3DH from the contact's prekey bundle.
+pub struct RatchetSessions {
+    session_client: Arc<SessionClient>,
+    http_client: Arc<HttpClient>,
+    prekey_manager: Arc<PrekeyManager>,
+    device_id: String,
+    // Reading and writing a state must not interleave, or a chain key gets reused
+    lock: Mutex<()>,
+}
+
+impl RatchetSessions {
+    pub fn new(
+        session_client: Arc<SessionClient>,
+        http_client: Arc<HttpClient>,
+        prekey_manager: Arc<PrekeyManager>,
+        device_id: String,
+    ) -> Arc<Self> {
+        Arc::new(Self {
+            session_client,
+            http_client,
+            prekey_manager,
+            device_id,
+            lock: Mutex::new(()),
+        })
+    }
+
+    /// Returns `None` when `to` has no prekey bundle, the caller falls back to `e2e::seal`.
+    pub async fn encrypt_to(&self, account: &Account, to: &str, plaintext: &str) -> NavajoResult<Option<String>> {
+        let _guard = self.lock.lock().await;
+        let mut session = match self.load(to).await {
+            Some(session) => session,
+            None => match self.initiate(account, to).await? {
+                Some(session) => session,
+                None => return Ok(None),
+            },
+        };
+        let ad = associated_data(&account.address, to);
+        let message = session.state.encrypt(plaintext.as_bytes(), &ad)
+            .ok_or_else(|| NavajoError::new(E2E_ENCRYPT_ERROR))?;
+        if session.state.has_received() {
+            session.init = None;
+        }
+        let content = RatchetContent {
+            init: session.init.clone(),
+            message,
+        };
+        let content = serde_json::to_string(&content).map_err(|_| NavajoError::new(E2E_ENCRYPT_ERROR))?;
+        self.save(to, &session).await?;
+        Ok(Some(content))
+    }
+
+    pub async fn decrypt_from(&self, account: &Account, from: &str, content: &str) -> NavajoResult<String> {
+        let _guard = self.lock.lock().await;
+        let content: RatchetContent = serde_json::from_str(content).map_err(|_| NavajoError::new(E2E_DECRYPT_ERROR))?;
+        let ad = associated_data(from, &account.address);
+
+        if let Some(mut session) = self.load(from).await {
+            if let Some(plaintext) = session.state.decrypt(&content.message, &ad) {
+                self.save(from, &session).await?;
+                return to_string(plaintext);
+            }
+        }
+        // No session yet, or the contact started a new one
+        let init = content.init.ok_or_else(|| NavajoError::new(E2E_DECRYPT_ERROR))?;
+        // Replaying an old init would replace the live session
+        if self.session_client.get_ratchet_init(&self.device_id, &init.ephemeral_key).await.is_some() {
+            return Err(NavajoError::new(REPLAY_ERROR));
+        }
+        let mut state = self.respond(from, &init).await?;
+        let plaintext = state.decrypt(&content.message, &ad)
+            .ok_or_else(|| NavajoError::new(E2E_DECRYPT_ERROR))?;
+        if let Some(key_id) = init.one_time_prekey_id {
+            self.session_client.del_one_time_prekey(&self.device_id, key_id).await;
+        }
+        self.session_client.set_ratchet_init(&self.device_id, &init.ephemeral_key, from).await;
+        self.save(from, &StoredSession { state, init: None }).await?;
+        to_string(plaintext)
+    }
+
+    async fn initiate(&self, account: &Account, to: &str) -> NavajoResult<Option<StoredSession>> {
+        let bundle = match self.http_client.get_prekey_bundle(to).await {
+            Ok(bundle) => bundle,
+            Err(_) => return Ok(None),
+        };
+        let identity = &bundle.identity;
+        if identity.address != to || !identity.verify() {
+            return Err(NavajoError::new(VERIFY_SIGN_ERROR));
+        }
+        let keys = PrekeyBundleKeys {
+            identity_key: &identity.identity_key,
+            signed_prekey: &identity.signed_prekey,
+            one_time_prekey: bundle.one_time_prekey.as_ref().map(|prekey| prekey.prekey.as_str()),
+        };
+        let identity_key = self.prekey_manager.identity_key().await?;
+        let initiation = initiate(&identity_key, &keys).ok_or_else(|| NavajoError::new(INVALID_KEY_PAIR))?;
+        let state = RatchetState::init_sender(&initiation.shared_secret, &identity.signed_prekey)
+            .ok_or_else(|| NavajoError::new(INVALID_KEY_PAIR))?;
+        let init = RatchetInit {
+            identity: self.prekey_manager.signed_identity(account).await?,
+            ephemeral_key: initiation.ephemeral_key,
+            signed_prekey_id: identity.signed_prekey_id,
+            one_time_prekey_id: bundle.one_time_prekey.map(|prekey| prekey.key_id),
+        };
+        Ok(Some(StoredSession { state, init: Some(init) }))
+    }
+
+    async fn respond(&self, from: &str, init: &RatchetInit) -> NavajoResult<RatchetState> {
+        if init.identity.address != from || !init.identity.verify() {
+            return Err(NavajoError::new(VERIFY_SIGN_ERROR));
+        }
+        let identity_key = self.prekey_manager.identity_key().await?;
+        let signed_prekey = self.prekey_manager.signed_prekey(init.signed_prekey_id)
+            .await.ok_or_else(|| NavajoError::new(E2E_DECRYPT_ERROR))?;
+        let one_time_prekey = match init.one_time_prekey_id {
+            Some(key_id) => {
+                let secret = self.session_client.get_one_time_prekey(&self.device_id, key_id)
+                    .await.ok_or_else(|| NavajoError::new(E2E_DECRYPT_ERROR))?;
+                Some(X25519KeyPair::from_secret_str(&secret).ok_or_else(|| NavajoError::new(INVALID_KEY_PAIR))?)
+            }
+            None => None,
+        };
+        let shared_secret = respond(
+            &identity_key,
+            &signed_prekey,
+            one_time_prekey.as_ref(),
+            &init.identity.identity_key,
+            &init.ephemeral_key,
+        ).ok_or_else(|| NavajoError::new(E2E_DECRYPT_ERROR))?;
+        Ok(RatchetState::init_receiver(&shared_secret, &signed_prekey))
+    }
+
+    async fn load(&self, address: &str) -> Option<StoredSession> {
+        let json_str = self.session_client.get_ratchet_session(&self.device_id, address).await?;
+        serde_json::from_str(&json_str).ok()
+    }
+
+    async fn save(&self, address: &str, session: &StoredSession) -> NavajoResult<()> {
+        let json_str = serde_json::to_string(session).map_err(|_| NavajoError::new(E2E_ENCRYPT_ERROR))?;
+        self.session_client.set_ratchet_session(&self.device_id, address, &json_str).await;
+        Ok(())
+    }
+}
+
+fn associated_data(from: &str, to: &str) -> Vec<u8> {
+    format!("{}:{}", from, to).into_bytes()
+}
+
+fn to_string(plaintext: Vec<u8>) -> NavajoResult<String> {
+    String::from_utf8(plaintext).map_err(|_| NavajoError::new(E2E_DECRYPT_ERROR))
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+    use uuid::Uuid;
+    use common::account::Account;
+    use common::e2e::{RatchetContent, RatchetInit};
+    use common::errors::REPLAY_ERROR;
+    use ncrypto::algo::ratchet::RatchetState;
+    use ncrypto::algo::x3dh::{initiate, PrekeyBundleKeys};
+    use crate::http::HttpClient;
+    use crate::keystore::storage::KeyDB;
+    use crate::prekey::PrekeyManager;
+    use crate::ratchet::{associated_data, RatchetSessions};
+    use crate::session::SessionClient;
+
+    /// Encrypts as the sender of a session started without a one-time prekey.
+    fn encrypt(state: &mut RatchetState, init: &RatchetInit, from: &str, to: &str, plaintext: &str) -> String {
+        let message = state.encrypt(plaintext.as_bytes(), &associated_data(from, to)).unwrap();
+        serde_json::to_string(&RatchetContent { init: Some(init.clone()), message }).unwrap()
+    }
+
+    #[actix_rt::test]
+    async fn test_replayed_init() {
+        let session_client = SessionClient::new(Arc::new(KeyDB::init().await.unwrap()));
+        let http_client = HttpClient::new("http://127.0.0.1:1");
+        let alice = Account::new();
+        let bob = Account::new();
+        let alice_prekeys = PrekeyManager::new(session_client.clone(), http_client.clone(), Uuid::new_v4().to_string());
+        let bob_device_id = Uuid::new_v4().to_string();
+        let bob_prekeys = PrekeyManager::new(session_client.clone(), http_client.clone(), bob_device_id.clone());
+        let bob_sessions = RatchetSessions::new(session_client, http_client, bob_prekeys.clone(), bob_device_id);
+
+        let bob_identity = bob_prekeys.signed_identity(&bob).await.unwrap();
+        let initiation = initiate(&alice_prekeys.identity_key().await.unwrap(), &PrekeyBundleKeys {
+            identity_key: &bob_identity.identity_key,
+            signed_prekey: &bob_identity.signed_prekey,
+            one_time_prekey: None,
+        }).unwrap();
+        let init = RatchetInit {
+            identity: alice_prekeys.signed_identity(&alice).await.unwrap(),
+            ephemeral_key: initiation.ephemeral_key,
+            signed_prekey_id: bob_identity.signed_prekey_id,
+            one_time_prekey_id: None,
+        };
+        let mut state = RatchetState::init_sender(&initiation.shared_secret, &bob_identity.signed_prekey).unwrap();
+
+        let first = encrypt(&mut state, &init, &alice.address, &bob.address, "first");
+        assert_eq!(bob_sessions.decrypt_from(&bob, &alice.address, &first).await.unwrap(), "first");
+        let second = encrypt(&mut state, &init, &alice.address, &bob.address, "second");
+        assert_eq!(bob_sessions.decrypt_from(&bob, &alice.address, &second).await.unwrap(), "second");
+
+        // Replaying the first message doesn't start the session over
+        let err = bob_sessions.decrypt_from(&bob, &alice.address, &first).await.unwrap_err();
+        assert!(err.is(&REPLAY_ERROR));
+        let third = encrypt(&mut state, &init, &alice.address, &bob.address, "third");
+        assert_eq!(bob_sessions.decrypt_from(&bob, &alice.address, &third).await.unwrap(), "third");
+    }
+}
@@ -8,13 +8,33 @@ struct Info {
     to: String,
 }
 
+#[derive(Deserialize)]
+struct MessageInfo {
+    request_id: String,
+}
+
+#[derive(Deserialize)]
+struct ReadInfo {
+    from: String,
+    request_id: String,
+}
+
+#[derive(Deserialize)]
+struct DeviceIdInfo {
+    device_id: String,
+}
+
 pub fn device_scope_cfg(cfg: &mut web::ServiceConfig) {
     cfg
         .service(register)
         .service(login)
         .service(logout)
         .service(create_session)
-        .service(testchat);
+        .service(testchat)
+        .service(message_status)
+        .service(mark_read)
+        .service(devices)
+        .service(remove_device);
 }
 
 #[get("/register")]
@@ -47,8 +67,42 @@ async fn create_session(data: web::Data<WebServer>) -> impl Responder {
 
 #[get("/testchat")]
 async fn testchat(data: web::Data<WebServer>, info: web::Query<Info>) -> impl Responder {
-    data.test_p2p(&info.to).await.unwrap();
-    HttpResponse::Ok().body("Hello world!")
+    data.test_p2p(&info.to).await.map_or_else(
+        error_response,
+        |request_id| HttpResponse::Ok().json(request_id)
+    )
+}
+
+#[get("/message_status")]
+async fn message_status(data: web::Data<WebServer>, info: web::Query<MessageInfo>) -> impl Responder {
+    data.message_status(&info.request_id).await.map_or_else(
+        error_response,
+        |res| HttpResponse::Ok().json(res)
+    )
+}
+
+#[post("/read")]
+async fn mark_read(data: web::Data<WebServer>, info: web::Json<ReadInfo>) -> impl Responder {
+    data.mark_read(&info.from, &info.request_id).await.map_or_else(
+        error_response,
+        |_| HttpResponse::Ok().body(())
+    )
+}
+
+#[get("/devices")]
+async fn devices(data: web::Data<WebServer>) -> impl Responder {
+    data.devices().await.map_or_else(
+        error_response,
+        |res| HttpResponse::Ok().json(res)
+    )
+}
+
+#[post("/remove_device")]
+async fn remove_device(data: web::Data<WebServer>, info: web::Json<DeviceIdInfo>) -> impl Responder {
+    data.remove_device(&info.device_id).await.map_or_else(
+        error_response,
+        |_| HttpResponse::Ok().body(())
+    )
 }
 
 #[post("/echo")]
@@ -2,13 +2,23 @@ use std::sync::Arc;
 use common::account::Account;
 use crate::keystore::storage::KeyDB;
 
-const SESSION_EXPIRE_SECONDS: u64 = 30 * 24 * 60 * 60; // 30 days
-
 const CLIENT_DEVICE_ACCOUNT: &str = "client_device_account:";
 
 const CLIENT_SESSION: &str = "client_session:";
 const CLIENT_SECRET: &str = "client_secret:";
+const CLIENT_SESSION_EXPIRE: &str = "client_session_expire:";
 const CLIENT_DEVICE_ID: &str = "client_device_id:";
+const CLIENT_SERVER_PUBLIC_KEY: &str = "client_server_public_key:";
+const CLIENT_CONTACT_PUBLIC_KEY: &str = "client_contact_public_key:";
+const CLIENT_IDENTITY_KEY: &str = "client_identity_key:";
+const CLIENT_SIGNED_PREKEY: &str = "client_signed_prekey:";
+const CLIENT_ONE_TIME_PREKEY: &str = "client_one_time_prekey:";
+const CLIENT_PREKEY_NEXT_ID: &str = "client_prekey_next_id:";
+const CLIENT_RATCHET_SESSION: &str = "client_ratchet_session:";
+const CLIENT_RATCHET_INIT: &str = "client_ratchet_init:";
+const CLIENT_MESSAGE_STATUS: &str = "client_message_status:";
+const CLIENT_RECEIVED_MESSAGE: &str = "client_received_message:";
+const CLIENT_OUTBOX: &str = "client_outbox:";
 
 pub struct SessionClient {
     key_db: Arc<KeyDB>,
@@ -21,7 +31,7 @@ impl SessionClient {
 
     pub async fn get_device_account(&self, device_id: &str) -> Option<Account> {
         let json_str = self.key_db.get(format!("{}{}", CLIENT_DEVICE_ACCOUNT, device_id).as_str()).await?;
-        Some(json_str.into())
+        json_str.try_into().ok()
     }
 
     pub async fn set_device_account(&self, device_id: &str, account: &Account) {
@@ -62,6 +72,20 @@ impl SessionClient {
         self.key_db.remove(format!("{}{}", CLIENT_SECRET, device_id).as_str()).await;
     }
 
+    pub async fn get_session_expire(&self, device_id: &str) -> Option<u128> {
+        let key = format!("{}{}", CLIENT_SESSION_EXPIRE, device_id);
+        self.key_db.get(&key).await?.parse().ok()
+    }
+
+    pub async fn set_session_expire(&self, device_id: &str, expire_ms: u128) {
+        let key = format!("{}{}", CLIENT_SESSION_EXPIRE, device_id);
+        self.key_db.set(&key, &expire_ms.to_string()).await;
+    }
+
+    pub async fn del_session_expire(&self, device_id: &str) {
+        self.key_db.remove(format!("{}{}", CLIENT_SESSION_EXPIRE, device_id).as_str()).await;
+    }
+
     pub async fn get_device_id(&self, client_name: &str) -> Option<String> {
         let key = format!("{}{}", CLIENT_DEVICE_ID, client_name);
         self.key_db.get(&key).await
@@ -71,6 +95,173 @@ impl SessionClient {
         let key = format!("{}{}", CLIENT_DEVICE_ID, client_name);
         self.key_db.set(&key, device_id).await;
     }
+
+    pub async fn get_server_public_key(&self, server_host: &str) -> Option<String> {
+        let key = format!("{}{}", CLIENT_SERVER_PUBLIC_KEY, server_host);
+        self.key_db.get(&key).await
+    }
+
+    pub async fn set_server_public_key(&self, server_host: &str, public_key: &str) {
+        let key = format!("{}{}", CLIENT_SERVER_PUBLIC_KEY, server_host);
+        self.key_db.set(&key, public_key).await;
+    }
+
+    pub async fn get_contact_public_key(&self, address: &str) -> Option<String> {
+        let key = format!("{}{}", CLIENT_CONTACT_PUBLIC_KEY, address);
+        self.key_db.get(&key).await
+    }
+
+    pub async fn set_contact_public_key(&self, address: &str, public_key: &str) {
+        let key = format!("{}{}", CLIENT_CONTACT_PUBLIC_KEY, address);
+        self.key_db.set(&key, public_key).await;
+    }
+
+    pub async fn get_identity_key(&self, device_id: &str) -> Option<String> {
+        let key = format!("{}{}", CLIENT_IDENTITY_KEY, device_id);
+        self.key_db.get(&key).await
+    }
+
+    pub async fn set_identity_key(&self, device_id: &str, secret: &str) {
+        let key = format!("{}{}", CLIENT_IDENTITY_KEY, device_id);
+        self.key_db.set(&key, secret).await;
+    }
+
+    /// Signed prekey stored as `<key_id>:<secret>`.
+    pub async fn get_signed_prekey(&self, device_id: &str) -> Option<(u32, String)> {
+        let key = format!("{}{}", CLIENT_SIGNED_PREKEY, device_id);
+        let value = self.key_db.get(&key).await?;
+        let (key_id, secret) = value.split_once(':')?;
+        Some((key_id.parse().ok()?, secret.to_string()))
+    }
+
+    pub async fn set_signed_prekey(&self, device_id: &str, key_id: u32, secret: &str) {
+        let key = format!("{}{}", CLIENT_SIGNED_PREKEY, device_id);
+        self.key_db.set(&key, &format!("{}:{}", key_id, secret)).await;
+    }
+
+    pub async fn set_one_time_prekey(&self, device_id: &str, key_id: u32, secret: &str) {
+        let key = format!("{}{}:{}", CLIENT_ONE_TIME_PREKEY, device_id, key_id);
+        self.key_db.set(&key, secret).await;
+    }
+
+    pub async fn get_one_time_prekey(&self, device_id: &str, key_id: u32) -> Option<String> {
+        let key = format!("{}{}:{}", CLIENT_ONE_TIME_PREKEY, device_id, key_id);
+        self.key_db.get(&key).await
+    }
+
+    pub async fn del_one_time_prekey(&self, device_id: &str, key_id: u32) {
+        self.key_db.remove(format!("{}{}:{}", CLIENT_ONE_TIME_PREKEY, device_id, key_id).as_str()).await;
+    }
+
+    pub async fn get_ratchet_session(&self, device_id: &str, address: &str) -> Option<String> {
+        let key = format!("{}{}:{}", CLIENT_RATCHET_SESSION, device_id, address);
+        self.key_db.get(&key).await
+    }
+
+    pub async fn set_ratchet_session(&self, device_id: &str, address: &str, state: &str) {
+        let key = format!("{}{}:{}", CLIENT_RATCHET_SESSION, device_id, address);
+        self.key_db.set(&key, state).await;
+    }
+
+    /// The address that started a session with the ephemeral key, if one did.
+    pub async fn get_ratchet_init(&self, device_id: &str, ephemeral_key: &str) -> Option<String> {
+        let key = format!("{}{}:{}", CLIENT_RATCHET_INIT, device_id, ephemeral_key);
+        self.key_db.get(&key).await
+    }
+
+    pub async fn set_ratchet_init(&self, device_id: &str, ephemeral_key: &str, address: &str) {
+        let key = format!("{}{}:{}", CLIENT_RATCHET_INIT, device_id, ephemeral_key);
+        self.key_db.set(&key, address).await;
+    }
+
+    /// Drops every ratchet session of the device and the inits that started them.
+    pub async fn del_ratchet_state(&self, device_id: &str) {
+        for prefix in [CLIENT_RATCHET_SESSION, CLIENT_RATCHET_INIT] {
+            let prefix = format!("{}{}:", prefix, device_id);
+            for (key, _) in self.key_db.find_by_prefix(&prefix).await {
+                self.key_db.remove(&key).await;
+            }
+        }
+    }
+
+    pub async fn get_message_status(&self, device_id: &str, request_id: &str) -> Option<String> {
+        let key = format!("{}{}:{}", CLIENT_MESSAGE_STATUS, device_id, request_id);
+        self.key_db.get(&key).await
+    }
+
+    pub async fn set_message_status(&self, device_id: &str, request_id: &str, status: &str) {
+        let key = format!("{}{}:{}", CLIENT_MESSAGE_STATUS, device_id, request_id);
+        self.key_db.set(&key, status).await;
+    }
+
+    /// Every status of the device, by `request_id`.
+    pub async fn get_message_statuses(&self, device_id: &str) -> Vec<(String, String)> {
+        let prefix = format!("{}{}:", CLIENT_MESSAGE_STATUS, device_id);
+        self.find_by_request_id(&prefix).await
+    }
+
+    pub async fn del_message_status(&self, device_id: &str, request_id: &str) {
+        self.key_db.remove(format!("{}{}:{}", CLIENT_MESSAGE_STATUS, device_id, request_id).as_str()).await;
+    }
+
+    pub async fn get_received_message(&self, device_id: &str, request_id: &str) -> Option<String> {
+        let key = format!("{}{}:{}", CLIENT_RECEIVED_MESSAGE, device_id, request_id);
+        self.key_db.get(&key).await
+    }
+
+    pub async fn set_received_message(&self, device_id: &str, request_id: &str, received: &str) {
+        let key = format!("{}{}:{}", CLIENT_RECEIVED_MESSAGE, device_id, request_id);
+        self.key_db.set(&key, received).await;
+    }
+
+    /// Every received chat of the device, by `request_id`.
+    pub async fn get_received_messages(&self, device_id: &str) -> Vec<(String, String)> {
+        let prefix = format!("{}{}:", CLIENT_RECEIVED_MESSAGE, device_id);
+        self.find_by_request_id(&prefix).await
+    }
+
+    pub async fn del_received_message(&self, device_id: &str, request_id: &str) {
+        self.key_db.remove(format!("{}{}:{}", CLIENT_RECEIVED_MESSAGE, device_id, request_id).as_str()).await;
+    }
+
+    async fn find_by_request_id(&self, prefix: &str) -> Vec<(String, String)> {
+        self.key_db.find_by_prefix(prefix).await.into_iter()
+            .filter_map(|(key, value)| Some((key.strip_prefix(prefix)?.to_string(), value)))
+            .collect()
+    }
+
+    /// The whole outbox as one list, as older clients saved it.
+    pub async fn get_legacy_outbox(&self, device_id: &str) -> Option<String> {
+        let key = format!("{}{}", CLIENT_OUTBOX, device_id);
+        self.key_db.get(&key).await
+    }
+
+    pub async fn del_legacy_outbox(&self, device_id: &str) {
+        self.key_db.remove(format!("{}{}", CLIENT_OUTBOX, device_id).as_str()).await;
+    }
+
+    pub async fn get_outbox_entries(&self, device_id: &str) -> Vec<String> {
+        let prefix = format!("{}{}:", CLIENT_OUTBOX, device_id);
+        self.key_db.find_by_prefix(&prefix).await.into_iter().map(|(_, entry)| entry).collect()
+    }
+
+    pub async fn set_outbox_entry(&self, device_id: &str, request_id: &str, entry: &str) {
+        let key = format!("{}{}:{}", CLIENT_OUTBOX, device_id, request_id);
+        self.key_db.set(&key, entry).await;
+    }
+
+    pub async fn del_outbox_entry(&self, device_id: &str, request_id: &str) {
+        self.key_db.remove(format!("{}{}:{}", CLIENT_OUTBOX, device_id, request_id).as_str()).await;
+    }
+
+    pub async fn next_prekey_id(&self, device_id: &str) -> u32 {
+        let key = format!("{}{}", CLIENT_PREKEY_NEXT_ID, device_id);
+        let next_id = self.key_db.get(&key).await
+            .and_then(|value| value.parse().ok())
+            .unwrap_or(1);
+        self.key_db.set(&key, &(next_id + 1).to_string()).await;
+        next_id
+    }
 }
 
 #[cfg(test)]
@@ -0,0 +1,229 @@
+use std::sync::Arc;
+use std::time::{SystemTime, UNIX_EPOCH};
+use serde::{Deserialize, Serialize};
+use p2p::message::{CHAT_STATUS_DELIVERED, CHAT_STATUS_QUEUED, ChatStatus, Message, RECEIPT_DELIVERED, RECEIPT_READ, ReceiptType};
+use crate::session::SessionClient;
+
+/// Written to the server socket.
+pub const STATUS_SENT: u8 = 0;
+/// The server accepted it and holds it until the recipient connects.
+pub const STATUS_QUEUED: u8 = 1;
+/// The server handed it to a connection of the recipient.
+pub const STATUS_RELAYED: u8 = 2;
+/// The recipient's device opened it.
+pub const STATUS_DELIVERED: u8 = 3;
+pub const STATUS_READ: u8 = 4;
+
+/// Statuses and received chats are forgotten once untouched for this long, as the server
+/// forgets a `request_id`.
+const STATUS_EXPIRE_MS: u128 = 7 * 24 * 60 * 60 * 1000; // 7 days
+
+/// Status of a chat this device sent, keyed by the chat's `request_id`.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct MessageStatus {
+    pub request_id: String,
+    pub to_address: String,
+    pub status: u8,
+    pub update_time_ms: u128,
+}
+
+/// A chat this device received, keyed by the chat's `request_id`.
+#[derive(Serialize, Deserialize)]
+struct ReceivedMessage {
+    from_address: String,
+    receive_time_ms: u128,
+}
+
+/// Tracks outgoing chats from the server statuses and receipts coming back.
+pub struct MessageStatuses {
+    session_client: Arc<SessionClient>,
+    device_id: String,
+}
+
+impl MessageStatuses {
+    pub fn new(session_client: Arc<SessionClient>, device_id: String) -> Arc<Self> {
+        Arc::new(Self { session_client, device_id })
+    }
+
+    pub async fn record_sent(&self, chat: &Message) {
+        if let Message::ChatInfoMessage { common_info, to_address, .. } = chat {
+            let status = MessageStatus {
+                request_id: common_info.request_id.clone(),
+                to_address: to_address.clone(),
+                status: STATUS_SENT,
+                update_time_ms: now_ms(),
+            };
+            self.save(&status).await;
+        }
+    }
+
+    /// Remembers a chat this device received and opened, only those can be marked read.
+    pub async fn record_received(&self, chat: &Message) {
+        if let Message::ChatInfoMessage { common_info, from_address, .. } = chat {
+            let received = ReceivedMessage { from_address: from_address.clone(), receive_time_ms: now_ms() };
+            if let Ok(json_str) = serde_json::to_string(&received) {
+                self.session_client.set_received_message(&self.device_id, &common_info.request_id, &json_str).await;
+            }
+        }
+    }
+
+    /// The sender of a chat this device received.
+    pub async fn received_from(&self, request_id: &str) -> Option<String> {
+        let json_str = self.session_client.get_received_message(&self.device_id, request_id).await?;
+        let received: ReceivedMessage = serde_json::from_str(&json_str).ok()?;
+        Some(received.from_address)
+    }
+
+    pub async fn get(&self, request_id: &str) -> Option<MessageStatus> {
+        let json_str = self.session_client.get_message_status(&self.device_id, request_id).await?;
+        serde_json::from_str(&json_str).ok()
+    }
+
+    /// Applies a status or receipt about one of our chats, other messages are ignored.
+    pub async fn apply(&self, message: &Message) {
+        let (from_address, status) = match message {
+            Message::ChatStatusMessage { from_address, status, .. } => (from_address, from_chat_status(*status)),
+            Message::ReceiptMessage { from_address, receipt, .. } => (from_address, from_receipt(*receipt)),
+            _ => return,
+        };
+        let (status, mut stored) = match (status, self.get(&message.common_info().response_id).await) {
+            (Some(status), Some(stored)) => (status, stored),
+            _ => return,
+        };
+        // Only the recipient of the chat can report on it, and a status never goes back
+        if stored.to_address != *from_address || status <= stored.status {
+            return;
+        }
+        stored.status = status;
+        stored.update_time_ms = now_ms();
+        self.save(&stored).await;
+    }
+
+    /// Forgets expired statuses and received chats, and those saved in an older format.
+    pub async fn prune(&self) {
+        let now = now_ms();
+        let expired = |time_ms: u128| now.saturating_sub(time_ms) >= STATUS_EXPIRE_MS;
+        for (request_id, json_str) in self.session_client.get_message_statuses(&self.device_id).await {
+            let status: Option<MessageStatus> = serde_json::from_str(&json_str).ok();
+            if status.is_none_or(|status| expired(status.update_time_ms)) {
+                self.session_client.del_message_status(&self.device_id, &request_id).await;
+            }
+        }
+        for (request_id, json_str) in self.session_client.get_received_messages(&self.device_id).await {
+            let received: Option<ReceivedMessage> = serde_json::from_str(&json_str).ok();
+            if received.is_none_or(|received| expired(received.receive_time_ms)) {
+                self.session_client.del_received_message(&self.device_id, &request_id).await;
+            }
+        }
+    }
+
+    async fn save(&self, status: &MessageStatus) {
+        if let Ok(json_str) = serde_json::to_string(status) {
+            self.session_client.set_message_status(&self.device_id, &status.request_id, &json_str).await;
+        }
+    }
+}
+
+fn from_chat_status(status: ChatStatus) -> Option<u8> {
+    match status {
+        CHAT_STATUS_QUEUED => Some(STATUS_QUEUED),
+        CHAT_STATUS_DELIVERED => Some(STATUS_RELAYED),
+        _ => None,
+    }
+}
+
+fn from_receipt(receipt: ReceiptType) -> Option<u8> {
+    match receipt {
+        RECEIPT_DELIVERED => Some(STATUS_DELIVERED),
+        RECEIPT_READ => Some(STATUS_READ),
+        _ => None,
+    }
+}
+
+fn now_ms() -> u128 {
+    SystemTime::now().duration_since(UNIX_EPOCH).unwrap().as_millis()
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+    use p2p::message::{CHAT_STATUS_QUEUED, Message, RECEIPT_DELIVERED, RECEIPT_READ};
+    use crate::keystore::storage::KeyDB;
+    use crate::session::SessionClient;
+    use crate::status::{MessageStatus, MessageStatuses, now_ms, ReceivedMessage, STATUS_DELIVERED, STATUS_EXPIRE_MS, STATUS_QUEUED, STATUS_READ, STATUS_SENT};
+
+    #[actix_rt::test]
+    async fn test_message_status() {
+        let key_db = Arc::new(KeyDB::init().await.unwrap());
+        let session_client = SessionClient::new(key_db);
+        let statuses = MessageStatuses::new(session_client.clone(), String::from("status_device"));
+        let chat = Message::ChatInfoMessage {
+            common_info: Default::default(),
+            from_address: String::from("alice"),
+            to_address: String::from("bob"),
+            info_type: 0,
+            content: String::from("hi"),
+        };
+        let request_id = chat.common_info().request_id.clone();
+        statuses.record_sent(&chat).await;
+        assert_eq!(statuses.get(&request_id).await.unwrap().status, STATUS_SENT);
+
+        statuses.apply(&Message::chat_status(&chat, CHAT_STATUS_QUEUED).unwrap()).await;
+        assert_eq!(statuses.get(&request_id).await.unwrap().status, STATUS_QUEUED);
+
+        statuses.apply(&Message::receipt(&chat, RECEIPT_READ).unwrap()).await;
+        assert_eq!(statuses.get(&request_id).await.unwrap().status, STATUS_READ);
+
+        // A late delivery receipt doesn't move the status back
+        statuses.apply(&Message::receipt(&chat, RECEIPT_DELIVERED).unwrap()).await;
+        assert_eq!(statuses.get(&request_id).await.unwrap().status, STATUS_READ);
+
+        // Nobody but the recipient can report on the chat
+        let other = Message::ChatInfoMessage {
+            common_info: Default::default(),
+            from_address: String::from("alice"),
+            to_address: String::from("carol"),
+            info_type: 0,
+            content: String::from("hi"),
+        };
+        statuses.record_sent(&other).await;
+        let other_id = other.common_info().request_id.clone();
+        let mut forged_other = Message::receipt(&other, RECEIPT_DELIVERED).unwrap();
+        if let Message::ReceiptMessage { from_address, .. } = &mut forged_other {
+            *from_address = String::from("mallory");
+        }
+        statuses.apply(&forged_other).await;
+        assert_eq!(statuses.get(&other_id).await.unwrap().status, STATUS_SENT);
+        statuses.apply(&Message::receipt(&other, RECEIPT_DELIVERED).unwrap()).await;
+        assert_eq!(statuses.get(&other_id).await.unwrap().status, STATUS_DELIVERED);
+
+        // Received chats are kept apart from sent ones
+        assert!(statuses.received_from(&request_id).await.is_none());
+        statuses.record_received(&chat).await;
+        assert_eq!(statuses.received_from(&request_id).await.unwrap(), "alice");
+        assert_eq!(statuses.get(&request_id).await.unwrap().status, STATUS_READ);
+
+        // Pruning keeps fresh entries and forgets expired ones
+        statuses.prune().await;
+        assert!(statuses.get(&request_id).await.is_some());
+        assert_eq!(statuses.received_from(&request_id).await.unwrap(), "alice");
+        let stale = MessageStatus {
+            request_id: String::from("stale_chat"),
+            to_address: String::from("bob"),
+            status: STATUS_SENT,
+            update_time_ms: now_ms() - STATUS_EXPIRE_MS,
+        };
+        statuses.save(&stale).await;
+        let stale_received = serde_json::to_string(&ReceivedMessage {
+            from_address: String::from("bob"),
+            receive_time_ms: now_ms() - STATUS_EXPIRE_MS,
+        }).unwrap();
+        session_client.set_received_message("status_device", "stale_chat", &stale_received).await;
+        session_client.set_received_message("status_device", "legacy_chat", "bob").await;
+        statuses.prune().await;
+        assert!(statuses.get("stale_chat").await.is_none());
+        assert!(statuses.received_from("stale_chat").await.is_none());
+        assert!(session_client.get_received_message("status_device", "legacy_chat").await.is_none());
+        assert!(statuses.get(&request_id).await.is_some());
+    }
+}
@@ -3,23 +3,31 @@ use actix_web::{App, HttpServer, web};
 use actix_web::web::Data;
 use serde::Deserialize;
 use tokio::sync::mpsc::Sender;
-use uuid::Uuid;
 use common::account::Account;
-use common::beans::{DeviceInfoRequest, DeviceInfoResponse};
-use common::errors::{HTTP_ERROR, INVALID_DEVICE_ID, LOGIN_ERROR, NavajoError, NavajoResult};
-use ncrypto::algo::base64::encode_to_str;
-use ncrypto::algo::diffie_hellman::DiffieHellman;
-use p2p::message::Message::ChatInfoMessage;
-use p2p::message::{MESSAGE_TYPE_CHAT_MESSAGE, P2PMessage, TEXT_TYPE};
+use common::beans::LinkedDevice;
+use common::e2e;
+use common::errors::{HTTP_ERROR, INVALID_ADDRESS_ERROR, INVALID_DEVICE_ID, LOGIN_ERROR, MESSAGE_NOT_FOUND, NavajoError, NavajoResult};
+use common::errors::NavajoErrorRepr::SocketError;
+use common::key_pair::address_from_public_key;
+use p2p::message::Message::{ChatInfoMessage, ReceiptMessage};
+use p2p::message::{CommonInfo, E2E_TEXT_TYPE, MESSAGE_TYPE_CHAT_MESSAGE, P2PMessage, RATCHET_TEXT_TYPE, RECEIPT_READ};
+use crate::handshake::SessionManager;
 use crate::http::HttpClient;
+use crate::outbox::Outbox;
+use crate::ratchet::RatchetSessions;
 use crate::route::device_scope_cfg;
 use crate::session::SessionClient;
+use crate::status::{MessageStatus, MessageStatuses};
 
 #[derive(Clone)]
 pub struct WebServer {
     config: WebServerConfig,
     session_client: Arc<SessionClient>,
     http_client: Arc<HttpClient>,
+    session_manager: Arc<SessionManager>,
+    ratchet_sessions: Arc<RatchetSessions>,
+    message_statuses: Arc<MessageStatuses>,
+    outbox: Arc<Outbox>,
     device_id: String,
     p2p_client_sender: Arc<Sender<P2PMessage>>,
 }
@@ -28,13 +36,24 @@ pub struct WebServer {
 pub struct WebServerConfig {
     pub port: u16,
     pub server_host: String,
+    /// Pinned server identity key. Without it the first key seen is trusted.
+    #[serde(default)]
+    pub server_public_key: Option<String>,
+    /// Talk to servers without an identity key, leaving sessions unauthenticated.
+    #[serde(default)]
+    pub allow_unsigned_server: bool,
 }
 
 impl WebServer {
+    #[allow(clippy::too_many_arguments)]
     pub fn new(
         config: WebServerConfig,
         session_client: Arc<SessionClient>,
         http_client: Arc<HttpClient>,
+        session_manager: Arc<SessionManager>,
+        ratchet_sessions: Arc<RatchetSessions>,
+        message_statuses: Arc<MessageStatuses>,
+        outbox: Arc<Outbox>,
         device_id: String,
         p2p_client_sender: Arc<Sender<P2PMessage>>,
     ) -> Self {
@@ -42,6 +61,10 @@ impl WebServer {
             config,
             session_client,
             http_client,
+            session_manager,
+            ratchet_sessions,
+            message_statuses,
+            outbox,
             device_id,
             p2p_client_sender,
         }
@@ -70,6 +93,7 @@ impl WebServer {
             session_client.set_device_account(device_id, &temp).await;
             account = Some(temp);
         }
+        self.establish_session().await;
         Ok(account.unwrap())
     }
 
@@ -81,71 +105,108 @@ impl WebServer {
         if account.is_some() {
             return Err(NavajoError::new(LOGIN_ERROR))
         }
-        let temp = Account::recover(mnemonic);
+        let temp = Account::recover(mnemonic)?;
         session_client.set_device_account(device_id, &temp).await;
+        // The account is only kept once the server took it, so that logging in can be retried
+        if let Err(err) = self.session_manager.ensure_session().await {
+            session_client.del_device_account(device_id).await;
+            return Err(err);
+        }
         Ok(temp)
     }
 
-    pub async fn logout(&self) {
-        let session_client = self.session_client.clone();
-        let device_id  = &self.device_id;
+    // Best effort, the session manager retries in the background
+    async fn establish_session(&self) {
+        if let Err(err) = self.session_manager.ensure_session().await {
+            println!("Establish session failed, {}", err);
+        }
+    }
 
-        session_client.del_device_account(device_id).await;
-        session_client.del_session(device_id).await;
-        session_client.del_secret(device_id).await;
+    pub async fn logout(&self) {
+        self.session_manager.logout().await
     }
 
     pub async fn create_session(&self) -> NavajoResult<(String, String)> {
-        self.logic_create_session().await
+        self.session_manager.create_session().await
     }
 
-    async fn logic_create_session(&self) -> NavajoResult<(String, String)> {
+    /// The server may lie about a key, but not about one that hashes to the address.
+    async fn contact_public_key(&self, address: &str) -> NavajoResult<String> {
         let session_client = self.session_client.clone();
-        let http_client = self.http_client.clone();
-        let device_id  = &self.device_id;
-
-        let account = session_client.get_device_account(device_id)
-            .await.ok_or_else(|| NavajoError::new(INVALID_DEVICE_ID))?;
-
-        let dh = DiffieHellman::new();
-        let dh_pub = dh.public_key_to_str();
-        let content = Uuid::new_v4().to_string();
-        let address = &account.address;
-        let public_key = account.key_pair.gen_public_key();
-        let sign = account.sign_data(&content);
-        let body = DeviceInfoRequest {
-            device_id: String::from(device_id),
-            content,
-            public_key,
-            address: String::from(address),
-            sign,
-            dh_pub,
-        };
-        let DeviceInfoResponse { session, dh_pub } = http_client.create_session(&body)
+        if let Some(public_key) = session_client.get_contact_public_key(address).await {
+            return Ok(public_key);
+        }
+        let response = self.http_client.get_public_key(address)
             .await.map_err(|_| NavajoError::new(HTTP_ERROR))?;
-        let shared_secret = dh.compute_shared_secret_from_str(&dh_pub);
-        let shared_secret = encode_to_str(&shared_secret);
-        session_client.set_session(device_id, &session).await;
-        session_client.set_secret(device_id, &shared_secret).await;
-        Ok((session, shared_secret))
+        if address_from_public_key(&response.public_key).as_deref() != Some(address) {
+            return Err(NavajoError::new(INVALID_ADDRESS_ERROR));
+        }
+        session_client.set_contact_public_key(address, &response.public_key).await;
+        Ok(response.public_key)
     }
 
-    pub async fn test_p2p(&self, to: &str) -> NavajoResult<()> {
+    /// Returns the chat's `request_id`, the key of its status.
+    pub async fn test_p2p(&self, to: &str) -> NavajoResult<String> {
         let session_client = self.session_client.clone();
         let account = session_client.get_device_account(&self.device_id).
             await.ok_or_else(|| NavajoError::new(INVALID_DEVICE_ID))?;
+        let (info_type, content) = match self.ratchet_sessions.encrypt_to(&account, to, "Hello").await? {
+            Some(content) => (RATCHET_TEXT_TYPE, content),
+            None => {
+                // Contacts without a prekey bundle only get the account key encryption
+                let public_key = self.contact_public_key(to).await?;
+                (E2E_TEXT_TYPE, e2e::seal(&account.key_pair, &public_key, "Hello")?)
+            }
+        };
         let message = ChatInfoMessage {
             common_info: Default::default(),
             from_address: account.address,
             to_address: to.to_string(),
-            info_type: TEXT_TYPE,
-            content: "Hello".to_string()
+            info_type,
+            content,
         };
         let p2p_message = P2PMessage {
             message_type: MESSAGE_TYPE_CHAT_MESSAGE,
             data: (&message).into(),
         };
+        self.message_statuses.record_sent(&message).await;
+        // Kept until the server reports a status, the client resends it meanwhile
+        self.outbox.push(&message).await;
         self.p2p_client_sender.send(p2p_message).await.unwrap();
+        Ok(message.common_info().request_id.clone())
+    }
+
+    pub async fn devices(&self) -> NavajoResult<Vec<LinkedDevice>> {
+        self.session_manager.list_devices().await
+    }
+
+    pub async fn remove_device(&self, device_id: &str) -> NavajoResult<()> {
+        self.session_manager.remove_device(device_id).await
+    }
+
+    pub async fn message_status(&self, request_id: &str) -> NavajoResult<MessageStatus> {
+        self.message_statuses.get(request_id).await.ok_or_else(|| NavajoError::new(MESSAGE_NOT_FOUND))
+    }
+
+    /// Tells `from`, the sender of chat `request_id`, that it has been read.
+    pub async fn mark_read(&self, from: &str, request_id: &str) -> NavajoResult<()> {
+        // Only chats this device received and opened
+        if self.message_statuses.received_from(request_id).await.as_deref() != Some(from) {
+            return Err(NavajoError::new(MESSAGE_NOT_FOUND));
+        }
+        let account = self.session_client.get_device_account(&self.device_id)
+            .await.ok_or_else(|| NavajoError::new(INVALID_DEVICE_ID))?;
+        let receipt = ReceiptMessage {
+            common_info: CommonInfo {
+                response_id: request_id.to_string(),
+                ..Default::default()
+            },
+            from_address: account.address,
+            to_address: from.to_string(),
+            receipt: RECEIPT_READ,
+        };
+        self.p2p_client_sender.send((&receipt).into()).await
+            .map_err(|_| NavajoError::new(SocketError { message: "P2P client stopped" }))?;
         Ok(())
     }
 }
\ No newline at end of file
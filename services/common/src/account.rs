@@ -1,4 +1,5 @@
 use serde::{Deserialize, Serialize};
+use crate::errors::{NavajoError, NavajoResult};
 use crate::key_pair::KeyPair;
 
 #[derive(Serialize, Deserialize, Debug)]
@@ -15,10 +16,10 @@ impl Account {
         Self { key_pair, address }
     }
 
-    pub fn recover(mnemonic: &str) -> Account {
-        let key_pair = KeyPair::recover(mnemonic);
+    pub fn recover(mnemonic: &str) -> NavajoResult<Account> {
+        let key_pair = KeyPair::recover(mnemonic)?;
         let address = key_pair.gen_address();
-        Account { key_pair, address }
+        Ok(Account { key_pair, address })
     }
 
     pub fn sign_data(&self, data: &str) -> String {
@@ -32,9 +33,11 @@ impl Default for Account {
     }
 }
 
-impl From<String> for Account {
-    fn from(value: String) -> Self {
-        serde_json::from_str(&value).unwrap()
+impl TryFrom<String> for Account {
+    type Error = NavajoError;
+
+    fn try_from(value: String) -> Result<Self, Self::Error> {
+        Ok(serde_json::from_str(&value)?)
     }
 }
 
@@ -1,5 +1,24 @@
 use serde::{Deserialize, Serialize};
-use crate::key_pair::verify;
+use crate::key_pair::{address_from_public_key, verify};
+
+/// Raw DH output is used as the only session key.
+pub const SESSION_PROTOCOL_LEGACY: u32 = 0;
+/// Directional session keys derived with HKDF from the DH output.
+pub const SESSION_PROTOCOL_HKDF: u32 = 1;
+/// HKDF keys, with both handshake messages signed over their full transcript.
+pub const SESSION_PROTOCOL_SIGNED: u32 = 2;
+
+const DEVICE_INFO_REQUEST_LABEL: &str = "navajo-create-session-request";
+const DEVICE_INFO_RESPONSE_LABEL: &str = "navajo-create-session-response";
+
+/// Length-prefixed join, so that no two field lists share the same signed string.
+pub fn canonical_transcript(label: &str, fields: &[&str]) -> String {
+    let mut transcript = String::from(label);
+    for field in fields {
+        transcript.push_str(&format!("|{}:{}", field.len(), field));
+    }
+    transcript
+}
 
 #[derive(Serialize, Deserialize, Debug, Default)]
 pub struct ApiResponse<T> {
@@ -35,11 +54,36 @@ pub struct DeviceInfoRequest {
     pub address: String,
     pub sign: String,
     pub dh_pub: String,
+    #[serde(default)]
+    pub protocol_version: u32,
+    #[serde(default)]
+    pub time_ms: u128,
 }
 
 impl DeviceInfoRequest {
+    pub fn transcript(&self) -> String {
+        canonical_transcript(DEVICE_INFO_REQUEST_LABEL, &[
+            &self.protocol_version.to_string(),
+            &self.device_id,
+            &self.content,
+            &self.public_key,
+            &self.address,
+            &self.dh_pub,
+            &self.time_ms.to_string(),
+        ])
+    }
+
+    /// Old clients only sign the random `content`, newer ones sign the whole transcript.
     pub fn verify_content(&self) -> bool {
-        verify(&self.content, &self.sign, &self.public_key)
+        if self.protocol_version >= SESSION_PROTOCOL_SIGNED {
+            verify(&self.transcript(), &self.sign, &self.public_key).is_ok()
+        } else {
+            verify(&self.content, &self.sign, &self.public_key).is_ok()
+        }
+    }
+
+    pub fn verify_address(&self) -> bool {
+        address_from_public_key(&self.public_key).is_some_and(|address| address == self.address)
     }
 }
 
@@ -47,4 +91,228 @@ impl DeviceInfoRequest {
 pub struct DeviceInfoResponse {
     pub session: String,
     pub dh_pub: String,
-}
\ No newline at end of file
+    #[serde(default)]
+    pub protocol_version: u32,
+    #[serde(default)]
+    pub time_ms: u128,
+    #[serde(default)]
+    pub server_public_key: String,
+    #[serde(default)]
+    pub sign: String,
+    /// When the server stops accepting the session, 0 from servers that don't expire sessions.
+    /// Not part of the signed transcript, the server enforces it regardless.
+    #[serde(default)]
+    pub session_expire_ms: u128,
+}
+
+impl DeviceInfoResponse {
+    /// Binds the server's DH key to the request it answers.
+    pub fn transcript(&self, request: &DeviceInfoRequest) -> String {
+        canonical_transcript(DEVICE_INFO_RESPONSE_LABEL, &[
+            &self.protocol_version.to_string(),
+            &self.session,
+            &self.dh_pub,
+            &self.time_ms.to_string(),
+            &self.server_public_key,
+            &request.transcript(),
+            &request.sign,
+        ])
+    }
+
+    pub fn verify_sign(&self, request: &DeviceInfoRequest) -> bool {
+        !self.sign.is_empty() && verify(&self.transcript(request), &self.sign, &self.server_public_key).is_ok()
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, Default)]
+pub struct PublicKeyResponse {
+    pub address: String,
+    pub public_key: String,
+}
+
+const REVOKE_SESSION_LABEL: &str = "navajo-revoke-session";
+const REMOVE_DEVICE_LABEL: &str = "navajo-remove-device";
+const LIST_DEVICES_LABEL: &str = "navajo-list-devices";
+
+/// Requests on behalf of an account are signed by its key, which must hash to `address`.
+fn verify_account_sign(address: &str, public_key: &str, transcript: &str, sign: &str) -> bool {
+    address_from_public_key(public_key).is_some_and(|key_address| key_address == address)
+        && verify(transcript, sign, public_key).is_ok()
+}
+
+/// Asks the server to forget the session of `device_id`, signed by the account key.
+#[derive(Serialize, Deserialize, Debug, Default)]
+pub struct RevokeSessionRequest {
+    pub address: String,
+    pub public_key: String,
+    pub device_id: String,
+    pub nonce: String,
+    pub time_ms: u128,
+    pub sign: String,
+}
+
+impl RevokeSessionRequest {
+    pub fn transcript(&self) -> String {
+        canonical_transcript(REVOKE_SESSION_LABEL, &[
+            &self.address,
+            &self.public_key,
+            &self.device_id,
+            &self.nonce,
+            &self.time_ms.to_string(),
+        ])
+    }
+
+    pub fn verify(&self) -> bool {
+        verify_account_sign(&self.address, &self.public_key, &self.transcript(), &self.sign)
+    }
+}
+
+/// Unlinks `device_id` from the signing account, dropping its session and queued messages.
+#[derive(Serialize, Deserialize, Debug, Default)]
+pub struct RemoveDeviceRequest {
+    pub address: String,
+    pub public_key: String,
+    pub device_id: String,
+    pub nonce: String,
+    pub time_ms: u128,
+    pub sign: String,
+}
+
+impl RemoveDeviceRequest {
+    pub fn transcript(&self) -> String {
+        canonical_transcript(REMOVE_DEVICE_LABEL, &[
+            &self.address,
+            &self.public_key,
+            &self.device_id,
+            &self.nonce,
+            &self.time_ms.to_string(),
+        ])
+    }
+
+    pub fn verify(&self) -> bool {
+        verify_account_sign(&self.address, &self.public_key, &self.transcript(), &self.sign)
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, Default)]
+pub struct ListDevicesRequest {
+    pub address: String,
+    pub public_key: String,
+    pub nonce: String,
+    pub time_ms: u128,
+    pub sign: String,
+}
+
+impl ListDevicesRequest {
+    pub fn transcript(&self) -> String {
+        canonical_transcript(LIST_DEVICES_LABEL, &[
+            &self.address,
+            &self.public_key,
+            &self.nonce,
+            &self.time_ms.to_string(),
+        ])
+    }
+
+    pub fn verify(&self) -> bool {
+        verify_account_sign(&self.address, &self.public_key, &self.transcript(), &self.sign)
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, Default, Clone, PartialEq, Eq)]
+pub struct LinkedDevice {
+    pub device_id: String,
+    pub last_seen_ms: u128,
+    /// Logged out, the device is kept until removed.
+    pub revoked: bool,
+}
+
+#[derive(Serialize, Deserialize, Debug, Default)]
+pub struct DeviceListResponse {
+    pub devices: Vec<LinkedDevice>,
+}
+
+const IDENTITY_KEY_LABEL: &str = "navajo-identity-key";
+const SIGNED_PREKEY_LABEL: &str = "navajo-signed-prekey";
+const PREKEY_UPLOAD_LABEL: &str = "navajo-prekey-upload";
+
+pub fn identity_key_transcript(identity_key: &str) -> String {
+    canonical_transcript(IDENTITY_KEY_LABEL, &[identity_key])
+}
+
+pub fn signed_prekey_transcript(key_id: u32, signed_prekey: &str) -> String {
+    canonical_transcript(SIGNED_PREKEY_LABEL, &[&key_id.to_string(), signed_prekey])
+}
+
+#[derive(Serialize, Deserialize, Debug, Default, Clone, PartialEq, Eq)]
+pub struct OneTimePrekey {
+    pub key_id: u32,
+    pub prekey: String,
+}
+
+/// X25519 identity key and signed prekey, both signed by the account key.
+#[derive(Serialize, Deserialize, Debug, Default, Clone)]
+pub struct SignedIdentity {
+    pub address: String,
+    pub public_key: String,
+    pub identity_key: String,
+    pub identity_key_sign: String,
+    pub signed_prekey_id: u32,
+    pub signed_prekey: String,
+    pub signed_prekey_sign: String,
+}
+
+impl SignedIdentity {
+    pub fn verify(&self) -> bool {
+        address_from_public_key(&self.public_key).is_some_and(|address| address == self.address)
+            && verify(&identity_key_transcript(&self.identity_key), &self.identity_key_sign, &self.public_key).is_ok()
+            && verify(&signed_prekey_transcript(self.signed_prekey_id, &self.signed_prekey), &self.signed_prekey_sign, &self.public_key).is_ok()
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, Default)]
+pub struct PrekeyUploadRequest {
+    pub identity: SignedIdentity,
+    pub one_time_prekeys: Vec<OneTimePrekey>,
+    pub nonce: String,
+    pub time_ms: u128,
+    pub sign: String,
+}
+
+impl PrekeyUploadRequest {
+    pub fn transcript(&self) -> String {
+        let identity = &self.identity;
+        let mut fields = vec![
+            identity.address.to_string(),
+            identity.public_key.to_string(),
+            identity.identity_key.to_string(),
+            identity.identity_key_sign.to_string(),
+            identity.signed_prekey_id.to_string(),
+            identity.signed_prekey.to_string(),
+            identity.signed_prekey_sign.to_string(),
+            self.nonce.to_string(),
+            self.time_ms.to_string(),
+        ];
+        for prekey in &self.one_time_prekeys {
+            fields.push(prekey.key_id.to_string());
+            fields.push(prekey.prekey.to_string());
+        }
+        let fields: Vec<&str> = fields.iter().map(|field| field.as_str()).collect();
+        canonical_transcript(PREKEY_UPLOAD_LABEL, &fields)
+    }
+
+    pub fn verify(&self) -> bool {
+        self.identity.verify() && verify(&self.transcript(), &self.sign, &self.identity.public_key).is_ok()
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, Default)]
+pub struct PrekeyBundle {
+    pub identity: SignedIdentity,
+    pub one_time_prekey: Option<OneTimePrekey>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Default)]
+pub struct PrekeyCountResponse {
+    pub count: u32,
+    pub low: bool,
+}
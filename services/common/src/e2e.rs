@@ -0,0 +1,134 @@
+use secp256k1::{PublicKey, Secp256k1, SecretKey};
+use secp256k1::ecdh::SharedSecret;
+use serde::{Deserialize, Serialize};
+use ncrypto::algo::{aes, base64};
+use ncrypto::algo::kdf::{hkdf_sha256, KEY_SIZE};
+use ncrypto::algo::ratchet::RatchetMessage;
+use crate::beans::{canonical_transcript, SignedIdentity};
+use crate::errors::{E2E_DECRYPT_ERROR, E2E_ENCRYPT_ERROR, INVALID_ADDRESS_ERROR, INVALID_KEY_PAIR, NavajoError, NavajoResult};
+use crate::key_pair::{address_from_public_key, KeyPair, verify};
+
+const E2E_INFO: &[u8] = b"navajo e2e v1";
+const SEALED_CONTENT_LABEL: &str = "navajo-sealed-content";
+
+/// Chat content encrypted to the recipient's account key (ECIES over secp256k1).
+/// Signed with the sender's account key, the server can't pass it off as someone else's.
+#[derive(Serialize, Deserialize, Debug)]
+pub struct SealedContent {
+    pub ephemeral_key: String,
+    pub data: String,
+    pub sender_public_key: String,
+    pub sign: String,
+}
+
+impl SealedContent {
+    pub fn transcript(&self, recipient_public_key: &str) -> String {
+        canonical_transcript(SEALED_CONTENT_LABEL, &[
+            &self.ephemeral_key,
+            &self.data,
+            &self.sender_public_key,
+            recipient_public_key,
+        ])
+    }
+}
+
+/// X3DH parameters the recipient needs to start its side of a ratchet session.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct RatchetInit {
+    pub identity: SignedIdentity,
+    pub ephemeral_key: String,
+    pub signed_prekey_id: u32,
+    pub one_time_prekey_id: Option<u32>,
+}
+
+/// Chat content encrypted with a Double Ratchet session. `init` is repeated on
+/// every message until the recipient answered, in case the first one got lost.
+#[derive(Serialize, Deserialize, Debug)]
+pub struct RatchetContent {
+    pub init: Option<RatchetInit>,
+    pub message: RatchetMessage,
+}
+
+pub fn seal(sender: &KeyPair, recipient_public_key: &str, plaintext: &str) -> NavajoResult<String> {
+    let recipient = parse_public_key(recipient_public_key)?;
+    let secp = Secp256k1::new();
+    let ephemeral_secret = SecretKey::new(&mut secp256k1::rand::thread_rng());
+    let ephemeral_key = PublicKey::from_secret_key(&secp, &ephemeral_secret);
+
+    let shared_secret = SharedSecret::new(&recipient, &ephemeral_secret).secret_bytes();
+    let key = content_key(&shared_secret, &ephemeral_key, &recipient);
+    let data = aes::encode(&key, plaintext.as_bytes()).map_err(|_| NavajoError::new(E2E_ENCRYPT_ERROR))?;
+
+    let mut sealed = SealedContent {
+        ephemeral_key: base64::encode_to_str(&ephemeral_key.serialize()),
+        data: base64::encode_to_str(&data),
+        sender_public_key: sender.gen_public_key(),
+        sign: String::new(),
+    };
+    sealed.sign = sender.sign(&sealed.transcript(&base64::encode_to_str(&recipient.serialize())));
+    serde_json::to_string(&sealed).map_err(|_| NavajoError::new(E2E_ENCRYPT_ERROR))
+}
+
+/// Decrypts content sealed by `from_address`, only once its signature checked out.
+pub fn open(key_pair: &KeyPair, from_address: &str, sealed: &str) -> NavajoResult<String> {
+    let sealed: SealedContent = serde_json::from_str(sealed).map_err(|_| NavajoError::new(E2E_DECRYPT_ERROR))?;
+    let ephemeral_key = parse_public_key(&sealed.ephemeral_key)?;
+    let recipient = parse_public_key(&key_pair.gen_public_key())?;
+    if address_from_public_key(&sealed.sender_public_key).as_deref() != Some(from_address) {
+        return Err(NavajoError::new(INVALID_ADDRESS_ERROR));
+    }
+    verify(&sealed.transcript(&base64::encode_to_str(&recipient.serialize())), &sealed.sign, &sealed.sender_public_key)?;
+
+    let shared_secret = key_pair.shared_secret(&ephemeral_key);
+    let key = content_key(&shared_secret, &ephemeral_key, &recipient);
+    let data = base64::decode_from_str(&sealed.data)?;
+    let plaintext = aes::decode(&key, &data).map_err(|_| NavajoError::new(E2E_DECRYPT_ERROR))?;
+    String::from_utf8(plaintext).map_err(|_| NavajoError::new(E2E_DECRYPT_ERROR))
+}
+
+fn content_key(shared_secret: &[u8], ephemeral_key: &PublicKey, recipient: &PublicKey) -> Vec<u8> {
+    let mut salt = ephemeral_key.serialize().to_vec();
+    salt.extend_from_slice(&recipient.serialize());
+    hkdf_sha256(shared_secret, &salt, E2E_INFO, KEY_SIZE)
+}
+
+fn parse_public_key(public_key: &str) -> NavajoResult<PublicKey> {
+    let bytes = base64::decode_from_str(public_key)?;
+    PublicKey::from_slice(&bytes).map_err(|_| NavajoError::new(INVALID_KEY_PAIR))
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::e2e::{open, seal, SealedContent};
+    use crate::errors::{INVALID_ADDRESS_ERROR, VERIFY_SIGN_ERROR};
+    use crate::key_pair::KeyPair;
+
+    #[test]
+    fn test_seal_open() {
+        let sender = KeyPair::new();
+        let recipient = KeyPair::new();
+        let sealed = seal(&sender, &recipient.gen_public_key(), "Hello").unwrap();
+        assert!(!sealed.contains("Hello"));
+        assert_eq!(open(&recipient, &sender.gen_address(), &sealed).unwrap(), "Hello");
+
+        let other = KeyPair::new();
+        assert!(open(&other, &sender.gen_address(), &sealed).is_err());
+
+        // Only the sender's address is taken
+        let err = open(&recipient, &other.gen_address(), &sealed).unwrap_err();
+        assert!(err.is(&INVALID_ADDRESS_ERROR));
+
+        // Re-signed by someone else, or with a swapped ciphertext, it doesn't verify
+        let mut forged: SealedContent = serde_json::from_str(&sealed).unwrap();
+        forged.sender_public_key = other.gen_public_key();
+        let forged = serde_json::to_string(&forged).unwrap();
+        let err = open(&recipient, &other.gen_address(), &forged).unwrap_err();
+        assert!(err.is(&VERIFY_SIGN_ERROR));
+        let mut swapped: SealedContent = serde_json::from_str(&sealed).unwrap();
+        let another: SealedContent = serde_json::from_str(&seal(&other, &recipient.gen_public_key(), "Bye").unwrap()).unwrap();
+        swapped.data = another.data;
+        swapped.ephemeral_key = another.ephemeral_key;
+        let swapped = serde_json::to_string(&swapped).unwrap();
+        assert!(open(&recipient, &sender.gen_address(), &swapped).unwrap_err().is(&VERIFY_SIGN_ERROR));
+    }
+}
@@ -1,12 +1,20 @@
 use std::fmt::{Display, Formatter};
 use std::io;
-use crate::errors::NavajoErrorRepr::{IoError, MessageError, SocketError};
+use ncrypto::algo::aes::AESError;
+use ncrypto::algo::errors::DecodeError;
+use crate::errors::NavajoErrorRepr::{CryptoError, DecodeError as DecodeErrorRepr, IoError, MessageError, ParseError, SocketError};
 
 #[derive(Debug)]
 pub enum NavajoErrorRepr {
     IoError(io::Error),
     MessageError { code: u32, message: &'static str },
     SocketError { message: &'static str },
+    /// Malformed base64/base58 or a key of the wrong length.
+    DecodeError(DecodeError),
+    /// Keys, signatures or ciphertexts that don't check out.
+    CryptoError { message: &'static str },
+    /// JSON that doesn't match the expected structure.
+    ParseError(serde_json::Error),
 }
 
 pub const INVALID_PARAM_ERROR: NavajoErrorRepr = MessageError { code: 101, message: "invalid param error" };
@@ -14,11 +22,27 @@ pub const ECDSA_ENCRYPT_ERROR: NavajoErrorRepr = MessageError { code: 104, messa
 pub const VERIFY_SIGN_ERROR: NavajoErrorRepr = MessageError { code: 108, message: "verify sign error" };
 pub const VERIFY_HASH_ERROR: NavajoErrorRepr = MessageError { code: 109, message: "verify hash error" };
 pub const INVALID_DH_ERROR: NavajoErrorRepr = MessageError { code: 110, message: "invalid dh key" };
+pub const INVALID_ADDRESS_ERROR: NavajoErrorRepr = MessageError { code: 111, message: "address does not match public key" };
+pub const REPLAY_ERROR: NavajoErrorRepr = MessageError { code: 112, message: "replayed request" };
+pub const STALE_REQUEST_ERROR: NavajoErrorRepr = MessageError { code: 113, message: "request timestamp out of window" };
+pub const INVALID_FRAME_ERROR: NavajoErrorRepr = MessageError { code: 114, message: "invalid frame" };
+pub const RATE_LIMITED_ERROR: NavajoErrorRepr = MessageError { code: 115, message: "too many requests" };
+
+pub const INVALID_PUBLIC_KEY: NavajoErrorRepr = CryptoError { message: "invalid public key" };
+pub const INVALID_SIGNATURE: NavajoErrorRepr = CryptoError { message: "invalid signature" };
+pub const INVALID_MNEMONIC: NavajoErrorRepr = CryptoError { message: "invalid mnemonic" };
 
 pub const INVALID_KEY_PAIR: NavajoErrorRepr = MessageError { code: 301, message: "invalid key pair" };
+pub const UNTRUSTED_SERVER_KEY: NavajoErrorRepr = MessageError { code: 302, message: "untrusted server identity key" };
+pub const E2E_ENCRYPT_ERROR: NavajoErrorRepr = MessageError { code: 303, message: "e2e encrypt error" };
+pub const E2E_DECRYPT_ERROR: NavajoErrorRepr = MessageError { code: 304, message: "e2e decrypt error" };
 
 pub const INVALID_DEVICE_ID: NavajoErrorRepr = MessageError { code: 401, message: "invalid device id" };
 pub const INVALID_SESSION: NavajoErrorRepr = MessageError { code: 402, message: "invalid session" };
+pub const USER_NOT_FOUND: NavajoErrorRepr = MessageError { code: 403, message: "user not found" };
+pub const ADDRESS_MISMATCH_ERROR: NavajoErrorRepr = MessageError { code: 404, message: "address does not own the session" };
+pub const MESSAGE_NOT_FOUND: NavajoErrorRepr = MessageError { code: 405, message: "message not found" };
+pub const SESSION_EXPIRED: NavajoErrorRepr = MessageError { code: 406, message: "session expired" };
 
 pub const DB_ERROR: NavajoErrorRepr = MessageError { code: 500, message: "db error" };
 pub const HTTP_ERROR: NavajoErrorRepr = MessageError { code: 600, message: "http error" };
@@ -39,12 +63,37 @@ impl From<io::Error> for NavajoError {
     }
 }
 
+impl From<DecodeError> for NavajoError {
+    fn from(err: DecodeError) -> Self {
+        NavajoError::new(DecodeErrorRepr(err))
+    }
+}
+
+impl From<AESError> for NavajoError {
+    fn from(err: AESError) -> Self {
+        let message = match err {
+            AESError::EncryptError => "AES encrypt error",
+            AESError::DecryptError => "AES decrypt error",
+        };
+        NavajoError::new(CryptoError { message })
+    }
+}
+
+impl From<serde_json::Error> for NavajoError {
+    fn from(err: serde_json::Error) -> Self {
+        NavajoError::new(ParseError(err))
+    }
+}
+
 impl Display for NavajoError {
     fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
         match &self.repr {
             IoError(err) => f.write_str(format!("IO error: {}", err).as_str()),
             MessageError { code, message } => f.write_str(format!("Got error: {} {}", code, message).as_str()),
-            SocketError { message } => f.write_str(format!("Got error: {}", message).as_str())
+            SocketError { message } => f.write_str(format!("Got error: {}", message).as_str()),
+            DecodeErrorRepr(err) => f.write_str(format!("Decode error: {}", err).as_str()),
+            CryptoError { message } => f.write_str(format!("Crypto error: {}", message).as_str()),
+            ParseError(err) => f.write_str(format!("Parse error: {}", err).as_str()),
         }
     }
 }
@@ -53,4 +102,9 @@ impl NavajoError {
     pub fn new(repr: NavajoErrorRepr) -> Self {
         Self { repr }
     }
+
+    /// Whether this is the `MessageError` `repr`, for the errors callers react to.
+    pub fn is(&self, repr: &NavajoErrorRepr) -> bool {
+        matches!((&self.repr, repr), (MessageError { code, .. }, MessageError { code: other, .. }) if code == other)
+    }
 }
\ No newline at end of file
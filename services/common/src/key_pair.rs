@@ -1,8 +1,10 @@
 use bip39::{Language, Mnemonic};
 use secp256k1::{Message, PublicKey, Secp256k1, SecretKey};
+use secp256k1::ecdh::SharedSecret;
 use secp256k1::ecdsa::Signature;
 use serde::{Deserialize, Serialize};
 use ncrypto::algo::{base58, base64, sha256};
+use crate::errors::{INVALID_MNEMONIC, INVALID_PUBLIC_KEY, INVALID_SIGNATURE, NavajoError, NavajoResult, VERIFY_SIGN_ERROR};
 
 #[derive(Serialize, Deserialize, Debug)]
 pub struct KeyPair {
@@ -32,9 +34,10 @@ impl KeyPair {
         m.into()
     }
 
-    pub fn recover(mnemonic: &str) -> KeyPair {
-        let m = Mnemonic::parse_in_normalized(Language::English, mnemonic).unwrap();
-        m.into()
+    pub fn recover(mnemonic: &str) -> NavajoResult<KeyPair> {
+        let m = Mnemonic::parse_in_normalized(Language::English, mnemonic)
+            .map_err(|_| NavajoError::new(INVALID_MNEMONIC))?;
+        Ok(m.into())
     }
 
     pub fn gen_mnemonic(&self) -> String {
@@ -50,8 +53,12 @@ impl KeyPair {
 
     pub fn gen_address(&self) -> String {
         let public_key = self.pub_key.serialize().to_vec();
-        let bytes = sha256::encode(&public_key);
-        base58::encode(&bytes)
+        address_from_bytes(&public_key)
+    }
+
+    /// ECDH with another secp256k1 public key, hashed with SHA256.
+    pub fn shared_secret(&self, public_key: &PublicKey) -> Vec<u8> {
+        SharedSecret::new(public_key, &self.sec_key).secret_bytes().to_vec()
     }
 
     pub fn sign(&self, data: &str) -> String {
@@ -68,21 +75,34 @@ impl Default for KeyPair {
     }
 }
 
-pub fn verify(src: &str, sign: &str, public_key: &str) -> bool {
+fn address_from_bytes(public_key: &[u8]) -> String {
+    let bytes = sha256::encode(public_key);
+    base58::encode(&bytes)
+}
+
+/// Address owned by a base64 encoded public key, `None` if the key is malformed.
+pub fn address_from_public_key(public_key: &str) -> Option<String> {
+    let public_key = base64::decode_from_str(public_key).ok()?;
+    PublicKey::from_slice(&public_key).ok()?;
+    Some(address_from_bytes(&public_key))
+}
+
+/// `Ok` only for a valid signature, malformed keys or signatures are errors too.
+pub fn verify(src: &str, sign: &str, public_key: &str) -> NavajoResult<()> {
     let src = src.as_bytes();
-    let sign = base64::decode_from_str(sign);
-    let public_key = base64::decode_from_str(public_key);
+    let sign = base64::decode_from_str(sign)?;
+    let public_key = base64::decode_from_str(public_key)?;
 
     let message = Message::from_hashed_data::<secp256k1::hashes::sha256::Hash>(src);
 
-    let pub_key = PublicKey::from_slice(public_key.as_slice()).unwrap();
-    let sign = Signature::from_compact(sign.as_slice()).unwrap();
-    sign.verify(&message, &pub_key).is_ok()
+    let pub_key = PublicKey::from_slice(public_key.as_slice()).map_err(|_| NavajoError::new(INVALID_PUBLIC_KEY))?;
+    let sign = Signature::from_compact(sign.as_slice()).map_err(|_| NavajoError::new(INVALID_SIGNATURE))?;
+    sign.verify(&message, &pub_key).map_err(|_| NavajoError::new(VERIFY_SIGN_ERROR))
 }
 
 #[cfg(test)]
 mod tests {
-    use crate::key_pair::{KeyPair, verify};
+    use crate::key_pair::{address_from_public_key, KeyPair, verify};
 
     #[test]
     fn test_key_pair() {
@@ -98,9 +118,17 @@ mod tests {
 
         let res = verify(data, &sign, &my_public_key);
         println!("{:?}", res);
+        assert!(res.is_ok());
+        assert!(verify("Another apple.", &sign, &my_public_key).is_err());
+        assert!(verify(data, "not base64!", &my_public_key).is_err());
+        assert!(verify(data, &sign, "AAAA").is_err());
+
+        let address = address_from_public_key(&my_public_key).unwrap();
+        assert_eq!(address, key_pair.gen_address());
 
         let m = key_pair.gen_mnemonic();
-        let recover = KeyPair::recover(&m);
+        let recover = KeyPair::recover(&m).unwrap();
         println!("{:?}", recover.gen_mnemonic());
+        assert!(KeyPair::recover("not a mnemonic").is_err());
     }
 }
\ No newline at end of file
@@ -4,15 +4,121 @@ pub mod account;
 pub mod key_pair;
 pub mod beans;
 pub mod errors;
+pub mod e2e;
 
 #[cfg(test)]
 mod tests {
     use std::time::{SystemTime, UNIX_EPOCH};
-
+    use crate::account::Account;
+    use crate::beans::{DeviceInfoRequest, DeviceInfoResponse, identity_key_transcript, OneTimePrekey, PrekeyUploadRequest, RemoveDeviceRequest, RevokeSessionRequest, SESSION_PROTOCOL_SIGNED, signed_prekey_transcript, SignedIdentity};
+    use crate::key_pair::KeyPair;
 
     #[test]
     fn test_account() {
         let t = SystemTime::now().duration_since(UNIX_EPOCH).unwrap().as_millis();
         println!("{}", t);
     }
+
+    #[test]
+    fn test_device_info_sign() {
+        let account = Account::new();
+        let mut request = DeviceInfoRequest {
+            device_id: "device".to_string(),
+            content: "nonce".to_string(),
+            public_key: account.key_pair.gen_public_key(),
+            address: account.address.to_string(),
+            dh_pub: "client dh".to_string(),
+            protocol_version: SESSION_PROTOCOL_SIGNED,
+            time_ms: 1,
+            ..Default::default()
+        };
+        request.sign = account.sign_data(&request.transcript());
+        assert!(request.verify_address());
+        assert!(request.verify_content());
+
+        let mut forged = DeviceInfoRequest { dh_pub: "attacker dh".to_string(), ..request };
+        assert!(!forged.verify_content());
+        forged.address = Account::new().address;
+        assert!(!forged.verify_address());
+
+        let server = KeyPair::new();
+        let mut response = DeviceInfoResponse {
+            session: "session".to_string(),
+            dh_pub: "server dh".to_string(),
+            protocol_version: SESSION_PROTOCOL_SIGNED,
+            time_ms: 2,
+            session_expire_ms: 3,
+            server_public_key: server.gen_public_key(),
+            sign: String::new(),
+        };
+        response.sign = server.sign(&response.transcript(&forged));
+        assert!(response.verify_sign(&forged));
+        response.dh_pub = "attacker dh".to_string();
+        assert!(!response.verify_sign(&forged));
+    }
+
+    #[test]
+    fn test_prekey_upload_sign() {
+        let account = Account::new();
+        let identity = SignedIdentity {
+            address: account.address.to_string(),
+            public_key: account.key_pair.gen_public_key(),
+            identity_key: "identity".to_string(),
+            identity_key_sign: account.sign_data(&identity_key_transcript("identity")),
+            signed_prekey_id: 1,
+            signed_prekey: "prekey".to_string(),
+            signed_prekey_sign: account.sign_data(&signed_prekey_transcript(1, "prekey")),
+        };
+        assert!(identity.verify());
+
+        let mut request = PrekeyUploadRequest {
+            identity,
+            one_time_prekeys: vec![OneTimePrekey { key_id: 2, prekey: "one time".to_string() }],
+            nonce: "nonce".to_string(),
+            time_ms: 1,
+            sign: String::new(),
+        };
+        request.sign = account.sign_data(&request.transcript());
+        assert!(request.verify());
+
+        request.one_time_prekeys[0].prekey = "attacker".to_string();
+        assert!(!request.verify());
+        request.identity.signed_prekey_id = 3;
+        assert!(!request.identity.verify());
+    }
+
+    #[test]
+    fn test_revoke_session_sign() {
+        let account = Account::new();
+        let mut request = RevokeSessionRequest {
+            address: account.address.to_string(),
+            public_key: account.key_pair.gen_public_key(),
+            device_id: "device".to_string(),
+            nonce: "nonce".to_string(),
+            time_ms: 1,
+            sign: String::new(),
+        };
+        request.sign = account.sign_data(&request.transcript());
+        assert!(request.verify());
+
+        request.device_id = "other device".to_string();
+        assert!(!request.verify());
+        request.device_id = "device".to_string();
+        request.address = Account::new().address;
+        assert!(!request.verify());
+
+        // The same fields don't make a valid removal
+        let remove = RemoveDeviceRequest {
+            address: account.address.to_string(),
+            public_key: account.key_pair.gen_public_key(),
+            device_id: "device".to_string(),
+            nonce: "nonce".to_string(),
+            time_ms: 1,
+            sign: account.sign_data(&RevokeSessionRequest {
+                address: account.address.to_string(),
+                ..request
+            }.transcript()),
+        };
+        assert!(!remove.verify());
+    }
 }
@@ -1,8 +1,15 @@
 use std::{error, fmt};
 use std::fmt::Formatter;
 use aes_gcm::{Aes256Gcm, KeyInit};
-use aes_gcm::aead::AeadMut;
+use aes_gcm::aead::{AeadMut, Payload};
 use aes_gcm::aead::generic_array::GenericArray;
+use rand_core::{OsRng, RngCore};
+
+/// Version byte of the current envelope: `version || nonce || ciphertext+tag`.
+pub const ENVELOPE_VERSION: u8 = 1;
+pub const NONCE_SIZE: usize = 12;
+
+const LEGACY_NONCE: [u8; NONCE_SIZE] = [0u8; NONCE_SIZE];
 
 #[derive(Debug, Clone)]
 pub enum AESError {
@@ -23,18 +30,85 @@ impl error::Error for AESError {}
 
 type AESResult<T> = Result<T, AESError>;
 
+/// Encrypts `data` with a fresh random nonce and wraps it into a versioned envelope.
 pub fn encode(key: &[u8], data: &[u8]) -> AESResult<Vec<u8>> {
-    let nonce = &[0u8; 12];
+    let mut nonce = [0u8; NONCE_SIZE];
+    OsRng.fill_bytes(&mut nonce);
+    encode_with_nonce(key, &nonce, data)
+}
+
+/// Encrypts `data` with a caller supplied nonce, e.g. a per-session counter.
+/// The nonce must never be reused under the same key.
+pub fn encode_with_nonce(key: &[u8], nonce: &[u8; NONCE_SIZE], data: &[u8]) -> AESResult<Vec<u8>> {
+    seal(key, nonce, data, &[])
+}
+
+/// Like [`encode`], additionally authenticating `aad` which is not part of the output.
+pub fn encode_with_aad(key: &[u8], data: &[u8], aad: &[u8]) -> AESResult<Vec<u8>> {
+    let mut nonce = [0u8; NONCE_SIZE];
+    OsRng.fill_bytes(&mut nonce);
+    seal(key, &nonce, data, aad)
+}
+
+/// Decrypts an envelope from [`encode_with_aad`], there is no legacy format for it.
+pub fn decode_with_aad(key: &[u8], data: &[u8], aad: &[u8]) -> AESResult<Vec<u8>> {
+    decode_envelope(key, data, aad)
+}
+
+fn seal(key: &[u8], nonce: &[u8; NONCE_SIZE], data: &[u8], aad: &[u8]) -> AESResult<Vec<u8>> {
+    let encrypted = encrypt(key, nonce, data, aad)?;
+    let mut envelope = Vec::with_capacity(1 + NONCE_SIZE + encrypted.len());
+    envelope.push(ENVELOPE_VERSION);
+    envelope.extend_from_slice(nonce);
+    envelope.extend_from_slice(&encrypted);
+    Ok(envelope)
+}
+
+/// Decrypts an envelope produced by [`encode`].
+pub fn decode(key: &[u8], data: &[u8]) -> AESResult<Vec<u8>> {
+    decode_envelope(key, data, &[])
+}
+
+/// Like [`decode`], also accepting data of versions before the envelope (all-zero nonce).
+/// Only for data that can be that old: key stores not rewritten yet and frames of
+/// legacy protocol sessions. It goes away with them.
+pub fn decode_with_legacy(key: &[u8], data: &[u8]) -> AESResult<Vec<u8>> {
+    decode(key, data).or_else(|_| decode_legacy(key, data))
+}
+
+fn decode_envelope(key: &[u8], data: &[u8], aad: &[u8]) -> AESResult<Vec<u8>> {
+    if data.len() <= 1 + NONCE_SIZE || data[0] != ENVELOPE_VERSION {
+        return Err(AESError::DecryptError);
+    }
+    let (nonce, encrypted) = data[1..].split_at(NONCE_SIZE);
+    decrypt(key, nonce, encrypted, aad)
+}
+
+fn decode_legacy(key: &[u8], data: &[u8]) -> AESResult<Vec<u8>> {
+    decrypt(key, &LEGACY_NONCE, data, &[])
+}
+
+fn encrypt(key: &[u8], nonce: &[u8], data: &[u8], aad: &[u8]) -> AESResult<Vec<u8>> {
+    if key.len() != 32 {
+        return Err(AESError::EncryptError);
+    }
     let key = GenericArray::from_slice(key);
     let mut cipher = Aes256Gcm::new(key);
     let nonce = GenericArray::from_slice(nonce);
-    cipher.encrypt(nonce, data).map_err(|_| { AESError::EncryptError })
+    cipher.encrypt(nonce, Payload { msg: data, aad }).map_err(|_| { AESError::EncryptError })
 }
 
-pub fn decode(key: &[u8], data: &[u8]) -> AESResult<Vec<u8>> {
-    let nonce = &[0u8; 12];
+fn decrypt(key: &[u8], nonce: &[u8], data: &[u8], aad: &[u8]) -> AESResult<Vec<u8>> {
+    if key.len() != 32 {
+        return Err(AESError::DecryptError);
+    }
     let key = GenericArray::from_slice(key);
     let mut cipher = Aes256Gcm::new(key);
     let nonce = GenericArray::from_slice(nonce);
-    cipher.decrypt(nonce, data).map_err(|_| { AESError::DecryptError })
-}
\ No newline at end of file
+    cipher.decrypt(nonce, Payload { msg: data, aad }).map_err(|_| { AESError::DecryptError })
+}
+
+#[cfg(test)]
+pub(crate) fn encode_legacy(key: &[u8], data: &[u8]) -> AESResult<Vec<u8>> {
+    encrypt(key, &LEGACY_NONCE, data, &[])
+}
@@ -1,9 +1,10 @@
 use base58::{FromBase58, ToBase58};
+use crate::algo::errors::{DecodeError, DecodeResult};
 
 pub fn encode(bytes: &[u8]) -> String {
     bytes.to_base58()
 }
 
-pub fn decode(str: &str) -> Vec<u8> {
-    str.from_base58().unwrap()
-}
\ No newline at end of file
+pub fn decode(str: &str) -> DecodeResult<Vec<u8>> {
+    str.from_base58().map_err(|_| DecodeError::Base58)
+}
@@ -1,7 +1,9 @@
+use crate::algo::errors::{DecodeError, DecodeResult};
+
 pub fn encode_to_str(data: &[u8]) -> String {
     base64::encode(data)
 }
 
-pub fn decode_from_str(data: &str) -> Vec<u8> {
-    base64::decode(data).unwrap()
-}
\ No newline at end of file
+pub fn decode_from_str(data: &str) -> DecodeResult<Vec<u8>> {
+    base64::decode(data).map_err(|_| DecodeError::Base64)
+}
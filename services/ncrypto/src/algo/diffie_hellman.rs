@@ -1,6 +1,7 @@
 use rand_core::{OsRng};
 use x25519_dalek::{EphemeralSecret, PublicKey};
 use crate::algo::base64::{decode_from_str, encode_to_str};
+use crate::algo::errors::{DecodeError, DecodeResult};
 
 pub struct DiffieHellman {
     pub public_key: PublicKey,
@@ -24,13 +25,11 @@ impl DiffieHellman {
         self.secret.diffie_hellman(other_public_key).as_bytes().to_vec()
     }
 
-    pub fn compute_shared_secret_from_str(self, other_public_key_str: &str) -> Vec<u8> {
-        let other_public_key = decode_from_str(other_public_key_str);
-        let other_public_key = other_public_key.as_ptr() as *const [u8; 32];
-        unsafe {
-            let other_public_key = PublicKey::from(*other_public_key);
-            self.secret.diffie_hellman(&other_public_key).as_bytes().to_vec()
-        }
+    pub fn compute_shared_secret_from_str(self, other_public_key_str: &str) -> DecodeResult<Vec<u8>> {
+        let other_public_key: [u8; 32] = decode_from_str(other_public_key_str)?
+            .try_into().map_err(|_| DecodeError::KeyLength)?;
+        let other_public_key = PublicKey::from(other_public_key);
+        Ok(self.compute_shared_secret(&other_public_key))
     }
 
     pub fn public_key_to_str(&self) -> String {
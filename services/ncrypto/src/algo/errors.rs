@@ -0,0 +1,23 @@
+use std::{error, fmt};
+use std::fmt::Formatter;
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum DecodeError {
+    Base64,
+    Base58,
+    KeyLength,
+}
+
+impl fmt::Display for DecodeError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        match self {
+            DecodeError::Base64 => write!(f, "base64 decode error"),
+            DecodeError::Base58 => write!(f, "base58 decode error"),
+            DecodeError::KeyLength => write!(f, "invalid key length"),
+        }
+    }
+}
+
+impl error::Error for DecodeError {}
+
+pub type DecodeResult<T> = Result<T, DecodeError>;
@@ -0,0 +1,102 @@
+use hkdf::Hkdf;
+use sha2::Sha256;
+use crate::algo::base64::{decode_from_str, encode_to_str};
+use crate::algo::sha256;
+
+pub const KEY_SIZE: usize = 32;
+
+const SESSION_SALT_LABEL: &[u8] = b"navajo-session-v1";
+const INFO_CLIENT_TO_SERVER: &[u8] = b"navajo client to server";
+const INFO_SERVER_TO_CLIENT: &[u8] = b"navajo server to client";
+
+/// HKDF-SHA256 extract and expand of `len` bytes.
+pub fn hkdf_sha256(ikm: &[u8], salt: &[u8], info: &[u8], len: usize) -> Vec<u8> {
+    let hk = Hkdf::<Sha256>::new(Some(salt), ikm);
+    let mut okm = vec![0u8; len];
+    // Only fails when len > 255 * 32, which callers never ask for
+    hk.expand(info, &mut okm).expect("hkdf output length");
+    okm
+}
+
+/// Public values of a create_session handshake, bound into the key schedule salt.
+pub struct SessionTranscript<'a> {
+    pub session: &'a str,
+    pub device_id: &'a str,
+    pub client_dh_pub: &'a str,
+    pub server_dh_pub: &'a str,
+}
+
+impl SessionTranscript<'_> {
+    pub fn salt(&self) -> Vec<u8> {
+        let mut data = SESSION_SALT_LABEL.to_vec();
+        for field in [self.session, self.device_id, self.client_dh_pub, self.server_dh_pub] {
+            data.extend_from_slice(&(field.len() as u32).to_be_bytes());
+            data.extend_from_slice(field.as_bytes());
+        }
+        sha256::encode(&data)
+    }
+}
+
+/// One AES-GCM key per direction, the frames carry their own authentication tag.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct SessionKeys {
+    pub client_to_server: Vec<u8>,
+    pub server_to_client: Vec<u8>,
+    legacy: bool,
+}
+
+impl SessionKeys {
+    pub fn derive(shared_secret: &[u8], transcript: &SessionTranscript) -> Self {
+        let salt = transcript.salt();
+        Self {
+            client_to_server: hkdf_sha256(shared_secret, &salt, INFO_CLIENT_TO_SERVER, KEY_SIZE),
+            server_to_client: hkdf_sha256(shared_secret, &salt, INFO_SERVER_TO_CLIENT, KEY_SIZE),
+            legacy: false,
+        }
+    }
+
+    /// Old clients use the raw DH output as the only key in both directions.
+    pub fn legacy(shared_secret: &[u8]) -> Self {
+        Self {
+            client_to_server: shared_secret.to_vec(),
+            server_to_client: shared_secret.to_vec(),
+            legacy: true,
+        }
+    }
+
+    pub fn is_legacy(&self) -> bool {
+        self.legacy
+    }
+
+    pub fn client_to_server_str(&self) -> String {
+        encode_to_str(&self.client_to_server)
+    }
+
+    pub fn server_to_client_str(&self) -> String {
+        encode_to_str(&self.server_to_client)
+    }
+
+    /// Legacy keys keep their old single-key form so stored secrets stay readable.
+    pub fn encode_to_str(&self) -> String {
+        if self.legacy {
+            return encode_to_str(&self.client_to_server);
+        }
+        let mut bytes = self.client_to_server.clone();
+        bytes.extend_from_slice(&self.server_to_client);
+        encode_to_str(&bytes)
+    }
+
+    /// Secrets stored before the unused MAC key was dropped still end with it.
+    pub fn decode_from_str(data: &str) -> Option<Self> {
+        let bytes = decode_from_str(data).ok()?;
+        match bytes.len() {
+            KEY_SIZE => Some(Self::legacy(&bytes)),
+            len if len == 2 * KEY_SIZE || len == 3 * KEY_SIZE => Some(Self {
+                client_to_server: bytes[..KEY_SIZE].to_vec(),
+                server_to_client: bytes[KEY_SIZE..2 * KEY_SIZE].to_vec(),
+                legacy: false,
+            }),
+            _ => None,
+        }
+    }
+}
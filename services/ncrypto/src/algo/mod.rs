@@ -1,8 +1,12 @@
+pub mod errors;
 pub mod aes;
 pub mod base58;
 pub mod diffie_hellman;
 pub mod sha256;
 pub mod base64;
+pub mod kdf;
+pub mod x3dh;
+pub mod ratchet;
 
 #[cfg(test)]
 mod tests {
@@ -11,15 +15,18 @@ mod tests {
     use crate::algo::base64;
     use crate::algo::base64::decode_from_str;
     use crate::algo::diffie_hellman::DiffieHellman;
+    use crate::algo::kdf::{KEY_SIZE, SessionKeys, SessionTranscript};
+    use crate::algo::ratchet::RatchetState;
     use crate::algo::sha256;
+    use crate::algo::x3dh::{initiate, PrekeyBundleKeys, respond, X25519KeyPair};
 
     #[test]
     fn test_aes_bytes() {
         let data = "MTM0NTY3";
         let sec = "fgVobm2TEGDyWX6GOJrXTuuUoNbfeMpJSa0WhdTcO0k=";
 
-        let sec = decode_from_str(sec);
-        let data = decode_from_str(data);
+        let sec = decode_from_str(sec).unwrap();
+        let data = decode_from_str(data).unwrap();
         println!("{:?}", data);
 
         let encrypted = aes::encode(&sec, &data).unwrap();
@@ -32,6 +39,37 @@ mod tests {
         println!("{:?}", decrypted);
     }
 
+    #[test]
+    fn test_aes_envelope() {
+        let sec = decode_from_str("fgVobm2TEGDyWX6GOJrXTuuUoNbfeMpJSa0WhdTcO0k=").unwrap();
+        let data = b"hello navajo";
+
+        let encrypted1 = aes::encode(&sec, data).unwrap();
+        let encrypted2 = aes::encode(&sec, data).unwrap();
+        assert_eq!(encrypted1[0], aes::ENVELOPE_VERSION);
+        assert_ne!(encrypted1, encrypted2);
+
+        assert_eq!(aes::decode(&sec, &encrypted1).unwrap(), data);
+        assert_eq!(aes::decode(&sec, &encrypted2).unwrap(), data);
+
+        let mut tampered = encrypted1.clone();
+        let last = tampered.len() - 1;
+        tampered[last] ^= 1;
+        assert!(aes::decode(&sec, &tampered).is_err());
+    }
+
+    #[test]
+    fn test_aes_legacy() {
+        let sec = decode_from_str("fgVobm2TEGDyWX6GOJrXTuuUoNbfeMpJSa0WhdTcO0k=").unwrap();
+        let data = b"written before the envelope";
+
+        let legacy = aes::encode_legacy(&sec, data).unwrap();
+        assert_eq!(aes::decode_with_legacy(&sec, &legacy).unwrap(), data);
+        assert!(aes::decode(&sec, &legacy).is_err());
+        let encrypted = aes::encode(&sec, data).unwrap();
+        assert_eq!(aes::decode_with_legacy(&sec, &encrypted).unwrap(), data);
+    }
+
     #[test]
     fn test_base58() {
         let data = String::from("test");
@@ -40,7 +78,7 @@ mod tests {
         let base58 = base58::encode(data.as_bytes());
         println!("{:?}", base58);
 
-        let data = base58::decode(&base58);
+        let data = base58::decode(&base58).unwrap();
         println!("{:?}", String::from_utf8(data).unwrap());
     }
 
@@ -55,11 +93,134 @@ mod tests {
         println!("{:?}", pub1_str);
         println!("{:?}", pub2_str);
 
-        let share1 = dh1.compute_shared_secret_from_str(&pub2_str);
-        let share2 = dh2.compute_shared_secret_from_str(&pub1_str);
+        let share1 = dh1.compute_shared_secret_from_str(&pub2_str).unwrap();
+        let share2 = dh2.compute_shared_secret_from_str(&pub1_str).unwrap();
 
         println!("{:?}", share1);
         println!("{:?}", share2);
+
+        // Short or malformed keys from a peer are rejected instead of read past their end
+        assert!(DiffieHellman::new().compute_shared_secret_from_str("AAAA").is_err());
+        assert!(DiffieHellman::new().compute_shared_secret_from_str("not base64!").is_err());
+    }
+
+    #[test]
+    fn test_session_keys() {
+        let dh1 = DiffieHellman::new();
+        let dh2 = DiffieHellman::new();
+        let pub1_str = dh1.public_key_to_str();
+        let pub2_str = dh2.public_key_to_str();
+        let share1 = dh1.compute_shared_secret_from_str(&pub2_str).unwrap();
+        let share2 = dh2.compute_shared_secret_from_str(&pub1_str).unwrap();
+
+        let transcript = SessionTranscript {
+            session: "session",
+            device_id: "device",
+            client_dh_pub: &pub1_str,
+            server_dh_pub: &pub2_str,
+        };
+        let keys1 = SessionKeys::derive(&share1, &transcript);
+        let keys2 = SessionKeys::derive(&share2, &transcript);
+        assert_eq!(keys1, keys2);
+        assert_ne!(keys1.client_to_server, keys1.server_to_client);
+        assert_ne!(keys1.client_to_server, share1);
+
+        let other = SessionTranscript { session: "other", ..transcript };
+        assert_ne!(SessionKeys::derive(&share1, &other), keys1);
+
+        let decoded = SessionKeys::decode_from_str(&keys1.encode_to_str()).unwrap();
+        assert_eq!(decoded, keys1);
+        // With the MAC key they were stored with before
+        let mut stored = base64::decode_from_str(&keys1.encode_to_str()).unwrap();
+        stored.extend_from_slice(&[7u8; KEY_SIZE]);
+        assert_eq!(SessionKeys::decode_from_str(&base64::encode_to_str(&stored)).unwrap(), keys1);
+
+        let legacy = SessionKeys::legacy(&share1);
+        let legacy_str = legacy.encode_to_str();
+        assert_eq!(legacy_str, base64::encode_to_str(&share1));
+        assert!(SessionKeys::decode_from_str(&legacy_str).unwrap().is_legacy());
+    }
+
+    #[test]
+    fn test_x3dh() {
+        let alice_identity = X25519KeyPair::new();
+        let bob_identity = X25519KeyPair::new();
+        let bob_signed_prekey = X25519KeyPair::new();
+        let bob_one_time_prekey = X25519KeyPair::new();
+
+        let bob_identity_str = bob_identity.public_key_to_str();
+        let bob_signed_prekey_str = bob_signed_prekey.public_key_to_str();
+        let bob_one_time_prekey_str = bob_one_time_prekey.public_key_to_str();
+        let bundle = PrekeyBundleKeys {
+            identity_key: &bob_identity_str,
+            signed_prekey: &bob_signed_prekey_str,
+            one_time_prekey: Some(&bob_one_time_prekey_str),
+        };
+        let initiation = initiate(&alice_identity, &bundle).unwrap();
+        let bob_secret = respond(
+            &bob_identity,
+            &bob_signed_prekey,
+            Some(&bob_one_time_prekey),
+            &alice_identity.public_key_to_str(),
+            &initiation.ephemeral_key,
+        ).unwrap();
+        assert_eq!(initiation.shared_secret, bob_secret);
+
+        let restored = X25519KeyPair::from_secret_str(&bob_identity.secret_to_str()).unwrap();
+        assert_eq!(restored.public_key_to_str(), bob_identity_str);
+
+        let without_one_time = respond(
+            &bob_identity,
+            &bob_signed_prekey,
+            None,
+            &alice_identity.public_key_to_str(),
+            &initiation.ephemeral_key,
+        ).unwrap();
+        assert_ne!(initiation.shared_secret, without_one_time);
+    }
+
+    #[test]
+    fn test_ratchet() {
+        let shared_secret = [7u8; 32];
+        let bob_signed_prekey = X25519KeyPair::new();
+        let mut alice = RatchetState::init_sender(&shared_secret, &bob_signed_prekey.public_key_to_str()).unwrap();
+        let mut bob = RatchetState::init_receiver(&shared_secret, &bob_signed_prekey);
+        assert!(bob.encrypt(b"too early", b"ad").is_none());
+
+        let m1 = alice.encrypt(b"m1", b"ad").unwrap();
+        let m2 = alice.encrypt(b"m2", b"ad").unwrap();
+        let m3 = alice.encrypt(b"m3", b"ad").unwrap();
+        assert!(bob.decrypt(&m1, b"other ad").is_none());
+
+        // out of order, m2 is served from the skipped keys
+        assert_eq!(bob.decrypt(&m1, b"ad").unwrap(), b"m1");
+        assert_eq!(bob.decrypt(&m3, b"ad").unwrap(), b"m3");
+        assert_eq!(bob.decrypt(&m2, b"ad").unwrap(), b"m2");
+        assert!(bob.decrypt(&m2, b"ad").is_none());
+
+        // replies trigger a DH ratchet step on both sides
+        assert!(!alice.has_received());
+        let r1 = bob.encrypt(b"r1", b"ad").unwrap();
+        assert_ne!(r1.header.dh, m1.header.dh);
+        assert_eq!(alice.decrypt(&r1, b"ad").unwrap(), b"r1");
+        assert!(alice.has_received());
+
+        let m4 = alice.encrypt(b"m4", b"ad").unwrap();
+        assert_ne!(m4.header.dh, m1.header.dh);
+        assert_eq!(m4.header.pn, 3);
+        assert_eq!(bob.decrypt(&m4, b"ad").unwrap(), b"m4");
+
+        // a lost message from an earlier chain is still readable
+        let lost = bob.encrypt(b"lost", b"ad").unwrap();
+        let r2 = bob.encrypt(b"r2", b"ad").unwrap();
+        assert_eq!(alice.decrypt(&r2, b"ad").unwrap(), b"r2");
+        assert_eq!(alice.decrypt(&lost, b"ad").unwrap(), b"lost");
+
+        // state survives persistence
+        let json = serde_json::to_string(&alice).unwrap();
+        let mut restored: RatchetState = serde_json::from_str(&json).unwrap();
+        let m5 = restored.encrypt(b"m5", b"ad").unwrap();
+        assert_eq!(bob.decrypt(&m5, b"ad").unwrap(), b"m5");
     }
 
     #[test]
@@ -74,7 +235,9 @@ mod tests {
         let src = "hello";
         println!("{:?}", src.as_bytes());
         let result = base64::encode_to_str(src.as_bytes());
-        let result = base64::decode_from_str(&result);
+        let result = base64::decode_from_str(&result).unwrap();
         println!("{:?}", result);
+        assert!(base64::decode_from_str("not base64!").is_err());
+        assert!(base58::decode("0OIl").is_err());
     }
 }
@@ -0,0 +1,215 @@
+use hmac::{Hmac, Mac, NewMac};
+use serde::{Deserialize, Serialize};
+use sha2::Sha256;
+use crate::algo::aes;
+use crate::algo::base64::{decode_from_str, encode_to_str};
+use crate::algo::kdf::{hkdf_sha256, KEY_SIZE};
+use crate::algo::x3dh::{public_key_from_str, X25519KeyPair};
+
+const RATCHET_INFO: &[u8] = b"navajo ratchet v1";
+
+/// Most message keys skipped within one receiving chain.
+pub const MAX_SKIP: u32 = 1000;
+/// Most skipped message keys kept across all chains, the oldest are dropped first.
+pub const MAX_SKIPPED_KEYS: usize = 2000;
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct RatchetHeader {
+    pub dh: String,
+    pub pn: u32,
+    pub n: u32,
+}
+
+impl RatchetHeader {
+    fn to_aad(&self, ad: &[u8]) -> Vec<u8> {
+        let mut aad = ad.to_vec();
+        aad.extend_from_slice(self.dh.as_bytes());
+        aad.extend_from_slice(&self.pn.to_be_bytes());
+        aad.extend_from_slice(&self.n.to_be_bytes());
+        aad
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct RatchetMessage {
+    pub header: RatchetHeader,
+    pub data: String,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+struct SkippedKey {
+    dh: String,
+    n: u32,
+    key: String,
+}
+
+/// Double Ratchet state of one side of a conversation, serializable for persistence.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct RatchetState {
+    dh_self: String,
+    dh_remote: Option<String>,
+    root_key: String,
+    send_chain: Option<String>,
+    recv_chain: Option<String>,
+    send_n: u32,
+    recv_n: u32,
+    prev_send_n: u32,
+    skipped: Vec<SkippedKey>,
+}
+
+impl RatchetState {
+    /// Initiator side, `remote_dh` is the responder's signed prekey used for X3DH.
+    pub fn init_sender(shared_secret: &[u8], remote_dh: &str) -> Option<Self> {
+        let dh_self = X25519KeyPair::new();
+        let remote_key = public_key_from_str(remote_dh)?;
+        let (root_key, send_chain) = kdf_rk(shared_secret, &dh_self.diffie_hellman(&remote_key));
+        Some(Self {
+            dh_self: dh_self.secret_to_str(),
+            dh_remote: Some(remote_dh.to_string()),
+            root_key: encode_to_str(&root_key),
+            send_chain: Some(encode_to_str(&send_chain)),
+            recv_chain: None,
+            send_n: 0,
+            recv_n: 0,
+            prev_send_n: 0,
+            skipped: vec![],
+        })
+    }
+
+    /// Responder side, `dh_self` is the signed prekey the initiator used.
+    pub fn init_receiver(shared_secret: &[u8], dh_self: &X25519KeyPair) -> Self {
+        Self {
+            dh_self: dh_self.secret_to_str(),
+            dh_remote: None,
+            root_key: encode_to_str(shared_secret),
+            send_chain: None,
+            recv_chain: None,
+            send_n: 0,
+            recv_n: 0,
+            prev_send_n: 0,
+            skipped: vec![],
+        }
+    }
+
+    /// Whether the other side has answered at least once.
+    pub fn has_received(&self) -> bool {
+        self.recv_chain.is_some()
+    }
+
+    pub fn encrypt(&mut self, plaintext: &[u8], ad: &[u8]) -> Option<RatchetMessage> {
+        let send_chain = decode_from_str(self.send_chain.as_ref()?).ok()?;
+        let (send_chain, message_key) = kdf_ck(&send_chain);
+        let dh_self = X25519KeyPair::from_secret_str(&self.dh_self)?;
+        let header = RatchetHeader {
+            dh: dh_self.public_key_to_str(),
+            pn: self.prev_send_n,
+            n: self.send_n,
+        };
+        let data = aes::encode_with_aad(&message_key, plaintext, &header.to_aad(ad)).ok()?;
+
+        self.send_chain = Some(encode_to_str(&send_chain));
+        self.send_n += 1;
+        Some(RatchetMessage {
+            header,
+            data: encode_to_str(&data),
+        })
+    }
+
+    /// Leaves the state untouched when the message can't be decrypted.
+    pub fn decrypt(&mut self, message: &RatchetMessage, ad: &[u8]) -> Option<Vec<u8>> {
+        let mut state = self.clone();
+        let plaintext = state.decrypt_in_place(message, ad)?;
+        *self = state;
+        Some(plaintext)
+    }
+
+    fn decrypt_in_place(&mut self, message: &RatchetMessage, ad: &[u8]) -> Option<Vec<u8>> {
+        let header = &message.header;
+        let data = decode_from_str(&message.data).ok()?;
+        let aad = header.to_aad(ad);
+
+        if let Some(index) = self.skipped.iter().position(|key| key.dh == header.dh && key.n == header.n) {
+            let message_key = decode_from_str(&self.skipped[index].key).ok()?;
+            let plaintext = aes::decode_with_aad(&message_key, &data, &aad).ok()?;
+            self.skipped.remove(index);
+            return Some(plaintext);
+        }
+
+        if self.dh_remote.as_deref() != Some(header.dh.as_str()) {
+            self.skip_message_keys(header.pn)?;
+            self.dh_ratchet(&header.dh)?;
+        }
+        self.skip_message_keys(header.n)?;
+
+        let recv_chain = decode_from_str(self.recv_chain.as_ref()?).ok()?;
+        let (recv_chain, message_key) = kdf_ck(&recv_chain);
+        let plaintext = aes::decode_with_aad(&message_key, &data, &aad).ok()?;
+        self.recv_chain = Some(encode_to_str(&recv_chain));
+        self.recv_n += 1;
+        Some(plaintext)
+    }
+
+    fn skip_message_keys(&mut self, until: u32) -> Option<()> {
+        let recv_chain = match &self.recv_chain {
+            Some(recv_chain) => recv_chain,
+            None => return Some(()),
+        };
+        if until > self.recv_n + MAX_SKIP {
+            return None;
+        }
+        let dh_remote = self.dh_remote.clone()?;
+        let mut recv_chain = decode_from_str(recv_chain).ok()?;
+        while self.recv_n < until {
+            let (next_chain, message_key) = kdf_ck(&recv_chain);
+            self.skipped.push(SkippedKey {
+                dh: dh_remote.to_string(),
+                n: self.recv_n,
+                key: encode_to_str(&message_key),
+            });
+            recv_chain = next_chain;
+            self.recv_n += 1;
+        }
+        if self.skipped.len() > MAX_SKIPPED_KEYS {
+            let overflow = self.skipped.len() - MAX_SKIPPED_KEYS;
+            self.skipped.drain(..overflow);
+        }
+        self.recv_chain = Some(encode_to_str(&recv_chain));
+        Some(())
+    }
+
+    fn dh_ratchet(&mut self, remote_dh: &str) -> Option<()> {
+        let remote_key = public_key_from_str(remote_dh)?;
+        self.prev_send_n = self.send_n;
+        self.send_n = 0;
+        self.recv_n = 0;
+        self.dh_remote = Some(remote_dh.to_string());
+
+        let dh_self = X25519KeyPair::from_secret_str(&self.dh_self)?;
+        let root_key = decode_from_str(&self.root_key).ok()?;
+        let (root_key, recv_chain) = kdf_rk(&root_key, &dh_self.diffie_hellman(&remote_key));
+
+        let dh_self = X25519KeyPair::new();
+        let (root_key, send_chain) = kdf_rk(&root_key, &dh_self.diffie_hellman(&remote_key));
+
+        self.dh_self = dh_self.secret_to_str();
+        self.root_key = encode_to_str(&root_key);
+        self.recv_chain = Some(encode_to_str(&recv_chain));
+        self.send_chain = Some(encode_to_str(&send_chain));
+        Some(())
+    }
+}
+
+fn kdf_rk(root_key: &[u8], dh_out: &[u8]) -> (Vec<u8>, Vec<u8>) {
+    let okm = hkdf_sha256(dh_out, root_key, RATCHET_INFO, 2 * KEY_SIZE);
+    (okm[..KEY_SIZE].to_vec(), okm[KEY_SIZE..].to_vec())
+}
+
+fn kdf_ck(chain_key: &[u8]) -> (Vec<u8>, Vec<u8>) {
+    (hmac_sha256(chain_key, &[0x02]), hmac_sha256(chain_key, &[0x01]))
+}
+
+fn hmac_sha256(key: &[u8], data: &[u8]) -> Vec<u8> {
+    let mut mac = Hmac::<Sha256>::new_from_slice(key).expect("hmac accepts any key length");
+    mac.update(data);
+    mac.finalize().into_bytes().to_vec()
+}
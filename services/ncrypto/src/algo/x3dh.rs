@@ -0,0 +1,112 @@
+use rand_core::OsRng;
+use x25519_dalek::{PublicKey, StaticSecret};
+use crate::algo::base64::{decode_from_str, encode_to_str};
+use crate::algo::kdf::{hkdf_sha256, KEY_SIZE};
+
+const X3DH_INFO: &[u8] = b"navajo x3dh v1";
+
+/// Long-lived X25519 key, used for identity keys and (signed or one-time) prekeys.
+#[derive(Clone)]
+pub struct X25519KeyPair {
+    secret: StaticSecret,
+    public_key: PublicKey,
+}
+
+impl Default for X25519KeyPair {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl X25519KeyPair {
+    pub fn new() -> Self {
+        let secret = StaticSecret::new(OsRng);
+        let public_key = PublicKey::from(&secret);
+        Self { secret, public_key }
+    }
+
+    pub fn from_secret_str(secret: &str) -> Option<Self> {
+        let bytes: [u8; 32] = decode_from_str(secret).ok()?.try_into().ok()?;
+        let secret = StaticSecret::from(bytes);
+        let public_key = PublicKey::from(&secret);
+        Some(Self { secret, public_key })
+    }
+
+    pub fn secret_to_str(&self) -> String {
+        encode_to_str(&self.secret.to_bytes())
+    }
+
+    pub fn public_key_to_str(&self) -> String {
+        encode_to_str(self.public_key.as_bytes())
+    }
+
+    pub fn diffie_hellman(&self, other_public_key: &PublicKey) -> Vec<u8> {
+        self.secret.diffie_hellman(other_public_key).as_bytes().to_vec()
+    }
+}
+
+pub fn public_key_from_str(public_key: &str) -> Option<PublicKey> {
+    let bytes: [u8; 32] = decode_from_str(public_key).ok()?.try_into().ok()?;
+    Some(PublicKey::from(bytes))
+}
+
+/// Public half of a recipient's prekey bundle, after its signatures were checked.
+pub struct PrekeyBundleKeys<'a> {
+    pub identity_key: &'a str,
+    pub signed_prekey: &'a str,
+    pub one_time_prekey: Option<&'a str>,
+}
+
+pub struct X3DHInitiation {
+    pub shared_secret: Vec<u8>,
+    pub ephemeral_key: String,
+}
+
+/// Sender side: agrees on a secret with an offline recipient from its bundle.
+pub fn initiate(identity: &X25519KeyPair, bundle: &PrekeyBundleKeys) -> Option<X3DHInitiation> {
+    let identity_key = public_key_from_str(bundle.identity_key)?;
+    let signed_prekey = public_key_from_str(bundle.signed_prekey)?;
+    let one_time_prekey = match bundle.one_time_prekey {
+        Some(key) => Some(public_key_from_str(key)?),
+        None => None,
+    };
+    let ephemeral = X25519KeyPair::new();
+
+    let mut dh = identity.diffie_hellman(&signed_prekey);
+    dh.extend(ephemeral.diffie_hellman(&identity_key));
+    dh.extend(ephemeral.diffie_hellman(&signed_prekey));
+    if let Some(one_time_prekey) = one_time_prekey {
+        dh.extend(ephemeral.diffie_hellman(&one_time_prekey));
+    }
+    Some(X3DHInitiation {
+        shared_secret: derive(&dh),
+        ephemeral_key: ephemeral.public_key_to_str(),
+    })
+}
+
+/// Recipient side: recomputes the secret from the sender's identity and ephemeral keys.
+pub fn respond(
+    identity: &X25519KeyPair,
+    signed_prekey: &X25519KeyPair,
+    one_time_prekey: Option<&X25519KeyPair>,
+    sender_identity_key: &str,
+    sender_ephemeral_key: &str,
+) -> Option<Vec<u8>> {
+    let sender_identity_key = public_key_from_str(sender_identity_key)?;
+    let sender_ephemeral_key = public_key_from_str(sender_ephemeral_key)?;
+
+    let mut dh = signed_prekey.diffie_hellman(&sender_identity_key);
+    dh.extend(identity.diffie_hellman(&sender_ephemeral_key));
+    dh.extend(signed_prekey.diffie_hellman(&sender_ephemeral_key));
+    if let Some(one_time_prekey) = one_time_prekey {
+        dh.extend(one_time_prekey.diffie_hellman(&sender_ephemeral_key));
+    }
+    Some(derive(&dh))
+}
+
+fn derive(dh: &[u8]) -> Vec<u8> {
+    // 32 0xFF bytes in front of the DH outputs, as in the X3DH spec
+    let mut ikm = vec![0xFFu8; KEY_SIZE];
+    ikm.extend_from_slice(dh);
+    hkdf_sha256(&ikm, &[0u8; KEY_SIZE], X3DH_INFO, KEY_SIZE)
+}
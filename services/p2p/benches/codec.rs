@@ -0,0 +1,62 @@
+use bytes::BytesMut;
+use criterion::{BenchmarkId, Criterion, criterion_group, criterion_main, Throughput};
+use tokio_util::codec::{Decoder, Encoder};
+use p2p::message::{CommonInfo, MESSAGE_TYPE_CHAT_MESSAGE, P2PMessage, TEXT_TYPE};
+use p2p::message::Message::ChatInfoMessage;
+use p2p::packet::codec::{Frame, Framing};
+use p2p::packet::readers::CryptoReader;
+use p2p::packet::writers::seal_frame;
+
+const SESSION: &str = "2f0c6d38-4c1b-4d5e-9a0e-3f1b8f0b5d7a";
+const SECRET: &str = "fgVobm2TEGDyWX6GOJrXTuuUoNbfeMpJSa0WhdTcO0k=";
+
+fn chat_message(content_size: usize) -> P2PMessage {
+    let message = ChatInfoMessage {
+        common_info: CommonInfo::default(),
+        from_address: String::from("1BoatSLRHtKNngkdXEeobR76b53LETtpyT"),
+        to_address: String::from("1HLoD9E4SDFFPDiYfNYnkBLQ85Y51J3Zb1"),
+        info_type: TEXT_TYPE,
+        content: "x".repeat(content_size),
+    };
+    P2PMessage {
+        message_type: MESSAGE_TYPE_CHAT_MESSAGE,
+        data: (&message).into(),
+    }
+}
+
+fn encode(framing: Framing, frame: Frame) -> BytesMut {
+    let mut buf = BytesMut::new();
+    framing.codec().encode(frame, &mut buf).unwrap();
+    buf
+}
+
+fn bench_codec(c: &mut Criterion) {
+    for content_size in [64, 1024, 16 * 1024] {
+        let message = chat_message(content_size);
+        let frame = seal_frame(SESSION, SECRET, &message).unwrap();
+        let mut group = c.benchmark_group(format!("codec/{}", content_size));
+        for framing in [Framing::Legacy, Framing::Binary] {
+            let wire = encode(framing, frame.clone());
+            println!("{:?} {} byte content: {} bytes on the wire", framing, content_size, wire.len());
+            group.throughput(Throughput::Bytes(wire.len() as u64));
+
+            group.bench_with_input(BenchmarkId::new("encode", format!("{:?}", framing)), &message, |b, message| {
+                b.iter(|| encode(framing, seal_frame(SESSION, SECRET, message).unwrap()))
+            });
+            group.bench_with_input(BenchmarkId::new("decode", format!("{:?}", framing)), &wire, |b, wire| {
+                b.iter(|| {
+                    let mut buf = wire.clone();
+                    let frame = framing.codec().decode(&mut buf).unwrap().unwrap();
+                    match frame {
+                        Frame::Data { payload, .. } => CryptoReader::new(SECRET).open(&payload).unwrap(),
+                        _ => unreachable!(),
+                    }
+                })
+            });
+        }
+        group.finish();
+    }
+}
+
+criterion_group!(benches, bench_codec);
+criterion_main!(benches);
@@ -2,6 +2,7 @@ extern crate core;
 
 pub mod packet;
 pub mod message;
+pub mod replay;
 
 pub fn add(left: usize, right: usize) -> usize {
     left + right
@@ -10,11 +11,18 @@ pub fn add(left: usize, right: usize) -> usize {
 #[cfg(test)]
 mod tests {
     
-    use crate::message::P2PMessage;
+    use bytes::BytesMut;
+    use proptest::prelude::*;
+    use tokio_util::codec::{Decoder, Encoder};
+    use ncrypto::algo::aes;
+    use ncrypto::algo::aes::NONCE_SIZE;
+    use ncrypto::algo::base64::decode_from_str;
+    use crate::message::{CHAT_STATUS_DELIVERED, CHAT_STATUS_QUEUED, CommonInfo, Message, MESSAGE_TYPE_CHAT_STATUS, P2PMessage};
+    use crate::packet::codec::{Frame, FrameCodec, Framing, MAX_FRAME_SIZE};
     use crate::packet::p2p_packet::P2PPacket;
     use crate::packet::readers::{CryptoReader, PacketExtractor};
-    use crate::packet::writers::{MessageWriter, Writer};
-    
+    use crate::packet::writers::{MessageWriter, seal_frame, Writer};
+    use crate::replay::ReplayWindow;
 
     #[test]
     fn test_writer_reader() {
@@ -29,7 +37,7 @@ mod tests {
         println!("{}", res);
 
         let mut extractor = PacketExtractor::new();
-        let packet_content = extractor.extract(&res).unwrap();
+        let packet_content = extractor.extract_all(&res).pop().unwrap().unwrap();
 
         let mut reader = CryptoReader::new("fgVobm2TEGDyWX6GOJrXTuuUoNbfeMpJSa0WhdTcO0k=");
 
@@ -37,6 +45,234 @@ mod tests {
         println!("{:?}", message);
     }
 
+    #[test]
+    fn test_frame_codec() {
+        let secret = "fgVobm2TEGDyWX6GOJrXTuuUoNbfeMpJSa0WhdTcO0k=";
+        let message = P2PMessage {
+            message_type: 0,
+            data: String::from("12345676")
+        };
+        let frame = seal_frame("123", secret, &message).unwrap();
+
+        let mut codec = FrameCodec;
+        let mut buf = BytesMut::new();
+        codec.encode(Frame::Hello { version: 1 }, &mut buf).unwrap();
+        codec.encode(frame.clone(), &mut buf).unwrap();
+        codec.encode(Frame::SessionInvalid { session: String::from("123") }, &mut buf).unwrap();
+        assert_eq!(Framing::detect(buf[0]), Framing::Binary);
+
+        // Bytes arrive one at a time, nothing is decoded before a frame is complete
+        let mut input = BytesMut::new();
+        let mut frames = vec![];
+        for byte in buf.iter() {
+            input.extend_from_slice(&[*byte]);
+            while let Some(frame) = codec.decode(&mut input).unwrap() {
+                frames.push(frame);
+            }
+        }
+        assert_eq!(frames, vec![
+            Frame::Hello { version: 1 },
+            frame.clone(),
+            Frame::SessionInvalid { session: String::from("123") },
+        ]);
+
+        let payload = match &frames[1] {
+            Frame::Data { payload, .. } => payload,
+            _ => panic!("expected data frame"),
+        };
+        let decoded = CryptoReader::new(secret).open(payload).unwrap();
+        assert_eq!(decoded.data, message.data);
+
+        let mut oversized = BytesMut::new();
+        oversized.extend_from_slice(&((MAX_FRAME_SIZE + 1) as u32).to_be_bytes());
+        assert!(codec.decode(&mut oversized).is_err());
+    }
+
+    #[test]
+    fn test_legacy_codec() {
+        let secret = "fgVobm2TEGDyWX6GOJrXTuuUoNbfeMpJSa0WhdTcO0k=";
+        let message = P2PMessage {
+            message_type: 0,
+            data: String::from("12345676")
+        };
+        let message_str = serde_json::to_string(&message).unwrap();
+        let written = MessageWriter.process(&message_str, &["123", secret]).unwrap();
+        assert_eq!(Framing::detect(written.as_bytes()[0]), Framing::Legacy);
+
+        // Frames from the old writer chain decode through the legacy codec
+        let mut codec = Framing::Legacy.codec();
+        let mut buf = BytesMut::from(written.as_bytes());
+        let frame = codec.decode(&mut buf).unwrap().unwrap();
+        let payload = match &frame {
+            Frame::Data { session, payload } => {
+                assert_eq!(session, "123");
+                payload.clone()
+            }
+            _ => panic!("expected data frame"),
+        };
+        assert_eq!(CryptoReader::new(secret).open(&payload).unwrap().data, message.data);
+
+        // and what it encodes is readable by the old extractor
+        let mut encoded = BytesMut::new();
+        codec.encode(frame, &mut encoded).unwrap();
+        let packet_content = PacketExtractor::new().extract_all(&String::from_utf8_lossy(&encoded)).pop().unwrap().unwrap();
+        assert_eq!(CryptoReader::new(secret).process(&packet_content).unwrap().data, message.data);
+        assert!(codec.encode(Frame::Hello { version: 1 }, &mut encoded).is_err());
+    }
+
+    #[test]
+    fn test_legacy_payload() {
+        let message = P2PMessage {
+            message_type: 0,
+            data: String::from("sealed before the envelope"),
+        };
+        // A zero-nonce envelope without its header is what old clients sent
+        let key = decode_from_str(SECRET).unwrap();
+        let sealed = aes::encode_with_nonce(&key, &[0u8; NONCE_SIZE], &serde_json::to_vec(&message).unwrap()).unwrap();
+        let legacy = &sealed[1 + NONCE_SIZE..];
+
+        assert!(CryptoReader::new(SECRET).open(legacy).is_err());
+        assert!(CryptoReader::new(SECRET).accept_legacy(false).open(legacy).is_err());
+        assert_eq!(CryptoReader::new(SECRET).accept_legacy(true).open(legacy).unwrap().data, message.data);
+        assert_eq!(CryptoReader::new(SECRET).accept_legacy(true).open(&sealed).unwrap().data, message.data);
+    }
+
+    const SECRET: &str = "fgVobm2TEGDyWX6GOJrXTuuUoNbfeMpJSa0WhdTcO0k=";
+
+    fn legacy_stream(contents: &[String]) -> String {
+        contents.iter()
+            .map(|data| {
+                let message = serde_json::to_string(&P2PMessage { message_type: 0, data: data.to_string() }).unwrap();
+                MessageWriter.process(&message, &["123", SECRET]).unwrap()
+            })
+            .collect()
+    }
+
+    /// Cuts `data` at the given points, which are taken modulo its length.
+    fn chunks(data: &[u8], mut splits: Vec<usize>) -> Vec<&[u8]> {
+        splits.iter_mut().for_each(|split| *split %= data.len() + 1);
+        splits.push(0);
+        splits.push(data.len());
+        splits.sort_unstable();
+        splits.windows(2).map(|range| &data[range[0]..range[1]]).collect()
+    }
+
+    #[test]
+    fn test_chat_status() {
+        let chat = Message::ChatInfoMessage {
+            common_info: CommonInfo::default(),
+            from_address: String::from("alice"),
+            to_address: String::from("bob"),
+            info_type: 0,
+            content: String::from("hi"),
+        };
+        let status = Message::chat_status(&chat, CHAT_STATUS_QUEUED).unwrap();
+        let p2p_message: P2PMessage = (&status).into();
+        assert_eq!(p2p_message.message_type, MESSAGE_TYPE_CHAT_STATUS);
+        match Message::try_from(&p2p_message).unwrap() {
+            Message::ChatStatusMessage { common_info, from_address, to_address, status } => {
+                assert_eq!(common_info.response_id, chat.common_info().request_id);
+                assert_eq!((from_address.as_str(), to_address.as_str()), ("bob", "alice"));
+                assert_eq!(status, CHAT_STATUS_QUEUED);
+            }
+            other => panic!("unexpected {:?}", other),
+        }
+        assert!(Message::chat_status(&status, CHAT_STATUS_DELIVERED).is_none());
+    }
+
+    #[test]
+    fn test_extract_all() {
+        let stream = legacy_stream(&[String::from("a"), String::from("b"), String::from("c")]);
+        let (first, rest) = stream.split_at(stream.len() - 5);
+
+        let mut extractor = PacketExtractor::new();
+        let mut reader = CryptoReader::new(SECRET);
+        let contents = extractor.extract_all(first);
+        assert_eq!(contents.len(), 2);
+        let contents: Vec<String> = contents.iter()
+            .chain(extractor.extract_all(rest).iter())
+            .map(|content| reader.process(content.as_ref().unwrap()).unwrap().data)
+            .collect();
+        assert_eq!(contents, vec!["a", "b", "c"]);
+
+        // Garbage outside of frames doesn't hide the frames around it
+        let noisy = format!("junk{}junk", legacy_stream(&[String::from("d")]));
+        assert_eq!(extractor.extract_all(&noisy).len(), 1);
+
+        // A malformed frame is reported, not panicked on
+        let contents = extractor.extract_all("<not base64!>");
+        assert_eq!(contents.len(), 1);
+        assert!(contents[0].is_err());
+        assert!(reader.open(b"garbage").is_err());
+        assert!(Message::try_from("{").is_err());
+    }
+
+    proptest! {
+        #[test]
+        fn prop_extract_all_split(
+            contents in prop::collection::vec("[a-z0-9 ]{0,64}", 1..8),
+            splits in prop::collection::vec(any::<usize>(), 0..16),
+        ) {
+            let stream = legacy_stream(&contents);
+            let mut extractor = PacketExtractor::new();
+            let mut reader = CryptoReader::new(SECRET);
+            let mut decoded = vec![];
+            for chunk in chunks(stream.as_bytes(), splits) {
+                for content in extractor.extract_all(std::str::from_utf8(chunk).unwrap()) {
+                    decoded.push(reader.process(&content.unwrap()).unwrap().data);
+                }
+            }
+            prop_assert_eq!(decoded, contents);
+        }
+
+        #[test]
+        fn prop_codec_split(
+            contents in prop::collection::vec("[a-z0-9 ]{0,64}", 1..8),
+            splits in prop::collection::vec(any::<usize>(), 0..16),
+            legacy in any::<bool>(),
+        ) {
+            let framing = if legacy { Framing::Legacy } else { Framing::Binary };
+            let mut codec = framing.codec();
+            let mut wire = BytesMut::new();
+            for data in &contents {
+                let message = P2PMessage { message_type: 0, data: data.to_string() };
+                codec.encode(seal_frame("123", SECRET, &message).unwrap(), &mut wire).unwrap();
+            }
+
+            let mut codec = framing.codec();
+            let mut reader = CryptoReader::new(SECRET);
+            let mut input = BytesMut::new();
+            let mut decoded = vec![];
+            for chunk in chunks(&wire, splits) {
+                input.extend_from_slice(chunk);
+                while let Some(frame) = codec.decode(&mut input).unwrap() {
+                    if let Frame::Data { payload, .. } = frame {
+                        decoded.push(reader.open(&payload).unwrap().data);
+                    }
+                }
+            }
+            prop_assert_eq!(decoded, contents);
+        }
+    }
+
+    #[test]
+    fn test_replay_window() {
+        let mut window = ReplayWindow::new(60 * 1000, 2);
+        let info1 = CommonInfo::default();
+        let info2 = CommonInfo::default();
+        let info3 = CommonInfo::default();
+        assert!(window.check(&info1).is_ok());
+        assert!(window.check(&info1).is_err());
+        assert!(window.check(&info2).is_ok());
+        assert!(window.check(&info3).is_ok());
+        // Evicted once the window is full
+        assert!(window.check(&info1).is_ok());
+
+        let stale = CommonInfo { time_ms: 0, ..Default::default() };
+        assert!(window.check(&stale).is_err());
+        assert!(ReplayWindow::dedupe_only(2).check(&stale).is_ok());
+    }
+
     #[test]
     fn test_packet() {
         let packet1 = P2PPacket {
@@ -1,14 +1,33 @@
 use std::time::{SystemTime, UNIX_EPOCH};
 use serde::{Serialize, Deserialize};
 use uuid::Uuid;
+use common::errors::NavajoError;
 use crate::message::Message::PingMessage;
 
 pub const TEXT_TYPE: MessageType = 0;
+/// `content` is sealed to the recipient's account key, the server only relays it.
+pub const E2E_TEXT_TYPE: MessageType = 1;
+/// `content` is a ratchet message, see `common::e2e::RatchetContent`.
+pub const RATCHET_TEXT_TYPE: MessageType = 2;
 
 pub const MESSAGE_TYPE_PING: MessageType = 0;
 pub const MESSAGE_TYPE_CHAT_MESSAGE: MessageType = 1;
+pub const MESSAGE_TYPE_CHAT_STATUS: MessageType = 2;
+pub const MESSAGE_TYPE_RECEIPT: MessageType = 3;
+
+/// The recipient is offline, the server stored the chat until its next connection.
+pub const CHAT_STATUS_QUEUED: ChatStatus = 0;
+/// The server handed the chat to a connection of the recipient.
+pub const CHAT_STATUS_DELIVERED: ChatStatus = 1;
+
+/// The recipient's device received and opened the chat.
+pub const RECEIPT_DELIVERED: ReceiptType = 0;
+/// The recipient read the chat.
+pub const RECEIPT_READ: ReceiptType = 1;
 
 type MessageType = u8;
+pub type ChatStatus = u8;
+pub type ReceiptType = u8;
 
 #[derive(Serialize, Deserialize, Debug, Default, Clone)]
 pub struct P2PMessage {
@@ -39,9 +58,25 @@ impl Default for CommonInfo {
     }
 }
 
+impl CommonInfo {
+    /// Stands in for the common info of messages sent without one, never within the
+    /// replay window.
+    pub fn unstamped() -> Self {
+        CommonInfo {
+            time_ms: 0,
+            request_id: String::from(""),
+            response_id: String::from(""),
+        }
+    }
+}
+
 #[derive(Serialize, Deserialize, Debug, Clone)]
 pub enum Message {
     PingMessage {
+        // Old clients don't send it. Such pings can't be told from replayed ones,
+        // they are refused as stale.
+        #[serde(default = "CommonInfo::unstamped")]
+        common_info: CommonInfo,
         address: String,
         device_id: String,
     },
@@ -52,6 +87,67 @@ pub enum Message {
         info_type: u8,
         content: String,
     },
+    /// Sent by the server to `to_address` once it accepted the chat whose
+    /// `request_id` is in `common_info.response_id`.
+    ChatStatusMessage {
+        common_info: CommonInfo,
+        from_address: String,
+        to_address: String,
+        status: ChatStatus,
+    },
+    /// Sent by the recipient's device back to the sender of the chat whose
+    /// `request_id` is in `common_info.response_id`, the server relays it like a chat.
+    ReceiptMessage {
+        common_info: CommonInfo,
+        from_address: String,
+        to_address: String,
+        receipt: ReceiptType,
+    },
+}
+
+impl Message {
+    /// Status of `chat` for its sender, `None` if `chat` isn't a chat message.
+    pub fn chat_status(chat: &Message, status: ChatStatus) -> Option<Message> {
+        let (common_info, from_address, to_address) = reply_to_chat(chat)?;
+        Some(Message::ChatStatusMessage { common_info, from_address, to_address, status })
+    }
+
+    /// Receipt of `chat` for its sender, `None` if `chat` isn't a chat message.
+    pub fn receipt(chat: &Message, receipt: ReceiptType) -> Option<Message> {
+        let (common_info, from_address, to_address) = reply_to_chat(chat)?;
+        Some(Message::ReceiptMessage { common_info, from_address, to_address, receipt })
+    }
+
+    pub fn common_info(&self) -> &CommonInfo {
+        match self {
+            PingMessage { common_info, .. } => common_info,
+            Message::ChatInfoMessage { common_info, .. } => common_info,
+            Message::ChatStatusMessage { common_info, .. } => common_info,
+            Message::ReceiptMessage { common_info, .. } => common_info,
+        }
+    }
+
+    /// The address the message claims to come from.
+    pub fn sender_address(&self) -> &str {
+        match self {
+            PingMessage { address, .. } => address,
+            Message::ChatInfoMessage { from_address, .. } => from_address,
+            Message::ChatStatusMessage { from_address, .. } => from_address,
+            Message::ReceiptMessage { from_address, .. } => from_address,
+        }
+    }
+}
+
+/// Common info answering `chat`, with the addresses swapped.
+fn reply_to_chat(chat: &Message) -> Option<(CommonInfo, String, String)> {
+    if let Message::ChatInfoMessage { common_info, from_address, to_address, .. } = chat {
+        let reply_info = CommonInfo {
+            response_id: common_info.request_id.clone(),
+            ..Default::default()
+        };
+        return Some((reply_info, to_address.clone(), from_address.clone()));
+    }
+    None
 }
 
 impl From<&Message> for String {
@@ -60,21 +156,27 @@ impl From<&Message> for String {
     }
 }
 
-impl From<&str> for Message {
-    fn from(value: &str) -> Self {
-        serde_json::from_str(value).unwrap()
+impl TryFrom<&str> for Message {
+    type Error = NavajoError;
+
+    fn try_from(value: &str) -> Result<Self, Self::Error> {
+        Ok(serde_json::from_str(value)?)
     }
 }
 
-impl From<&[u8]> for Message {
-    fn from(value: &[u8]) -> Self {
-        serde_json::from_slice(value).unwrap()
+impl TryFrom<&[u8]> for Message {
+    type Error = NavajoError;
+
+    fn try_from(value: &[u8]) -> Result<Self, Self::Error> {
+        Ok(serde_json::from_slice(value)?)
     }
 }
 
-impl From<Vec<u8>> for Message {
-    fn from(value: Vec<u8>) -> Self {
-        serde_json::from_slice(&value).unwrap()
+impl TryFrom<Vec<u8>> for Message {
+    type Error = NavajoError;
+
+    fn try_from(value: Vec<u8>) -> Result<Self, Self::Error> {
+        value.as_slice().try_into()
     }
 }
 
@@ -88,6 +190,8 @@ impl From<&Message> for P2PMessage {
     fn from(value: &Message) -> Self {
         let message_type = match value {
             PingMessage { .. } => MESSAGE_TYPE_PING,
+            Message::ChatStatusMessage { .. } => MESSAGE_TYPE_CHAT_STATUS,
+            Message::ReceiptMessage { .. } => MESSAGE_TYPE_RECEIPT,
             _ => MESSAGE_TYPE_CHAT_MESSAGE,
         };
         P2PMessage {
@@ -97,21 +201,27 @@ impl From<&Message> for P2PMessage {
     }
 }
 
-impl From<&P2PMessage> for Message {
-    fn from(value: &P2PMessage) -> Self {
-        value.data.as_str().into()
+impl TryFrom<&P2PMessage> for Message {
+    type Error = NavajoError;
+
+    fn try_from(value: &P2PMessage) -> Result<Self, Self::Error> {
+        value.data.as_str().try_into()
     }
 }
 
-impl From<&[u8]> for P2PMessage {
-    fn from(value: &[u8]) -> Self {
-        serde_json::from_slice(value).unwrap()
+impl TryFrom<&[u8]> for P2PMessage {
+    type Error = NavajoError;
+
+    fn try_from(value: &[u8]) -> Result<Self, Self::Error> {
+        Ok(serde_json::from_slice(value)?)
     }
 }
 
-impl From<Vec<u8>> for P2PMessage {
-    fn from(value: Vec<u8>) -> Self {
-        serde_json::from_slice(&value).unwrap()
+impl TryFrom<Vec<u8>> for P2PMessage {
+    type Error = NavajoError;
+
+    fn try_from(value: Vec<u8>) -> Result<Self, Self::Error> {
+        value.as_slice().try_into()
     }
 }
 
@@ -121,9 +231,11 @@ impl From<&P2PMessage> for Vec<u8> {
     }
 }
 
-impl From<&str> for P2PMessage {
-    fn from(value: &str) -> Self {
-        serde_json::from_str(value).unwrap()
+impl TryFrom<&str> for P2PMessage {
+    type Error = NavajoError;
+
+    fn try_from(value: &str) -> Result<Self, Self::Error> {
+        Ok(serde_json::from_str(value)?)
     }
 }
 
@@ -131,4 +243,4 @@ impl From<&P2PMessage> for String {
     fn from(value: &P2PMessage) -> Self {
         serde_json::to_string(value).unwrap()
     }
-}
\ No newline at end of file
+}
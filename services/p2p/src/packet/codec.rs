@@ -0,0 +1,214 @@
+use std::collections::VecDeque;
+use bytes::{Buf, BufMut, BytesMut};
+use tokio_util::codec::{Decoder, Encoder};
+use common::errors::{INVALID_FRAME_ERROR, NavajoError, NavajoResult};
+use ncrypto::algo::base64;
+use crate::packet::p2p_packet::PacketContent;
+use crate::packet::readers::PacketExtractor;
+
+/// Version sent in the hello frame, bumped on incompatible frame layout changes.
+pub const FRAME_PROTOCOL_VERSION: u8 = 1;
+/// Upper bound of type byte plus body, a peer announcing more is dropped.
+pub const MAX_FRAME_SIZE: usize = 1024 * 1024;
+
+pub const FRAME_TYPE_HELLO: u8 = 0;
+pub const FRAME_TYPE_DATA: u8 = 1;
+pub const FRAME_TYPE_SESSION_INVALID: u8 = 2;
+
+const LENGTH_SIZE: usize = 4;
+const LEGACY_FRAME_HEAD: u8 = b'<';
+
+/// `Data` carries the session and the AES envelope of a serialized `P2PMessage`.
+/// `SessionInvalid` tells a client the server rejects `session`, it has to handshake again.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Frame {
+    Hello { version: u8 },
+    Data { session: String, payload: Vec<u8> },
+    SessionInvalid { session: String },
+}
+
+/// Binary framing: `u32 length || type || body`, the length covering type and body.
+/// A data body is `u16 session length || session || payload`.
+#[derive(Debug, Default)]
+pub struct FrameCodec;
+
+impl Decoder for FrameCodec {
+    type Item = Frame;
+    type Error = NavajoError;
+
+    fn decode(&mut self, src: &mut BytesMut) -> Result<Option<Frame>, NavajoError> {
+        if src.len() < LENGTH_SIZE {
+            return Ok(None);
+        }
+        let len = u32::from_be_bytes([src[0], src[1], src[2], src[3]]) as usize;
+        if len == 0 || len > MAX_FRAME_SIZE {
+            return Err(NavajoError::new(INVALID_FRAME_ERROR));
+        }
+        if src.len() < LENGTH_SIZE + len {
+            src.reserve(LENGTH_SIZE + len - src.len());
+            return Ok(None);
+        }
+        src.advance(LENGTH_SIZE);
+        let mut body = src.split_to(len);
+        let frame_type = body.get_u8();
+        match frame_type {
+            FRAME_TYPE_HELLO if body.len() == 1 => Ok(Some(Frame::Hello { version: body.get_u8() })),
+            FRAME_TYPE_DATA if body.len() >= 2 => {
+                let session_len = body.get_u16() as usize;
+                if body.len() < session_len {
+                    return Err(NavajoError::new(INVALID_FRAME_ERROR));
+                }
+                let session = body.split_to(session_len);
+                let session = String::from_utf8(session.to_vec()).map_err(|_| NavajoError::new(INVALID_FRAME_ERROR))?;
+                Ok(Some(Frame::Data { session, payload: body.to_vec() }))
+            }
+            FRAME_TYPE_SESSION_INVALID => {
+                let session = String::from_utf8(body.to_vec()).map_err(|_| NavajoError::new(INVALID_FRAME_ERROR))?;
+                Ok(Some(Frame::SessionInvalid { session }))
+            }
+            _ => Err(NavajoError::new(INVALID_FRAME_ERROR)),
+        }
+    }
+}
+
+impl Encoder<Frame> for FrameCodec {
+    type Error = NavajoError;
+
+    fn encode(&mut self, frame: Frame, dst: &mut BytesMut) -> Result<(), NavajoError> {
+        match frame {
+            Frame::Hello { version } => {
+                dst.reserve(LENGTH_SIZE + 2);
+                dst.put_u32(2);
+                dst.put_u8(FRAME_TYPE_HELLO);
+                dst.put_u8(version);
+            }
+            Frame::Data { session, payload } => {
+                let len = 1 + 2 + session.len() + payload.len();
+                if session.len() > u16::MAX as usize || len > MAX_FRAME_SIZE {
+                    return Err(NavajoError::new(INVALID_FRAME_ERROR));
+                }
+                dst.reserve(LENGTH_SIZE + len);
+                dst.put_u32(len as u32);
+                dst.put_u8(FRAME_TYPE_DATA);
+                dst.put_u16(session.len() as u16);
+                dst.put_slice(session.as_bytes());
+                dst.put_slice(&payload);
+            }
+            Frame::SessionInvalid { session } => {
+                let len = 1 + session.len();
+                if len > MAX_FRAME_SIZE {
+                    return Err(NavajoError::new(INVALID_FRAME_ERROR));
+                }
+                dst.reserve(LENGTH_SIZE + len);
+                dst.put_u32(len as u32);
+                dst.put_u8(FRAME_TYPE_SESSION_INVALID);
+                dst.put_slice(session.as_bytes());
+            }
+        }
+        Ok(())
+    }
+}
+
+/// The original `<base64(json)>` framing, kept for peers that don't speak [`FrameCodec`].
+#[derive(Default)]
+pub struct LegacyCodec {
+    extractor: PacketExtractor,
+    // Frames already extracted from a read but not handed out yet
+    pending: VecDeque<NavajoResult<Frame>>,
+}
+
+impl Decoder for LegacyCodec {
+    type Item = Frame;
+    type Error = NavajoError;
+
+    fn decode(&mut self, src: &mut BytesMut) -> Result<Option<Frame>, NavajoError> {
+        if self.pending.is_empty() && !src.is_empty() {
+            let data = src.split();
+            let str = String::from_utf8_lossy(&data);
+            for packet_content in self.extractor.extract_all(&str) {
+                let frame = packet_content.and_then(|packet_content| {
+                    let payload = base64::decode_from_str(&packet_content.data)?;
+                    Ok(Frame::Data { session: packet_content.session, payload })
+                });
+                self.pending.push_back(frame);
+            }
+        }
+        self.pending.pop_front().transpose()
+    }
+}
+
+impl Encoder<Frame> for LegacyCodec {
+    type Error = NavajoError;
+
+    fn encode(&mut self, frame: Frame, dst: &mut BytesMut) -> Result<(), NavajoError> {
+        let (session, payload) = match frame {
+            Frame::Data { session, payload } => (session, payload),
+            Frame::Hello { .. } => return Err(NavajoError::new(INVALID_FRAME_ERROR)),
+            // Legacy peers have no way to hear about it, they find out on their own
+            Frame::SessionInvalid { .. } => return Ok(()),
+        };
+        let content = PacketContent {
+            data: base64::encode_to_str(&payload),
+            session,
+        };
+        let json: String = (&content).into();
+        dst.put_u8(LEGACY_FRAME_HEAD);
+        dst.put_slice(base64::encode_to_str(json.as_bytes()).as_bytes());
+        dst.put_u8(b'>');
+        Ok(())
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Framing {
+    Binary,
+    Legacy,
+}
+
+impl Framing {
+    /// Legacy frames always start with `<`, a binary length prefix never does
+    /// since it would announce more than [`MAX_FRAME_SIZE`].
+    pub fn detect(first_byte: u8) -> Self {
+        if first_byte == LEGACY_FRAME_HEAD {
+            Framing::Legacy
+        } else {
+            Framing::Binary
+        }
+    }
+
+    pub fn codec(self) -> P2PCodec {
+        match self {
+            Framing::Binary => P2PCodec::Binary(FrameCodec),
+            Framing::Legacy => P2PCodec::Legacy(Default::default()),
+        }
+    }
+}
+
+/// Either framing behind one type, so both halves of a connection share the read and write paths.
+pub enum P2PCodec {
+    Binary(FrameCodec),
+    Legacy(LegacyCodec),
+}
+
+impl Decoder for P2PCodec {
+    type Item = Frame;
+    type Error = NavajoError;
+
+    fn decode(&mut self, src: &mut BytesMut) -> Result<Option<Frame>, NavajoError> {
+        match self {
+            P2PCodec::Binary(codec) => codec.decode(src),
+            P2PCodec::Legacy(codec) => codec.decode(src),
+        }
+    }
+}
+
+impl Encoder<Frame> for P2PCodec {
+    type Error = NavajoError;
+
+    fn encode(&mut self, frame: Frame, dst: &mut BytesMut) -> Result<(), NavajoError> {
+        match self {
+            P2PCodec::Binary(codec) => codec.encode(frame, dst),
+            P2PCodec::Legacy(codec) => codec.encode(frame, dst),
+        }
+    }
+}
@@ -1,3 +1,4 @@
 pub mod p2p_packet;
 pub mod writers;
 pub mod readers;
+pub mod codec;
@@ -1,4 +1,5 @@
 use serde::{Serialize, Deserialize};
+use common::errors::NavajoError;
 
 #[derive(Debug, Clone, Default)]
 pub struct P2PPacket {
@@ -33,9 +34,11 @@ pub struct PacketContent {
     pub session: String,
 }
 
-impl From<&str> for PacketContent {
-    fn from(value: &str) -> Self {
-        serde_json::from_str(value).unwrap()
+impl TryFrom<&str> for PacketContent {
+    type Error = NavajoError;
+
+    fn try_from(value: &str) -> Result<Self, Self::Error> {
+        Ok(serde_json::from_str(value)?)
     }
 }
 
@@ -1,26 +1,52 @@
+use common::errors::NavajoResult;
 use ncrypto::algo::aes;
 use ncrypto::algo::base64::decode_from_str;
-use crate::message::P2PMessage;
+use crate::message::{Message, P2PMessage};
 use crate::packet::p2p_packet::{P2PPacket, PacketContent};
+use crate::replay::ReplayWindow;
 
 pub struct CryptoReader {
     secret: String,
+    legacy: bool,
 }
 
 impl CryptoReader {
     pub fn new(secret: &str) -> Self {
         Self {
             secret: secret.to_string(),
+            legacy: false,
         }
     }
 
-    pub fn process(&mut self, packet_content: &PacketContent) -> Option<P2PMessage> {
-        let secret = decode_from_str(&self.secret);
-        let data = decode_from_str(&packet_content.data);
-        let content = aes::decode(secret.as_slice(), &data).ok()?;
+    /// Also opens payloads sealed without the envelope, which only clients of the
+    /// legacy session protocol still send.
+    pub fn accept_legacy(mut self, legacy: bool) -> Self {
+        self.legacy = legacy;
+        self
+    }
+
+    pub fn process(&mut self, packet_content: &PacketContent) -> NavajoResult<P2PMessage> {
+        let data = decode_from_str(&packet_content.data)?;
+        self.open(&data)
+    }
+
+    /// Decrypts the payload of a data frame.
+    pub fn open(&mut self, payload: &[u8]) -> NavajoResult<P2PMessage> {
+        let secret = decode_from_str(&self.secret)?;
+        let content = if self.legacy {
+            aes::decode_with_legacy(secret.as_slice(), payload)?
+        } else {
+            aes::decode(secret.as_slice(), payload)?
+        };
+        content.as_slice().try_into()
+    }
 
-        let content = String::from_utf8(content).unwrap();
-        serde_json::from_str(&content).ok()
+    /// Like `open`, but rejects messages the window has already seen or considers stale.
+    pub fn open_checked(&mut self, payload: &[u8], window: &mut ReplayWindow) -> NavajoResult<P2PMessage> {
+        let p2p_message = self.open(payload)?;
+        let message = Message::try_from(&p2p_message)?;
+        window.check(message.common_info())?;
+        Ok(p2p_message)
     }
 }
 
@@ -41,33 +67,28 @@ impl PacketExtractor {
         }
     }
 
-    pub fn extract(&mut self, data: &str) -> Option<PacketContent> {
-        let packets = packets_from_string(data);
-        for packet in packets {
+    /// Returns every frame completed by `data`, in order. A trailing partial frame
+    /// is kept until a later read completes it, a malformed one becomes an error.
+    pub fn extract_all(&mut self, data: &str) -> Vec<NavajoResult<PacketContent>> {
+        let mut contents = vec![];
+        for packet in packets_from_string(data) {
+            let packet = match self.temp_packet.take() {
+                Some(temp) => temp.concat(&packet),
+                None => packet,
+            };
             if packet.with_head && packet.with_tail {
-                let packet_content = self.gen_packet_content(&packet.content);
-                return Some(packet_content);
-            } else {
-                if self.temp_packet.is_some() {
-                    self.temp_packet = self.temp_packet.as_mut().map(|x| { x.concat(&packet) });
-                } else {
-                    self.temp_packet = Some(packet);
-                }
-                let temp = self.temp_packet.as_ref().unwrap();
-                if temp.with_tail && temp.with_head {
-                    let packet_content = self.gen_packet_content(&temp.content);
-                    self.temp_packet = None;
-                    return Some(packet_content);
-                }
+                contents.push(self.gen_packet_content(&packet.content));
+            } else if packet.with_head {
+                self.temp_packet = Some(packet);
             }
+            // Bytes outside of any frame can't be recovered and are dropped
         }
-        None
+        contents
     }
 
-    fn gen_packet_content(&self, str: &str) -> PacketContent {
-        let decoded = decode_from_str(str);
-        let decoded = String::from_utf8(decoded).unwrap();
-        decoded.as_str().into()
+    fn gen_packet_content(&self, str: &str) -> NavajoResult<PacketContent> {
+        let decoded = decode_from_str(str)?;
+        Ok(serde_json::from_slice(&decoded)?)
     }
 }
 
@@ -1,5 +1,8 @@
+use common::errors::NavajoResult;
 use ncrypto::algo::{aes, base64};
 use ncrypto::algo::base64::decode_from_str;
+use crate::message::P2PMessage;
+use crate::packet::codec::Frame;
 use crate::packet::p2p_packet::PacketContent;
 
 pub trait Writer {
@@ -35,8 +38,8 @@ impl Writer for CryptoWriter {
         let session = params[0];
         let secret = params[1];
 
-        let secret = decode_from_str(secret);
-        let data = decode_from_str(data);
+        let secret = decode_from_str(secret).ok()?;
+        let data = decode_from_str(data).ok()?;
 
         let encrypted_data = aes::encode(&secret, &data).ok()?;
 
@@ -69,3 +72,13 @@ impl Writer for MessageWriter {
     }
 }
 
+/// Encrypts a message straight into a data frame, skipping the base64 layers of the writer chain.
+pub fn seal_frame(session: &str, secret: &str, message: &P2PMessage) -> NavajoResult<Frame> {
+    let secret = decode_from_str(secret)?;
+    let message_str: String = message.into();
+    let payload = aes::encode(&secret, message_str.as_bytes())?;
+    Ok(Frame::Data {
+        session: session.to_string(),
+        payload,
+    })
+}
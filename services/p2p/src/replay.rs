@@ -0,0 +1,55 @@
+use std::collections::{HashSet, VecDeque};
+use std::time::{SystemTime, UNIX_EPOCH};
+use common::errors::{NavajoError, NavajoResult, REPLAY_ERROR, STALE_REQUEST_ERROR};
+use crate::message::CommonInfo;
+
+pub const DEFAULT_WINDOW_CAPACITY: usize = 4096;
+
+/// In-process record of recently seen `CommonInfo.request_id`s.
+pub struct ReplayWindow {
+    max_age_ms: Option<u128>,
+    capacity: usize,
+    seen: HashSet<String>,
+    order: VecDeque<String>,
+}
+
+impl ReplayWindow {
+    pub fn new(max_age_ms: u128, capacity: usize) -> Self {
+        Self {
+            max_age_ms: Some(max_age_ms),
+            capacity,
+            seen: HashSet::new(),
+            order: VecDeque::new(),
+        }
+    }
+
+    /// Only rejects duplicates, for peers that legitimately deliver old messages.
+    pub fn dedupe_only(capacity: usize) -> Self {
+        Self {
+            max_age_ms: None,
+            capacity,
+            seen: HashSet::new(),
+            order: VecDeque::new(),
+        }
+    }
+
+    pub fn check(&mut self, common_info: &CommonInfo) -> NavajoResult<()> {
+        if let Some(max_age_ms) = self.max_age_ms {
+            let now = SystemTime::now().duration_since(UNIX_EPOCH).unwrap().as_millis();
+            if now.abs_diff(common_info.time_ms) > max_age_ms {
+                return Err(NavajoError::new(STALE_REQUEST_ERROR));
+            }
+        }
+        if self.seen.contains(&common_info.request_id) {
+            return Err(NavajoError::new(REPLAY_ERROR));
+        }
+        if self.order.len() >= self.capacity {
+            if let Some(oldest) = self.order.pop_front() {
+                self.seen.remove(&oldest);
+            }
+        }
+        self.seen.insert(common_info.request_id.to_string());
+        self.order.push_back(common_info.request_id.to_string());
+        Ok(())
+    }
+}
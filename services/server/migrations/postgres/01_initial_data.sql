@@ -0,0 +1,45 @@
+-- `user` is reserved in PostgreSQL, it is always quoted
+CREATE TABLE IF NOT EXISTS "user"
+(
+    id                SERIAL PRIMARY KEY,
+    address           VARCHAR(256) NOT NULL DEFAULT '' UNIQUE,
+    device_id         VARCHAR(256) NOT NULL DEFAULT '',
+    session           VARCHAR(256) NOT NULL DEFAULT '',
+    secret            VARCHAR(256) NOT NULL DEFAULT '',
+    public_key        VARCHAR(256) NOT NULL DEFAULT '',
+    session_expire_ms BIGINT NOT NULL DEFAULT 0
+);
+
+CREATE TABLE IF NOT EXISTS device
+(
+    id                SERIAL PRIMARY KEY,
+    address           VARCHAR(256) NOT NULL DEFAULT '',
+    device_id         VARCHAR(256) NOT NULL DEFAULT '' UNIQUE,
+    session           VARCHAR(256) NOT NULL DEFAULT '',
+    secret            VARCHAR(256) NOT NULL DEFAULT '',
+    session_expire_ms BIGINT NOT NULL DEFAULT 0,
+    last_seen_ms      BIGINT NOT NULL DEFAULT 0
+);
+CREATE INDEX IF NOT EXISTS device_address_index ON device (address);
+CREATE INDEX IF NOT EXISTS device_session_index ON device (session);
+
+CREATE TABLE IF NOT EXISTS identity_key
+(
+    id                 SERIAL PRIMARY KEY,
+    address            VARCHAR(256) NOT NULL DEFAULT '' UNIQUE,
+    public_key         VARCHAR(256) NOT NULL DEFAULT '',
+    identity_key       VARCHAR(256) NOT NULL DEFAULT '',
+    identity_key_sign  VARCHAR(256) NOT NULL DEFAULT '',
+    signed_prekey_id   BIGINT NOT NULL DEFAULT 0,
+    signed_prekey      VARCHAR(256) NOT NULL DEFAULT '',
+    signed_prekey_sign VARCHAR(256) NOT NULL DEFAULT ''
+);
+
+CREATE TABLE IF NOT EXISTS one_time_prekey
+(
+    id      SERIAL PRIMARY KEY,
+    address VARCHAR(256) NOT NULL DEFAULT '',
+    key_id  BIGINT NOT NULL DEFAULT 0,
+    prekey  VARCHAR(256) NOT NULL DEFAULT '',
+    UNIQUE (address, key_id)
+);
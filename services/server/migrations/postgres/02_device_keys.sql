@@ -0,0 +1,4 @@
+-- Devices are told apart per account, the same device_id may come back on another one
+ALTER TABLE device DROP CONSTRAINT IF EXISTS device_device_id_key;
+ALTER TABLE device ADD CONSTRAINT device_address_device_id_key UNIQUE (address, device_id);
+DROP INDEX IF EXISTS device_address_index;
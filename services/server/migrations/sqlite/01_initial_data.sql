@@ -0,0 +1,65 @@
+CREATE TABLE IF NOT EXISTS `user`
+(
+    `id`                INTEGER PRIMARY KEY AUTOINCREMENT,
+    `address`           TEXT NOT NULL DEFAULT '' UNIQUE,
+    `device_id`         TEXT NOT NULL DEFAULT '',
+    `session`           TEXT NOT NULL DEFAULT '',
+    `secret`            TEXT NOT NULL DEFAULT '',
+    `public_key`        TEXT NOT NULL DEFAULT '',
+    `session_expire_ms` INTEGER NOT NULL DEFAULT 0
+);
+
+CREATE TABLE IF NOT EXISTS `device`
+(
+    `id`                INTEGER PRIMARY KEY AUTOINCREMENT,
+    `address`           TEXT NOT NULL DEFAULT '',
+    `device_id`         TEXT NOT NULL DEFAULT '' UNIQUE,
+    `session`           TEXT NOT NULL DEFAULT '',
+    `secret`            TEXT NOT NULL DEFAULT '',
+    `session_expire_ms` INTEGER NOT NULL DEFAULT 0,
+    `last_seen_ms`      INTEGER NOT NULL DEFAULT 0
+);
+CREATE INDEX IF NOT EXISTS `device_address_INDEX` ON `device` (`address`);
+CREATE INDEX IF NOT EXISTS `device_session_INDEX` ON `device` (`session`);
+
+CREATE TABLE IF NOT EXISTS `identity_key`
+(
+    `id`                 INTEGER PRIMARY KEY AUTOINCREMENT,
+    `address`            TEXT NOT NULL DEFAULT '' UNIQUE,
+    `public_key`         TEXT NOT NULL DEFAULT '',
+    `identity_key`       TEXT NOT NULL DEFAULT '',
+    `identity_key_sign`  TEXT NOT NULL DEFAULT '',
+    `signed_prekey_id`   INTEGER NOT NULL DEFAULT 0,
+    `signed_prekey`      TEXT NOT NULL DEFAULT '',
+    `signed_prekey_sign` TEXT NOT NULL DEFAULT ''
+);
+
+CREATE TABLE IF NOT EXISTS `one_time_prekey`
+(
+    `id`      INTEGER PRIMARY KEY AUTOINCREMENT,
+    `address` TEXT NOT NULL DEFAULT '',
+    `key_id`  INTEGER NOT NULL DEFAULT 0,
+    `prekey`  TEXT NOT NULL DEFAULT '',
+    UNIQUE (`address`, `key_id`)
+);
+
+-- Offline messages, kept in Redis on the MySQL setup
+CREATE TABLE IF NOT EXISTS `queued_message`
+(
+    `id`         INTEGER PRIMARY KEY AUTOINCREMENT,
+    `queue`      TEXT NOT NULL DEFAULT '',
+    `message`    BLOB NOT NULL,
+    `created_ms` INTEGER NOT NULL DEFAULT 0
+);
+CREATE INDEX IF NOT EXISTS `queued_message_queue_INDEX` ON `queued_message` (`queue`, `id`);
+
+-- `status` is NULL while the chat is being handled
+CREATE TABLE IF NOT EXISTS `chat_accepted`
+(
+    `from_address` TEXT NOT NULL DEFAULT '',
+    `request_id`   TEXT NOT NULL DEFAULT '',
+    `status`       INTEGER,
+    `expire_ms`    INTEGER NOT NULL DEFAULT 0,
+    PRIMARY KEY (`from_address`, `request_id`)
+);
+CREATE INDEX IF NOT EXISTS `chat_accepted_expire_INDEX` ON `chat_accepted` (`expire_ms`);
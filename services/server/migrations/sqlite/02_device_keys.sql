@@ -0,0 +1,18 @@
+-- Devices are told apart per account, the same device_id may come back on another one.
+-- SQLite can't drop a constraint, so the table is rebuilt
+CREATE TABLE `device_new`
+(
+    `id`                INTEGER PRIMARY KEY AUTOINCREMENT,
+    `address`           TEXT NOT NULL DEFAULT '',
+    `device_id`         TEXT NOT NULL DEFAULT '',
+    `session`           TEXT NOT NULL DEFAULT '',
+    `secret`            TEXT NOT NULL DEFAULT '',
+    `session_expire_ms` INTEGER NOT NULL DEFAULT 0,
+    `last_seen_ms`      INTEGER NOT NULL DEFAULT 0,
+    UNIQUE (`address`, `device_id`)
+);
+INSERT INTO `device_new` (`id`, `address`, `device_id`, `session`, `secret`, `session_expire_ms`, `last_seen_ms`)
+SELECT `id`, `address`, `device_id`, `session`, `secret`, `session_expire_ms`, `last_seen_ms` FROM `device`;
+DROP TABLE `device`;
+ALTER TABLE `device_new` RENAME TO `device`;
+CREATE INDEX IF NOT EXISTS `device_session_INDEX` ON `device` (`session`);
@@ -0,0 +1,7 @@
+-- Nonces of signed requests, kept in Redis on the MySQL setup
+CREATE TABLE IF NOT EXISTS `nonce`
+(
+    `key`       TEXT PRIMARY KEY NOT NULL,
+    `expire_ms` INTEGER NOT NULL DEFAULT 0
+);
+CREATE INDEX IF NOT EXISTS `nonce_expire_INDEX` ON `nonce` (`expire_ms`);
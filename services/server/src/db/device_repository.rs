@@ -0,0 +1,82 @@
+use std::sync::Arc;
+use mysql_async::{Conn, params, Pool};
+use mysql_async::prelude::{Query, WithParams};
+use common::errors::{DB_ERROR, NavajoError, NavajoResult};
+use crate::db::models::Device;
+
+pub struct DeviceRepository {
+    pool: Arc<Pool>,
+}
+
+impl DeviceRepository {
+    pub fn new(pool: Arc<Pool>) -> Arc<Self> {
+        Arc::new(Self { pool })
+    }
+
+    pub async fn find_by_address(&self, address: &str) -> NavajoResult<Vec<Device>> {
+        let mut conn = self.get_conn().await.ok_or_else(|| NavajoError::new(DB_ERROR))?;
+        "SELECT * FROM device WHERE address = :address"
+            .with(params! { address }).fetch(&mut conn)
+            .await.map_err(|_| NavajoError::new(DB_ERROR))
+    }
+
+    pub async fn find(&self, address: &str, device_id: &str) -> NavajoResult<Option<Device>> {
+        let mut conn = self.get_conn().await.ok_or_else(|| NavajoError::new(DB_ERROR))?;
+        let devices: Vec<Device> = "SELECT * FROM device WHERE address = :address AND device_id = :device_id"
+            .with(params! { address, device_id }).fetch(&mut conn)
+            .await.map_err(|_| NavajoError::new(DB_ERROR))?;
+        Ok(devices.into_iter().next())
+    }
+
+    pub async fn find_by_session(&self, session: &str) -> NavajoResult<Option<Device>> {
+        let mut conn = self.get_conn().await.ok_or_else(|| NavajoError::new(DB_ERROR))?;
+        let devices: Vec<Device> = "SELECT * FROM device WHERE session = :session"
+            .with(params! { session }).fetch(&mut conn)
+            .await.map_err(|_| NavajoError::new(DB_ERROR))?;
+        Ok(devices.into_iter().next())
+    }
+
+    pub async fn insert_or_update(&self, device: &Device) -> NavajoResult<()> {
+        let mut conn = self.get_conn().await.ok_or_else(|| NavajoError::new(DB_ERROR))?;
+        let params = params! {
+            "address" => &device.address,
+            "device_id" => &device.device_id,
+            "session" => &device.session,
+            "secret" => &device.secret,
+            "session_expire_ms" => device.session_expire_ms,
+            "last_seen_ms" => device.last_seen_ms,
+        };
+        r"INSERT INTO device(address, device_id, session, secret, session_expire_ms, last_seen_ms)
+          VALUES (:address, :device_id, :session, :secret, :session_expire_ms, :last_seen_ms)
+          ON DUPLICATE KEY UPDATE session = VALUES(session), secret = VALUES(secret),
+          session_expire_ms = VALUES(session_expire_ms), last_seen_ms = VALUES(last_seen_ms)"
+            .with(params).run(&mut conn).await.map_err(|_| NavajoError::new(DB_ERROR)).map(|_| ())
+    }
+
+    pub async fn touch(&self, address: &str, device_id: &str, last_seen_ms: u64) -> NavajoResult<()> {
+        let mut conn = self.get_conn().await.ok_or_else(|| NavajoError::new(DB_ERROR))?;
+        "UPDATE device SET last_seen_ms = :last_seen_ms WHERE address = :address AND device_id = :device_id"
+            .with(params! { address, device_id, last_seen_ms }).run(&mut conn)
+            .await.map_err(|_| NavajoError::new(DB_ERROR)).map(|_| ())
+    }
+
+    /// Clears the session, unless it was replaced in the meantime.
+    pub async fn revoke_session(&self, address: &str, device_id: &str, session: &str) -> NavajoResult<()> {
+        let mut conn = self.get_conn().await.ok_or_else(|| NavajoError::new(DB_ERROR))?;
+        r"UPDATE device SET session = '', secret = '', session_expire_ms = 0
+          WHERE address = :address AND device_id = :device_id AND session = :session"
+            .with(params! { address, device_id, session }).run(&mut conn)
+            .await.map_err(|_| NavajoError::new(DB_ERROR)).map(|_| ())
+    }
+
+    pub async fn remove(&self, address: &str, device_id: &str) -> NavajoResult<()> {
+        let mut conn = self.get_conn().await.ok_or_else(|| NavajoError::new(DB_ERROR))?;
+        "DELETE FROM device WHERE address = :address AND device_id = :device_id"
+            .with(params! { address, device_id }).run(&mut conn)
+            .await.map_err(|_| NavajoError::new(DB_ERROR)).map(|_| ())
+    }
+
+    async fn get_conn(&self) -> Option<Conn> {
+        self.pool.get_conn().await.ok()
+    }
+}
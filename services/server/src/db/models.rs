@@ -1,5 +1,6 @@
 use mysql_async::prelude::FromRow;
 use mysql_async::{from_row, FromRowError, Row};
+use common::beans::SignedIdentity;
 
 #[derive(Debug, Default, PartialEq, Eq, Clone)]
 pub struct User {
@@ -8,6 +9,8 @@ pub struct User {
     pub device_id: String,
     pub session: String,
     pub secret: String,
+    pub public_key: String,
+    pub session_expire_ms: u64,
 }
 
 impl FromRow for User {
@@ -17,7 +20,10 @@ impl FromRow for User {
             address: row.get(1).unwrap(),
             device_id: row.get(2).unwrap(),
             session: row.get(3).unwrap(),
-            secret: row.get(4).unwrap()
+            secret: row.get(4).unwrap(),
+            public_key: row.get(5).unwrap(),
+            // Rows from before the column existed count as expired
+            session_expire_ms: row.get(6).unwrap_or(0),
         }
     }
 
@@ -25,4 +31,107 @@ impl FromRow for User {
         let user = from_row(row);
         Ok(user)
     }
-}
\ No newline at end of file
+}
+
+/// One device of an account, with its own session.
+#[derive(Debug, Default, PartialEq, Eq, Clone)]
+pub struct Device {
+    pub id: i32,
+    pub address: String,
+    pub device_id: String,
+    // Empty once revoked
+    pub session: String,
+    pub secret: String,
+    pub session_expire_ms: u64,
+    pub last_seen_ms: u64,
+}
+
+impl Device {
+    pub fn session_expired(&self, now_ms: u128) -> bool {
+        (self.session_expire_ms as u128) <= now_ms
+    }
+
+    pub fn session_revoked(&self) -> bool {
+        self.session.is_empty()
+    }
+}
+
+impl FromRow for Device {
+    fn from_row(row: Row) -> Self where Self: Sized {
+        Self {
+            id: row.get(0).unwrap(),
+            address: row.get(1).unwrap(),
+            device_id: row.get(2).unwrap(),
+            session: row.get(3).unwrap(),
+            secret: row.get(4).unwrap(),
+            session_expire_ms: row.get(5).unwrap(),
+            last_seen_ms: row.get(6).unwrap(),
+        }
+    }
+
+    fn from_row_opt(row: Row) -> Result<Self, FromRowError> where Self: Sized {
+        let device = from_row(row);
+        Ok(device)
+    }
+}
+
+#[derive(Debug, Default, PartialEq, Eq, Clone)]
+pub struct IdentityKey {
+    pub id: i32,
+    pub address: String,
+    pub public_key: String,
+    pub identity_key: String,
+    pub identity_key_sign: String,
+    pub signed_prekey_id: u32,
+    pub signed_prekey: String,
+    pub signed_prekey_sign: String,
+}
+
+impl FromRow for IdentityKey {
+    fn from_row(row: Row) -> Self where Self: Sized {
+        Self {
+            id: row.get(0).unwrap(),
+            address: row.get(1).unwrap(),
+            public_key: row.get(2).unwrap(),
+            identity_key: row.get(3).unwrap(),
+            identity_key_sign: row.get(4).unwrap(),
+            signed_prekey_id: row.get(5).unwrap(),
+            signed_prekey: row.get(6).unwrap(),
+            signed_prekey_sign: row.get(7).unwrap(),
+        }
+    }
+
+    fn from_row_opt(row: Row) -> Result<Self, FromRowError> where Self: Sized {
+        let identity_key = from_row(row);
+        Ok(identity_key)
+    }
+}
+
+impl From<&IdentityKey> for SignedIdentity {
+    fn from(value: &IdentityKey) -> Self {
+        SignedIdentity {
+            address: value.address.to_string(),
+            public_key: value.public_key.to_string(),
+            identity_key: value.identity_key.to_string(),
+            identity_key_sign: value.identity_key_sign.to_string(),
+            signed_prekey_id: value.signed_prekey_id,
+            signed_prekey: value.signed_prekey.to_string(),
+            signed_prekey_sign: value.signed_prekey_sign.to_string(),
+        }
+    }
+}
+
+impl From<&SignedIdentity> for IdentityKey {
+    fn from(value: &SignedIdentity) -> Self {
+        IdentityKey {
+            id: 0,
+            address: value.address.to_string(),
+            public_key: value.public_key.to_string(),
+            identity_key: value.identity_key.to_string(),
+            identity_key_sign: value.identity_key_sign.to_string(),
+            signed_prekey_id: value.signed_prekey_id,
+            signed_prekey: value.signed_prekey.to_string(),
+            signed_prekey_sign: value.signed_prekey_sign.to_string(),
+        }
+    }
+}
@@ -0,0 +1,256 @@
+use std::sync::Arc;
+use async_trait::async_trait;
+use deadpool_postgres::{Client, Config, Pool, Runtime};
+use deadpool_postgres::tokio_postgres::{Error, NoTls, Row};
+use common::beans::OneTimePrekey;
+use common::errors::{DB_ERROR, NavajoError, NavajoResult};
+use crate::db::models::{Device, IdentityKey, User};
+use crate::store::{PrekeyStore, UserStore};
+
+/// Applied in order, the `schema_migrations` table records the ones a database has.
+const MIGRATIONS: &[&str] = &[
+    include_str!("../../migrations/postgres/01_initial_data.sql"),
+    include_str!("../../migrations/postgres/02_device_keys.sql"),
+];
+
+// Keeps servers starting at the same time from migrating together
+const MIGRATION_LOCK_ID: i64 = 0x6e61_7661_6a6f;
+
+const USER_COLUMNS: &str = "id, address, device_id, session, secret, public_key, session_expire_ms";
+const DEVICE_COLUMNS: &str = "id, address, device_id, session, secret, session_expire_ms, last_seen_ms";
+const IDENTITY_KEY_COLUMNS: &str =
+    "id, address, public_key, identity_key, identity_key_sign, signed_prekey_id, signed_prekey, signed_prekey_sign";
+
+/// Users, devices and prekeys in PostgreSQL. Unsigned values are stored as BIGINT.
+pub struct PostgresStore {
+    pool: Pool,
+}
+
+impl PostgresStore {
+    /// Connects to `url`, a `postgres://` URL, and brings its schema up to date.
+    pub async fn connect(url: &str) -> NavajoResult<Arc<Self>> {
+        let config = Config {
+            url: Some(url.to_string()),
+            ..Default::default()
+        };
+        let pool = config.create_pool(Some(Runtime::Tokio1), NoTls).map_err(|_| NavajoError::new(DB_ERROR))?;
+        let store = Self { pool };
+        store.migrate().await?;
+        Ok(Arc::new(store))
+    }
+
+    async fn get_client(&self) -> NavajoResult<Client> {
+        self.pool.get().await.map_err(|_| NavajoError::new(DB_ERROR))
+    }
+
+    async fn migrate(&self) -> NavajoResult<()> {
+        let mut client = self.get_client().await?;
+        let tx = client.transaction().await.map_err(db_error)?;
+        tx.batch_execute(&format!("SELECT pg_advisory_xact_lock({})", MIGRATION_LOCK_ID)).await.map_err(db_error)?;
+        tx.batch_execute("CREATE TABLE IF NOT EXISTS schema_migrations (version INTEGER PRIMARY KEY)")
+            .await.map_err(db_error)?;
+        let version: i32 = tx.query_one("SELECT COALESCE(MAX(version), 0) FROM schema_migrations", &[])
+            .await.map_err(db_error)?.get(0);
+        for (index, migration) in MIGRATIONS.iter().enumerate().skip(version as usize) {
+            let version = index as i32 + 1;
+            tx.batch_execute(migration).await.map_err(db_error)?;
+            tx.execute("INSERT INTO schema_migrations(version) VALUES ($1)", &[&version]).await.map_err(db_error)?;
+            println!("Applied PostgreSQL migration {}", version);
+        }
+        tx.commit().await.map_err(db_error)
+    }
+
+    async fn find_device_where(&self, column: &str, value: &str) -> NavajoResult<Option<Device>> {
+        let client = self.get_client().await?;
+        let sql = format!("SELECT {} FROM device WHERE {} = $1", DEVICE_COLUMNS, column);
+        let row = client.query_opt(&sql, &[&value]).await.map_err(db_error)?;
+        Ok(row.as_ref().map(device_from_row))
+    }
+}
+
+fn db_error(_: Error) -> NavajoError {
+    NavajoError::new(DB_ERROR)
+}
+
+fn user_from_row(row: &Row) -> User {
+    User {
+        id: row.get(0),
+        address: row.get(1),
+        device_id: row.get(2),
+        session: row.get(3),
+        secret: row.get(4),
+        public_key: row.get(5),
+        session_expire_ms: row.get::<_, i64>(6) as u64,
+    }
+}
+
+fn device_from_row(row: &Row) -> Device {
+    Device {
+        id: row.get(0),
+        address: row.get(1),
+        device_id: row.get(2),
+        session: row.get(3),
+        secret: row.get(4),
+        session_expire_ms: row.get::<_, i64>(5) as u64,
+        last_seen_ms: row.get::<_, i64>(6) as u64,
+    }
+}
+
+fn identity_key_from_row(row: &Row) -> IdentityKey {
+    IdentityKey {
+        id: row.get(0),
+        address: row.get(1),
+        public_key: row.get(2),
+        identity_key: row.get(3),
+        identity_key_sign: row.get(4),
+        signed_prekey_id: row.get::<_, i64>(5) as u32,
+        signed_prekey: row.get(6),
+        signed_prekey_sign: row.get(7),
+    }
+}
+
+#[async_trait]
+impl UserStore for PostgresStore {
+    async fn find_user(&self, address: &str) -> NavajoResult<Option<User>> {
+        let client = self.get_client().await?;
+        let sql = format!(r#"SELECT {} FROM "user" WHERE address = $1"#, USER_COLUMNS);
+        let row = client.query_opt(&sql, &[&address]).await.map_err(db_error)?;
+        Ok(row.as_ref().map(user_from_row))
+    }
+
+    async fn save_user(&self, user: &User) -> NavajoResult<()> {
+        let client = self.get_client().await?;
+        client.execute(
+            r#"INSERT INTO "user"(address, device_id, session, secret, public_key, session_expire_ms) VALUES ($1, $2, $3, $4, $5, $6)
+               ON CONFLICT (address) DO UPDATE SET device_id = EXCLUDED.device_id, session = EXCLUDED.session,
+               secret = EXCLUDED.secret, public_key = EXCLUDED.public_key, session_expire_ms = EXCLUDED.session_expire_ms"#,
+            &[&user.address, &user.device_id, &user.session, &user.secret, &user.public_key, &(user.session_expire_ms as i64)],
+        ).await.map_err(db_error).map(|_| ())
+    }
+
+    async fn find_devices(&self, address: &str) -> NavajoResult<Vec<Device>> {
+        let client = self.get_client().await?;
+        let sql = format!("SELECT {} FROM device WHERE address = $1", DEVICE_COLUMNS);
+        let rows = client.query(&sql, &[&address]).await.map_err(db_error)?;
+        Ok(rows.iter().map(device_from_row).collect())
+    }
+
+    async fn find_device(&self, address: &str, device_id: &str) -> NavajoResult<Option<Device>> {
+        let client = self.get_client().await?;
+        let sql = format!("SELECT {} FROM device WHERE address = $1 AND device_id = $2", DEVICE_COLUMNS);
+        let row = client.query_opt(&sql, &[&address, &device_id]).await.map_err(db_error)?;
+        Ok(row.as_ref().map(device_from_row))
+    }
+
+    async fn find_device_by_session(&self, session: &str) -> NavajoResult<Option<Device>> {
+        self.find_device_where("session", session).await
+    }
+
+    async fn save_device(&self, device: &Device) -> NavajoResult<()> {
+        let client = self.get_client().await?;
+        client.execute(
+            r"INSERT INTO device(address, device_id, session, secret, session_expire_ms, last_seen_ms) VALUES ($1, $2, $3, $4, $5, $6)
+              ON CONFLICT (address, device_id) DO UPDATE SET session = EXCLUDED.session, secret = EXCLUDED.secret,
+              session_expire_ms = EXCLUDED.session_expire_ms, last_seen_ms = EXCLUDED.last_seen_ms",
+            &[
+                &device.address,
+                &device.device_id,
+                &device.session,
+                &device.secret,
+                &(device.session_expire_ms as i64),
+                &(device.last_seen_ms as i64),
+            ],
+        ).await.map_err(db_error).map(|_| ())
+    }
+
+    async fn touch_device(&self, address: &str, device_id: &str, last_seen_ms: u64) -> NavajoResult<()> {
+        let client = self.get_client().await?;
+        client.execute(
+            "UPDATE device SET last_seen_ms = $1 WHERE address = $2 AND device_id = $3",
+            &[&(last_seen_ms as i64), &address, &device_id],
+        ).await.map_err(db_error).map(|_| ())
+    }
+
+    async fn revoke_device_session(&self, address: &str, device_id: &str, session: &str) -> NavajoResult<()> {
+        let client = self.get_client().await?;
+        client.execute(
+            "UPDATE device SET session = '', secret = '', session_expire_ms = 0 WHERE address = $1 AND device_id = $2 AND session = $3",
+            &[&address, &device_id, &session],
+        ).await.map_err(db_error).map(|_| ())
+    }
+
+    async fn remove_device(&self, address: &str, device_id: &str) -> NavajoResult<()> {
+        let client = self.get_client().await?;
+        client.execute("DELETE FROM device WHERE address = $1 AND device_id = $2", &[&address, &device_id])
+            .await.map_err(db_error).map(|_| ())
+    }
+}
+
+#[async_trait]
+impl PrekeyStore for PostgresStore {
+    async fn find_identity_key(&self, address: &str) -> Option<IdentityKey> {
+        let client = self.get_client().await.ok()?;
+        let sql = format!("SELECT {} FROM identity_key WHERE address = $1", IDENTITY_KEY_COLUMNS);
+        let row = client.query_opt(&sql, &[&address]).await.ok()??;
+        Some(identity_key_from_row(&row))
+    }
+
+    async fn save_identity_key(&self, key: &IdentityKey) -> NavajoResult<()> {
+        let client = self.get_client().await?;
+        client.execute(
+            r"INSERT INTO identity_key(address, public_key, identity_key, identity_key_sign, signed_prekey_id, signed_prekey, signed_prekey_sign)
+              VALUES ($1, $2, $3, $4, $5, $6, $7)
+              ON CONFLICT (address) DO UPDATE SET public_key = EXCLUDED.public_key, identity_key = EXCLUDED.identity_key,
+              identity_key_sign = EXCLUDED.identity_key_sign, signed_prekey_id = EXCLUDED.signed_prekey_id,
+              signed_prekey = EXCLUDED.signed_prekey, signed_prekey_sign = EXCLUDED.signed_prekey_sign",
+            &[
+                &key.address,
+                &key.public_key,
+                &key.identity_key,
+                &key.identity_key_sign,
+                &(key.signed_prekey_id as i64),
+                &key.signed_prekey,
+                &key.signed_prekey_sign,
+            ],
+        ).await.map_err(db_error).map(|_| ())
+    }
+
+    async fn remove_one_time_prekeys(&self, address: &str) -> NavajoResult<()> {
+        let client = self.get_client().await?;
+        client.execute("DELETE FROM one_time_prekey WHERE address = $1", &[&address])
+            .await.map_err(db_error).map(|_| ())
+    }
+
+    async fn add_one_time_prekeys(&self, address: &str, prekeys: &[OneTimePrekey]) -> NavajoResult<()> {
+        let mut client = self.get_client().await?;
+        let tx = client.transaction().await.map_err(db_error)?;
+        let statement = tx.prepare(
+            "INSERT INTO one_time_prekey(address, key_id, prekey) VALUES ($1, $2, $3) ON CONFLICT (address, key_id) DO NOTHING"
+        ).await.map_err(db_error)?;
+        for prekey in prekeys {
+            tx.execute(&statement, &[&address, &(prekey.key_id as i64), &prekey.prekey]).await.map_err(db_error)?;
+        }
+        tx.commit().await.map_err(db_error)
+    }
+
+    async fn count_one_time_prekeys(&self, address: &str) -> NavajoResult<u32> {
+        let client = self.get_client().await?;
+        let row = client.query_one("SELECT COUNT(*) FROM one_time_prekey WHERE address = $1", &[&address])
+            .await.map_err(db_error)?;
+        Ok(row.get::<_, i64>(0) as u32)
+    }
+
+    async fn take_one_time_prekey(&self, address: &str) -> NavajoResult<Option<OneTimePrekey>> {
+        let client = self.get_client().await?;
+        let row = client.query_opt(
+            r"DELETE FROM one_time_prekey WHERE id =
+              (SELECT id FROM one_time_prekey WHERE address = $1 ORDER BY id LIMIT 1 FOR UPDATE SKIP LOCKED)
+              RETURNING key_id, prekey",
+            &[&address],
+        ).await.map_err(db_error)?;
+        Ok(row.map(|row| OneTimePrekey {
+            key_id: row.get::<_, i64>(0) as u32,
+            prekey: row.get(1),
+        }))
+    }
+}
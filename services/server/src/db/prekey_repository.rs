@@ -0,0 +1,99 @@
+use std::sync::Arc;
+use async_trait::async_trait;
+use mysql_async::{Conn, params, Pool, TxOpts};
+use mysql_async::prelude::{Query, Queryable, WithParams};
+use common::beans::OneTimePrekey;
+use common::errors::{DB_ERROR, NavajoError, NavajoResult};
+use crate::db::models::IdentityKey;
+use crate::store::PrekeyStore;
+
+pub struct PrekeyRepository {
+    pool: Arc<Pool>,
+}
+
+impl PrekeyRepository {
+    pub fn new(pool: Arc<Pool>) -> Arc<Self> {
+        Arc::new(Self { pool })
+    }
+
+    async fn get_conn(&self) -> Option<Conn> {
+        self.pool.get_conn().await.ok()
+    }
+}
+
+#[async_trait]
+impl PrekeyStore for PrekeyRepository {
+    async fn find_identity_key(&self, address: &str) -> Option<IdentityKey> {
+        let mut conn = self.get_conn().await?;
+        let keys: Vec<IdentityKey> = "SELECT * FROM identity_key WHERE address = :address"
+            .with(params! { address }).fetch(&mut conn)
+            .await.ok()?;
+        keys.into_iter().next()
+    }
+
+    async fn save_identity_key(&self, key: &IdentityKey) -> NavajoResult<()> {
+        let mut conn = self.get_conn().await.ok_or_else(|| NavajoError::new(DB_ERROR))?;
+        let params = params! {
+            "address" => &key.address,
+            "public_key" => &key.public_key,
+            "identity_key" => &key.identity_key,
+            "identity_key_sign" => &key.identity_key_sign,
+            "signed_prekey_id" => key.signed_prekey_id,
+            "signed_prekey" => &key.signed_prekey,
+            "signed_prekey_sign" => &key.signed_prekey_sign,
+        };
+        r"INSERT INTO identity_key(address, public_key, identity_key, identity_key_sign, signed_prekey_id, signed_prekey, signed_prekey_sign)
+          VALUES (:address, :public_key, :identity_key, :identity_key_sign, :signed_prekey_id, :signed_prekey, :signed_prekey_sign)
+          ON DUPLICATE KEY UPDATE public_key = VALUES(public_key), identity_key = VALUES(identity_key),
+          identity_key_sign = VALUES(identity_key_sign), signed_prekey_id = VALUES(signed_prekey_id),
+          signed_prekey = VALUES(signed_prekey), signed_prekey_sign = VALUES(signed_prekey_sign)"
+            .with(params).run(&mut conn).await.map_err(|_| NavajoError::new(DB_ERROR)).map(|_| ())
+    }
+
+    async fn remove_one_time_prekeys(&self, address: &str) -> NavajoResult<()> {
+        let mut conn = self.get_conn().await.ok_or_else(|| NavajoError::new(DB_ERROR))?;
+        "DELETE FROM one_time_prekey WHERE address = :address"
+            .with(params! { address }).run(&mut conn)
+            .await.map_err(|_| NavajoError::new(DB_ERROR)).map(|_| ())
+    }
+
+    async fn add_one_time_prekeys(&self, address: &str, prekeys: &[OneTimePrekey]) -> NavajoResult<()> {
+        let mut conn = self.get_conn().await.ok_or_else(|| NavajoError::new(DB_ERROR))?;
+        conn.exec_batch(
+            r"INSERT IGNORE INTO one_time_prekey(address, key_id, prekey) VALUES (:address, :key_id, :prekey)",
+            prekeys.iter().map(|prekey| params! {
+                "address" => address,
+                "key_id" => prekey.key_id,
+                "prekey" => &prekey.prekey,
+            }),
+        ).await.map_err(|_| NavajoError::new(DB_ERROR))
+    }
+
+    async fn count_one_time_prekeys(&self, address: &str) -> NavajoResult<u32> {
+        let mut conn = self.get_conn().await.ok_or_else(|| NavajoError::new(DB_ERROR))?;
+        let count: Option<u32> = "SELECT COUNT(*) FROM one_time_prekey WHERE address = :address"
+            .with(params! { address }).first(&mut conn)
+            .await.map_err(|_| NavajoError::new(DB_ERROR))?;
+        Ok(count.unwrap_or_default())
+    }
+
+    async fn take_one_time_prekey(&self, address: &str) -> NavajoResult<Option<OneTimePrekey>> {
+        let mut conn = self.get_conn().await.ok_or_else(|| NavajoError::new(DB_ERROR))?;
+        let mut tx = conn.start_transaction(TxOpts::default())
+            .await.map_err(|_| NavajoError::new(DB_ERROR))?;
+        let row: Option<(i32, u32, String)> = tx.exec_first(
+            r"SELECT id, key_id, prekey FROM one_time_prekey WHERE address = :address ORDER BY id LIMIT 1 FOR UPDATE",
+            params! { address },
+        ).await.map_err(|_| NavajoError::new(DB_ERROR))?;
+        let prekey = match row {
+            Some((id, key_id, prekey)) => {
+                tx.exec_drop("DELETE FROM one_time_prekey WHERE id = :id", params! { id })
+                    .await.map_err(|_| NavajoError::new(DB_ERROR))?;
+                Some(OneTimePrekey { key_id, prekey })
+            }
+            None => None,
+        };
+        tx.commit().await.map_err(|_| NavajoError::new(DB_ERROR))?;
+        Ok(prekey)
+    }
+}
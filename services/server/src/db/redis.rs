@@ -1,49 +1,325 @@
+use std::collections::HashMap;
+use std::future::Future;
 use std::sync::Arc;
-use redis::{AsyncCommands, Client, RedisResult};
-use redis::aio::Connection;
+use std::time::Duration;
+use async_trait::async_trait;
+use redis::{Client, Cmd, from_redis_value, FromRedisValue, Pipeline, RedisResult, Script, Value};
+use redis::aio::{ConnectionManager, PubSub};
+use tokio::sync::OnceCell;
+use tokio::time::timeout;
+use common::errors::{DB_ERROR, NavajoError, NavajoResult};
 use crate::db::RedisConfig;
+use crate::store::NonceCache;
 
+/// What a stream keeps: at most `max_len` entries, none older than `min_id`, and nothing
+/// once `expire_secs` passed since its last entry was added.
+pub struct StreamBounds {
+    pub max_len: usize,
+    pub min_id: String,
+    pub expire_secs: usize,
+}
+
+/// Commands share one multiplexed connection, reconnected after it breaks. Failures and
+/// timeouts come back as `DB_ERROR`.
 pub struct RedisClient {
     rc: Client,
+    // Connected by the first command, so that the server starts while Redis is down
+    con: OnceCell<ConnectionManager>,
+    connect_timeout: Duration,
+    response_timeout: Duration,
 }
 
 impl RedisClient {
-    pub fn new(redis_config: RedisConfig) -> Arc<Self> {
-        let host = redis_config.host;
-        let rc = Client::open(host).expect("failed to connect redis");
-        Arc::new(Self { rc })
+    /// Fails if the host is not a Redis URL, Redis itself is only reached by the first command.
+    pub fn new(redis_config: RedisConfig) -> NavajoResult<Arc<Self>> {
+        let rc = Client::open(redis_config.host).map_err(redis_error)?;
+        Ok(Arc::new(Self {
+            rc,
+            con: OnceCell::new(),
+            connect_timeout: Duration::from_millis(redis_config.connect_timeout_ms),
+            response_timeout: Duration::from_millis(redis_config.response_timeout_ms),
+        }))
+    }
+
+    pub async fn get(&self, key: &str) -> NavajoResult<Option<String>> {
+        self.query(redis::cmd("GET").arg(key)).await
+    }
+
+    pub async fn set_ex(&self, key: &str, value: &str, secs: usize) -> NavajoResult<()> {
+        self.query(redis::cmd("SETEX").arg(key).arg(secs).arg(value)).await
+    }
+
+    /// Sets `key` only if it does not exist yet, returns whether it was set.
+    pub async fn set_nx_ex(&self, key: &str, value: &str, secs: usize) -> NavajoResult<bool> {
+        let res: Option<String> = self.query(redis::cmd("SET").arg(key).arg(value).arg("NX").arg("EX").arg(secs)).await?;
+        Ok(res.is_some())
+    }
+
+    pub async fn remove(&self, key: &str) -> NavajoResult<()> {
+        self.query(redis::cmd("DEL").arg(key)).await
+    }
+
+    /// Appends one entry to the stream at `key` in a single transaction, the oldest ones
+    /// past `bounds` are dropped. Returns the new entry id and the stream length.
+    pub async fn stream_add(&self, key: &str, field: &str, value: &str, bounds: &StreamBounds) -> NavajoResult<(String, usize)> {
+        let (id, _, len): (String, usize, usize) = self.query_pipe(redis::pipe().atomic()
+            .cmd("XADD").arg(key).arg("MAXLEN").arg(bounds.max_len).arg("*").arg(field).arg(value)
+            .cmd("XTRIM").arg(key).arg("MINID").arg(&bounds.min_id)
+            .cmd("XLEN").arg(key)
+            .cmd("EXPIRE").arg(key).arg(bounds.expire_secs).ignore()
+        ).await?;
+        Ok((id, len))
+    }
+
+    /// Splits the string at `from` at each `separator` and appends the parts in order to the
+    /// stream at `to`, like `stream_add`, then deletes `from`. All at once, the parts can't
+    /// be appended twice. Returns how many were.
+    pub async fn stream_add_split(&self, from: &str, separator: &str, to: &str, field: &str, bounds: &StreamBounds) -> NavajoResult<usize> {
+        let mut con = self.con().await?;
+        let script = Script::new(r"
+            local value = redis.call('GET', KEYS[1])
+            if not value then
+                return 0
+            end
+            local count, start = 0, 1
+            repeat
+                local stop = string.find(value, ARGV[1], start, true)
+                local part = string.sub(value, start, stop and stop - 1 or -1)
+                redis.call('XADD', KEYS[2], 'MAXLEN', ARGV[3], '*', ARGV[2], part)
+                count = count + 1
+                if stop then
+                    start = stop + #ARGV[1]
+                end
+            until not stop
+            redis.call('XTRIM', KEYS[2], 'MINID', ARGV[4])
+            redis.call('EXPIRE', KEYS[2], ARGV[5])
+            redis.call('DEL', KEYS[1])
+            return count
+        ");
+        self.timed(script.key(from).key(to)
+            .arg(separator).arg(field).arg(bounds.max_len).arg(&bounds.min_id).arg(bounds.expire_secs)
+            .invoke_async(&mut con)).await
+    }
+
+    /// All entries of the stream at each key from `min_id` on, as `(id, value of field)`,
+    /// in one round trip.
+    pub async fn stream_ranges(&self, keys: &[String], field: &str, min_id: &str) -> NavajoResult<Vec<Vec<(String, String)>>> {
+        let mut pipe = redis::pipe();
+        for key in keys {
+            pipe.cmd("XRANGE").arg(key).arg(min_id).arg("+");
+        }
+        // Each entry on its own, a list of pairs parses as a flat one
+        let streams: Vec<Vec<Value>> = self.query_pipe(&pipe).await?;
+        Ok(streams.iter().map(|entries| entries.iter().filter_map(|entry| {
+            let (id, fields): (String, Vec<String>) = from_redis_value(entry).ok()?;
+            let value = fields.chunks(2).find(|pair| pair[0] == field).and_then(|pair| pair.get(1))?.clone();
+            Some((id, value))
+        }).collect()).collect())
+    }
+
+    /// Deletes entries of several streams, by key, in one round trip.
+    pub async fn stream_dels(&self, ids_by_key: &HashMap<String, Vec<String>>) -> NavajoResult<()> {
+        let mut pipe = redis::pipe();
+        let mut empty = true;
+        for (key, ids) in ids_by_key.iter().filter(|(_, ids)| !ids.is_empty()) {
+            pipe.cmd("XDEL").arg(key).arg(ids).ignore();
+            empty = false;
+        }
+        if empty {
+            return Ok(());
+        }
+        self.query_pipe(&pipe).await
+    }
+
+    pub async fn exists(&self, key: &str) -> NavajoResult<bool> {
+        self.query(redis::cmd("EXISTS").arg(key)).await
+    }
+
+    pub async fn hash_set(&self, key: &str, field: &str, value: &str) -> NavajoResult<()> {
+        self.query(redis::cmd("HSET").arg(key).arg(field).arg(value)).await
+    }
+
+    pub async fn hash_get_all(&self, key: &str) -> NavajoResult<HashMap<String, String>> {
+        self.query(redis::cmd("HGETALL").arg(key)).await
+    }
+
+    /// Removes `field` of the hash at `key` only while it is still set to `value`.
+    pub async fn hash_del_if(&self, key: &str, field: &str, value: &str) -> NavajoResult<()> {
+        let mut con = self.con().await?;
+        let script = Script::new(r"
+            if redis.call('HGET', KEYS[1], ARGV[1]) == ARGV[2] then
+                return redis.call('HDEL', KEYS[1], ARGV[1])
+            end
+            return 0
+        ");
+        self.timed(script.key(key).arg(field).arg(value).invoke_async(&mut con)).await
+    }
+
+    pub async fn set_add(&self, key: &str, member: &str) -> NavajoResult<()> {
+        self.query(redis::cmd("SADD").arg(key).arg(member)).await
     }
 
-    pub async fn get(&self, key: &str) -> Option<String> {
-        let mut con = self.con().await;
-        let res: RedisResult<String> = con.get(key).await;
-        if res.is_err() {
-            return None;
+    pub async fn set_remove(&self, key: &str, member: &str) -> NavajoResult<()> {
+        self.query(redis::cmd("SREM").arg(key).arg(member)).await
+    }
+
+    pub async fn set_members(&self, key: &str) -> NavajoResult<Vec<String>> {
+        self.query(redis::cmd("SMEMBERS").arg(key)).await
+    }
+
+    /// Returns how many subscribers got the message.
+    pub async fn publish(&self, channel: &str, payload: &str) -> NavajoResult<usize> {
+        self.query(redis::cmd("PUBLISH").arg(channel).arg(payload)).await
+    }
+
+    /// A dedicated connection subscribed to `channels`.
+    pub async fn subscribe(&self, channels: &[String]) -> NavajoResult<PubSub> {
+        let con = match timeout(self.connect_timeout, self.rc.get_async_connection()).await {
+            Ok(con) => con.map_err(redis_error)?,
+            Err(_) => return Err(timed_out()),
+        };
+        let mut pubsub = con.into_pubsub();
+        for channel in channels {
+            self.timed(pubsub.subscribe(channel)).await?;
         }
-        Some(res.unwrap())
+        Ok(pubsub)
+    }
+
+    async fn query<T: FromRedisValue>(&self, cmd: &Cmd) -> NavajoResult<T> {
+        let mut con = self.con().await?;
+        self.timed(cmd.query_async(&mut con)).await
     }
 
-    pub async fn set(&self, key: &str, value: &str) {
-        let mut con = self.con().await;
-        con.set(key, value).await.expect("redis error")
+    async fn query_pipe<T: FromRedisValue>(&self, pipe: &Pipeline) -> NavajoResult<T> {
+        let mut con = self.con().await?;
+        self.timed(pipe.query_async(&mut con)).await
     }
 
-    pub async fn set_ex(&self, key: &str, value: &str, secs: usize) {
-        let mut con = self.con().await;
-        con.set_ex(key, value, secs).await.expect("redis error")
+    async fn timed<T>(&self, future: impl Future<Output = RedisResult<T>>) -> NavajoResult<T> {
+        match timeout(self.response_timeout, future).await {
+            Ok(res) => res.map_err(redis_error),
+            Err(_) => Err(timed_out()),
+        }
     }
 
-    pub async fn set_nx(&self, key: &str, value: &str) {
-        let mut con = self.con().await;
-        con.set_nx(key, value).await.expect("redis error")
+    /// A handle on the shared connection, connecting it the first time.
+    async fn con(&self) -> NavajoResult<ConnectionManager> {
+        let con = self.con.get_or_try_init(|| async {
+            match timeout(self.connect_timeout, ConnectionManager::new(self.rc.clone())).await {
+                Ok(con) => con.map_err(redis_error),
+                Err(_) => Err(timed_out()),
+            }
+        }).await?;
+        Ok(con.clone())
     }
+}
+
+fn redis_error(err: redis::RedisError) -> NavajoError {
+    println!("Redis failed, {}", err);
+    NavajoError::new(DB_ERROR)
+}
 
-    pub async fn remove(&self, key: &str) {
-        let mut con = self.con().await;
-        con.del(key).await.expect("redis error")
+fn timed_out() -> NavajoError {
+    println!("Redis timed out");
+    NavajoError::new(DB_ERROR)
+}
+
+#[async_trait]
+impl NonceCache for RedisClient {
+    async fn insert(&self, key: &str, secs: u64) -> NavajoResult<bool> {
+        self.set_nx_ex(key, "1", secs as usize).await
     }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::env;
+    use std::time::{Duration, Instant};
+    use tokio::net::TcpListener;
+    use tokio::spawn;
+    use tokio::time::sleep;
+    use uuid::Uuid;
+    use crate::db::RedisConfig;
+    use crate::db::redis::{RedisClient, StreamBounds};
+    use crate::store::tests::check_nonce_cache;
 
-    async fn con(&self) -> Connection {
-        self.rc.get_async_connection().await.unwrap()
+    fn client(host: String) -> std::sync::Arc<RedisClient> {
+        RedisClient::new(RedisConfig { host, connect_timeout_ms: 200, response_timeout_ms: 200 }).unwrap()
     }
-}
\ No newline at end of file
+
+    #[test]
+    fn test_invalid_host() {
+        assert!(RedisClient::new(RedisConfig { host: String::from("127.0.0.1:6379"), connect_timeout_ms: 200, response_timeout_ms: 200 }).is_err());
+    }
+
+    #[actix_rt::test]
+    async fn test_redis_unavailable() {
+        // Accepts connections but never answers
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let port = listener.local_addr().unwrap().port();
+        spawn(async move {
+            let mut sockets = vec![];
+            while let Ok((socket, _)) = listener.accept().await {
+                sockets.push(socket);
+            }
+        });
+        let silent = client(format!("redis://127.0.0.1:{}/", port));
+        let started = Instant::now();
+        assert!(silent.get("key").await.is_err());
+        assert!(silent.set_nx_ex("key", "1", 10).await.is_err());
+        assert!(started.elapsed() < Duration::from_secs(2));
+
+        // Nothing listening
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let port = listener.local_addr().unwrap().port();
+        drop(listener);
+        let gone = client(format!("redis://127.0.0.1:{}/", port));
+        assert!(gone.get("key").await.is_err());
+        assert!(gone.remove("key").await.is_err());
+    }
+
+    #[actix_rt::test]
+    async fn test_stream_trim() {
+        let host = env::var("NAVAJO_TEST_REDIS_URL").unwrap_or_else(|_| String::from("redis://127.0.0.1/"));
+        let redis = client(host);
+        let key = format!("test_stream:{}", Uuid::new_v4());
+        let bounds = StreamBounds { max_len: 3, min_id: String::from("0"), expire_secs: 60 };
+        let (old_id, _) = redis.stream_add(&key, "field", "old", &bounds).await.unwrap();
+        sleep(Duration::from_millis(5)).await;
+
+        // Entries below the min id are trimmed, ids start with their time in ms
+        let old_ms: u128 = old_id.split('-').next().unwrap().parse().unwrap();
+        let bounds = StreamBounds { min_id: (old_ms + 1).to_string(), ..bounds };
+        assert_eq!(redis.stream_add(&key, "field", "new", &bounds).await.unwrap().1, 1);
+
+        // And the oldest past the max length
+        for value in ["a", "b", "c"] {
+            redis.stream_add(&key, "field", value, &bounds).await.unwrap();
+        }
+        let streams = redis.stream_ranges(std::slice::from_ref(&key), "field", "0").await.unwrap();
+        let values: Vec<&str> = streams[0].iter().map(|(_, value)| value.as_str()).collect();
+        assert_eq!(values, ["a", "b", "c"]);
+
+        // Split values are appended the same way, once
+        let from = format!("test_split:{}", Uuid::new_v4());
+        redis.set_ex(&from, "d>e", 60).await.unwrap();
+        assert_eq!(redis.stream_add_split(&from, ">", &key, "field", &bounds).await.unwrap(), 2);
+        assert_eq!(redis.stream_add_split(&from, ">", &key, "field", &bounds).await.unwrap(), 0);
+        assert!(redis.get(&from).await.unwrap().is_none());
+        let streams = redis.stream_ranges(std::slice::from_ref(&key), "field", "0").await.unwrap();
+        let values: Vec<&str> = streams[0].iter().map(|(_, value)| value.as_str()).collect();
+        assert_eq!(values, ["c", "d", "e"]);
+
+        // The stream goes once no entry was added for a while
+        let bounds = StreamBounds { expire_secs: 1, ..bounds };
+        redis.stream_add(&key, "field", "f", &bounds).await.unwrap();
+        sleep(Duration::from_millis(1100)).await;
+        assert!(!redis.exists(&key).await.unwrap());
+    }
+
+    #[actix_rt::test]
+    async fn test_redis_nonce_cache() {
+        let host = env::var("NAVAJO_TEST_REDIS_URL").unwrap_or_else(|_| String::from("redis://127.0.0.1/"));
+        check_nonce_cache(client(host).as_ref()).await;
+    }
+}
@@ -20,20 +20,6 @@ impl UserRepository {
             .await.ok()
     }
 
-    pub async fn find_by_device_id(&self, device_id: &str) -> Option<Vec<User>> {
-        let mut conn = self.get_conn().await?;
-        "SELECT * FROM user WHERE device_id = :device_id"
-            .with(params! { device_id }).fetch(&mut conn)
-            .await.ok()
-    }
-
-    pub async fn find_by_session(&self, session: &str) -> Option<Vec<User>> {
-        let mut conn = self.get_conn().await?;
-        "SELECT * FROM user WHERE session = :session"
-            .with(params! { session }).fetch(&mut conn)
-            .await.ok()
-    }
-
     pub async fn insert_or_update(&self, user: &User) -> NavajoResult<()> {
         let mut conn = self.get_conn().await.ok_or_else(|| NavajoError::new(DB_ERROR))?;
         let params = params! {
@@ -41,12 +27,14 @@ impl UserRepository {
             "device_id" => &user.device_id,
             "session" => &user.session,
             "secret" => &user.secret,
+            "public_key" => &user.public_key,
+            "session_expire_ms" => user.session_expire_ms,
         };
 
-        let insert_res = r"INSERT INTO user(address, device_id, session, secret) VALUES (:address, :device_id, :session, :secret)"
+        let insert_res = r"INSERT INTO user(address, device_id, session, secret, public_key, session_expire_ms) VALUES (:address, :device_id, :session, :secret, :public_key, :session_expire_ms)"
             .with(&params).run(&mut conn).await;
         if insert_res.is_err() {
-            r"UPDATE user SET device_id = :device_id, session = :session, secret = :secret WHERE address = :address"
+            r"UPDATE user SET device_id = :device_id, session = :session, secret = :secret, public_key = :public_key, session_expire_ms = :session_expire_ms WHERE address = :address"
                 .with(&params).run(&mut conn).await.map_err(|_| NavajoError::new(DB_ERROR)).map(|_| ())
         } else {
             Ok(())
@@ -0,0 +1,64 @@
+use std::sync::Arc;
+use async_trait::async_trait;
+use mysql_async::Pool;
+use common::errors::{DB_ERROR, NavajoError, NavajoResult};
+use crate::db::device_repository::DeviceRepository;
+use crate::db::models::{Device, User};
+use crate::db::repository::UserRepository;
+use crate::store::UserStore;
+
+/// Users and devices in MySQL.
+pub struct MysqlUserStore {
+    user_repository: Arc<UserRepository>,
+    device_repository: Arc<DeviceRepository>,
+}
+
+impl MysqlUserStore {
+    pub fn new(pool: Arc<Pool>) -> Arc<Self> {
+        Arc::new(Self {
+            user_repository: UserRepository::new(pool.clone()),
+            device_repository: DeviceRepository::new(pool),
+        })
+    }
+}
+
+#[async_trait]
+impl UserStore for MysqlUserStore {
+    async fn find_user(&self, address: &str) -> NavajoResult<Option<User>> {
+        let users = self.user_repository.find_by_address(address)
+            .await.ok_or_else(|| NavajoError::new(DB_ERROR))?;
+        Ok(users.into_iter().next())
+    }
+
+    async fn save_user(&self, user: &User) -> NavajoResult<()> {
+        self.user_repository.insert_or_update(user).await
+    }
+
+    async fn find_devices(&self, address: &str) -> NavajoResult<Vec<Device>> {
+        self.device_repository.find_by_address(address).await
+    }
+
+    async fn find_device(&self, address: &str, device_id: &str) -> NavajoResult<Option<Device>> {
+        self.device_repository.find(address, device_id).await
+    }
+
+    async fn find_device_by_session(&self, session: &str) -> NavajoResult<Option<Device>> {
+        self.device_repository.find_by_session(session).await
+    }
+
+    async fn save_device(&self, device: &Device) -> NavajoResult<()> {
+        self.device_repository.insert_or_update(device).await
+    }
+
+    async fn touch_device(&self, address: &str, device_id: &str, last_seen_ms: u64) -> NavajoResult<()> {
+        self.device_repository.touch(address, device_id, last_seen_ms).await
+    }
+
+    async fn revoke_device_session(&self, address: &str, device_id: &str, session: &str) -> NavajoResult<()> {
+        self.device_repository.revoke_session(address, device_id, session).await
+    }
+
+    async fn remove_device(&self, address: &str, device_id: &str) -> NavajoResult<()> {
+        self.device_repository.remove(address, device_id).await
+    }
+}
@@ -1,39 +1,67 @@
-use crate::config::Config;
-use crate::db::connect_mysql;
+use std::sync::Arc;
+use crate::config::{Config, Storage};
 use crate::db::redis::RedisClient;
-use crate::db::repository::UserRepository;
+use crate::p2p::cluster::Cluster;
+use crate::p2p::cluster::memory::MemoryCluster;
+use crate::p2p::cluster::redis::RedisCluster;
 use crate::p2p::server::P2PServer;
-use crate::queue::QueueManager;
+use crate::rate_limit::RateLimiter;
+use crate::replay::ReplayGuard;
 use crate::server::Server;
+use crate::store::Stores;
 
 mod db;
 mod queue;
+mod rate_limit;
+mod replay;
 mod p2p;
 mod server;
 mod errors;
 pub mod route;
 mod config;
+mod store;
 
 #[actix_web::main]
 async fn main() -> std::io::Result<()> {
     let config = Config::new().unwrap();
 
-    let mysql_pool = connect_mysql(&config.mysql);
-    let user_repository = UserRepository::new(mysql_pool.clone());
-    let redis_client = RedisClient::new(config.redis);
-
-    let server = Server {
-        config: config.server,
-        user_repository: user_repository.clone(),
+    let cluster: Arc<dyn Cluster> = match &config.p2p.node_id {
+        Some(node_id) => RedisCluster::new(node_id, RedisClient::new(config.redis.clone()).expect("Invalid NAVAJO_REDIS_HOST")),
+        None => MemoryCluster::single(),
+    };
+    let stores = match config.storage {
+        Storage::Mysql(url) => Stores::mysql(&url, config.redis).expect("Invalid NAVAJO_REDIS_HOST"),
+        Storage::Postgres(url) => Stores::postgres(&url, config.redis).await.expect("Connect PostgreSQL failed"),
+        Storage::Sqlite(path) => {
+            println!("Running on SQLite storage at {:?}", path);
+            Stores::sqlite(&path).expect("Open SQLite database failed")
+        }
+        Storage::Memory => {
+            println!("Running on in-memory storage, nothing is kept after a restart");
+            Stores::memory()
+        }
     };
+    let replay_guard = ReplayGuard::new(stores.nonce_cache.clone());
+    let rate_limiter = RateLimiter::new(stores.nonce_cache.clone());
 
-    let queue_manager = QueueManager::new(redis_client.clone());
     let p2p_server = P2PServer::new(
         config.p2p,
-        user_repository,
-        queue_manager
+        stores.user_store.clone(),
+        stores.message_queue.clone(),
+        replay_guard.clone(),
+        cluster,
     );
-    p2p_server.start().await.unwrap();
+    let p2p_signal_tx = p2p_server.start().await.unwrap();
+
+    let server = Server {
+        config: config.server,
+        user_store: stores.user_store,
+        message_queue: stores.message_queue,
+        replay_guard,
+        rate_limiter,
+        prekey_store: stores.prekey_store,
+        p2p_signal_tx,
+    };
 
     server.start().await
 }
@@ -1,17 +1,29 @@
 use tokio::sync::mpsc::{channel, Receiver, Sender};
-use p2p::message::Message;
+use crate::p2p::cluster::ClusterMessage;
+use crate::p2p::connection::{ConnectionCommand, Outgoing};
 
 #[derive(Debug)]
 pub enum ChannelSignal {
-    ConnectionClose(String),
-    ConnectionError(String),
-    RemoteMessage { peer_addr: String, message: Message },
+    // The device's session was revoked or the device removed, its connections are closed
+    RevokeSession { address: String, device_id: String },
+    // The device got a new session, its connections seal with it from now on
+    SessionCreated { address: String, device_id: String },
+    // Sent by another node of the cluster
+    Cluster(ClusterMessage),
 }
 
-pub fn create_connection_channel() -> (Sender<Vec<u8>>, Receiver<Vec<u8>>) {
+pub fn create_connection_channel() -> (Sender<ConnectionCommand>, Receiver<ConnectionCommand>) {
+    channel(1024)
+}
+
+// Holds a full queue flush, see `MAX_QUEUE_SIZE`
+pub fn create_frame_channel() -> (Sender<Outgoing>, Receiver<Outgoing>) {
     channel(1024)
 }
 
 pub fn create_server_channel() -> (Sender<ChannelSignal>, Receiver<ChannelSignal>) {
     channel(1024)
-}
\ No newline at end of file
+}
+pub fn create_cluster_channel() -> (Sender<ClusterMessage>, Receiver<ClusterMessage>) {
+    channel(1024)
+}
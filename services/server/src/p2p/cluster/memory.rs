@@ -0,0 +1,164 @@
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+use async_trait::async_trait;
+use tokio::sync::mpsc::{Receiver, Sender};
+use common::errors::NavajoResult;
+use crate::p2p::channel::create_cluster_channel;
+use crate::p2p::cluster::{Cluster, ClusterMessage};
+
+/// Nodes of one process. A node is dead once it stops receiving.
+#[derive(Default)]
+pub struct ClusterHub {
+    // Address to the node of each of its devices, by device_id
+    presence: Mutex<HashMap<String, HashMap<String, String>>>,
+    nodes: Mutex<HashMap<String, Sender<ClusterMessage>>>,
+}
+
+impl ClusterHub {
+    pub fn new() -> Arc<Self> {
+        Arc::new(Self::default())
+    }
+
+    fn node(&self, node_id: &str) -> Option<Sender<ClusterMessage>> {
+        self.nodes.lock().unwrap().get(node_id).filter(|tx| !tx.is_closed()).cloned()
+    }
+
+    fn forget_node(&self, node_id: &str) {
+        let mut presence = self.presence.lock().unwrap();
+        for devices in presence.values_mut() {
+            devices.retain(|_, node| node != node_id);
+        }
+        presence.retain(|_, devices| !devices.is_empty());
+    }
+}
+
+pub struct MemoryCluster {
+    node_id: String,
+    hub: Arc<ClusterHub>,
+}
+
+impl MemoryCluster {
+    pub fn new(node_id: &str, hub: Arc<ClusterHub>) -> Arc<Self> {
+        Arc::new(Self { node_id: node_id.to_string(), hub })
+    }
+
+    /// A cluster of this node only.
+    pub fn single() -> Arc<Self> {
+        Self::new("single", ClusterHub::new())
+    }
+}
+
+#[async_trait]
+impl Cluster for MemoryCluster {
+    fn node_id(&self) -> &str {
+        &self.node_id
+    }
+
+    async fn join(&self) -> NavajoResult<Receiver<ClusterMessage>> {
+        self.hub.forget_node(&self.node_id);
+        let (tx, rx) = create_cluster_channel();
+        self.hub.nodes.lock().unwrap().insert(self.node_id.clone(), tx);
+        Ok(rx)
+    }
+
+    async fn heartbeat(&self) -> NavajoResult<()> {
+        let dead: Vec<String> = {
+            let mut nodes = self.hub.nodes.lock().unwrap();
+            let dead = nodes.iter().filter(|(_, tx)| tx.is_closed()).map(|(node_id, _)| node_id.clone()).collect();
+            nodes.retain(|_, tx| !tx.is_closed());
+            dead
+        };
+        for node_id in dead {
+            self.hub.forget_node(&node_id);
+            println!("Node {:?} is gone, forgot its devices", node_id);
+        }
+        Ok(())
+    }
+
+    async fn register(&self, address: &str, device_id: &str) -> NavajoResult<()> {
+        self.hub.presence.lock().unwrap().entry(address.to_string()).or_default()
+            .insert(device_id.to_string(), self.node_id.clone());
+        Ok(())
+    }
+
+    async fn unregister(&self, address: &str, device_id: &str) -> NavajoResult<()> {
+        let mut presence = self.hub.presence.lock().unwrap();
+        if let Some(devices) = presence.get_mut(address) {
+            if devices.get(device_id) == Some(&self.node_id) {
+                devices.remove(device_id);
+            }
+            if devices.is_empty() {
+                presence.remove(address);
+            }
+        }
+        Ok(())
+    }
+
+    async fn find_nodes(&self, address: &str) -> NavajoResult<HashMap<String, String>> {
+        let devices = self.hub.presence.lock().unwrap().get(address).cloned().unwrap_or_default();
+        Ok(devices.into_iter().filter(|(_, node_id)| self.hub.node(node_id).is_some()).collect())
+    }
+
+    async fn send(&self, node_id: &str, message: &ClusterMessage) -> NavajoResult<bool> {
+        match self.hub.node(node_id) {
+            Some(tx) => Ok(tx.send(message.clone()).await.is_ok()),
+            None => Ok(false),
+        }
+    }
+
+    async fn broadcast(&self, message: &ClusterMessage) -> NavajoResult<()> {
+        let others: Vec<Sender<ClusterMessage>> = self.hub.nodes.lock().unwrap().iter()
+            .filter(|(node_id, _)| **node_id != self.node_id)
+            .map(|(_, tx)| tx.clone())
+            .collect();
+        for tx in others {
+            let _ = tx.send(message.clone()).await;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use p2p::message::Message;
+    use crate::p2p::cluster::{Cluster, ClusterMessage};
+    use crate::p2p::cluster::memory::{ClusterHub, MemoryCluster};
+
+    #[actix_rt::test]
+    async fn test_memory_cluster() {
+        let hub = ClusterHub::new();
+        let node_a = MemoryCluster::new("a", hub.clone());
+        let node_b = MemoryCluster::new("b", hub);
+        let _rx_a = node_a.join().await.unwrap();
+        let mut rx_b = node_b.join().await.unwrap();
+
+        node_b.register("bob", "phone").await.unwrap();
+        let nodes = node_a.find_nodes("bob").await.unwrap();
+        assert_eq!(nodes.get("phone").map(String::as_str), Some("b"));
+
+        let forward = ClusterMessage::Forward {
+            address: String::from("bob"),
+            device_id: String::from("phone"),
+            message: Message::PingMessage {
+                common_info: Default::default(),
+                address: String::from("bob"),
+                device_id: String::from("phone"),
+            },
+        };
+        assert!(node_a.send("b", &forward).await.unwrap());
+        assert!(matches!(rx_b.recv().await, Some(ClusterMessage::Forward { .. })));
+        node_a.broadcast(&ClusterMessage::RevokeSession { address: String::from("bob"), device_id: String::from("phone") }).await.unwrap();
+        assert!(matches!(rx_b.recv().await, Some(ClusterMessage::RevokeSession { .. })));
+
+        // Only the node a device is connected to forgets it
+        node_a.unregister("bob", "phone").await.unwrap();
+        assert_eq!(node_a.find_nodes("bob").await.unwrap().len(), 1);
+
+        // A dead node's devices are gone
+        drop(rx_b);
+        assert!(node_a.find_nodes("bob").await.unwrap().is_empty());
+        assert!(!node_a.send("b", &forward).await.unwrap());
+        node_a.heartbeat().await.unwrap();
+        assert!(node_a.hub.presence.lock().unwrap().is_empty());
+    }
+}
@@ -0,0 +1,57 @@
+use std::collections::HashMap;
+use async_trait::async_trait;
+use serde::{Deserialize, Serialize};
+use tokio::sync::mpsc::Receiver;
+use common::errors::NavajoResult;
+use p2p::message::Message;
+
+pub mod memory;
+pub mod redis;
+
+/// How often a node tells the others it is alive.
+pub const HEARTBEAT_INTERVAL_SECONDS: u64 = 5;
+
+/// A node without a heartbeat for this long is dead, its devices are forgotten.
+pub const NODE_EXPIRE_SECONDS: u64 = 15;
+
+/// What nodes send each other.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub enum ClusterMessage {
+    /// For a device connected to the receiving node, queued there if it left meanwhile.
+    Forward { address: String, device_id: String, message: Message },
+    /// Sent to every node, they close the device's connections.
+    RevokeSession { address: String, device_id: String },
+    /// Sent to every node, they reload the device in its connections.
+    SessionCreated { address: String, device_id: String },
+}
+
+/// The server nodes sharing users, with the node each online device is connected to.
+/// A single node server runs on its own one node cluster.
+#[async_trait]
+pub trait Cluster: Send + Sync {
+    fn node_id(&self) -> &str;
+
+    /// Forgets the devices left from an earlier run of this node, then starts receiving
+    /// what the other nodes send to it.
+    async fn join(&self) -> NavajoResult<Receiver<ClusterMessage>>;
+
+    /// Tells the other nodes this one is alive and forgets the devices of dead ones.
+    /// Called every `HEARTBEAT_INTERVAL_SECONDS`.
+    async fn heartbeat(&self) -> NavajoResult<()>;
+
+    /// Records that the device is connected to this node.
+    async fn register(&self, address: &str, device_id: &str) -> NavajoResult<()>;
+
+    /// Forgets the device, unless it has connected to another node since.
+    async fn unregister(&self, address: &str, device_id: &str) -> NavajoResult<()>;
+
+    /// Node of each online device of `address`, by device_id. Devices of dead nodes are left out.
+    async fn find_nodes(&self, address: &str) -> NavajoResult<HashMap<String, String>>;
+
+    /// Returns whether `node_id` was there to receive it. Only that, the receiving node
+    /// reports what became of a forwarded chat itself.
+    async fn send(&self, node_id: &str, message: &ClusterMessage) -> NavajoResult<bool>;
+
+    /// Sends to every other node.
+    async fn broadcast(&self, message: &ClusterMessage) -> NavajoResult<()>;
+}
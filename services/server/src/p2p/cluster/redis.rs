@@ -0,0 +1,214 @@
+use std::collections::HashMap;
+use std::sync::Arc;
+use std::time::Duration;
+use async_trait::async_trait;
+use futures::StreamExt;
+use tokio::spawn;
+use tokio::sync::mpsc::{Receiver, Sender};
+use tokio::time::sleep;
+use common::errors::{DB_ERROR, NavajoError, NavajoResult};
+use crate::db::redis::RedisClient;
+use crate::p2p::channel::create_cluster_channel;
+use crate::p2p::cluster::{Cluster, ClusterMessage, NODE_EXPIRE_SECONDS};
+
+// Hash of device_id to node_id, per address
+const KEY_PRESENCE: &str = "key_presence:";
+// Set of the "address/device_id" a node registered, to clean up after it
+const KEY_NODE_PRESENCE: &str = "key_node_presence:";
+// Expires once the node stops its heartbeat
+const KEY_NODE_ALIVE: &str = "key_node_alive:";
+const KEY_NODES: &str = "key_nodes";
+const CHANNEL_NODE: &str = "channel_node:";
+const CHANNEL_BROADCAST: &str = "channel_broadcast";
+
+/// Nodes sharing one Redis. Presence lives in hashes, nodes reach each other
+/// over pub/sub, one channel per node plus one for all of them.
+pub struct RedisCluster {
+    node_id: String,
+    redis_client: Arc<RedisClient>,
+}
+
+impl RedisCluster {
+    pub fn new(node_id: &str, redis_client: Arc<RedisClient>) -> Arc<Self> {
+        Arc::new(Self { node_id: node_id.to_string(), redis_client })
+    }
+
+    async fn is_alive(&self, node_id: &str) -> NavajoResult<bool> {
+        self.redis_client.exists(&alive_key(node_id)).await
+    }
+
+    /// Forgets the devices registered by `node_id`.
+    async fn forget_node(&self, node_id: &str) -> NavajoResult<()> {
+        let key = node_presence_key(node_id);
+        for entry in self.redis_client.set_members(&key).await? {
+            if let Some((address, device_id)) = entry.split_once('/') {
+                self.redis_client.hash_del_if(&presence_key(address), device_id, node_id).await?;
+            }
+        }
+        self.redis_client.remove(&key).await
+    }
+}
+
+#[async_trait]
+impl Cluster for RedisCluster {
+    fn node_id(&self) -> &str {
+        &self.node_id
+    }
+
+    async fn join(&self) -> NavajoResult<Receiver<ClusterMessage>> {
+        self.forget_node(&self.node_id).await?;
+        self.heartbeat().await?;
+        let channels = vec![node_channel(&self.node_id), CHANNEL_BROADCAST.to_string()];
+        // Fails early if Redis can't be subscribed to, the listener resubscribes after that
+        let pubsub = self.redis_client.subscribe(&channels).await?;
+        let (tx, rx) = create_cluster_channel();
+        spawn(listen(self.redis_client.clone(), channels, pubsub, tx));
+        Ok(rx)
+    }
+
+    async fn heartbeat(&self) -> NavajoResult<()> {
+        self.redis_client.set_ex(&alive_key(&self.node_id), "1", NODE_EXPIRE_SECONDS as usize).await?;
+        self.redis_client.set_add(KEY_NODES, &self.node_id).await?;
+        for node_id in self.redis_client.set_members(KEY_NODES).await? {
+            if node_id == self.node_id || self.is_alive(&node_id).await? {
+                continue;
+            }
+            self.forget_node(&node_id).await?;
+            self.redis_client.set_remove(KEY_NODES, &node_id).await?;
+            println!("Node {:?} is gone, forgot its devices", node_id);
+        }
+        Ok(())
+    }
+
+    async fn register(&self, address: &str, device_id: &str) -> NavajoResult<()> {
+        self.redis_client.set_add(&node_presence_key(&self.node_id), &presence_entry(address, device_id)).await?;
+        self.redis_client.hash_set(&presence_key(address), device_id, &self.node_id).await
+    }
+
+    async fn unregister(&self, address: &str, device_id: &str) -> NavajoResult<()> {
+        self.redis_client.hash_del_if(&presence_key(address), device_id, &self.node_id).await?;
+        self.redis_client.set_remove(&node_presence_key(&self.node_id), &presence_entry(address, device_id)).await
+    }
+
+    async fn find_nodes(&self, address: &str) -> NavajoResult<HashMap<String, String>> {
+        let devices = self.redis_client.hash_get_all(&presence_key(address)).await?;
+        let mut alive: HashMap<String, bool> = HashMap::new();
+        let mut nodes = HashMap::new();
+        for (device_id, node_id) in devices {
+            let node_alive = match alive.get(&node_id) {
+                Some(node_alive) => *node_alive,
+                None => {
+                    let node_alive = self.is_alive(&node_id).await?;
+                    alive.insert(node_id.clone(), node_alive);
+                    node_alive
+                }
+            };
+            if node_alive {
+                nodes.insert(device_id, node_id);
+            }
+        }
+        Ok(nodes)
+    }
+
+    async fn send(&self, node_id: &str, message: &ClusterMessage) -> NavajoResult<bool> {
+        let payload = serde_json::to_string(message).map_err(|_| NavajoError::new(DB_ERROR))?;
+        // A subscriber is the node listening, not the device getting it
+        Ok(self.redis_client.publish(&node_channel(node_id), &payload).await? > 0)
+    }
+
+    async fn broadcast(&self, message: &ClusterMessage) -> NavajoResult<()> {
+        let payload = serde_json::to_string(message).map_err(|_| NavajoError::new(DB_ERROR))?;
+        self.redis_client.publish(CHANNEL_BROADCAST, &payload).await.map(|_| ())
+    }
+}
+
+/// Hands what arrives on `channels` to `tx`, until the node stops receiving.
+async fn listen(redis_client: Arc<RedisClient>, channels: Vec<String>, pubsub: redis::aio::PubSub, tx: Sender<ClusterMessage>) {
+    let mut pubsub = Some(pubsub);
+    loop {
+        let subscribed = match pubsub.take() {
+            Some(pubsub) => Ok(pubsub),
+            None => redis_client.subscribe(&channels).await,
+        };
+        match subscribed {
+            Ok(pubsub) => {
+                let mut messages = pubsub.into_on_message();
+                while let Some(message) = messages.next().await {
+                    let message = message.get_payload::<String>().ok()
+                        .and_then(|payload| serde_json::from_str::<ClusterMessage>(&payload).ok());
+                    match message {
+                        Some(message) => {
+                            if tx.send(message).await.is_err() {
+                                return;
+                            }
+                        }
+                        None => println!("Drop unreadable cluster message"),
+                    }
+                }
+                println!("Cluster subscription lost, resubscribing");
+            }
+            Err(err) => println!("Subscribe cluster channels failed, {}", err),
+        }
+        // Nodes sending meanwhile find no subscriber, and queue instead
+        sleep(Duration::from_secs(1)).await;
+    }
+}
+
+fn presence_key(address: &str) -> String {
+    format!("{}{}", KEY_PRESENCE, address)
+}
+
+fn presence_entry(address: &str, device_id: &str) -> String {
+    format!("{}/{}", address, device_id)
+}
+
+fn node_presence_key(node_id: &str) -> String {
+    format!("{}{}", KEY_NODE_PRESENCE, node_id)
+}
+
+fn alive_key(node_id: &str) -> String {
+    format!("{}{}", KEY_NODE_ALIVE, node_id)
+}
+
+fn node_channel(node_id: &str) -> String {
+    format!("{}{}", CHANNEL_NODE, node_id)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::env;
+    use uuid::Uuid;
+    use crate::db::redis::RedisClient;
+    use crate::db::RedisConfig;
+    use crate::p2p::cluster::{Cluster, ClusterMessage};
+    use crate::p2p::cluster::redis::{alive_key, presence_key, RedisCluster};
+
+    #[actix_rt::test]
+    async fn test_redis_cluster() {
+        let host = env::var("NAVAJO_TEST_REDIS_URL").unwrap_or_else(|_| String::from("redis://127.0.0.1/"));
+        let redis_client = RedisClient::new(RedisConfig { host, connect_timeout_ms: 1000, response_timeout_ms: 1000 }).unwrap();
+        let (a, b) = (Uuid::new_v4().to_string(), Uuid::new_v4().to_string());
+        let node_a = RedisCluster::new(&a, redis_client.clone());
+        let node_b = RedisCluster::new(&b, redis_client.clone());
+        let _rx_a = node_a.join().await.unwrap();
+        let mut rx_b = node_b.join().await.unwrap();
+
+        let address = Uuid::new_v4().to_string();
+        node_b.register(&address, "phone").await.unwrap();
+        assert_eq!(node_a.find_nodes(&address).await.unwrap().get("phone"), Some(&b));
+
+        let revoke = ClusterMessage::RevokeSession { address: address.clone(), device_id: String::from("phone") };
+        assert!(node_a.send(&b, &revoke).await.unwrap());
+        assert!(matches!(rx_b.recv().await, Some(ClusterMessage::RevokeSession { .. })));
+
+        // Only the node a device is connected to forgets it
+        node_a.unregister(&address, "phone").await.unwrap();
+        assert_eq!(node_a.find_nodes(&address).await.unwrap().len(), 1);
+
+        // A node whose heartbeat expired is forgotten
+        redis_client.remove(&alive_key(&b)).await.unwrap();
+        assert!(node_a.find_nodes(&address).await.unwrap().is_empty());
+        node_a.heartbeat().await.unwrap();
+        assert!(redis_client.hash_get_all(&presence_key(&address)).await.unwrap().is_empty());
+    }
+}
@@ -1,136 +1,406 @@
+use std::collections::HashSet;
 use std::sync::Arc;
-use tokio::io::{AsyncReadExt, AsyncWriteExt, ReadHalf, WriteHalf};
+use std::sync::atomic::{AtomicU64, Ordering};
+use futures::{SinkExt, StreamExt};
+use tokio::io::{ReadHalf, WriteHalf};
 use tokio::net::TcpStream;
-use tokio::{io, spawn};
-use tokio::sync::Mutex;
+use tokio::{io, select, spawn};
 use tokio::sync::mpsc::{Receiver, Sender};
+use tokio::sync::mpsc::error::TrySendError;
+use tokio_util::codec::{FramedRead, FramedWrite};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use tokio::time::timeout;
+use common::errors::{ADDRESS_MISMATCH_ERROR, INVALID_SESSION, NavajoError, NavajoResult, SESSION_EXPIRED};
+use ncrypto::algo::kdf::SessionKeys;
 use p2p::message::{Message, P2PMessage};
-use p2p::packet::readers::{CryptoReader, PacketExtractor};
-use p2p::packet::writers::{MessageWriter, Writer};
-use crate::db::repository::UserRepository;
-use crate::p2p::channel::ChannelSignal::{ConnectionClose, ConnectionError, RemoteMessage};
-use crate::p2p::channel::{ChannelSignal, create_connection_channel};
+use p2p::packet::codec::{Frame, FRAME_PROTOCOL_VERSION, Framing, P2PCodec};
+use p2p::packet::readers::CryptoReader;
+use p2p::packet::writers::seal_frame;
+use crate::db::models::Device;
+use crate::replay::{ReplayGuard, SCOPE_P2P};
+use crate::store::{QueuedMessage, UserStore};
+use crate::p2p::channel::{create_connection_channel, create_frame_channel};
+use crate::p2p::router::Router;
 
-type ConnectionReceiver = Arc<Mutex<Receiver<Vec<u8>>>>;
+/// How long a client has to send its first byte. Legacy clients speak first with their
+/// ping, a few seconds in. Short in tests, so that they can wait it out.
+const FIRST_BYTE_TIMEOUT_SECONDS: u64 = if cfg!(test) { 1 } else { 30 };
 
+/// What the rest of the server asks of a connection.
+#[derive(Debug)]
+pub enum ConnectionCommand {
+    Call(Message),
+    // The queue of the bound device as read on a ping, oldest first
+    CallQueue(Vec<QueuedMessage>),
+    // Closes the connection if it is bound to the device
+    CloseIfBound { address: String, device_id: String },
+    // Reloads the device the connection is bound to, if it is this one
+    SessionCreated { address: String, device_id: String },
+}
+
+/// A message a connection was given, the router hears whether it got written, see
+/// `Router::written` and `Router::not_written`.
+#[derive(Debug)]
+pub enum Delivery {
+    // Handed over live, queued for the device if it isn't written
+    Live { message: Message, device_id: String },
+    // Read from a queue, it stays there until written
+    Queued(QueuedMessage),
+}
+
+impl Delivery {
+    pub fn message(&self) -> &Message {
+        match self {
+            Delivery::Live { message, .. } => message,
+            Delivery::Queued(queued) => &queued.message,
+        }
+    }
+}
+
+/// A frame for the socket, with the message it delivers if any.
+pub struct Outgoing {
+    frame: Frame,
+    delivery: Option<Delivery>,
+}
+
+impl From<Frame> for Outgoing {
+    fn from(frame: Frame) -> Self {
+        Self { frame, delivery: None }
+    }
+}
+
+/// Handle of a connection. The connection itself is a task reading the socket and its
+/// mailbox, see `serve`.
+#[derive(Clone)]
 pub struct Connection {
-    con_tx: Sender<Vec<u8>>,
-    con_rx: ConnectionReceiver,
-    user_repository: Arc<UserRepository>,
-    message_writer: Arc<MessageWriter>,
+    mailbox: Sender<ConnectionCommand>,
 }
 
 impl Connection {
-    pub fn new(user_repository: Arc<UserRepository>) -> Self {
-        let (con_tx, con_rx) = create_connection_channel();
-        Self {
-            con_tx,
-            con_rx: Arc::new(Mutex::new(con_rx)),
-            user_repository,
-            message_writer: Arc::new(MessageWriter),
-        }
+    /// The handle and the mailbox to `serve` with.
+    pub fn new() -> (Self, Receiver<ConnectionCommand>) {
+        let (mailbox, mailbox_rx) = create_connection_channel();
+        (Self { mailbox }, mailbox_rx)
     }
 
-    pub async fn start(&self, server_channel_tx: Sender<ChannelSignal>, socket: TcpStream) {
-        let peer_addr = format!("{}", &socket.peer_addr().unwrap());
-        let (r, w) = io::split(socket);
-        self.start_channel_handle_thread(w);
-        self.start_socket_read_thread(r, server_channel_tx, peer_addr);
+    /// Returns whether the message was handed to the connection, without waiting for it.
+    /// A connection too far behind to take it gets it from its queue instead.
+    pub fn call(&self, message: &Message) -> bool {
+        self.mailbox.try_send(ConnectionCommand::Call(message.clone())).is_ok()
     }
 
-    pub async fn call(&self, to_address: &str, message: P2PMessage) {
-        println!("Call, {:?}", &message);
-        if let Some(encoded) = encode_message(to_address, &self.user_repository, &self.message_writer, message).await {
-            let ers = self.con_tx.send(encoded).await;
-            println!("Sent, {:?}", ers);
-        }
+    /// Like `call`, for the messages read from a queue.
+    pub fn call_queue(&self, queue: Vec<QueuedMessage>) -> bool {
+        self.mailbox.try_send(ConnectionCommand::CallQueue(queue)).is_ok()
     }
 
-    fn start_channel_handle_thread(&self, mut w: WriteHalf<TcpStream>) {
-        let con_rx = self.con_rx.clone();
-        // Call from others
+    /// Commands aren't queued elsewhere, they wait for room in the mailbox without holding
+    /// up the caller.
+    pub fn tell(&self, command: ConnectionCommand) {
+        let mailbox = self.mailbox.clone();
         spawn(async move {
-            while let Some(message) = con_rx.lock().await.recv().await {
-                w.write_all(message.as_slice()).await.unwrap();
-            }
+            let _ = mailbox.send(command).await;
         });
     }
+}
 
-    fn start_socket_read_thread(
-        &self,
-        mut r: ReadHalf<TcpStream>,
-        server_channel_tx: Sender<ChannelSignal>,
-        peer_addr: String
-    ) {
-        let user_repository = self.user_repository.clone();
-        // Serve the socket read
-        spawn(async move {
-            let mut extractor = PacketExtractor::new();
-            let mut buf = vec![0; 1024];
-            loop {
-                match r.read(&mut buf).await {
-                    Ok(0) => {
-                        server_channel_tx.send(ConnectionClose(peer_addr.clone())).await.unwrap();
-                        return;
+/// Serves the socket until it closes, then has the router forget the connection.
+pub async fn serve(
+    socket: TcpStream,
+    peer_addr: String,
+    mut mailbox: Receiver<ConnectionCommand>,
+    router: Arc<Router>,
+    replay_guard: Arc<ReplayGuard>,
+    bad_frames: Arc<AtomicU64>,
+) {
+    let mut bound = None;
+    // Clients always speak first, their first byte tells which framing they use
+    let mut first_byte = [0u8; 1];
+    let peeked = timeout(Duration::from_secs(FIRST_BYTE_TIMEOUT_SECONDS), socket.peek(&mut first_byte)).await;
+    if peeked.is_err() {
+        println!("Connection {:?} sent nothing, closing it", peer_addr);
+    }
+    if let Ok(Ok(1)) = peeked {
+        let framing = Framing::detect(first_byte[0]);
+        println!("Connection {:?} uses {:?} framing", peer_addr, framing);
+        let (r, w) = io::split(socket);
+        let (frame_tx, frame_rx) = create_frame_channel();
+        // Writes apart, a client slow to read doesn't hold up its connection's reads
+        spawn(write_handle(FramedWrite::new(w, framing.codec()), frame_rx, router.clone()));
+        let mut handler = Handler {
+            peer_addr: peer_addr.clone(),
+            frame_tx,
+            router: &router,
+            replay_guard,
+            bad_frames,
+            bound: None,
+            handed: HashSet::new(),
+        };
+        handler.run(FramedRead::new(r, framing.codec()), &mut mailbox).await;
+        bound = handler.bound.take();
+    }
+    router.forget_connection(&peer_addr, bound.as_ref()).await;
+    // Nothing calls the connection once forgotten, what it was given goes back to the queues
+    mailbox.close();
+    while let Some(command) = mailbox.recv().await {
+        if let (ConnectionCommand::Call(message), Some(device)) = (command, &bound) {
+            router.not_written(Delivery::Live { message, device_id: device.device_id.clone() }).await;
+        }
+    }
+}
+
+/// Writes frames until the socket fails, telling the router which deliveries were written.
+async fn write_handle(mut w: FramedWrite<WriteHalf<TcpStream>, P2PCodec>, mut frame_rx: Receiver<Outgoing>, router: Arc<Router>) {
+    while let Some(Outgoing { frame, delivery }) = frame_rx.recv().await {
+        let written = w.send(frame).await;
+        if let Some(delivery) = delivery {
+            match written {
+                Ok(_) => router.written(delivery).await,
+                Err(_) => router.not_written(delivery).await,
+            }
+        }
+        if let Err(err) = written {
+            println!("Write frame failed, {}", err);
+            break;
+        }
+    }
+    frame_rx.close();
+    while let Some(outgoing) = frame_rx.recv().await {
+        if let Some(delivery) = outgoing.delivery {
+            router.not_written(delivery).await;
+        }
+    }
+}
+
+struct Handler<'a> {
+    peer_addr: String,
+    frame_tx: Sender<Outgoing>,
+    router: &'a Router,
+    replay_guard: Arc<ReplayGuard>,
+    bad_frames: Arc<AtomicU64>,
+    // Set by the first valid packet, every later one must come from the same device
+    bound: Option<Device>,
+    // Ids of the queued messages handed to the writer, until they leave the queue
+    handed: HashSet<String>,
+}
+
+impl Handler<'_> {
+    async fn run(&mut self, mut r: FramedRead<ReadHalf<TcpStream>, P2PCodec>, mailbox: &mut Receiver<ConnectionCommand>) {
+        loop {
+            select! {
+                next = r.next() => match next {
+                    None => return,
+                    Some(Ok(Frame::Hello { version })) => {
+                        println!("Hello from {:?}, version {}", self.peer_addr, version);
+                        let _ = self.frame_tx.send(Frame::Hello { version: FRAME_PROTOCOL_VERSION }.into()).await;
                     }
-                    Ok(n) => {
-                        handle_message(
-                            n,
-                            &buf,
-                            &mut extractor,
-                            &server_channel_tx,
-                            peer_addr.clone(),
-                            &user_repository.clone()
-                        ).await;
+                    Some(Ok(Frame::Data { session, payload })) => {
+                        if let Err(err) = self.handle_frame(&session, &payload).await {
+                            count_bad_frame(&self.bad_frames, &self.peer_addr, &err);
+                            if is_session_rejected(&err) {
+                                let _ = self.frame_tx.send(Frame::SessionInvalid { session }.into()).await;
+                                // The device lost its session, it is told so before it goes
+                                if self.bound.is_some() {
+                                    println!("Close socket {:?}, its session is gone", self.peer_addr);
+                                    return;
+                                }
+                            }
+                        }
                     }
-                    Err(_) => {
-                        server_channel_tx.send(ConnectionError(peer_addr.clone())).await.unwrap();
+                    // Only ever sent by the server
+                    Some(Ok(Frame::SessionInvalid { .. })) => {}
+                    Some(Err(err)) => {
+                        // The stream can't be resynchronized after a broken frame
+                        count_bad_frame(&self.bad_frames, &self.peer_addr, &err);
                         return;
                     }
-                }
+                },
+                command = mailbox.recv() => match command {
+                    Some(ConnectionCommand::Call(message)) => self.call(message).await,
+                    Some(ConnectionCommand::CallQueue(queue)) => self.deliver_queue(queue).await,
+                    Some(ConnectionCommand::CloseIfBound { address, device_id }) => {
+                        if self.is_bound_to(&address, &device_id) {
+                            println!("Close socket {:?}, its session was revoked", self.peer_addr);
+                            return;
+                        }
+                    }
+                    Some(ConnectionCommand::SessionCreated { address, device_id }) => {
+                        if self.is_bound_to(&address, &device_id) {
+                            self.reload_device(&address, &device_id).await;
+                        }
+                    }
+                    None => return,
+                },
             }
-        });
+        }
+    }
+
+    async fn handle_frame(&mut self, session: &str, payload: &[u8]) -> NavajoResult<()> {
+        let (device, message) = open_message(
+            session,
+            payload,
+            self.bound.as_ref(),
+            self.router.user_store(),
+            &self.replay_guard,
+        ).await?;
+        if self.bound.is_none() {
+            println!("Connection {:?} bound to {:?} of {:?}", self.peer_addr, device.device_id, device.address);
+            self.router.bind_connection(&self.peer_addr, &device.address, &device.device_id);
+        }
+        let address = device.address.clone();
+        let device_id = device.device_id.clone();
+        self.bound = Some(device);
+        self.router.handle_message(&self.peer_addr, &address, &device_id, message).await;
+        Ok(())
+    }
+
+    fn is_bound_to(&self, address: &str, device_id: &str) -> bool {
+        self.bound.as_ref().is_some_and(|device| device.address == address && device.device_id == device_id)
+    }
+
+    /// Picks up the new session of the bound device, the client may already use it.
+    async fn reload_device(&mut self, address: &str, device_id: &str) {
+        match self.router.user_store().find_device(address, device_id).await {
+            Ok(Some(device)) => self.bound = Some(device),
+            Ok(None) => {}
+            Err(err) => println!("Reload {:?} failed, {}", device_id, err),
+        }
+    }
+
+    async fn call(&self, message: Message) {
+        let device_id = match &self.bound {
+            Some(device) => device.device_id.clone(),
+            None => return,
+        };
+        self.deliver(Delivery::Live { message, device_id }).await;
+    }
+
+    /// Hands over what of the queue this connection hasn't yet. Pings come faster than a long
+    /// queue is written, what was handed over stays queued until it is acknowledged.
+    async fn deliver_queue(&mut self, queue: Vec<QueuedMessage>) {
+        // No longer queued, so acknowledged
+        let queued_ids: HashSet<&str> = queue.iter().map(|queued| queued.id.as_str()).collect();
+        self.handed.retain(|id| queued_ids.contains(id.as_str()));
+        for queued in queue {
+            if self.handed.contains(&queued.id) {
+                continue;
+            }
+            let id = queued.id.clone();
+            // What doesn't fit waits for the next ping
+            if !self.deliver(Delivery::Queued(queued)).await {
+                break;
+            }
+            self.handed.insert(id);
+        }
+    }
+
+    /// Encoded here rather than by the sender, each connection with its own session.
+    /// Returns whether the writer got it, the router hears about it if not.
+    async fn deliver(&self, delivery: Delivery) -> bool {
+        let frame = match self.bound.as_ref().and_then(|device| encode_message(device, &delivery.message().into())) {
+            Some(frame) => frame,
+            None => {
+                self.router.not_written(delivery).await;
+                return false;
+            }
+        };
+        if let Err(err) = self.frame_tx.try_send(Outgoing { frame, delivery: Some(delivery) }) {
+            if let Some(delivery) = match err {
+                TrySendError::Full(outgoing) | TrySendError::Closed(outgoing) => outgoing.delivery,
+            } {
+                self.router.not_written(delivery).await;
+            }
+            return false;
+        }
+        true
     }
 }
 
-async fn handle_message(
-    n: usize,
-    buf: &[u8],
-    extractor: &mut PacketExtractor,
-    server_channel_tx: &Sender<ChannelSignal>,
-    addr: String,
-    user_repository: &UserRepository,
-) -> Option<P2PMessage> {
-    let str = String::from_utf8_lossy(&buf[..n]).to_string();
-    println!("{:?}", str);
-    let packet_content = extractor.extract(&str)?;
-    let session = packet_content.session.as_str();
-    let users = user_repository.find_by_session(session).await?;
-    let user = users.first()?;
-    let secret = user.secret.as_str();
-    let mut crypto_reader = CryptoReader::new(secret);
-    let p2p_message = crypto_reader.process(&packet_content)?;
-    let message: Message = (&p2p_message).into();
-    server_channel_tx.send(RemoteMessage {
-        peer_addr: addr,
-        message,
-    }).await.unwrap();
-    Some(p2p_message)
-}
-
-async fn encode_message(
-    to_address: &str,
-    user_repository: &UserRepository,
-    message_writer: &MessageWriter,
-    message: P2PMessage,
-) -> Option<Vec<u8>> {
-    let message_str: String = (&message).into();
-    let users = user_repository.find_by_address(to_address).await?;
-    let user = users.first()?;
-    let session = &user.session;
-    let secret = &user.secret;
-    let params = &[session.as_str(), secret.as_str()];
-    let result = message_writer.process(&message_str, params)?;
-    Some(result.as_bytes().to_vec())
+/// Opens a frame with the session it names, which must belong to the device the connection
+/// is bound to, if it is already.
+async fn open_message(
+    session: &str,
+    payload: &[u8],
+    bound: Option<&Device>,
+    user_store: &dyn UserStore,
+    replay_guard: &ReplayGuard,
+) -> NavajoResult<(Device, Message)> {
+    let cached = bound.is_some_and(|device| device.session == session);
+    let mut device = match bound {
+        Some(device) if cached => device.clone(),
+        _ => find_session_device(session, user_store).await?,
+    };
+    let now = SystemTime::now().duration_since(UNIX_EPOCH).unwrap().as_millis();
+    if device.session_expired(now) {
+        return Err(NavajoError::new(SESSION_EXPIRED));
+    }
+    if let Some(bound) = bound {
+        if bound.address != device.address || bound.device_id != device.device_id {
+            return Err(NavajoError::new(ADDRESS_MISMATCH_ERROR));
+        }
+    }
+    let keys = SessionKeys::decode_from_str(&device.secret).ok_or_else(|| NavajoError::new(INVALID_SESSION))?;
+    let mut crypto_reader = CryptoReader::new(&keys.client_to_server_str()).accept_legacy(keys.is_legacy());
+    let p2p_message = crypto_reader.open(payload)?;
+    let message = Message::try_from(&p2p_message)?;
+    if message.sender_address() != device.address {
+        return Err(NavajoError::new(ADDRESS_MISMATCH_ERROR));
+    }
+    let common_info = message.common_info();
+    match message {
+        // Chats are resent until acknowledged, the queue dedupes them by request_id
+        Message::ChatInfoMessage { .. } => replay_guard.check_time(common_info.time_ms)?,
+        _ => replay_guard.check(SCOPE_P2P, &common_info.request_id, common_info.time_ms).await?,
+    }
+    if let Message::PingMessage { .. } = message {
+        // The bound device is read again, the node may have missed a revocation announced by another one
+        if cached {
+            device = find_current_device(&device, user_store, now).await?;
+        }
+        if let Err(err) = user_store.touch_device(&device.address, &device.device_id, now as u64).await {
+            println!("Update last seen of {:?} failed, {}", device.device_id, err);
+        }
+    }
+    Ok((device, message))
+}
+
+async fn find_session_device(session: &str, user_store: &dyn UserStore) -> NavajoResult<Device> {
+    // Revoked sessions are stored empty
+    if session.is_empty() {
+        return Err(NavajoError::new(INVALID_SESSION));
+    }
+    user_store.find_device_by_session(session).await?
+        .ok_or_else(|| NavajoError::new(INVALID_SESSION))
+}
+
+/// The device as stored, as long as it still has the session it had and that isn't expired.
+async fn find_current_device(device: &Device, user_store: &dyn UserStore, now: u128) -> NavajoResult<Device> {
+    let current = user_store.find_device(&device.address, &device.device_id).await?
+        .filter(|current| current.session == device.session)
+        .ok_or_else(|| NavajoError::new(INVALID_SESSION))?;
+    if current.session_expired(now) {
+        return Err(NavajoError::new(SESSION_EXPIRED));
+    }
+    Ok(current)
+}
+
+fn is_session_rejected(err: &NavajoError) -> bool {
+    err.is(&INVALID_SESSION) || err.is(&SESSION_EXPIRED)
+}
+
+fn count_bad_frame(bad_frames: &AtomicU64, peer_addr: &str, err: &NavajoError) {
+    let total = bad_frames.fetch_add(1, Ordering::Relaxed) + 1;
+    println!("Bad frame from {:?}, {}, {} in total", peer_addr, err, total);
+}
+
+/// Seals with the session of the bound device, reloaded whenever the device gets a new one.
+fn encode_message(device: &Device, message: &P2PMessage) -> Option<Frame> {
+    if device.session_revoked() {
+        return None;
+    }
+    let session = &device.session;
+    let keys = SessionKeys::decode_from_str(&device.secret)?;
+    let secret = keys.server_to_client_str();
+    seal_frame(session, &secret, message).ok()
 }
 
@@ -1,3 +1,5 @@
 pub mod server;
 pub mod connection;
-pub mod channel;
\ No newline at end of file
+pub mod channel;
+pub mod cluster;
+pub mod router;
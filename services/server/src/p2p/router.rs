@@ -0,0 +1,362 @@
+use std::collections::{HashMap, HashSet};
+use std::sync::Arc;
+use dashmap::DashMap;
+use common::errors::{DB_ERROR, NavajoError, NavajoResult};
+use p2p::message::{CHAT_STATUS_DELIVERED, CHAT_STATUS_QUEUED, ChatStatus, Message};
+use p2p::message::Message::{ChatInfoMessage, ChatStatusMessage, PingMessage, ReceiptMessage};
+use crate::db::models::Device;
+use crate::p2p::cluster::{Cluster, ClusterMessage};
+use crate::p2p::connection::{Connection, ConnectionCommand, Delivery};
+use crate::store::{Accepted, MessageQueue, UserStore};
+
+/// Finds the connections messages go to. Shared by every connection, each handles what it
+/// reads itself, so a slow recipient only holds up its own connection.
+pub struct Router {
+    // By peer_addr
+    connections: DashMap<String, Connection>,
+    // Address to the connections of its online devices, by device_id
+    devices: DashMap<String, HashMap<String, String>>,
+    // The address and device_id each connection pinged as, by peer_addr
+    pinged: DashMap<String, (String, String)>,
+    // The connections bound to each device, pinged or not, by address and device_id
+    bound: DashMap<(String, String), HashSet<String>>,
+    user_store: Arc<dyn UserStore>,
+    message_queue: Arc<dyn MessageQueue>,
+    cluster: Arc<dyn Cluster>,
+}
+
+impl Router {
+    pub fn new(user_store: Arc<dyn UserStore>, message_queue: Arc<dyn MessageQueue>, cluster: Arc<dyn Cluster>) -> Self {
+        Self {
+            connections: DashMap::new(),
+            devices: DashMap::new(),
+            pinged: DashMap::new(),
+            bound: DashMap::new(),
+            user_store,
+            message_queue,
+            cluster,
+        }
+    }
+
+    pub fn user_store(&self) -> &dyn UserStore {
+        self.user_store.as_ref()
+    }
+
+    pub fn cluster(&self) -> &dyn Cluster {
+        self.cluster.as_ref()
+    }
+
+    pub fn add_connection(&self, peer_addr: &str, connection: Connection) {
+        self.connections.insert(peer_addr.to_string(), connection);
+    }
+
+    /// The connection `peer_addr` opened its first frame with a session of the device.
+    pub fn bind_connection(&self, peer_addr: &str, address: &str, device_id: &str) {
+        self.bound.entry((address.to_string(), device_id.to_string())).or_default()
+            .insert(peer_addr.to_string());
+    }
+
+    /// Forgets a closed connection, bound to `bound` if it got that far, and the device that
+    /// pinged from it unless it has reconnected since.
+    pub async fn forget_connection(&self, peer_addr: &str, bound: Option<&Device>) {
+        println!("Close socket {:?}", peer_addr);
+        self.connections.remove(peer_addr);
+        if let Some(device) = bound {
+            let key = (device.address.clone(), device.device_id.clone());
+            if let Some(mut peer_addrs) = self.bound.get_mut(&key) {
+                peer_addrs.remove(peer_addr);
+            }
+            self.bound.remove_if(&key, |_, peer_addrs| peer_addrs.is_empty());
+        }
+        let (address, device_id) = match self.pinged.remove(peer_addr) {
+            Some((_, pinged)) => pinged,
+            None => return,
+        };
+        let gone = match self.devices.get_mut(&address) {
+            Some(mut devices) if devices.get(&device_id).is_some_and(|ip| ip == peer_addr) => {
+                devices.remove(&device_id);
+                true
+            }
+            _ => false,
+        };
+        self.devices.remove_if(&address, |_, devices| devices.is_empty());
+        if gone {
+            if let Err(err) = self.cluster.unregister(&address, &device_id).await {
+                println!("Unregister {:?} from the cluster failed, {}", device_id, err);
+            }
+        }
+    }
+
+    /// Handles a message read by the connection `peer_addr`, bound to the session of
+    /// `device_id` of `address`.
+    pub async fn handle_message(&self, peer_addr: &str, address: &str, device_id: &str, message: Message) {
+        match message {
+            // The connection already checked the ping comes from the session owner
+            PingMessage { .. } => self.handle_ping(peer_addr, address, device_id).await,
+            ChatInfoMessage { ref common_info, .. } => {
+                // Clients resend until they get a status, a known chat only gets it again
+                match self.message_queue.accept(address, &common_info.request_id).await {
+                    Ok(Accepted::New) => {}
+                    // The copy being handled gets the status
+                    Ok(Accepted::InProgress) => return,
+                    Ok(Accepted::Done(status)) => {
+                        self.notify_sender(&message, status).await;
+                        return;
+                    }
+                    Err(err) => {
+                        println!("Accept message from {:?} failed, {}", address, err);
+                        return;
+                    }
+                }
+                match self.deliver(&message).await {
+                    Ok(Some(status)) => {
+                        self.message_queue.set_accepted_status(address, &common_info.request_id, status).await;
+                        self.notify_sender(&message, status).await;
+                    }
+                    // The connection that has it reports once it is written
+                    Ok(None) => {}
+                    Err(err) => {
+                        println!("Deliver message from {:?} failed, {}", address, err);
+                        self.message_queue.release(address, &common_info.request_id).await;
+                    }
+                }
+            }
+            ReceiptMessage { .. } => {
+                if let Err(err) = self.deliver(&message).await {
+                    println!("Deliver receipt from {:?} failed, {}", address, err);
+                }
+            }
+            // Only the server reports chat status
+            ChatStatusMessage { .. } => {}
+        }
+    }
+
+    async fn handle_ping(&self, peer_addr: &str, address: &str, device_id: &str) {
+        self.devices.entry(address.to_string()).or_default()
+            .insert(device_id.to_string(), peer_addr.to_string());
+        self.pinged.insert(peer_addr.to_string(), (address.to_string(), device_id.to_string()));
+        if let Err(err) = self.cluster.register(address, device_id).await {
+            println!("Register {:?} in the cluster failed, {}", device_id, err);
+        }
+        let queue_mes = match self.message_queue.acquire_queue(address, device_id).await {
+            Ok(queue_mes) => queue_mes,
+            Err(err) => {
+                println!("Read queue of {:?} failed, {}", device_id, err);
+                return;
+            }
+        };
+        let connection = match self.connections.get(peer_addr) {
+            Some(connection) => connection.clone(),
+            None => return,
+        };
+        // A full mailbox gets it on the next ping. The connection skips what it handed over
+        // already, the rest is acknowledged once written
+        if !queue_mes.is_empty() && !connection.call_queue(queue_mes) {
+            println!("Mailbox of {:?} is full, its queue waits for the next ping", peer_addr);
+        }
+    }
+
+    /// A connection wrote `delivery` to its socket.
+    pub async fn written(&self, delivery: Delivery) {
+        let message = match delivery {
+            Delivery::Live { message, .. } => message,
+            Delivery::Queued(queued) => {
+                if let Err(err) = self.message_queue.ack(std::slice::from_ref(&queued)).await {
+                    println!("Ack queued message {:?} failed, {}", queued.id, err);
+                }
+                queued.message
+            }
+        };
+        self.chat_status(&message, CHAT_STATUS_DELIVERED).await;
+    }
+
+    /// A connection couldn't write `delivery`, it is queued for the device instead.
+    pub async fn not_written(&self, delivery: Delivery) {
+        // Queued ones are still in their queue, the device gets them on its next ping
+        let (message, device_id) = match delivery {
+            Delivery::Live { message, device_id } => (message, device_id),
+            Delivery::Queued(_) => return,
+        };
+        match self.message_queue.add_queue(&message, Some(&device_id)).await {
+            Ok(_) => self.chat_status(&message, CHAT_STATUS_QUEUED).await,
+            Err(err) => {
+                println!("Queue message for {:?} failed, {}", device_id, err);
+                // Handled anew when the sender resends it
+                if let ChatInfoMessage { from_address, common_info, .. } = &message {
+                    self.message_queue.release(from_address, &common_info.request_id).await;
+                }
+            }
+        }
+    }
+
+    /// Records the status of a chat the router no longer has and tells its sender.
+    async fn chat_status(&self, message: &Message, status: ChatStatus) {
+        if let ChatInfoMessage { from_address, common_info, .. } = message {
+            self.message_queue.set_accepted_status(from_address, &common_info.request_id, status).await;
+            self.notify_sender(message, status).await;
+        }
+    }
+
+    /// Handles what another node of the cluster sent.
+    pub async fn handle_cluster_message(&self, message: ClusterMessage) {
+        match message {
+            ClusterMessage::RevokeSession { address, device_id } => {
+                self.user_store.forget_cached(&address, &device_id);
+                self.tell_device(&address, &device_id, || ConnectionCommand::CloseIfBound { address: address.clone(), device_id: device_id.clone() });
+            }
+            ClusterMessage::SessionCreated { address, device_id } => {
+                self.user_store.forget_cached(&address, &device_id);
+                self.tell_device(&address, &device_id, || ConnectionCommand::SessionCreated { address: address.clone(), device_id: device_id.clone() });
+            }
+            ClusterMessage::Forward { address, device_id, message } => {
+                // The device may have left since the other node looked it up
+                if !self.call_local_device(&address, &device_id, &message) {
+                    self.not_written(Delivery::Live { message, device_id }).await;
+                }
+            }
+        }
+    }
+
+    /// Closes the connections of a device whose session was revoked, on every node.
+    pub async fn revoke_session(&self, address: &str, device_id: &str) {
+        self.tell_device(address, device_id, || ConnectionCommand::CloseIfBound { address: address.to_string(), device_id: device_id.to_string() });
+        self.broadcast(ClusterMessage::RevokeSession { address: address.to_string(), device_id: device_id.to_string() }).await;
+    }
+
+    /// Has the connections of a device seal with its new session, on every node.
+    pub async fn session_created(&self, address: &str, device_id: &str) {
+        self.tell_device(address, device_id, || ConnectionCommand::SessionCreated { address: address.to_string(), device_id: device_id.to_string() });
+        self.broadcast(ClusterMessage::SessionCreated { address: address.to_string(), device_id: device_id.to_string() }).await;
+    }
+
+    async fn broadcast(&self, message: ClusterMessage) {
+        if let Err(err) = self.cluster.broadcast(&message).await {
+            println!("Broadcast {:?} failed, {}", message, err);
+        }
+    }
+
+    /// Hands a chat or receipt to every device of its recipient, queueing it for those offline.
+    /// Recipients without devices get it once their first device connects. No status while
+    /// a connection has it, on this node or another one, it reports once the message is written.
+    async fn deliver(&self, message: &Message) -> NavajoResult<Option<ChatStatus>> {
+        let to_address = match message {
+            ChatInfoMessage { to_address, .. } | ReceiptMessage { to_address, .. } => to_address,
+            _ => return Ok(Some(CHAT_STATUS_QUEUED)),
+        };
+        let devices: Vec<Device> = self.user_store.find_devices(to_address).await?
+            .into_iter().filter(|device| !device.session_revoked()).collect();
+        if devices.is_empty() {
+            self.message_queue.add_queue(message, None).await?;
+            return Ok(Some(CHAT_STATUS_QUEUED));
+        }
+        let mut in_flight = false;
+        let mut queued = false;
+        for device in &devices {
+            if self.call_device(to_address, &device.device_id, message).await {
+                in_flight = true;
+            } else {
+                match self.message_queue.add_queue(message, Some(&device.device_id)).await {
+                    Ok(_) => queued = true,
+                    Err(err) => println!("Queue message for {:?} failed, {}", device.device_id, err),
+                }
+            }
+        }
+        match (in_flight, queued) {
+            (true, _) => Ok(None),
+            (false, true) => Ok(Some(CHAT_STATUS_QUEUED)),
+            (false, false) => Err(NavajoError::new(DB_ERROR)),
+        }
+    }
+
+    /// Hands `message` to the connection the device pinged from, on this node or another one.
+    /// False if it has none.
+    async fn call_device(&self, address: &str, device_id: &str, message: &Message) -> bool {
+        if self.call_local_device(address, device_id, message) {
+            return true;
+        }
+        match self.cluster.find_nodes(address).await {
+            Ok(nodes) => match nodes.get(device_id) {
+                Some(node_id) => self.forward(node_id, address, device_id, message).await,
+                None => false,
+            },
+            Err(err) => {
+                println!("Find node of {:?} failed, {}", device_id, err);
+                false
+            }
+        }
+    }
+
+    fn call_local_device(&self, address: &str, device_id: &str, message: &Message) -> bool {
+        let ip = match self.devices.get(address).and_then(|devices| devices.get(device_id).cloned()) {
+            Some(ip) => ip,
+            None => return false,
+        };
+        match self.connections.get(&ip) {
+            Some(connection) => connection.call(message),
+            None => false,
+        }
+    }
+
+    /// Hands `message` to the node of a device connected elsewhere, false if it isn't listening.
+    async fn forward(&self, node_id: &str, address: &str, device_id: &str, message: &Message) -> bool {
+        // This node has no connection for it, a stale entry
+        if node_id == self.cluster.node_id() {
+            return false;
+        }
+        let forward = ClusterMessage::Forward {
+            address: address.to_string(),
+            device_id: device_id.to_string(),
+            message: message.clone(),
+        };
+        match self.cluster.send(node_id, &forward).await {
+            Ok(sent) => sent,
+            Err(err) => {
+                println!("Forward message to node {:?} failed, {}", node_id, err);
+                false
+            }
+        }
+    }
+
+    /// Hands `message` to every online device of `address`, false if none got it.
+    async fn call_address(&self, address: &str, message: &Message) -> bool {
+        let local_device_ids: Vec<String> = match self.devices.get(address) {
+            Some(devices) => devices.keys().cloned().collect(),
+            None => vec![],
+        };
+        let mut called = false;
+        for device_id in &local_device_ids {
+            called |= self.call_local_device(address, device_id, message);
+        }
+        match self.cluster.find_nodes(address).await {
+            Ok(nodes) => {
+                for (device_id, node_id) in nodes {
+                    if !local_device_ids.contains(&device_id) {
+                        called |= self.forward(&node_id, address, &device_id, message).await;
+                    }
+                }
+            }
+            Err(err) => println!("Find nodes of {:?} failed, {}", address, err),
+        }
+        called
+    }
+
+    /// Sends the command to every connection bound to the device on this node.
+    fn tell_device(&self, address: &str, device_id: &str, command: impl Fn() -> ConnectionCommand) {
+        let peer_addrs: Vec<String> = match self.bound.get(&(address.to_string(), device_id.to_string())) {
+            Some(peer_addrs) => peer_addrs.iter().cloned().collect(),
+            None => return,
+        };
+        // Not holding the map of bound connections while looking up the connections
+        for peer_addr in peer_addrs {
+            if let Some(connection) = self.connections.get(&peer_addr) {
+                connection.tell(command());
+            }
+        }
+    }
+
+    async fn notify_sender(&self, chat: &Message, status: ChatStatus) {
+        if let (ChatInfoMessage { from_address, .. }, Some(status_message)) = (chat, Message::chat_status(chat, status)) {
+            self.call_address(from_address, &status_message).await;
+        }
+    }
+}
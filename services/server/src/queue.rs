@@ -1,14 +1,24 @@
+use std::collections::HashMap;
 use std::sync::Arc;
+use std::time::{SystemTime, UNIX_EPOCH};
+use async_trait::async_trait;
+use common::errors::NavajoResult;
 use ncrypto::algo::base64::{decode_from_str, encode_to_str};
-use p2p::message::Message;
-use crate::db::redis::RedisClient;
+use p2p::message::{ChatStatus, Message};
+use crate::db::redis::{RedisClient, StreamBounds};
+use crate::store::{Accepted, CHAT_ACCEPTED_EXPIRE_SECONDS, CHAT_ACCEPTING_EXPIRE_SECONDS, CHAT_MESSAGE_EXPIRE_SECONDS, MAX_QUEUE_SIZE, MessageQueue, queue_name, queued_recipient, QueuedMessage};
 
-const CHAT_MESSAGE_EXPIRE_SECONDS: u64 = 180 * 24 * 60 * 60; // 180 days
+const KEY_MESSAGE_QUEUE_STREAM: &str = "key_message_queue_stream:";
+const KEY_CHAT_ACCEPTED: &str = "key_chat_accepted:";
+// Stored while the chat is being handled, before its status is known
+const CHAT_ACCEPTING: &str = "accepting";
+const STREAM_FIELD_MESSAGE: &str = "message";
 
+// Queues written before the stream layout, drained into the stream on first read
 const KEY_MESSAGE_QUEUE_ADDRESS: &str = "key_message_queue_address:";
-
 const STORE_SPLITER: &str = ">";
 
+/// Offline messages in Redis, one stream per queue.
 pub struct QueueManager {
     redis_client: Arc<RedisClient>,
 }
@@ -18,31 +28,163 @@ impl QueueManager {
         Arc::new(Self { redis_client })
     }
 
-    pub async fn acquire_queue(&self, address: &str) -> Option<Vec<Message>> {
-        let key = format!("{}{}", KEY_MESSAGE_QUEUE_ADDRESS, address);
-        let value = self.redis_client.get(&key).await?;
-        let splits: Vec<&str> = value.split(STORE_SPLITER).collect();
-        let messages: Vec<Message> = splits.iter().map(|item| {
-            decode_from_str(item).into()
-        }).collect();
-        Some(messages)
+    /// Reads the queues in one round trip, in order.
+    async fn read_queues(&self, queues: &[String]) -> NavajoResult<Vec<QueuedMessage>> {
+        let keys: Vec<String> = queues.iter().map(|queue| stream_key(queue)).collect();
+        let streams = self.redis_client.stream_ranges(&keys, STREAM_FIELD_MESSAGE, &stream_bounds().min_id).await?;
+        let mut messages = vec![];
+        let mut unreadable: HashMap<String, Vec<String>> = HashMap::new();
+        for ((queue, key), entries) in queues.iter().zip(keys).zip(streams) {
+            for (id, value) in entries {
+                match decode_from_str(&value).ok().and_then(|data| Message::try_from(data).ok()) {
+                    Some(message) => messages.push(QueuedMessage { id, queue: queue.clone(), message }),
+                    None => unreadable.entry(key.clone()).or_default().push(id),
+                }
+            }
+        }
+        for (key, ids) in &unreadable {
+            println!("Drop {} unreadable queued messages from {:?}", ids.len(), key);
+        }
+        self.redis_client.stream_dels(&unreadable).await?;
+        Ok(messages)
     }
 
-    pub async fn add_queue(&self, message: &Message) {
-        let json: Vec<u8> = message.into();
-        if let Message::ChatInfoMessage { to_address, .. } = message {
-            let key = format!("{}{}", KEY_MESSAGE_QUEUE_ADDRESS, to_address);
-            let value = encode_to_str(&json);
-            let stored_value: String = self.redis_client.get(&key).await.map_or_else(
-                || value.clone(),
-                |current| format!("{}{}{}", current, STORE_SPLITER, value.clone())
-            );
-            self.redis_client.set_ex(&key, &stored_value, CHAT_MESSAGE_EXPIRE_SECONDS as usize).await
+    async fn push(&self, queue: &str, value: &str) -> NavajoResult<()> {
+        let (_, len) = self.redis_client.stream_add(&stream_key(queue), STREAM_FIELD_MESSAGE, value, &stream_bounds()).await?;
+        if len >= MAX_QUEUE_SIZE {
+            println!("Queue of {:?} is full, dropped its oldest message", queue);
         }
+        Ok(())
     }
 
-    pub async fn remove(&self, address: &str) {
+    async fn migrate_legacy_queue(&self, address: &str) -> NavajoResult<()> {
         let key = format!("{}{}", KEY_MESSAGE_QUEUE_ADDRESS, address);
-        self.redis_client.remove(&key).await
+        self.redis_client.stream_add_split(&key, STORE_SPLITER, &stream_key(address), STREAM_FIELD_MESSAGE, &stream_bounds())
+            .await.map(|_| ())
+    }
+}
+
+#[async_trait]
+impl MessageQueue for QueueManager {
+    async fn acquire_queue(&self, address: &str, device_id: &str) -> NavajoResult<Vec<QueuedMessage>> {
+        self.migrate_legacy_queue(address).await?;
+        self.read_queues(&[queue_name(address, Some(device_id)), queue_name(address, None)]).await
+    }
+
+    async fn add_queue(&self, message: &Message, device_id: Option<&str>) -> NavajoResult<()> {
+        match queued_recipient(message) {
+            Some(to_address) => {
+                let json: Vec<u8> = message.into();
+                self.push(&queue_name(to_address, device_id), &encode_to_str(&json)).await
+            }
+            None => Ok(()),
+        }
     }
-}
\ No newline at end of file
+
+    async fn remove_queue(&self, address: &str, device_id: &str) {
+        if let Err(err) = self.redis_client.remove(&stream_key(&queue_name(address, Some(device_id)))).await {
+            println!("Remove queue of {:?} failed, {}", device_id, err);
+        }
+    }
+
+    async fn ack(&self, delivered: &[QueuedMessage]) -> NavajoResult<()> {
+        let mut ids_by_key: HashMap<String, Vec<String>> = HashMap::new();
+        for message in delivered {
+            ids_by_key.entry(stream_key(&message.queue)).or_default().push(message.id.clone());
+        }
+        self.redis_client.stream_dels(&ids_by_key).await
+    }
+
+    async fn accept(&self, from_address: &str, request_id: &str) -> NavajoResult<Accepted> {
+        let key = accepted_key(from_address, request_id);
+        let fresh = self.redis_client.set_nx_ex(&key, CHAT_ACCEPTING, CHAT_ACCEPTING_EXPIRE_SECONDS as usize).await?;
+        if fresh {
+            return Ok(Accepted::New);
+        }
+        // Also while released in between, the next resend claims it again
+        let status = self.redis_client.get(&key).await?.and_then(|status| status.parse().ok());
+        Ok(status.map_or(Accepted::InProgress, Accepted::Done))
+    }
+
+    async fn set_accepted_status(&self, from_address: &str, request_id: &str, status: ChatStatus) {
+        let key = accepted_key(from_address, request_id);
+        if let Err(err) = self.redis_client.set_ex(&key, &status.to_string(), CHAT_ACCEPTED_EXPIRE_SECONDS as usize).await {
+            println!("Save status of {:?} failed, {}", request_id, err);
+        }
+    }
+
+    async fn release(&self, from_address: &str, request_id: &str) {
+        if let Err(err) = self.redis_client.remove(&accepted_key(from_address, request_id)).await {
+            println!("Release {:?} failed, {}", request_id, err);
+        }
+    }
+}
+
+fn accepted_key(from_address: &str, request_id: &str) -> String {
+    format!("{}{}:{}", KEY_CHAT_ACCEPTED, from_address, request_id)
+}
+
+fn stream_key(queue: &str) -> String {
+    format!("{}{}", KEY_MESSAGE_QUEUE_STREAM, queue)
+}
+
+/// Stream ids start with their insertion time in ms, entries below the min id have expired.
+/// A queue nothing was added to for as long is gone.
+fn stream_bounds() -> StreamBounds {
+    let now = SystemTime::now().duration_since(UNIX_EPOCH).unwrap().as_millis();
+    let expire_ms = CHAT_MESSAGE_EXPIRE_SECONDS as u128 * 1000;
+    StreamBounds {
+        max_len: MAX_QUEUE_SIZE,
+        min_id: format!("{}", now.saturating_sub(expire_ms)),
+        expire_secs: CHAT_MESSAGE_EXPIRE_SECONDS as usize,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::env;
+    use uuid::Uuid;
+    use ncrypto::algo::base64::encode_to_str;
+    use p2p::message::Message;
+    use crate::db::RedisConfig;
+    use crate::db::redis::RedisClient;
+    use crate::queue::{KEY_MESSAGE_QUEUE_ADDRESS, QueueManager, STORE_SPLITER};
+    use crate::store::MessageQueue;
+    use crate::store::tests::check_message_queue;
+
+    #[actix_rt::test]
+    async fn test_redis_queue() {
+        let host = env::var("NAVAJO_TEST_REDIS_URL").unwrap_or_else(|_| String::from("redis://127.0.0.1/"));
+        let queue = QueueManager::new(RedisClient::new(RedisConfig { host, connect_timeout_ms: 1000, response_timeout_ms: 1000 }).unwrap());
+        check_message_queue(queue.as_ref()).await;
+    }
+
+    #[actix_rt::test]
+    async fn test_redis_legacy_queue() {
+        let host = env::var("NAVAJO_TEST_REDIS_URL").unwrap_or_else(|_| String::from("redis://127.0.0.1/"));
+        let redis_client = RedisClient::new(RedisConfig { host, connect_timeout_ms: 1000, response_timeout_ms: 1000 }).unwrap();
+        let queue = QueueManager::new(redis_client.clone());
+        let bob = Uuid::new_v4().to_string();
+        let chats: Vec<Message> = (0..2).map(|_| Message::ChatInfoMessage {
+            common_info: Default::default(),
+            from_address: String::from("alice"),
+            to_address: bob.clone(),
+            info_type: 0,
+            content: String::from("hi"),
+        }).collect();
+        let legacy: Vec<String> = chats.iter().map(|chat| encode_to_str(&Vec::<u8>::from(chat))).collect();
+        let legacy_key = format!("{}{}", KEY_MESSAGE_QUEUE_ADDRESS, bob);
+        redis_client.set_ex(&legacy_key, &legacy.join(STORE_SPLITER), 60).await.unwrap();
+
+        // Moved into the stream of the address on first read, in order
+        let queued = queue.acquire_queue(&bob, "phone").await.unwrap();
+        let request_ids: Vec<&str> = queued.iter().map(|mes| mes.message.common_info().request_id.as_str()).collect();
+        let expected: Vec<&str> = chats.iter().map(|chat| chat.common_info().request_id.as_str()).collect();
+        assert_eq!(request_ids, expected);
+        assert!(redis_client.get(&legacy_key).await.unwrap().is_none());
+        assert_eq!(queue.acquire_queue(&bob, "laptop").await.unwrap().len(), 2);
+
+        queue.ack(&queued).await.unwrap();
+        assert!(queue.acquire_queue(&bob, "laptop").await.unwrap().is_empty());
+    }
+}
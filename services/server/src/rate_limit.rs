@@ -0,0 +1,36 @@
+use std::sync::Arc;
+use std::time::{SystemTime, UNIX_EPOCH};
+use common::errors::{NavajoError, NavajoResult, RATE_LIMITED_ERROR};
+use crate::store::NonceCache;
+
+const KEY_RATE_LIMIT: &str = "key_rate_limit:";
+
+pub const SCOPE_PREKEY_BUNDLE: &str = "prekey_bundle";
+
+/// Each bundle handed out uses up a one-time prekey of the target.
+pub const PREKEY_BUNDLE_LIMIT: u32 = 5;
+pub const PREKEY_BUNDLE_WINDOW_SECONDS: u64 = 60;
+
+/// Allows a number of requests per fixed window. Each request takes a slot, a nonce that
+/// expires with the window, so the limit holds across nodes sharing the nonce cache.
+pub struct RateLimiter {
+    nonce_cache: Arc<dyn NonceCache>,
+}
+
+impl RateLimiter {
+    pub fn new(nonce_cache: Arc<dyn NonceCache>) -> Arc<Self> {
+        Arc::new(Self { nonce_cache })
+    }
+
+    pub async fn check(&self, scope: &str, key: &str, limit: u32, window_secs: u64) -> NavajoResult<()> {
+        let now = SystemTime::now().duration_since(UNIX_EPOCH).unwrap().as_secs();
+        let window = now / window_secs;
+        for slot in 0..limit {
+            let key = format!("{}{}:{}:{}:{}", KEY_RATE_LIMIT, scope, key, window, slot);
+            if self.nonce_cache.insert(&key, window_secs).await? {
+                return Ok(());
+            }
+        }
+        Err(NavajoError::new(RATE_LIMITED_ERROR))
+    }
+}
@@ -0,0 +1,117 @@
+use std::sync::Arc;
+use std::time::{SystemTime, UNIX_EPOCH};
+use common::errors::{NavajoError, NavajoResult, REPLAY_ERROR, STALE_REQUEST_ERROR};
+use crate::server::SESSION_EXPIRE_MS;
+use crate::store::NonceCache;
+
+pub const REQUEST_MAX_SKEW_MS: u128 = 5 * 60 * 1000; // 5 minutes
+
+// Nonces only need to outlive the window in which their timestamp is accepted
+const NONCE_EXPIRE_SECONDS: u64 = 2 * (REQUEST_MAX_SKEW_MS / 1000) as u64;
+
+// Without a timestamp a request can be replayed whenever its nonce is forgotten, so it is
+// kept as long as the session the request created
+const LEGACY_NONCE_EXPIRE_SECONDS: u64 = (SESSION_EXPIRE_MS / 1000) as u64;
+
+const KEY_REPLAY_NONCE: &str = "key_replay_nonce:";
+
+pub const SCOPE_CREATE_SESSION: &str = "create_session";
+pub const SCOPE_P2P: &str = "p2p";
+pub const SCOPE_PREKEY_UPLOAD: &str = "prekey_upload";
+pub const SCOPE_REVOKE_SESSION: &str = "revoke_session";
+pub const SCOPE_REMOVE_DEVICE: &str = "remove_device";
+pub const SCOPE_LIST_DEVICES: &str = "list_devices";
+
+pub struct ReplayGuard {
+    nonce_cache: Arc<dyn NonceCache>,
+}
+
+impl ReplayGuard {
+    pub fn new(nonce_cache: Arc<dyn NonceCache>) -> Arc<Self> {
+        Arc::new(Self { nonce_cache })
+    }
+
+    pub fn check_time(&self, time_ms: u128) -> NavajoResult<()> {
+        let now = SystemTime::now().duration_since(UNIX_EPOCH).unwrap().as_millis();
+        if now.abs_diff(time_ms) > REQUEST_MAX_SKEW_MS {
+            return Err(NavajoError::new(STALE_REQUEST_ERROR));
+        }
+        Ok(())
+    }
+
+    pub async fn check_nonce(&self, scope: &str, nonce: &str) -> NavajoResult<()> {
+        self.insert_nonce(scope, nonce, NONCE_EXPIRE_SECONDS).await
+    }
+
+    /// For requests of old clients, which have no timestamp to check.
+    pub async fn check_legacy_nonce(&self, scope: &str, nonce: &str) -> NavajoResult<()> {
+        self.insert_nonce(scope, nonce, LEGACY_NONCE_EXPIRE_SECONDS).await
+    }
+
+    async fn insert_nonce(&self, scope: &str, nonce: &str, secs: u64) -> NavajoResult<()> {
+        let key = format!("{}{}:{}", KEY_REPLAY_NONCE, scope, nonce);
+        let fresh = self.nonce_cache.insert(&key, secs).await?;
+        if !fresh {
+            return Err(NavajoError::new(REPLAY_ERROR));
+        }
+        Ok(())
+    }
+
+    pub async fn check(&self, scope: &str, nonce: &str, time_ms: u128) -> NavajoResult<()> {
+        self.check_time(time_ms)?;
+        self.check_nonce(scope, nonce).await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashMap;
+    use std::sync::{Arc, Mutex};
+    use async_trait::async_trait;
+    use common::errors::{NavajoResult, REPLAY_ERROR};
+    use crate::replay::{LEGACY_NONCE_EXPIRE_SECONDS, NONCE_EXPIRE_SECONDS, ReplayGuard, SCOPE_CREATE_SESSION};
+    use crate::store::NonceCache;
+
+    /// Forgets keys once its clock passes their expiry, the clock only moves when told.
+    #[derive(Default)]
+    struct ClockNonceCache {
+        now: Mutex<u64>,
+        expiries: Mutex<HashMap<String, u64>>,
+    }
+
+    impl ClockNonceCache {
+        fn advance(&self, secs: u64) {
+            *self.now.lock().unwrap() += secs;
+        }
+    }
+
+    #[async_trait]
+    impl NonceCache for ClockNonceCache {
+        async fn insert(&self, key: &str, secs: u64) -> NavajoResult<bool> {
+            let now = *self.now.lock().unwrap();
+            let mut expiries = self.expiries.lock().unwrap();
+            if expiries.get(key).is_some_and(|expiry| *expiry > now) {
+                return Ok(false);
+            }
+            expiries.insert(key.to_string(), now + secs);
+            Ok(true)
+        }
+    }
+
+    #[actix_rt::test]
+    async fn test_legacy_nonce_outlives_timestamp_window() {
+        let cache = Arc::new(ClockNonceCache::default());
+        let guard = ReplayGuard::new(cache.clone());
+        guard.check_nonce(SCOPE_CREATE_SESSION, "signed").await.unwrap();
+        guard.check_legacy_nonce(SCOPE_CREATE_SESSION, "legacy").await.unwrap();
+
+        // Signed requests are stale by now, their timestamp stops the replay
+        cache.advance(NONCE_EXPIRE_SECONDS);
+        guard.check_nonce(SCOPE_CREATE_SESSION, "signed").await.unwrap();
+        let replayed = guard.check_legacy_nonce(SCOPE_CREATE_SESSION, "legacy").await;
+        assert!(replayed.unwrap_err().is(&REPLAY_ERROR));
+
+        cache.advance(LEGACY_NONCE_EXPIRE_SECONDS - NONCE_EXPIRE_SECONDS - 1);
+        assert!(guard.check_legacy_nonce(SCOPE_CREATE_SESSION, "legacy").await.is_err());
+    }
+}
@@ -1,10 +1,28 @@
-use actix_web::{HttpResponse, post, Responder, web};
-use common::beans::{ApiResponse, DeviceInfoRequest};
+use actix_web::{get, HttpRequest, HttpResponse, post, Responder, web};
+use serde::Deserialize;
+use common::beans::{ApiResponse, DeviceInfoRequest, ListDevicesRequest, PrekeyUploadRequest, RemoveDeviceRequest, RevokeSessionRequest};
 use crate::errors::error_response;
 use crate::Server;
 
+#[derive(Deserialize)]
+struct AddressInfo {
+    address: String,
+}
+
 pub fn device_scope_cfg(cfg: &mut web::ServiceConfig) {
-    cfg.service(create_session);
+    cfg
+        .service(create_session)
+        .service(revoke_session)
+        .service(list_devices)
+        .service(remove_device)
+        .service(public_key);
+}
+
+pub fn prekey_scope_cfg(cfg: &mut web::ServiceConfig) {
+    cfg
+        .service(upload_prekeys)
+        .service(prekey_count)
+        .service(prekey_bundle);
 }
 
 #[post("/create_session")]
@@ -17,4 +35,86 @@ async fn create_session(data: web::Data<Server>, body: web::Json<DeviceInfoReque
             HttpResponse::Ok().json(response)
         }
     )
-}
\ No newline at end of file
+}
+
+#[post("/revoke_session")]
+async fn revoke_session(data: web::Data<Server>, body: web::Json<RevokeSessionRequest>) -> impl Responder {
+    let request = body.0;
+    data.revoke_session(&request).await.map_or_else(
+        error_response,
+        |_|{
+            let response = ApiResponse::<()>::empty_success();
+            HttpResponse::Ok().json(response)
+        }
+    )
+}
+
+#[post("/list")]
+async fn list_devices(data: web::Data<Server>, body: web::Json<ListDevicesRequest>) -> impl Responder {
+    let request = body.0;
+    data.list_devices(&request).await.map_or_else(
+        error_response,
+        |res|{
+            let response = ApiResponse::success(res);
+            HttpResponse::Ok().json(response)
+        }
+    )
+}
+
+#[post("/remove")]
+async fn remove_device(data: web::Data<Server>, body: web::Json<RemoveDeviceRequest>) -> impl Responder {
+    let request = body.0;
+    data.remove_device(&request).await.map_or_else(
+        error_response,
+        |_|{
+            let response = ApiResponse::<()>::empty_success();
+            HttpResponse::Ok().json(response)
+        }
+    )
+}
+
+#[get("/public_key")]
+async fn public_key(data: web::Data<Server>, info: web::Query<AddressInfo>) -> impl Responder {
+    data.get_public_key(&info.address).await.map_or_else(
+        error_response,
+        |res|{
+            let response = ApiResponse::success(res);
+            HttpResponse::Ok().json(response)
+        }
+    )
+}
+
+#[post("/upload")]
+async fn upload_prekeys(data: web::Data<Server>, body: web::Json<PrekeyUploadRequest>) -> impl Responder {
+    let request = body.0;
+    data.upload_prekeys(&request).await.map_or_else(
+        error_response,
+        |res|{
+            let response = ApiResponse::success(res);
+            HttpResponse::Ok().json(response)
+        }
+    )
+}
+
+#[get("/count")]
+async fn prekey_count(data: web::Data<Server>, info: web::Query<AddressInfo>) -> impl Responder {
+    data.count_prekeys(&info.address).await.map_or_else(
+        error_response,
+        |res|{
+            let response = ApiResponse::success(res);
+            HttpResponse::Ok().json(response)
+        }
+    )
+}
+
+#[get("/bundle")]
+async fn prekey_bundle(data: web::Data<Server>, info: web::Query<AddressInfo>, req: HttpRequest) -> impl Responder {
+    let requester = req.peer_addr().map(|addr| addr.ip().to_string()).unwrap_or_default();
+    data.get_prekey_bundle(&requester, &info.address).await.map_or_else(
+        error_response,
+        |res|{
+            let response = ApiResponse::success(res);
+            HttpResponse::Ok().json(response)
+        }
+    )
+}
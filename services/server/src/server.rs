@@ -1,24 +1,42 @@
 use std::sync::Arc;
+use std::time::{SystemTime, UNIX_EPOCH};
 use actix_web::{App, HttpServer, web};
 use actix_web::web::Data;
+use tokio::sync::mpsc::Sender;
 use uuid::Uuid;
-use common::beans::{DeviceInfoRequest, DeviceInfoResponse};
-use common::errors::{NavajoError, NavajoResult, VERIFY_SIGN_ERROR};
-use ncrypto::algo::base64::encode_to_str;
+use common::beans::{DeviceInfoRequest, DeviceInfoResponse, DeviceListResponse, LinkedDevice, ListDevicesRequest, PrekeyBundle, PrekeyCountResponse, PrekeyUploadRequest, PublicKeyResponse, RemoveDeviceRequest, RevokeSessionRequest, SESSION_PROTOCOL_LEGACY, SESSION_PROTOCOL_SIGNED};
+use common::errors::{INVALID_ADDRESS_ERROR, INVALID_DEVICE_ID, INVALID_DH_ERROR, INVALID_PARAM_ERROR, INVALID_SESSION, NavajoError, NavajoResult, USER_NOT_FOUND, VERIFY_SIGN_ERROR};
+use common::key_pair::KeyPair;
 use ncrypto::algo::diffie_hellman::DiffieHellman;
-use crate::db::models::User;
-use crate::db::repository::UserRepository;
-use crate::route::device_scope_cfg;
+use ncrypto::algo::kdf::{SessionKeys, SessionTranscript};
+use crate::db::models::{Device, IdentityKey, User};
+use crate::p2p::channel::ChannelSignal;
+use crate::p2p::channel::ChannelSignal::{RevokeSession, SessionCreated};
+use crate::rate_limit::{PREKEY_BUNDLE_LIMIT, PREKEY_BUNDLE_WINDOW_SECONDS, RateLimiter, SCOPE_PREKEY_BUNDLE};
+use crate::replay::{ReplayGuard, SCOPE_CREATE_SESSION, SCOPE_LIST_DEVICES, SCOPE_PREKEY_UPLOAD, SCOPE_REMOVE_DEVICE, SCOPE_REVOKE_SESSION};
+use crate::route::{device_scope_cfg, prekey_scope_cfg};
+use crate::store::{MessageQueue, PrekeyStore, UserStore};
+
+/// Clients are told to upload more one-time prekeys below this count.
+const PREKEY_LOW_WATERMARK: u32 = 10;
+const MAX_PREKEYS_PER_UPLOAD: usize = 100;
+pub const SESSION_EXPIRE_MS: u128 = 30 * 24 * 60 * 60 * 1000; // 30 days
 
 #[derive(Clone)]
 pub struct Server {
     pub(crate) config: ServerConfig,
-    pub(crate) user_repository: Arc<UserRepository>,
+    pub(crate) user_store: Arc<dyn UserStore>,
+    pub(crate) message_queue: Arc<dyn MessageQueue>,
+    pub(crate) replay_guard: Arc<ReplayGuard>,
+    pub(crate) rate_limiter: Arc<RateLimiter>,
+    pub(crate) prekey_store: Arc<dyn PrekeyStore>,
+    pub(crate) p2p_signal_tx: Sender<ChannelSignal>,
 }
 
 #[derive(Clone)]
 pub struct ServerConfig {
     pub port: u16,
+    pub identity: Arc<KeyPair>,
 }
 
 impl Server {
@@ -29,6 +47,7 @@ impl Server {
             App::new()
                 .app_data(arc_state.clone())
                 .service(web::scope("device").configure(device_scope_cfg))
+                .service(web::scope("prekey").configure(prekey_scope_cfg))
         })
             .bind(("127.0.0.1", port))?
             .run()
@@ -36,34 +55,323 @@ impl Server {
     }
 
     pub async fn create_session(&self, info: &DeviceInfoRequest) -> NavajoResult<DeviceInfoResponse> {
-        if !info.verify_content() {
+        if !info.verify_address() {
+            Err(NavajoError::new(INVALID_ADDRESS_ERROR))
+        } else if !info.verify_content() {
             Err(NavajoError::new(VERIFY_SIGN_ERROR))
         } else {
+            self.check_replay(info).await?;
             self.logic_create_session(info).await
         }
     }
 
+    /// Invalidates the session of a device of the signing account and closes its connections.
+    pub async fn revoke_session(&self, request: &RevokeSessionRequest) -> NavajoResult<()> {
+        if !request.verify() {
+            return Err(NavajoError::new(VERIFY_SIGN_ERROR));
+        }
+        self.replay_guard.check(SCOPE_REVOKE_SESSION, &request.nonce, request.time_ms).await?;
+
+        let device = self.find_device(&request.address, &request.device_id).await?;
+        if device.session_revoked() {
+            return Err(NavajoError::new(INVALID_SESSION));
+        }
+        self.user_store.revoke_device_session(&device.address, &device.device_id, &device.session).await?;
+        self.close_device_connections(&device).await;
+        Ok(())
+    }
+
+    /// Unlinks a device of the signing account, which may be the signing device itself.
+    /// The device is logged out, logging in again links it anew.
+    pub async fn remove_device(&self, request: &RemoveDeviceRequest) -> NavajoResult<()> {
+        if !request.verify() {
+            return Err(NavajoError::new(VERIFY_SIGN_ERROR));
+        }
+        self.replay_guard.check(SCOPE_REMOVE_DEVICE, &request.nonce, request.time_ms).await?;
+
+        let device = self.find_device(&request.address, &request.device_id).await?;
+        self.user_store.remove_device(&device.address, &device.device_id).await?;
+        self.message_queue.remove_queue(&device.address, &device.device_id).await;
+        self.close_device_connections(&device).await;
+        Ok(())
+    }
+
+    pub async fn list_devices(&self, request: &ListDevicesRequest) -> NavajoResult<DeviceListResponse> {
+        if !request.verify() {
+            return Err(NavajoError::new(VERIFY_SIGN_ERROR));
+        }
+        self.replay_guard.check(SCOPE_LIST_DEVICES, &request.nonce, request.time_ms).await?;
+
+        let devices = self.user_store.find_devices(&request.address).await?;
+        Ok(DeviceListResponse {
+            devices: devices.iter().map(|device| LinkedDevice {
+                device_id: device.device_id.to_string(),
+                last_seen_ms: device.last_seen_ms as u128,
+                revoked: device.session_revoked(),
+            }).collect(),
+        })
+    }
+
+    async fn find_device(&self, address: &str, device_id: &str) -> NavajoResult<Device> {
+        self.user_store.find_device(address, device_id).await?
+            .ok_or_else(|| NavajoError::new(INVALID_DEVICE_ID))
+    }
+
+    async fn close_device_connections(&self, device: &Device) {
+        let signal = RevokeSession { address: device.address.clone(), device_id: device.device_id.clone() };
+        if self.p2p_signal_tx.send(signal).await.is_err() {
+            println!("P2P server is gone, connections of {:?} stay open", device.device_id);
+        }
+    }
+
+    /// Public keys are self-authenticating, clients check them against the address.
+    pub async fn get_public_key(&self, address: &str) -> NavajoResult<PublicKeyResponse> {
+        let user = self.user_store.find_user(address).await?
+            .filter(|user| !user.public_key.is_empty())
+            .ok_or_else(|| NavajoError::new(USER_NOT_FOUND))?;
+        Ok(PublicKeyResponse {
+            address: user.address.to_string(),
+            public_key: user.public_key.to_string(),
+        })
+    }
+
+    pub async fn upload_prekeys(&self, request: &PrekeyUploadRequest) -> NavajoResult<PrekeyCountResponse> {
+        if request.one_time_prekeys.len() > MAX_PREKEYS_PER_UPLOAD {
+            return Err(NavajoError::new(INVALID_PARAM_ERROR));
+        }
+        if !request.verify() {
+            return Err(NavajoError::new(VERIFY_SIGN_ERROR));
+        }
+        self.replay_guard.check(SCOPE_PREKEY_UPLOAD, &request.nonce, request.time_ms).await?;
+
+        let store = self.prekey_store.clone();
+        let identity = &request.identity;
+        let current = store.find_identity_key(&identity.address).await;
+        if current.is_some_and(|current| current.identity_key != identity.identity_key) {
+            store.remove_one_time_prekeys(&identity.address).await?;
+        }
+        store.save_identity_key(&IdentityKey::from(identity)).await?;
+        store.add_one_time_prekeys(&identity.address, &request.one_time_prekeys).await?;
+        self.count_prekeys(&identity.address).await
+    }
+
+    pub async fn count_prekeys(&self, address: &str) -> NavajoResult<PrekeyCountResponse> {
+        let count = self.prekey_store.count_one_time_prekeys(address).await?;
+        Ok(PrekeyCountResponse {
+            count,
+            low: count < PREKEY_LOW_WATERMARK,
+        })
+    }
+
+    /// Hands out one one-time prekey per call, or none once they ran out. Each requester
+    /// only gets a few bundles of an address per window, so it can't drain the prekeys.
+    pub async fn get_prekey_bundle(&self, requester: &str, address: &str) -> NavajoResult<PrekeyBundle> {
+        let key = format!("{}:{}", requester, address);
+        self.rate_limiter.check(SCOPE_PREKEY_BUNDLE, &key, PREKEY_BUNDLE_LIMIT, PREKEY_BUNDLE_WINDOW_SECONDS).await?;
+        let store = self.prekey_store.clone();
+        let identity = store.find_identity_key(address)
+            .await.ok_or_else(|| NavajoError::new(USER_NOT_FOUND))?;
+        let one_time_prekey = store.take_one_time_prekey(address).await?;
+        Ok(PrekeyBundle {
+            identity: (&identity).into(),
+            one_time_prekey,
+        })
+    }
+
+    async fn check_replay(&self, info: &DeviceInfoRequest) -> NavajoResult<()> {
+        // Old clients don't sign their timestamp, only the nonce can be trusted
+        if info.protocol_version >= SESSION_PROTOCOL_SIGNED {
+            self.replay_guard.check(SCOPE_CREATE_SESSION, &info.content, info.time_ms).await
+        } else {
+            self.replay_guard.check_legacy_nonce(SCOPE_CREATE_SESSION, &info.content).await
+        }
+    }
+
     async fn logic_create_session(&self, info: &DeviceInfoRequest) -> NavajoResult<DeviceInfoResponse> {
         let dh = DiffieHellman::new();
         let client_dh_pub = &info.dh_pub;
         let server_dh_pub = &dh.public_key_to_str();
-        let secret = dh.compute_shared_secret_from_str(client_dh_pub);
-        let secret = encode_to_str(&secret);
+        let shared_secret = dh.compute_shared_secret_from_str(client_dh_pub)
+            .map_err(|_| NavajoError::new(INVALID_DH_ERROR))?;
         let session = Uuid::new_v4().to_string();
-        let repo = self.user_repository.clone();
+        let protocol_version = info.protocol_version.min(SESSION_PROTOCOL_SIGNED);
+        let keys = if protocol_version == SESSION_PROTOCOL_LEGACY {
+            SessionKeys::legacy(&shared_secret)
+        } else {
+            let transcript = SessionTranscript {
+                session: &session,
+                device_id: &info.device_id,
+                client_dh_pub,
+                server_dh_pub,
+            };
+            SessionKeys::derive(&shared_secret, &transcript)
+        };
+        let secret = keys.encode_to_str();
+        let now = SystemTime::now().duration_since(UNIX_EPOCH).unwrap().as_millis();
+        let session_expire_ms = now + SESSION_EXPIRE_MS;
 
         let user = User {
             id: 0,
             address: info.address.to_string(),
             device_id: info.device_id.to_string(),
             session: session.clone(),
-            secret
+            secret: secret.clone(),
+            public_key: info.public_key.to_string(),
+            session_expire_ms: session_expire_ms as u64,
+        };
+        self.user_store.save_user(&user).await?;
+        // Each device keeps its own session, the user row only has the latest
+        let device = Device {
+            id: 0,
+            address: info.address.to_string(),
+            device_id: info.device_id.to_string(),
+            session: session.clone(),
+            secret,
+            session_expire_ms: session_expire_ms as u64,
+            last_seen_ms: now as u64,
         };
-        repo.insert_or_update(&user).await?;
-        Ok(DeviceInfoResponse {
+        self.user_store.save_device(&device).await?;
+        // Open connections of the device, here or on other nodes, seal with the new session
+        let signal = SessionCreated { address: device.address.clone(), device_id: device.device_id.clone() };
+        if self.p2p_signal_tx.send(signal).await.is_err() {
+            println!("P2P server is gone, connections of {:?} keep the old session", device.device_id);
+        }
+
+        let identity = &self.config.identity;
+        let mut response = DeviceInfoResponse {
             session,
-            dh_pub: server_dh_pub.to_string()
-        })
+            dh_pub: server_dh_pub.to_string(),
+            protocol_version,
+            time_ms: now,
+            server_public_key: identity.gen_public_key(),
+            sign: String::new(),
+            session_expire_ms,
+        };
+        response.sign = identity.sign(&response.transcript(info));
+        Ok(response)
     }
 }
 
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+    use std::time::{SystemTime, UNIX_EPOCH};
+    use tokio::sync::mpsc;
+    use uuid::Uuid;
+    use common::account::Account;
+    use common::beans::{identity_key_transcript, OneTimePrekey, PrekeyUploadRequest, signed_prekey_transcript, SignedIdentity};
+    use common::errors::{RATE_LIMITED_ERROR, REPLAY_ERROR, USER_NOT_FOUND, VERIFY_SIGN_ERROR};
+    use common::key_pair::KeyPair;
+    use crate::rate_limit::{PREKEY_BUNDLE_LIMIT, RateLimiter};
+    use crate::replay::ReplayGuard;
+    use crate::server::{Server, ServerConfig};
+    use crate::store::Stores;
+
+    fn server() -> Server {
+        let stores = Stores::memory();
+        // Signals to the P2P server are only sent by session and device routes
+        let (p2p_signal_tx, _) = mpsc::channel(1);
+        Server {
+            config: ServerConfig { port: 0, identity: Arc::new(KeyPair::new()) },
+            user_store: stores.user_store,
+            message_queue: stores.message_queue,
+            replay_guard: ReplayGuard::new(stores.nonce_cache.clone()),
+            rate_limiter: RateLimiter::new(stores.nonce_cache),
+            prekey_store: stores.prekey_store,
+            p2p_signal_tx,
+        }
+    }
+
+    /// The keys are opaque to the server, only their signatures are checked.
+    fn upload_request(account: &Account, identity_key: &str, key_ids: &[u32]) -> PrekeyUploadRequest {
+        let mut identity = SignedIdentity {
+            address: account.address.to_string(),
+            public_key: account.key_pair.gen_public_key(),
+            identity_key: identity_key.to_string(),
+            signed_prekey_id: 1,
+            signed_prekey: String::from("signed_prekey"),
+            ..Default::default()
+        };
+        identity.identity_key_sign = account.sign_data(&identity_key_transcript(&identity.identity_key));
+        identity.signed_prekey_sign = account.sign_data(&signed_prekey_transcript(1, &identity.signed_prekey));
+        let mut request = PrekeyUploadRequest {
+            identity,
+            one_time_prekeys: key_ids.iter().map(|key_id| OneTimePrekey {
+                key_id: *key_id,
+                prekey: format!("prekey_{}", key_id),
+            }).collect(),
+            nonce: Uuid::new_v4().to_string(),
+            time_ms: SystemTime::now().duration_since(UNIX_EPOCH).unwrap().as_millis(),
+            sign: String::new(),
+        };
+        request.sign = account.sign_data(&request.transcript());
+        request
+    }
+
+    #[actix_rt::test]
+    async fn test_prekeys() {
+        let server = server();
+        let alice = Account::new();
+
+        let err = server.get_prekey_bundle("requester", &alice.address).await.unwrap_err();
+        assert!(err.is(&USER_NOT_FOUND));
+
+        // Upload
+        let request = upload_request(&alice, "identity_key", &[1, 2]);
+        let count = server.upload_prekeys(&request).await.unwrap();
+        assert_eq!(count.count, 2);
+        assert!(count.low);
+        let err = server.upload_prekeys(&request).await.unwrap_err();
+        assert!(err.is(&REPLAY_ERROR));
+        let mut forged = upload_request(&alice, "identity_key", &[3]);
+        forged.one_time_prekeys.push(OneTimePrekey { key_id: 4, prekey: String::from("injected") });
+        let err = server.upload_prekeys(&forged).await.unwrap_err();
+        assert!(err.is(&VERIFY_SIGN_ERROR));
+        assert_eq!(server.count_prekeys(&alice.address).await.unwrap().count, 2);
+
+        // Each fetch consumes one one-time prekey, oldest first
+        let bundle = server.get_prekey_bundle("requester", &alice.address).await.unwrap();
+        assert!(bundle.identity.verify());
+        assert_eq!(bundle.identity.identity_key, "identity_key");
+        assert_eq!(bundle.one_time_prekey.unwrap().key_id, 1);
+        assert_eq!(server.count_prekeys(&alice.address).await.unwrap().count, 1);
+        let bundle = server.get_prekey_bundle("requester", &alice.address).await.unwrap();
+        assert_eq!(bundle.one_time_prekey.unwrap().key_id, 2);
+
+        // Once they ran out, the bundle comes without one
+        let bundle = server.get_prekey_bundle("requester", &alice.address).await.unwrap();
+        assert!(bundle.one_time_prekey.is_none());
+        assert_eq!(bundle.identity.identity_key, "identity_key");
+        assert_eq!(server.count_prekeys(&alice.address).await.unwrap().count, 0);
+
+        // A new identity key drops the prekeys uploaded with the old one
+        server.upload_prekeys(&upload_request(&alice, "identity_key", &[5])).await.unwrap();
+        let count = server.upload_prekeys(&upload_request(&alice, "new_identity_key", &[6])).await.unwrap();
+        assert_eq!(count.count, 1);
+        let bundle = server.get_prekey_bundle("other_requester", &alice.address).await.unwrap();
+        assert_eq!(bundle.one_time_prekey.unwrap().key_id, 6);
+    }
+
+    #[actix_rt::test]
+    async fn test_prekey_bundle_rate_limit() {
+        let server = server();
+        let alice = Account::new();
+        let bob = Account::new();
+        let key_ids: Vec<u32> = (1..=2 * PREKEY_BUNDLE_LIMIT).collect();
+        server.upload_prekeys(&upload_request(&alice, "identity_key", &key_ids)).await.unwrap();
+        server.upload_prekeys(&upload_request(&bob, "identity_key", &key_ids)).await.unwrap();
+
+        for _ in 0..PREKEY_BUNDLE_LIMIT {
+            server.get_prekey_bundle("requester", &alice.address).await.unwrap();
+        }
+        let err = server.get_prekey_bundle("requester", &alice.address).await.unwrap_err();
+        assert!(err.is(&RATE_LIMITED_ERROR));
+        // Refused fetches don't consume a prekey
+        assert_eq!(server.count_prekeys(&alice.address).await.unwrap().count, PREKEY_BUNDLE_LIMIT);
+
+        // Limited per requester and target
+        server.get_prekey_bundle("other_requester", &alice.address).await.unwrap();
+        server.get_prekey_bundle("requester", &bob.address).await.unwrap();
+    }
+}
@@ -0,0 +1,163 @@
+use std::sync::Arc;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::{Duration, Instant};
+use async_trait::async_trait;
+use dashmap::DashMap;
+use common::errors::NavajoResult;
+use crate::db::models::{Device, User};
+use crate::store::UserStore;
+
+/// How long devices stay cached. Other nodes tell about sessions they change, not about
+/// devices they see, so their last seen times can be this old.
+const CACHE_EXPIRE: Duration = Duration::from_secs(60);
+
+/// Past this many cached addresses the cache starts over.
+const MAX_CACHED_ADDRESSES: usize = 100_000;
+
+struct CachedDevices {
+    devices: Vec<Device>,
+    cached_at: Instant,
+}
+
+/// Keeps the devices of each address looked up, every relayed chat needs those of its
+/// recipient. Changes through it drop what they touch, those of other nodes are dropped
+/// with `forget_cached`.
+pub struct CachedUserStore {
+    inner: Arc<dyn UserStore>,
+    // By address
+    devices: DashMap<String, CachedDevices>,
+    // Bumped by every change, a lookup racing one doesn't keep what it read
+    generation: AtomicU64,
+}
+
+impl CachedUserStore {
+    pub fn new(inner: Arc<dyn UserStore>) -> Arc<Self> {
+        Arc::new(Self {
+            inner,
+            devices: DashMap::new(),
+            generation: AtomicU64::new(0),
+        })
+    }
+
+    fn forget(&self, address: &str) {
+        self.generation.fetch_add(1, Ordering::SeqCst);
+        self.devices.remove(address);
+    }
+}
+
+#[async_trait]
+impl UserStore for CachedUserStore {
+    async fn find_user(&self, address: &str) -> NavajoResult<Option<User>> {
+        self.inner.find_user(address).await
+    }
+
+    async fn save_user(&self, user: &User) -> NavajoResult<()> {
+        self.inner.save_user(user).await
+    }
+
+    async fn find_devices(&self, address: &str) -> NavajoResult<Vec<Device>> {
+        if let Some(cached) = self.devices.get(address) {
+            if cached.cached_at.elapsed() < CACHE_EXPIRE {
+                return Ok(cached.devices.clone());
+            }
+        }
+        let generation = self.generation.load(Ordering::SeqCst);
+        let devices = self.inner.find_devices(address).await?;
+        if self.devices.len() >= MAX_CACHED_ADDRESSES {
+            self.devices.clear();
+        }
+        self.devices.insert(address.to_string(), CachedDevices { devices: devices.clone(), cached_at: Instant::now() });
+        // Changed while it was read, what was read may be stale
+        if self.generation.load(Ordering::SeqCst) != generation {
+            self.devices.remove(address);
+        }
+        Ok(devices)
+    }
+
+    async fn find_device(&self, address: &str, device_id: &str) -> NavajoResult<Option<Device>> {
+        self.inner.find_device(address, device_id).await
+    }
+
+    async fn find_device_by_session(&self, session: &str) -> NavajoResult<Option<Device>> {
+        self.inner.find_device_by_session(session).await
+    }
+
+    async fn save_device(&self, device: &Device) -> NavajoResult<()> {
+        let result = self.inner.save_device(device).await;
+        self.forget(&device.address);
+        result
+    }
+
+    async fn touch_device(&self, address: &str, device_id: &str, last_seen_ms: u64) -> NavajoResult<()> {
+        self.inner.touch_device(address, device_id, last_seen_ms).await?;
+        if let Some(mut cached) = self.devices.get_mut(address) {
+            for device in cached.devices.iter_mut().filter(|device| device.device_id == device_id) {
+                device.last_seen_ms = last_seen_ms;
+            }
+        }
+        Ok(())
+    }
+
+    async fn revoke_device_session(&self, address: &str, device_id: &str, session: &str) -> NavajoResult<()> {
+        let result = self.inner.revoke_device_session(address, device_id, session).await;
+        self.forget(address);
+        result
+    }
+
+    async fn remove_device(&self, address: &str, device_id: &str) -> NavajoResult<()> {
+        let result = self.inner.remove_device(address, device_id).await;
+        self.forget(address);
+        result
+    }
+
+    fn forget_cached(&self, address: &str, _device_id: &str) {
+        self.forget(address);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::db::models::Device;
+    use crate::store::UserStore;
+    use crate::store::cache::CachedUserStore;
+    use crate::store::memory::MemoryUserStore;
+
+    fn device(address: &str, device_id: &str) -> Device {
+        Device {
+            id: 0,
+            address: address.to_string(),
+            device_id: device_id.to_string(),
+            session: format!("{}_session", device_id),
+            secret: String::from("secret"),
+            session_expire_ms: u64::MAX,
+            last_seen_ms: 0,
+        }
+    }
+
+    #[actix_rt::test]
+    async fn test_cached_user_store() {
+        let inner = MemoryUserStore::new();
+        let store = CachedUserStore::new(inner.clone());
+        store.save_device(&device("bob", "phone")).await.unwrap();
+        assert_eq!(store.find_devices("bob").await.unwrap().len(), 1);
+
+        // Changes through the cache are seen at once
+        store.save_device(&device("bob", "laptop")).await.unwrap();
+        assert_eq!(store.find_devices("bob").await.unwrap().len(), 2);
+        store.touch_device("bob", "phone", 42).await.unwrap();
+        let devices = store.find_devices("bob").await.unwrap();
+        assert_eq!(devices.iter().find(|device| device.device_id == "phone").unwrap().last_seen_ms, 42);
+        store.revoke_device_session("bob", "phone", "phone_session").await.unwrap();
+        let devices = store.find_devices("bob").await.unwrap();
+        assert!(devices.iter().find(|device| device.device_id == "phone").unwrap().session_revoked());
+
+        store.remove_device("bob", "laptop").await.unwrap();
+        assert_eq!(store.find_devices("bob").await.unwrap().len(), 1);
+
+        // Changes made elsewhere only once forgotten
+        inner.save_device(&device("bob", "tablet")).await.unwrap();
+        assert_eq!(store.find_devices("bob").await.unwrap().len(), 1);
+        store.forget_cached("bob", "tablet");
+        assert_eq!(store.find_devices("bob").await.unwrap().len(), 2);
+    }
+}
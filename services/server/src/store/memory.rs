@@ -0,0 +1,279 @@
+use std::collections::{HashMap, VecDeque};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+use async_trait::async_trait;
+use common::beans::OneTimePrekey;
+use common::errors::NavajoResult;
+use p2p::message::{ChatStatus, Message};
+use crate::db::models::{Device, IdentityKey, User};
+use crate::store::{Accepted, CHAT_ACCEPTED_EXPIRE_SECONDS, CHAT_ACCEPTING_EXPIRE_SECONDS, MAX_QUEUE_SIZE, MessageQueue, NonceCache, PrekeyStore, queue_name, queued_recipient, QueuedMessage, UserStore};
+
+// In-memory backends, for running the server without MySQL or Redis. Nothing
+// survives a restart, and nothing expires except nonces.
+
+#[derive(Default)]
+pub struct MemoryUserStore {
+    users: Mutex<HashMap<String, User>>,
+    // By address and device_id
+    devices: Mutex<HashMap<(String, String), Device>>,
+}
+
+impl MemoryUserStore {
+    pub fn new() -> Arc<Self> {
+        Arc::new(Self::default())
+    }
+}
+
+fn device_key(address: &str, device_id: &str) -> (String, String) {
+    (address.to_string(), device_id.to_string())
+}
+
+#[async_trait]
+impl UserStore for MemoryUserStore {
+    async fn find_user(&self, address: &str) -> NavajoResult<Option<User>> {
+        Ok(self.users.lock().unwrap().get(address).cloned())
+    }
+
+    async fn save_user(&self, user: &User) -> NavajoResult<()> {
+        self.users.lock().unwrap().insert(user.address.clone(), user.clone());
+        Ok(())
+    }
+
+    async fn find_devices(&self, address: &str) -> NavajoResult<Vec<Device>> {
+        let devices = self.devices.lock().unwrap();
+        Ok(devices.values().filter(|device| device.address == address).cloned().collect())
+    }
+
+    async fn find_device(&self, address: &str, device_id: &str) -> NavajoResult<Option<Device>> {
+        Ok(self.devices.lock().unwrap().get(&device_key(address, device_id)).cloned())
+    }
+
+    async fn find_device_by_session(&self, session: &str) -> NavajoResult<Option<Device>> {
+        let devices = self.devices.lock().unwrap();
+        Ok(devices.values().find(|device| device.session == session).cloned())
+    }
+
+    async fn save_device(&self, device: &Device) -> NavajoResult<()> {
+        self.devices.lock().unwrap().insert(device_key(&device.address, &device.device_id), device.clone());
+        Ok(())
+    }
+
+    async fn touch_device(&self, address: &str, device_id: &str, last_seen_ms: u64) -> NavajoResult<()> {
+        if let Some(device) = self.devices.lock().unwrap().get_mut(&device_key(address, device_id)) {
+            device.last_seen_ms = last_seen_ms;
+        }
+        Ok(())
+    }
+
+    async fn revoke_device_session(&self, address: &str, device_id: &str, session: &str) -> NavajoResult<()> {
+        if let Some(device) = self.devices.lock().unwrap().get_mut(&device_key(address, device_id)) {
+            if device.session == session {
+                device.session.clear();
+                device.secret.clear();
+                device.session_expire_ms = 0;
+            }
+        }
+        Ok(())
+    }
+
+    async fn remove_device(&self, address: &str, device_id: &str) -> NavajoResult<()> {
+        self.devices.lock().unwrap().remove(&device_key(address, device_id));
+        Ok(())
+    }
+}
+
+#[derive(Default)]
+pub struct MemoryPrekeyStore {
+    identity_keys: Mutex<HashMap<String, IdentityKey>>,
+    // Oldest first
+    one_time_prekeys: Mutex<HashMap<String, VecDeque<OneTimePrekey>>>,
+}
+
+impl MemoryPrekeyStore {
+    pub fn new() -> Arc<Self> {
+        Arc::new(Self::default())
+    }
+}
+
+#[async_trait]
+impl PrekeyStore for MemoryPrekeyStore {
+    async fn find_identity_key(&self, address: &str) -> Option<IdentityKey> {
+        self.identity_keys.lock().unwrap().get(address).cloned()
+    }
+
+    async fn save_identity_key(&self, key: &IdentityKey) -> NavajoResult<()> {
+        self.identity_keys.lock().unwrap().insert(key.address.clone(), key.clone());
+        Ok(())
+    }
+
+    async fn remove_one_time_prekeys(&self, address: &str) -> NavajoResult<()> {
+        self.one_time_prekeys.lock().unwrap().remove(address);
+        Ok(())
+    }
+
+    async fn add_one_time_prekeys(&self, address: &str, prekeys: &[OneTimePrekey]) -> NavajoResult<()> {
+        let mut one_time_prekeys = self.one_time_prekeys.lock().unwrap();
+        let stored = one_time_prekeys.entry(address.to_string()).or_default();
+        for prekey in prekeys {
+            if !stored.iter().any(|stored| stored.key_id == prekey.key_id) {
+                stored.push_back(prekey.clone());
+            }
+        }
+        Ok(())
+    }
+
+    async fn count_one_time_prekeys(&self, address: &str) -> NavajoResult<u32> {
+        let one_time_prekeys = self.one_time_prekeys.lock().unwrap();
+        Ok(one_time_prekeys.get(address).map_or(0, |stored| stored.len() as u32))
+    }
+
+    async fn take_one_time_prekey(&self, address: &str) -> NavajoResult<Option<OneTimePrekey>> {
+        let mut one_time_prekeys = self.one_time_prekeys.lock().unwrap();
+        Ok(one_time_prekeys.get_mut(address).and_then(|stored| stored.pop_front()))
+    }
+}
+
+#[derive(Default)]
+struct Queues {
+    next_id: u64,
+    entries: HashMap<String, VecDeque<(String, Message)>>,
+}
+
+// `None` while the chat is being handled, forgotten at the instant
+type AcceptedEntry = (Option<ChatStatus>, Instant);
+
+#[derive(Default)]
+pub struct MemoryMessageQueue {
+    queues: Mutex<Queues>,
+    accepted: Mutex<HashMap<(String, String), AcceptedEntry>>,
+}
+
+impl MemoryMessageQueue {
+    pub fn new() -> Arc<Self> {
+        Arc::new(Self::default())
+    }
+
+    fn read_queue(&self, queue: &str) -> Vec<QueuedMessage> {
+        let queues = self.queues.lock().unwrap();
+        queues.entries.get(queue).map_or_else(Vec::new, |entries| {
+            entries.iter().map(|(id, message)| QueuedMessage {
+                id: id.clone(),
+                queue: queue.to_string(),
+                message: message.clone(),
+            }).collect()
+        })
+    }
+}
+
+#[async_trait]
+impl MessageQueue for MemoryMessageQueue {
+    async fn acquire_queue(&self, address: &str, device_id: &str) -> NavajoResult<Vec<QueuedMessage>> {
+        let mut messages = self.read_queue(&queue_name(address, Some(device_id)));
+        messages.extend(self.read_queue(&queue_name(address, None)));
+        Ok(messages)
+    }
+
+    async fn add_queue(&self, message: &Message, device_id: Option<&str>) -> NavajoResult<()> {
+        let to_address = match queued_recipient(message) {
+            Some(to_address) => to_address,
+            None => return Ok(()),
+        };
+        let queue = queue_name(to_address, device_id);
+        let mut queues = self.queues.lock().unwrap();
+        queues.next_id += 1;
+        let id = queues.next_id.to_string();
+        let entries = queues.entries.entry(queue.clone()).or_default();
+        entries.push_back((id, message.clone()));
+        if entries.len() > MAX_QUEUE_SIZE {
+            entries.pop_front();
+            println!("Queue of {:?} is full, dropped its oldest message", queue);
+        }
+        Ok(())
+    }
+
+    async fn remove_queue(&self, address: &str, device_id: &str) {
+        self.queues.lock().unwrap().entries.remove(&queue_name(address, Some(device_id)));
+    }
+
+    async fn ack(&self, delivered: &[QueuedMessage]) -> NavajoResult<()> {
+        let mut queues = self.queues.lock().unwrap();
+        for message in delivered {
+            if let Some(entries) = queues.entries.get_mut(&message.queue) {
+                entries.retain(|(id, _)| *id != message.id);
+            }
+        }
+        queues.entries.retain(|_, entries| !entries.is_empty());
+        Ok(())
+    }
+
+    async fn accept(&self, from_address: &str, request_id: &str) -> NavajoResult<Accepted> {
+        let mut accepted = self.accepted.lock().unwrap();
+        let key = (from_address.to_string(), request_id.to_string());
+        let now = Instant::now();
+        match accepted.get(&key) {
+            Some((Some(status), expire)) if *expire > now => Ok(Accepted::Done(*status)),
+            Some((None, expire)) if *expire > now => Ok(Accepted::InProgress),
+            _ => {
+                accepted.insert(key, (None, now + Duration::from_secs(CHAT_ACCEPTING_EXPIRE_SECONDS)));
+                Ok(Accepted::New)
+            }
+        }
+    }
+
+    async fn set_accepted_status(&self, from_address: &str, request_id: &str, status: ChatStatus) {
+        let key = (from_address.to_string(), request_id.to_string());
+        let expire = Instant::now() + Duration::from_secs(CHAT_ACCEPTED_EXPIRE_SECONDS);
+        self.accepted.lock().unwrap().insert(key, (Some(status), expire));
+    }
+
+    async fn release(&self, from_address: &str, request_id: &str) {
+        let key = (from_address.to_string(), request_id.to_string());
+        self.accepted.lock().unwrap().remove(&key);
+    }
+}
+
+#[derive(Default)]
+pub struct MemoryNonceCache {
+    expiries: Mutex<HashMap<String, Instant>>,
+}
+
+impl MemoryNonceCache {
+    pub fn new() -> Arc<Self> {
+        Arc::new(Self::default())
+    }
+}
+
+#[async_trait]
+impl NonceCache for MemoryNonceCache {
+    async fn insert(&self, key: &str, secs: u64) -> NavajoResult<bool> {
+        let now = Instant::now();
+        let mut expiries = self.expiries.lock().unwrap();
+        expiries.retain(|_, expiry| *expiry > now);
+        if expiries.contains_key(key) {
+            return Ok(false);
+        }
+        expiries.insert(key.to_string(), now + Duration::from_secs(secs));
+        Ok(true)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::store::memory::{MemoryMessageQueue, MemoryNonceCache, MemoryUserStore};
+    use crate::store::tests::{check_message_queue, check_nonce_cache, check_user_store};
+
+    #[actix_rt::test]
+    async fn test_memory_user_store() {
+        check_user_store(MemoryUserStore::new().as_ref()).await;
+    }
+
+    #[actix_rt::test]
+    async fn test_memory_queue() {
+        check_message_queue(MemoryMessageQueue::new().as_ref()).await;
+    }
+
+    #[actix_rt::test]
+    async fn test_memory_nonce_cache() {
+        check_nonce_cache(MemoryNonceCache::new().as_ref()).await;
+    }
+}
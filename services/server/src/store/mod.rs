@@ -0,0 +1,323 @@
+use std::sync::Arc;
+use async_trait::async_trait;
+use common::beans::OneTimePrekey;
+use common::errors::NavajoResult;
+use p2p::message::{ChatStatus, Message};
+use crate::db::{connect_mysql, RedisConfig};
+use crate::db::models::{Device, IdentityKey, User};
+use crate::db::postgres::PostgresStore;
+use crate::db::prekey_repository::PrekeyRepository;
+use crate::db::redis::RedisClient;
+use crate::db::user_store::MysqlUserStore;
+use crate::queue::QueueManager;
+use crate::store::cache::CachedUserStore;
+use crate::store::memory::{MemoryMessageQueue, MemoryNonceCache, MemoryPrekeyStore, MemoryUserStore};
+use crate::store::sqlite::SqliteStore;
+
+pub mod cache;
+pub mod memory;
+pub mod sqlite;
+
+/// Most messages queued for one recipient, the oldest are dropped to make room.
+pub const MAX_QUEUE_SIZE: usize = 1000;
+
+pub const CHAT_MESSAGE_EXPIRE_SECONDS: u64 = 180 * 24 * 60 * 60; // 180 days
+
+/// How long a chat's `request_id` is remembered, clients stop resending before that.
+pub const CHAT_ACCEPTED_EXPIRE_SECONDS: u64 = 7 * 24 * 60 * 60; // 7 days
+
+/// How long a claim lasts until the chat gets its status. A chat whose handling was lost,
+/// to a crash or a lost forward, is handled again when resent after that. Short in tests,
+/// so that they can wait it out.
+pub const CHAT_ACCEPTING_EXPIRE_SECONDS: u64 = if cfg!(test) { 1 } else { 60 };
+
+/// The backends the server runs on.
+#[derive(Clone)]
+pub struct Stores {
+    pub user_store: Arc<dyn UserStore>,
+    pub prekey_store: Arc<dyn PrekeyStore>,
+    pub message_queue: Arc<dyn MessageQueue>,
+    pub nonce_cache: Arc<dyn NonceCache>,
+}
+
+impl Stores {
+    pub fn mysql(url: &str, redis: RedisConfig) -> NavajoResult<Self> {
+        let mysql_pool = connect_mysql(url);
+        let redis_client = RedisClient::new(redis)?;
+        Ok(Self {
+            user_store: CachedUserStore::new(MysqlUserStore::new(mysql_pool.clone())),
+            prekey_store: PrekeyRepository::new(mysql_pool),
+            message_queue: QueueManager::new(redis_client.clone()),
+            nonce_cache: redis_client,
+        })
+    }
+
+    pub async fn postgres(url: &str, redis: RedisConfig) -> NavajoResult<Self> {
+        let store = PostgresStore::connect(url).await?;
+        let redis_client = RedisClient::new(redis)?;
+        Ok(Self {
+            user_store: CachedUserStore::new(store.clone()),
+            prekey_store: store,
+            message_queue: QueueManager::new(redis_client.clone()),
+            nonce_cache: redis_client,
+        })
+    }
+
+    /// Everything in one SQLite file.
+    pub fn sqlite(path: &str) -> NavajoResult<Self> {
+        let store = SqliteStore::open(path)?;
+        Ok(Self {
+            user_store: CachedUserStore::new(store.clone()),
+            prekey_store: store.clone(),
+            message_queue: store.clone(),
+            nonce_cache: store,
+        })
+    }
+
+    pub fn memory() -> Self {
+        Self {
+            user_store: MemoryUserStore::new(),
+            prekey_store: MemoryPrekeyStore::new(),
+            message_queue: MemoryMessageQueue::new(),
+            nonce_cache: MemoryNonceCache::new(),
+        }
+    }
+}
+
+/// Accounts, keyed by address, and their devices, keyed by address and device_id.
+/// Accounts can't see or change devices of another one with the same device_id.
+#[async_trait]
+pub trait UserStore: Send + Sync {
+    async fn find_user(&self, address: &str) -> NavajoResult<Option<User>>;
+
+    async fn save_user(&self, user: &User) -> NavajoResult<()>;
+
+    async fn find_devices(&self, address: &str) -> NavajoResult<Vec<Device>>;
+
+    async fn find_device(&self, address: &str, device_id: &str) -> NavajoResult<Option<Device>>;
+
+    async fn find_device_by_session(&self, session: &str) -> NavajoResult<Option<Device>>;
+
+    async fn save_device(&self, device: &Device) -> NavajoResult<()>;
+
+    async fn touch_device(&self, address: &str, device_id: &str, last_seen_ms: u64) -> NavajoResult<()>;
+
+    /// Clears the session, unless it was replaced in the meantime.
+    async fn revoke_device_session(&self, address: &str, device_id: &str, session: &str) -> NavajoResult<()>;
+
+    async fn remove_device(&self, address: &str, device_id: &str) -> NavajoResult<()>;
+
+    /// Drops anything cached of the device and of `address`, after another node changed them.
+    fn forget_cached(&self, _address: &str, _device_id: &str) {}
+}
+
+/// Identity keys and one-time prekeys uploaded for X3DH.
+#[async_trait]
+pub trait PrekeyStore: Send + Sync {
+    async fn find_identity_key(&self, address: &str) -> Option<IdentityKey>;
+
+    async fn save_identity_key(&self, key: &IdentityKey) -> NavajoResult<()>;
+
+    /// A new identity key invalidates every one-time prekey uploaded with the old one.
+    async fn remove_one_time_prekeys(&self, address: &str) -> NavajoResult<()>;
+
+    /// Prekeys whose `key_id` is already stored are skipped.
+    async fn add_one_time_prekeys(&self, address: &str, prekeys: &[OneTimePrekey]) -> NavajoResult<()>;
+
+    async fn count_one_time_prekeys(&self, address: &str) -> NavajoResult<u32>;
+
+    /// Removes and returns the oldest one-time prekey, so that each one is handed out once.
+    async fn take_one_time_prekey(&self, address: &str) -> NavajoResult<Option<OneTimePrekey>>;
+}
+
+/// A message waiting for its recipient, `id` acknowledges it in `queue` once delivered.
+#[derive(Debug)]
+pub struct QueuedMessage {
+    pub id: String,
+    pub queue: String,
+    pub message: Message,
+}
+
+/// What `MessageQueue::accept` knows of a chat's `request_id`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Accepted {
+    /// Claimed now, the caller handles the chat.
+    New,
+    /// Claimed by another copy of the chat, still being handled.
+    InProgress,
+    /// Handled already, with the status it got.
+    Done(ChatStatus),
+}
+
+/// Offline messages, one queue per recipient device. Messages for an address without
+/// devices wait in a queue of the address, for whichever device connects first.
+/// Also remembers which chats were accepted, so that resent ones are handled once.
+#[async_trait]
+pub trait MessageQueue: Send + Sync {
+    /// Messages still waiting for the device, oldest first. They stay queued until acknowledged.
+    async fn acquire_queue(&self, address: &str, device_id: &str) -> NavajoResult<Vec<QueuedMessage>>;
+
+    /// Queues chats and receipts for one device of the recipient, or for the address without
+    /// `device_id`. Other messages are only meaningful while both sides are online.
+    async fn add_queue(&self, message: &Message, device_id: Option<&str>) -> NavajoResult<()>;
+
+    /// Drops everything queued for a removed device.
+    async fn remove_queue(&self, address: &str, device_id: &str);
+
+    /// Removes delivered messages, anything not acknowledged is delivered again next time.
+    async fn ack(&self, delivered: &[QueuedMessage]) -> NavajoResult<()>;
+
+    /// Claims `request_id` of a chat from `from_address` for `CHAT_ACCEPTING_EXPIRE_SECONDS`,
+    /// unless it is claimed or has its status already.
+    async fn accept(&self, from_address: &str, request_id: &str) -> NavajoResult<Accepted>;
+
+    /// Remembers the status for `CHAT_ACCEPTED_EXPIRE_SECONDS`.
+    async fn set_accepted_status(&self, from_address: &str, request_id: &str, status: ChatStatus);
+
+    /// Forgets a claimed `request_id` that couldn't be handled, so a resend is handled again.
+    async fn release(&self, from_address: &str, request_id: &str);
+}
+
+/// Keys remembered for a while, for replay protection.
+#[async_trait]
+pub trait NonceCache: Send + Sync {
+    /// Remembers `key` for `secs`, returns false if it is remembered already.
+    async fn insert(&self, key: &str, secs: u64) -> NavajoResult<bool>;
+}
+
+/// Name of the queue of a device, or of the address without one.
+pub fn queue_name(address: &str, device_id: Option<&str>) -> String {
+    match device_id {
+        Some(device_id) => format!("{}/{}", address, device_id),
+        None => address.to_string(),
+    }
+}
+
+/// Only chats and receipts are queued, returns their recipient.
+pub fn queued_recipient(message: &Message) -> Option<&str> {
+    match message {
+        Message::ChatInfoMessage { to_address, .. } | Message::ReceiptMessage { to_address, .. } => Some(to_address),
+        _ => None,
+    }
+}
+
+#[cfg(test)]
+pub mod tests {
+    use std::time::Duration;
+    use tokio::time::sleep;
+    use uuid::Uuid;
+    use p2p::message::{CHAT_STATUS_DELIVERED, CHAT_STATUS_QUEUED, Message};
+    use crate::db::models::Device;
+    use crate::store::{Accepted, CHAT_ACCEPTING_EXPIRE_SECONDS, MAX_QUEUE_SIZE, MessageQueue, NonceCache, UserStore};
+
+    fn chat(from: &str, to: &str) -> Message {
+        Message::ChatInfoMessage {
+            common_info: Default::default(),
+            from_address: from.to_string(),
+            to_address: to.to_string(),
+            info_type: 0,
+            content: String::from("hi"),
+        }
+    }
+
+    /// What every `MessageQueue` must do, with fresh addresses so that a shared backend can be reused.
+    pub async fn check_message_queue(queue: &dyn MessageQueue) {
+        let alice = Uuid::new_v4().to_string();
+        let bob = Uuid::new_v4().to_string();
+        let first = chat(&alice, &bob);
+        queue.add_queue(&first, Some("phone")).await.unwrap();
+        queue.add_queue(&chat(&alice, &bob), None).await.unwrap();
+        queue.add_queue(&chat(&alice, &bob), Some("laptop")).await.unwrap();
+        // Only chats and receipts are queued
+        queue.add_queue(&Message::chat_status(&first, CHAT_STATUS_QUEUED).unwrap(), None).await.unwrap();
+
+        // The device's own queue first, then the one of the address
+        let queued = queue.acquire_queue(&bob, "phone").await.unwrap();
+        assert_eq!(queued.len(), 2);
+        assert_eq!(queued[0].message.common_info().request_id, first.common_info().request_id);
+        assert_eq!(queued[1].queue, bob);
+
+        queue.ack(&queued).await.unwrap();
+        assert!(queue.acquire_queue(&bob, "phone").await.unwrap().is_empty());
+        assert_eq!(queue.acquire_queue(&bob, "laptop").await.unwrap().len(), 1);
+        queue.remove_queue(&bob, "laptop").await;
+        assert!(queue.acquire_queue(&bob, "laptop").await.unwrap().is_empty());
+
+        let carol = Uuid::new_v4().to_string();
+        for _ in 0..MAX_QUEUE_SIZE + 1 {
+            queue.add_queue(&chat(&alice, &carol), None).await.unwrap();
+        }
+        assert_eq!(queue.acquire_queue(&carol, "phone").await.unwrap().len(), MAX_QUEUE_SIZE);
+
+        let request_id = Uuid::new_v4().to_string();
+        assert_eq!(queue.accept(&alice, &request_id).await.unwrap(), Accepted::New);
+        assert_eq!(queue.accept(&alice, &request_id).await.unwrap(), Accepted::InProgress);
+        queue.release(&alice, &request_id).await;
+        assert_eq!(queue.accept(&alice, &request_id).await.unwrap(), Accepted::New);
+
+        // A claim that never got a status lapses, a resend is handled again. One with a status
+        // is kept much longer.
+        sleep(Duration::from_millis(CHAT_ACCEPTING_EXPIRE_SECONDS * 1000 + 100)).await;
+        assert_eq!(queue.accept(&alice, &request_id).await.unwrap(), Accepted::New);
+        queue.set_accepted_status(&alice, &request_id, CHAT_STATUS_DELIVERED).await;
+        sleep(Duration::from_millis(CHAT_ACCEPTING_EXPIRE_SECONDS * 1000 + 100)).await;
+        assert_eq!(queue.accept(&alice, &request_id).await.unwrap(), Accepted::Done(CHAT_STATUS_DELIVERED));
+    }
+
+    fn device(address: &str, device_id: &str) -> Device {
+        Device {
+            address: address.to_string(),
+            device_id: device_id.to_string(),
+            session: Uuid::new_v4().to_string(),
+            secret: String::from("secret"),
+            session_expire_ms: u64::MAX / 2,
+            ..Default::default()
+        }
+    }
+
+    /// What every `NonceCache` must do, with fresh keys so that a shared backend can be reused.
+    pub async fn check_nonce_cache(cache: &dyn NonceCache) {
+        let nonce = Uuid::new_v4().to_string();
+        assert!(cache.insert(&nonce, 60).await.unwrap());
+        assert!(!cache.insert(&nonce, 60).await.unwrap());
+        // Redis takes no shorter expiry
+        let expiring = Uuid::new_v4().to_string();
+        assert!(cache.insert(&expiring, 1).await.unwrap());
+        sleep(Duration::from_millis(1100)).await;
+        assert!(cache.insert(&expiring, 1).await.unwrap());
+    }
+
+    /// What every `UserStore` must do with devices, with fresh addresses so that a shared backend can be reused.
+    pub async fn check_user_store(store: &dyn UserStore) {
+        let alice = Uuid::new_v4().to_string();
+        let bob = Uuid::new_v4().to_string();
+        let phone = device(&bob, "phone");
+        store.save_device(&phone).await.unwrap();
+        store.save_device(&device(&bob, "laptop")).await.unwrap();
+        assert_eq!(store.find_devices(&bob).await.unwrap().len(), 2);
+        store.touch_device(&bob, "phone", 42).await.unwrap();
+        let found = store.find_device_by_session(&phone.session).await.unwrap().unwrap();
+        assert_eq!((found.address.as_str(), found.last_seen_ms), (bob.as_str(), 42));
+        assert_eq!(found.session_expire_ms, u64::MAX / 2);
+
+        // Another account with the same device_id has a device of its own
+        store.save_device(&device(&alice, "phone")).await.unwrap();
+        assert_eq!(store.find_devices(&alice).await.unwrap().len(), 1);
+        assert_eq!(store.find_devices(&bob).await.unwrap().len(), 2);
+        assert_eq!(store.find_device(&bob, "phone").await.unwrap().unwrap().session, phone.session);
+        store.revoke_device_session(&alice, "phone", &phone.session).await.unwrap();
+        assert!(store.find_device_by_session(&phone.session).await.unwrap().is_some());
+
+        store.revoke_device_session(&bob, "phone", &phone.session).await.unwrap();
+        assert!(store.find_device(&bob, "phone").await.unwrap().unwrap().session_revoked());
+        assert!(store.find_device_by_session(&phone.session).await.unwrap().is_none());
+
+        store.remove_device(&bob, "phone").await.unwrap();
+        assert!(store.find_device(&bob, "phone").await.unwrap().is_none());
+        assert!(store.find_device(&alice, "phone").await.unwrap().is_some());
+
+        // A removed device may log in again, as a new device
+        store.save_device(&device(&bob, "phone")).await.unwrap();
+        assert!(store.find_device(&bob, "phone").await.unwrap().is_some());
+    }
+}
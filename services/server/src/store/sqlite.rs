@@ -0,0 +1,484 @@
+use std::sync::{Arc, Mutex};
+use std::time::{SystemTime, UNIX_EPOCH};
+use async_trait::async_trait;
+use rusqlite::{Connection, OptionalExtension, params, Row};
+use tokio::task::spawn_blocking;
+use common::beans::OneTimePrekey;
+use common::errors::{DB_ERROR, NavajoError, NavajoResult};
+use p2p::message::{ChatStatus, Message};
+use crate::db::models::{Device, IdentityKey, User};
+use crate::store::{Accepted, CHAT_ACCEPTED_EXPIRE_SECONDS, CHAT_ACCEPTING_EXPIRE_SECONDS, CHAT_MESSAGE_EXPIRE_SECONDS, MAX_QUEUE_SIZE, MessageQueue, NonceCache, PrekeyStore, queue_name, queued_recipient, QueuedMessage, UserStore};
+
+/// Applied in order, `PRAGMA user_version` counts the ones a database has.
+const MIGRATIONS: &[&str] = &[
+    include_str!("../../migrations/sqlite/01_initial_data.sql"),
+    include_str!("../../migrations/sqlite/02_device_keys.sql"),
+    include_str!("../../migrations/sqlite/03_nonce.sql"),
+];
+
+const DEVICE_COLUMNS: &str = "id, address, device_id, session, secret, session_expire_ms, last_seen_ms";
+
+/// Users, prekeys, offline messages and nonces in a single SQLite file, for small installs
+/// that don't want to run MySQL and Redis. Queries run one at a time on one connection,
+/// on the blocking pool so that they don't hold up the async runtime.
+pub struct SqliteStore {
+    conn: Arc<Mutex<Connection>>,
+}
+
+impl SqliteStore {
+    /// Opens or creates the database at `path`, `:memory:` keeps it in memory.
+    pub fn open(path: &str) -> NavajoResult<Arc<Self>> {
+        let mut conn = Connection::open(path).map_err(db_error)?;
+        conn.pragma_update(None, "journal_mode", "WAL").map_err(db_error)?;
+        conn.pragma_update(None, "synchronous", "NORMAL").map_err(db_error)?;
+        migrate(&mut conn).map_err(db_error)?;
+        Ok(Arc::new(Self { conn: Arc::new(Mutex::new(conn)) }))
+    }
+
+    /// Runs `query` on the connection, off the async runtime.
+    async fn run<T, F>(&self, query: F) -> NavajoResult<T>
+    where
+        T: Send + 'static,
+        F: FnOnce(&mut Connection) -> rusqlite::Result<T> + Send + 'static,
+    {
+        let conn = self.conn.clone();
+        spawn_blocking(move || query(&mut conn.lock().unwrap()))
+            .await.map_err(|_| NavajoError::new(DB_ERROR))?
+            .map_err(db_error)
+    }
+}
+
+fn migrate(conn: &mut Connection) -> rusqlite::Result<()> {
+    let version: usize = conn.query_row("PRAGMA user_version", [], |row| row.get(0))?;
+    for (index, migration) in MIGRATIONS.iter().enumerate().skip(version) {
+        let tx = conn.transaction()?;
+        tx.execute_batch(migration)?;
+        tx.pragma_update(None, "user_version", index + 1)?;
+        tx.commit()?;
+        println!("Applied SQLite migration {}", index + 1);
+    }
+    Ok(())
+}
+
+fn db_error(_: rusqlite::Error) -> NavajoError {
+    NavajoError::new(DB_ERROR)
+}
+
+fn now_ms() -> u64 {
+    SystemTime::now().duration_since(UNIX_EPOCH).unwrap().as_millis() as u64
+}
+
+fn device_from_row(row: &Row) -> rusqlite::Result<Device> {
+    Ok(Device {
+        id: row.get(0)?,
+        address: row.get(1)?,
+        device_id: row.get(2)?,
+        session: row.get(3)?,
+        secret: row.get(4)?,
+        session_expire_ms: row.get(5)?,
+        last_seen_ms: row.get(6)?,
+    })
+}
+
+#[async_trait]
+impl UserStore for SqliteStore {
+    async fn find_user(&self, address: &str) -> NavajoResult<Option<User>> {
+        let address = address.to_string();
+        self.run(move |conn| conn.query_row(
+            "SELECT id, address, device_id, session, secret, public_key, session_expire_ms FROM user WHERE address = ?1",
+            params![address],
+            |row| Ok(User {
+                id: row.get(0)?,
+                address: row.get(1)?,
+                device_id: row.get(2)?,
+                session: row.get(3)?,
+                secret: row.get(4)?,
+                public_key: row.get(5)?,
+                session_expire_ms: row.get(6)?,
+            }),
+        ).optional()).await
+    }
+
+    async fn save_user(&self, user: &User) -> NavajoResult<()> {
+        let user = user.clone();
+        self.run(move |conn| conn.execute(
+            r"INSERT INTO user(address, device_id, session, secret, public_key, session_expire_ms) VALUES (?1, ?2, ?3, ?4, ?5, ?6)
+              ON CONFLICT(address) DO UPDATE SET device_id = excluded.device_id, session = excluded.session,
+              secret = excluded.secret, public_key = excluded.public_key, session_expire_ms = excluded.session_expire_ms",
+            params![user.address, user.device_id, user.session, user.secret, user.public_key, user.session_expire_ms],
+        ).map(|_| ())).await
+    }
+
+    async fn find_devices(&self, address: &str) -> NavajoResult<Vec<Device>> {
+        let address = address.to_string();
+        self.run(move |conn| {
+            let sql = format!("SELECT {} FROM device WHERE address = ?1", DEVICE_COLUMNS);
+            let mut statement = conn.prepare(&sql)?;
+            let devices = statement.query_map(params![address], device_from_row)?;
+            devices.collect()
+        }).await
+    }
+
+    async fn find_device(&self, address: &str, device_id: &str) -> NavajoResult<Option<Device>> {
+        let (address, device_id) = (address.to_string(), device_id.to_string());
+        self.run(move |conn| {
+            let sql = format!("SELECT {} FROM device WHERE address = ?1 AND device_id = ?2", DEVICE_COLUMNS);
+            conn.query_row(&sql, params![address, device_id], device_from_row).optional()
+        }).await
+    }
+
+    async fn find_device_by_session(&self, session: &str) -> NavajoResult<Option<Device>> {
+        let session = session.to_string();
+        self.run(move |conn| {
+            let sql = format!("SELECT {} FROM device WHERE session = ?1", DEVICE_COLUMNS);
+            conn.query_row(&sql, params![session], device_from_row).optional()
+        }).await
+    }
+
+    async fn save_device(&self, device: &Device) -> NavajoResult<()> {
+        let device = device.clone();
+        self.run(move |conn| conn.execute(
+            r"INSERT INTO device(address, device_id, session, secret, session_expire_ms, last_seen_ms) VALUES (?1, ?2, ?3, ?4, ?5, ?6)
+              ON CONFLICT(address, device_id) DO UPDATE SET session = excluded.session, secret = excluded.secret,
+              session_expire_ms = excluded.session_expire_ms, last_seen_ms = excluded.last_seen_ms",
+            params![device.address, device.device_id, device.session, device.secret, device.session_expire_ms, device.last_seen_ms],
+        ).map(|_| ())).await
+    }
+
+    async fn touch_device(&self, address: &str, device_id: &str, last_seen_ms: u64) -> NavajoResult<()> {
+        let (address, device_id) = (address.to_string(), device_id.to_string());
+        self.run(move |conn| conn.execute(
+            "UPDATE device SET last_seen_ms = ?1 WHERE address = ?2 AND device_id = ?3",
+            params![last_seen_ms, address, device_id],
+        ).map(|_| ())).await
+    }
+
+    async fn revoke_device_session(&self, address: &str, device_id: &str, session: &str) -> NavajoResult<()> {
+        let (address, device_id, session) = (address.to_string(), device_id.to_string(), session.to_string());
+        self.run(move |conn| conn.execute(
+            "UPDATE device SET session = '', secret = '', session_expire_ms = 0 WHERE address = ?1 AND device_id = ?2 AND session = ?3",
+            params![address, device_id, session],
+        ).map(|_| ())).await
+    }
+
+    async fn remove_device(&self, address: &str, device_id: &str) -> NavajoResult<()> {
+        let (address, device_id) = (address.to_string(), device_id.to_string());
+        self.run(move |conn| conn.execute(
+            "DELETE FROM device WHERE address = ?1 AND device_id = ?2",
+            params![address, device_id],
+        ).map(|_| ())).await
+    }
+}
+
+#[async_trait]
+impl PrekeyStore for SqliteStore {
+    async fn find_identity_key(&self, address: &str) -> Option<IdentityKey> {
+        let address = address.to_string();
+        self.run(move |conn| conn.query_row(
+            r"SELECT id, address, public_key, identity_key, identity_key_sign, signed_prekey_id, signed_prekey, signed_prekey_sign
+              FROM identity_key WHERE address = ?1",
+            params![address],
+            |row| Ok(IdentityKey {
+                id: row.get(0)?,
+                address: row.get(1)?,
+                public_key: row.get(2)?,
+                identity_key: row.get(3)?,
+                identity_key_sign: row.get(4)?,
+                signed_prekey_id: row.get(5)?,
+                signed_prekey: row.get(6)?,
+                signed_prekey_sign: row.get(7)?,
+            }),
+        ).optional()).await.ok().flatten()
+    }
+
+    async fn save_identity_key(&self, key: &IdentityKey) -> NavajoResult<()> {
+        let key = key.clone();
+        self.run(move |conn| conn.execute(
+            r"INSERT INTO identity_key(address, public_key, identity_key, identity_key_sign, signed_prekey_id, signed_prekey, signed_prekey_sign)
+              VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7)
+              ON CONFLICT(address) DO UPDATE SET public_key = excluded.public_key, identity_key = excluded.identity_key,
+              identity_key_sign = excluded.identity_key_sign, signed_prekey_id = excluded.signed_prekey_id,
+              signed_prekey = excluded.signed_prekey, signed_prekey_sign = excluded.signed_prekey_sign",
+            params![
+                key.address,
+                key.public_key,
+                key.identity_key,
+                key.identity_key_sign,
+                key.signed_prekey_id,
+                key.signed_prekey,
+                key.signed_prekey_sign,
+            ],
+        ).map(|_| ())).await
+    }
+
+    async fn remove_one_time_prekeys(&self, address: &str) -> NavajoResult<()> {
+        let address = address.to_string();
+        self.run(move |conn| conn.execute("DELETE FROM one_time_prekey WHERE address = ?1", params![address]).map(|_| ())).await
+    }
+
+    async fn add_one_time_prekeys(&self, address: &str, prekeys: &[OneTimePrekey]) -> NavajoResult<()> {
+        let (address, prekeys) = (address.to_string(), prekeys.to_vec());
+        self.run(move |conn| {
+            let tx = conn.transaction()?;
+            for prekey in prekeys {
+                tx.execute(
+                    "INSERT OR IGNORE INTO one_time_prekey(address, key_id, prekey) VALUES (?1, ?2, ?3)",
+                    params![address, prekey.key_id, prekey.prekey],
+                )?;
+            }
+            tx.commit()
+        }).await
+    }
+
+    async fn count_one_time_prekeys(&self, address: &str) -> NavajoResult<u32> {
+        let address = address.to_string();
+        self.run(move |conn| conn.query_row(
+            "SELECT COUNT(*) FROM one_time_prekey WHERE address = ?1",
+            params![address],
+            |row| row.get(0),
+        )).await
+    }
+
+    async fn take_one_time_prekey(&self, address: &str) -> NavajoResult<Option<OneTimePrekey>> {
+        let address = address.to_string();
+        self.run(move |conn| {
+            let tx = conn.transaction()?;
+            let row: Option<(i64, u32, String)> = tx.query_row(
+                "SELECT id, key_id, prekey FROM one_time_prekey WHERE address = ?1 ORDER BY id LIMIT 1",
+                params![address],
+                |row| Ok((row.get(0)?, row.get(1)?, row.get(2)?)),
+            ).optional()?;
+            let prekey = match row {
+                Some((id, key_id, prekey)) => {
+                    tx.execute("DELETE FROM one_time_prekey WHERE id = ?1", params![id])?;
+                    Some(OneTimePrekey { key_id, prekey })
+                }
+                None => None,
+            };
+            tx.commit()?;
+            Ok(prekey)
+        }).await
+    }
+}
+
+fn read_queue(conn: &Connection, queue: &str) -> rusqlite::Result<Vec<QueuedMessage>> {
+    let mut statement = conn.prepare(
+        "SELECT id, message FROM queued_message WHERE queue = ?1 AND created_ms >= ?2 ORDER BY id"
+    )?;
+    let min_created_ms = now_ms().saturating_sub(CHAT_MESSAGE_EXPIRE_SECONDS * 1000);
+    let rows = statement.query_map(params![queue, min_created_ms], |row| {
+        Ok((row.get::<_, i64>(0)?, row.get::<_, Vec<u8>>(1)?))
+    })?;
+    let mut messages = vec![];
+    for row in rows {
+        let (id, data) = row?;
+        match Message::try_from(data) {
+            Ok(message) => messages.push(QueuedMessage { id: id.to_string(), queue: queue.to_string(), message }),
+            // Left for the expiry to clean up
+            Err(_) => println!("Skip unreadable queued message {} for {:?}", id, queue),
+        }
+    }
+    Ok(messages)
+}
+
+#[async_trait]
+impl MessageQueue for SqliteStore {
+    async fn acquire_queue(&self, address: &str, device_id: &str) -> NavajoResult<Vec<QueuedMessage>> {
+        let device_queue = queue_name(address, Some(device_id));
+        let address_queue = queue_name(address, None);
+        self.run(move |conn| {
+            let mut messages = read_queue(conn, &device_queue)?;
+            messages.extend(read_queue(conn, &address_queue)?);
+            Ok(messages)
+        }).await
+    }
+
+    async fn add_queue(&self, message: &Message, device_id: Option<&str>) -> NavajoResult<()> {
+        let to_address = match queued_recipient(message) {
+            Some(to_address) => to_address,
+            None => return Ok(()),
+        };
+        let queue = queue_name(to_address, device_id);
+        let data: Vec<u8> = message.into();
+        let now = now_ms();
+        let dropped = self.run({
+            let queue = queue.clone();
+            move |conn| {
+                let tx = conn.transaction()?;
+                tx.execute(
+                    "INSERT INTO queued_message(queue, message, created_ms) VALUES (?1, ?2, ?3)",
+                    params![queue, data, now],
+                )?;
+                tx.execute(
+                    "DELETE FROM queued_message WHERE queue = ?1 AND created_ms < ?2",
+                    params![queue, now.saturating_sub(CHAT_MESSAGE_EXPIRE_SECONDS * 1000)],
+                )?;
+                let dropped = tx.execute(
+                    r"DELETE FROM queued_message WHERE queue = ?1 AND id NOT IN
+                      (SELECT id FROM queued_message WHERE queue = ?1 ORDER BY id DESC LIMIT ?2)",
+                    params![queue, MAX_QUEUE_SIZE],
+                )?;
+                tx.commit()?;
+                Ok(dropped)
+            }
+        }).await?;
+        if dropped > 0 {
+            println!("Queue of {:?} is full, dropped its oldest message", queue);
+        }
+        Ok(())
+    }
+
+    async fn remove_queue(&self, address: &str, device_id: &str) {
+        let queue = queue_name(address, Some(device_id));
+        let result = self.run({
+            let queue = queue.clone();
+            move |conn| conn.execute("DELETE FROM queued_message WHERE queue = ?1", params![queue])
+        }).await;
+        if let Err(err) = result {
+            println!("Remove queue {:?} failed, {}", queue, err);
+        }
+    }
+
+    async fn ack(&self, delivered: &[QueuedMessage]) -> NavajoResult<()> {
+        let ids = delivered.iter()
+            .map(|message| message.id.parse::<i64>().map_err(|_| NavajoError::new(DB_ERROR)))
+            .collect::<NavajoResult<Vec<_>>>()?;
+        self.run(move |conn| {
+            let tx = conn.transaction()?;
+            for id in ids {
+                tx.execute("DELETE FROM queued_message WHERE id = ?1", params![id])?;
+            }
+            tx.commit()
+        }).await
+    }
+
+    async fn accept(&self, from_address: &str, request_id: &str) -> NavajoResult<Accepted> {
+        let (from_address, request_id) = (from_address.to_string(), request_id.to_string());
+        let now = now_ms();
+        self.run(move |conn| {
+            let tx = conn.transaction()?;
+            tx.execute("DELETE FROM chat_accepted WHERE expire_ms <= ?1", params![now])?;
+            let fresh = tx.execute(
+                "INSERT OR IGNORE INTO chat_accepted(from_address, request_id, status, expire_ms) VALUES (?1, ?2, NULL, ?3)",
+                params![from_address, request_id, now + CHAT_ACCEPTING_EXPIRE_SECONDS * 1000],
+            )? == 1;
+            let accepted = if fresh {
+                Accepted::New
+            } else {
+                let status: Option<ChatStatus> = tx.query_row(
+                    "SELECT status FROM chat_accepted WHERE from_address = ?1 AND request_id = ?2",
+                    params![from_address, request_id],
+                    |row| row.get(0),
+                )?;
+                status.map_or(Accepted::InProgress, Accepted::Done)
+            };
+            tx.commit()?;
+            Ok(accepted)
+        }).await
+    }
+
+    async fn set_accepted_status(&self, from_address: &str, request_id: &str, status: ChatStatus) {
+        let (from_address, owned_request_id) = (from_address.to_string(), request_id.to_string());
+        let result = self.run(move |conn| conn.execute(
+            "INSERT OR REPLACE INTO chat_accepted(from_address, request_id, status, expire_ms) VALUES (?1, ?2, ?3, ?4)",
+            params![from_address, owned_request_id, status, now_ms() + CHAT_ACCEPTED_EXPIRE_SECONDS * 1000],
+        )).await;
+        if let Err(err) = result {
+            println!("Set status of {:?} failed, {}", request_id, err);
+        }
+    }
+
+    async fn release(&self, from_address: &str, request_id: &str) {
+        let (from_address, owned_request_id) = (from_address.to_string(), request_id.to_string());
+        let result = self.run(move |conn| conn.execute(
+            "DELETE FROM chat_accepted WHERE from_address = ?1 AND request_id = ?2",
+            params![from_address, owned_request_id],
+        )).await;
+        if let Err(err) = result {
+            println!("Release {:?} failed, {}", request_id, err);
+        }
+    }
+}
+
+#[async_trait]
+impl NonceCache for SqliteStore {
+    async fn insert(&self, key: &str, secs: u64) -> NavajoResult<bool> {
+        let key = key.to_string();
+        let now = now_ms();
+        self.run(move |conn| {
+            let tx = conn.transaction()?;
+            tx.execute("DELETE FROM nonce WHERE expire_ms <= ?1", params![now])?;
+            let fresh = tx.execute(
+                "INSERT OR IGNORE INTO nonce(key, expire_ms) VALUES (?1, ?2)",
+                params![key, now + secs * 1000],
+            )? == 1;
+            tx.commit()?;
+            Ok(fresh)
+        }).await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::env;
+    use uuid::Uuid;
+    use common::beans::OneTimePrekey;
+    use crate::db::models::User;
+    use crate::store::{NonceCache, PrekeyStore, UserStore};
+    use crate::store::sqlite::{MIGRATIONS, SqliteStore};
+    use crate::store::tests::{check_message_queue, check_nonce_cache, check_user_store};
+
+    #[actix_rt::test]
+    async fn test_sqlite_user_store() {
+        let store = SqliteStore::open(":memory:").unwrap();
+        let user = User {
+            address: String::from("alice"),
+            public_key: String::from("key"),
+            ..Default::default()
+        };
+        store.save_user(&user).await.unwrap();
+        store.save_user(&User { public_key: String::from("new key"), ..user.clone() }).await.unwrap();
+        assert_eq!(store.find_user("alice").await.unwrap().unwrap().public_key, "new key");
+
+        check_user_store(store.as_ref()).await;
+
+        let prekeys = [1, 2, 1].map(|key_id| OneTimePrekey { key_id, prekey: key_id.to_string() });
+        store.add_one_time_prekeys("alice", &prekeys).await.unwrap();
+        assert_eq!(store.count_one_time_prekeys("alice").await.unwrap(), 2);
+        assert_eq!(store.take_one_time_prekey("alice").await.unwrap().unwrap().key_id, 1);
+        store.remove_one_time_prekeys("alice").await.unwrap();
+        assert!(store.take_one_time_prekey("alice").await.unwrap().is_none());
+    }
+
+    #[actix_rt::test]
+    async fn test_sqlite_queue() {
+        check_message_queue(SqliteStore::open(":memory:").unwrap().as_ref()).await;
+    }
+
+    #[actix_rt::test]
+    async fn test_sqlite_nonce_cache() {
+        check_nonce_cache(SqliteStore::open(":memory:").unwrap().as_ref()).await;
+    }
+
+    #[actix_rt::test]
+    async fn test_sqlite_reopen() {
+        let path = env::temp_dir().join(format!("navajo-{}.db", Uuid::new_v4()));
+        let path = path.to_str().unwrap();
+        let store = SqliteStore::open(path).unwrap();
+        store.save_user(&User { address: String::from("alice"), ..Default::default() }).await.unwrap();
+        assert!(store.insert("nonce", 60).await.unwrap());
+        drop(store);
+
+        // Migrations already applied are skipped
+        let store = SqliteStore::open(path).unwrap();
+        let version: usize = store.conn.lock().unwrap().query_row("PRAGMA user_version", [], |row| row.get(0)).unwrap();
+        assert_eq!(version, MIGRATIONS.len());
+        assert!(store.find_user("alice").await.unwrap().is_some());
+        // Nonces seen before a restart are still refused
+        assert!(!store.insert("nonce", 60).await.unwrap());
+        drop(store);
+        for suffix in ["", "-wal", "-shm"] {
+            let _ = std::fs::remove_file(format!("{}{}", path, suffix));
+        }
+    }
+}